@@ -0,0 +1,34 @@
+use std::env;
+use std::path::Path;
+use std::process::Command;
+
+/// Embeds build metadata printed by `dotman version`. Values which can't be retrieved,
+/// e.g. the commit of a source tarball, are `unknown`.
+fn main() {
+    let commit = output("git", &["rev-parse", "--short=12", "HEAD"]);
+    let rustc = output(
+        &env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string()),
+        &["--version"],
+    );
+    let target = env::var("TARGET").unwrap_or_else(|_| "unknown".to_string());
+    println!("cargo:rustc-env=DOTMAN_GIT_COMMIT={}", commit);
+    println!("cargo:rustc-env=DOTMAN_RUSTC_VERSION={}", rustc);
+    println!("cargo:rustc-env=DOTMAN_TARGET={}", target);
+    for path in &[".git/HEAD", ".git/refs"] {
+        if Path::new(path).exists() {
+            println!("cargo:rerun-if-changed={}", path);
+        }
+    }
+    println!("cargo:rerun-if-changed=build.rs");
+}
+
+fn output(program: &str, args: &[&str]) -> String {
+    Command::new(program)
+        .args(args)
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string())
+        .filter(|output| !output.is_empty())
+        .unwrap_or_else(|| "unknown".to_string())
+}
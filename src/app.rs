@@ -1,398 +1,5669 @@
+use crate::audit::{AuditEntry, AuditLog};
+use crate::backup::BackupStore;
+use crate::config::{Config, SymlinkStyle};
+use crate::error::ErrorKind;
+use crate::lock::Lock;
+use crate::oplog::{Move, OpLog, Operation};
+use crate::progress::Progress;
+use crate::state::RestoreState;
 use anyhow::Result;
-use std::collections::{btree_map, BTreeMap};
+use chrono::{DateTime, NaiveDate, Utc};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::cell::OnceCell;
+use std::collections::{BTreeMap, BTreeSet};
 use std::env;
-use std::fs::{self, File};
-use std::io::{BufReader, BufWriter, Read, Write};
+use std::ffi::OsStr;
+use std::fmt;
+use std::fs::{self, File, FileType};
+use std::io::{self, BufReader, BufWriter, Read, Write};
 use std::path::{Component, Path, PathBuf};
-use std::process::{Command, Stdio};
+use std::process::{Command, Output, Stdio};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 use unicode_width::UnicodeWidthStr;
 
+const DEFAULT_GITIGNORE: &str = "*.swp\n*~\n.DS_Store\n";
+/// Commit message of `save` without `--message`
+pub const DEFAULT_COMMIT_MESSAGE: &str = "Update dotfiles";
+/// Delay before the first retry of network git operations. Doubled on each retry.
+const GIT_RETRY_BASE_DELAY: Duration = Duration::from_secs(1);
+/// Upper bound of the delay between retries, however many retries are configured.
+const GIT_RETRY_MAX_DELAY: Duration = Duration::from_secs(60);
+const OPLOG_FILE_NAME: &str = ".dotman_operations.jsonl";
+const FILE_MAPPINGS_FILE_NAME: &str = ".file_mappings.json";
+const LOCK_FILE_NAME: &str = ".dotman.lock";
+/// Files in the workspace which are written by dotman
+const INTERNAL_FILE_NAMES: &[&str] = &[FILE_MAPPINGS_FILE_NAME, OPLOG_FILE_NAME, LOCK_FILE_NAME];
+/// Files in the workspace root which are used by git
+const GIT_FILE_NAMES: &[&str] = &[".git", ".gitignore", ".gitattributes", ".gitmodules"];
+/// Default of `WalkOptions::max_depth`
+const MAX_WALK_DEPTH: usize = 64;
+
 #[derive(Debug)]
 pub struct App {
-    workspace: PathBuf,
-    file_mappings_path: PathBuf,
-    file_mappings: FileMappings,
+    workspace: Workspace,
+    /// Loaded on first access by `file_mappings` or `file_mappings_mut`
+    file_mappings: OnceCell<FileMappings>,
+    /// Whether `file_mappings` may be modified and needs to be saved
+    file_mappings_dirty: bool,
+    /// Taken on first `file_mappings_mut` and released on drop. See `Lock`.
+    lock: Option<Lock>,
+    case_insensitive: bool,
+    backup_dir: Option<PathBuf>,
+    /// Name of the mappings overlay loaded on top of the mappings file. See
+    /// `set_profile`.
+    profile: Option<String>,
+    /// File of `RestoreState`. `restore` checks every entry if `None`.
+    restore_state: Option<PathBuf>,
+    /// Whether operations are appended to the operation log. Disabled while undoing.
+    record_operations: bool,
+    /// How many times network git operations are retried
+    git_retries: u32,
+    /// Tags attached to entries created by `link`
+    link_tags: Vec<String>,
+    /// Sources which entries created by `link` are restored after
+    link_after: Vec<String>,
+    /// Whether `link` creates hard links instead of symlinks
+    link_hardlink: bool,
+    /// Mode of directories which `link` creates for dests. Unix only.
+    link_dir_mode: Option<u32>,
+    /// Case of dests derived from sources
+    dest_case: DestCase,
+    /// Receives events of `restore` and batch or recursive `link`
+    progress: Progress,
+    /// What batch operations do when an entry failed
+    error_policy: ErrorPolicy,
+    config: Config,
 }
 
 impl App {
-    pub fn new() -> Result<Self> {
-        let workspace = dirs::home_dir()
-            .ok_or_else(|| anyhow!("Cannot retrieve home directory"))?
-            .join("dotfiles");
+    /// Creates app for the workspace of `repo`, or the default workspace of `config`.
+    ///
+    /// Fails if `workspace_check` in config is enabled and the workspace is not an
+    /// existing directory. See `Workspace::check`.
+    pub fn new(config: Config, repo: Option<&str>) -> Result<Self> {
+        let workspace = config.resolve_workspace(repo)?;
+        if config.workspace_check {
+            Workspace::new(workspace.clone()).check()?;
+        }
+        let mut app = Self::with_workspace(workspace)?;
+        app.git_retries = config.git_retries;
+        app.config = config;
+        Ok(app)
+    }
+
+    /// The workspace is not created until a command writes into it. See `ensure_workspace`.
+    pub fn with_workspace(workspace: PathBuf) -> Result<Self> {
         debug!("Workspace: {}", workspace.to_string_lossy());
-        if !workspace.exists() {
-            debug!("Creating workspace: {}", workspace.to_string_lossy());
-            std::fs::create_dir_all(&workspace)?;
-        }
-        let file_mappings_path = workspace.join(".file_mappings.json");
-        let file_mappings = {
-            if !file_mappings_path.exists() {
-                FileMappings::new(&workspace)
-            } else {
-                FileMappings::load_entries(
-                    &workspace,
-                    BufReader::new(File::open(&file_mappings_path)?),
-                )?
-            }
-        };
         Ok(Self {
-            workspace,
-            file_mappings_path,
-            file_mappings,
+            workspace: Workspace::new(workspace),
+            file_mappings: OnceCell::new(),
+            file_mappings_dirty: false,
+            lock: None,
+            case_insensitive: cfg!(any(target_os = "macos", target_os = "windows")),
+            backup_dir: None,
+            profile: None,
+            restore_state: None,
+            record_operations: true,
+            git_retries: 0,
+            link_tags: Vec::new(),
+            link_after: Vec::new(),
+            link_hardlink: false,
+            link_dir_mode: None,
+            dest_case: DestCase::Keep,
+            progress: Progress::default(),
+            error_policy: ErrorPolicy::ContinueOnError,
+            config: Config::default(),
         })
     }
 
-    pub fn git(&self, subcommands: &[String]) {
-        debug!("Executing 'git {}'", subcommands.join("' '"));
-        let status = Command::new("git")
-            .current_dir(&self.workspace)
-            .args(subcommands)
-            .stdin(Stdio::inherit())
-            .stdout(Stdio::inherit())
-            .stderr(Stdio::inherit())
-            .status();
-        let status = match status {
-            Ok(status) => status,
-            Err(err) => {
-                error!("Failed to execute process! error: {}", err);
-                return;
-            }
-        };
-        if status.success() {
-            return;
-        }
-        println!();
-        if let Some(code) = status.code() {
-            println!("Process exited abnormally. Exited with code {}", code);
-        } else {
-            println!("Process terminated by signal");
+    /// Treats sources which differ only in case as the same file.
+    ///
+    /// Defaults to `true` on macOS and Windows.
+    pub fn set_case_insensitive(&mut self, case_insensitive: bool) {
+        self.case_insensitive = case_insensitive;
+        if let Some(file_mappings) = self.file_mappings.get_mut() {
+            file_mappings.set_case_insensitive(case_insensitive);
         }
     }
 
-    pub fn mappings(&self) {
-        let map = self.file_mappings.as_map();
-        let max_key_len = map.keys().map(|s| s.width()).max().unwrap_or(0);
-        let max_value_len = map.values().map(|s| s.width()).max().unwrap_or(0);
-        let counter_len = map.len().to_string().len();
-        println!("There are {} mapped files.", map.len());
-        let header_footer = (0..(counter_len + max_key_len + max_value_len + 6))
-            .map(|_| "=")
-            .collect::<Vec<&str>>()
-            .join("");
-        println!("{}", header_footer);
-        for (counter, (dest, src)) in map.iter().enumerate() {
-            println!(
-                "{:counter_len$}. {:key_len$} -> {:value_len$}",
-                counter + 1,
-                dest,
-                src,
-                counter_len = counter_len,
-                key_len = max_key_len,
-                value_len = max_value_len
-            );
+    /// Replaces home directory which `~` of sources refers to. See `Workspace::set_home`.
+    #[cfg(test)]
+    pub fn set_home(&mut self, home: PathBuf) {
+        self.workspace.set_home(home);
+        if let Some(file_mappings) = self.file_mappings.get_mut() {
+            file_mappings.set_home(self.workspace.home.clone());
         }
-        println!("{}", header_footer);
     }
 
-    pub fn status(&self) {
-        self.git(&["status".to_string()]);
+    /// Disables git. See `no_git` in config.
+    pub fn set_no_git(&mut self, no_git: bool) {
+        self.config.no_git = no_git;
     }
 
-    pub fn link<P: AsRef<Path>>(&mut self, source: P, dest: &str) {
-        let source = source.as_ref();
-        if !source.exists() {
-            error!("Source file: {} does not exist!", source.to_string_lossy());
-            return;
-        }
-        if !source.is_file() {
-            error!(
-                "Source file: {} is not a regular file!",
-                source.to_string_lossy()
-            );
-            return;
-        }
-        let dest_abs = {
-            let mut builder = PathBuf::new();
-            builder.push(&self.workspace);
-            builder.push(dest);
-            builder
-        };
-        if let Some(parent) = dest_abs.parent() {
+    /// Creates the workspace if it does not exist. Called by commands which write into it.
+    fn ensure_workspace(&self) -> Result<()> {
+        if !self.workspace.exists() {
             debug!(
-                "Creating parent directories for '{}'",
-                dest_abs.to_string_lossy()
-            );
-            if let Err(err) = fs::create_dir_all(parent) {
-                error!(
-                    "Failed to create directory: {} error: {}",
-                    parent.to_string_lossy(),
-                    err
-                );
-                return;
-            }
-        }
-        debug!("Updating entries...");
-        if let Err(err) = self.file_mappings.add(source, dest) {
-            error!("Failed to update entries! error: {}", err);
-            return;
-        }
-        debug!(
-            "Creating symbolic link from '{}' to '{}'",
-            source.to_string_lossy(),
-            dest_abs.to_string_lossy()
-        );
-        if let Err(err) = fs::rename(source, &dest_abs) {
-            error!(
-                "Failed to move {} into {} error: {}",
-                source.to_string_lossy(),
-                dest_abs.to_string_lossy(),
-                err
-            );
-            return;
-        }
-        if let Err(err) = Self::create_symlink(&dest_abs, source) {
-            error!(
-                "Failed to create symlink! dest: '{}' source: '{}' error: {}",
-                source.to_string_lossy(),
-                dest_abs.to_string_lossy(),
-                err
+                "Creating workspace: {}",
+                self.workspace.root().to_string_lossy()
             );
-            return;
+            fs::create_dir_all(self.workspace.root())?;
         }
-        println!("Linked!");
-    }
-
-    #[cfg(not(target_os = "windows"))]
-    fn create_symlink(source: &Path, dest: &Path) -> Result<()> {
-        std::os::unix::fs::symlink(source, dest)?;
         Ok(())
     }
 
-    #[cfg(target_os = "windows")]
-    fn create_symlink(source: &Path, dest: &Path) -> Result<()> {
-        std::os::windows::fs::symlink_file(source, &dest)
-    }
-
-    pub fn unlink<P: AsRef<Path>>(&mut self, source: P) {
-        let source = source.as_ref();
-        if !source.exists() {
-            error!("Source file: {} does not exist!", source.to_string_lossy());
-            return;
+    /// Returns mappings, loading them on first access.
+    ///
+    /// Commands which don't need mappings (e.g. `git`) never parse the mappings file, and
+    /// it is rewritten on drop only if it may be modified. With 100,000 entries,
+    /// `dotman repos` takes about 1ms instead of 55ms in release build.
+    fn file_mappings(&self) -> Result<&FileMappings> {
+        if let Some(file_mappings) = self.file_mappings.get() {
+            return Ok(file_mappings);
         }
-        if !self.file_mappings.contains(source) {
-            error!(
-                "File: {} is not managed by this tool!",
-                source.to_string_lossy()
-            );
-            return;
+        if !self.workspace.exists() {
+            return Err(ErrorKind::Config.error(format!(
+                "Workspace: {} does not exist! Run 'dotman init' or link a file first.",
+                self.workspace.root().to_string_lossy()
+            )));
         }
-        let dest = match fs::read_link(source) {
-            Ok(dest) => dest,
-            Err(err) => {
-                error!(
-                    "Source file: {} is not a symlink! error: {}",
-                    source.to_string_lossy(),
-                    err
+        let base = self.workspace.store_path();
+        let overlay = self
+            .profile
+            .as_ref()
+            .map(|profile| self.workspace.overlay_path(profile))
+            .filter(|overlay| overlay.exists());
+        let mut file_mappings = match (base.exists(), overlay) {
+            (_, Some(overlay)) => {
+                debug!(
+                    "Loading mappings: {} and {}",
+                    base.to_string_lossy(),
+                    overlay.to_string_lossy()
                 );
-                return;
+                let base: Box<dyn Read> = if base.exists() {
+                    Box::new(BufReader::new(File::open(&base)?))
+                } else {
+                    Box::new("{}".as_bytes())
+                };
+                FileMappings::load_layered(base, BufReader::new(File::open(&overlay)?))?
+            }
+            (true, None) => {
+                debug!("Loading mappings: {}", base.to_string_lossy());
+                FileMappings::load_entries(BufReader::new(File::open(&base)?))?
             }
+            (false, None) => FileMappings::new(),
         };
-        debug!("Removing symbolic link: {}", source.to_string_lossy());
-        if let Err(err) = fs::remove_file(&source) {
-            error!(
-                "Cannot remove symlink! {} error: {}",
-                source.to_string_lossy(),
-                err
-            );
-            return;
-        }
-        debug!(
-            "Renaming '{}' to '{}'",
-            dest.to_string_lossy(),
-            source.to_string_lossy()
-        );
-        if let Err(err) = fs::rename(&dest, &source) {
-            error!(
-                "Cannot move file {} into {} error: {}",
-                dest.to_string_lossy(),
-                source.to_string_lossy(),
-                err
-            );
-            return;
-        }
-        debug!("Updating entries...");
-        if let Err(err) = self.file_mappings.remove(source) {
-            error!("Failed to update entries! error: {}", err);
-            return;
+        file_mappings.set_case_insensitive(self.case_insensitive);
+        file_mappings.set_home(self.workspace.home.clone());
+        Ok(self.file_mappings.get_or_init(|| file_mappings))
+    }
+
+    /// Same as `file_mappings` but marks mappings to be saved on drop. The workspace is
+    /// locked first, so that other instances can't modify mappings until this is dropped.
+    fn file_mappings_mut(&mut self) -> Result<&mut FileMappings> {
+        if self.lock.is_none() {
+            self.file_mappings()?;
+            self.lock = Some(Lock::acquire(self.workspace.lock_path())?);
+            if !self.file_mappings_dirty {
+                // Another instance may have saved mappings since they were loaded
+                self.file_mappings.take();
+            }
         }
-        println!("Unlinked!");
+        self.file_mappings()?;
+        self.file_mappings_dirty = true;
+        Ok(self.file_mappings.get_mut().unwrap())
     }
 
-    pub fn restore(&self) {
-        unimplemented!();
+    /// Saves mappings if they are modified. Call this instead of relying on `Drop`
+    /// so that save failures can be reported.
+    pub fn finish(mut self) -> Result<()> {
+        let result = self.save_mappings();
+        // Already reported to the caller. Don't retry on drop.
+        self.file_mappings_dirty = false;
+        result
     }
-}
 
-impl Drop for App {
-    fn drop(&mut self) {
+    fn save_mappings(&mut self) -> Result<()> {
+        let file_mappings = match self.file_mappings.get() {
+            Some(file_mappings) if self.file_mappings_dirty => file_mappings,
+            _ => return Ok(()),
+        };
         debug!("Saving mappings...");
-        self.file_mappings
-            .save_entries(&mut BufWriter::new(
-                File::create(&self.file_mappings_path).unwrap(),
-            ))
-            .unwrap();
+        let path = self.workspace.store_path();
+        match &self.profile {
+            Some(profile) => {
+                let overlay_path = self.workspace.overlay_path(profile);
+                let load = |path: &Path| -> Result<FileMappings> {
+                    if path.exists() {
+                        FileMappings::load_entries_unchecked(BufReader::new(File::open(path)?))
+                    } else {
+                        Ok(FileMappings::new())
+                    }
+                };
+                let (base, overlay) = file_mappings.split(&load(&path)?, &load(&overlay_path)?);
+                write_mappings_file(&path, &base)?;
+                write_mappings_file(&overlay_path, &overlay)?;
+            }
+            None => write_mappings_file(&path, file_mappings)?,
+        }
+        self.file_mappings_dirty = false;
         debug!("Successfully saved!");
+        Ok(())
     }
-}
 
-#[derive(Debug)]
-struct FileMappings {
-    entries: BTreeMap<String, String>,
-    workspace: PathBuf,
-}
+    /// Retries network git operations (e.g. `pull`) up to `retries` times with
+    /// exponential backoff if they failed by network errors.
+    pub fn set_git_retries(&mut self, retries: u32) {
+        self.git_retries = retries;
+    }
 
-impl FileMappings {
-    pub fn new<P: AsRef<Path>>(workspace: P) -> Self {
-        Self {
-            entries: BTreeMap::new(),
-            workspace: workspace.as_ref().to_path_buf(),
+    /// Loads `.file_mappings.<profile>.json` on top of the mappings file. Its entries
+    /// replace the entries of the same sources. Entries which are added or changed are
+    /// saved into it, while the mappings file keeps its own entries. Unlinking an entry
+    /// of the overlay reveals the entry it replaced.
+    pub fn set_profile(&mut self, profile: &str) -> Result<()> {
+        let valid = !profile.is_empty()
+            && profile
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        if !valid {
+            return Err(ErrorKind::Config.error(format!(
+                "Profile: {} must consist of letters, digits, '-' and '_'!",
+                profile
+            )));
         }
+        self.profile = Some(profile.to_string());
+        Ok(())
     }
 
-    pub fn as_map(&self) -> &BTreeMap<String, String> {
-        &self.entries
+    /// Overrides `dest_root` in config.
+    pub fn set_dest_root(&mut self, root: &str) {
+        self.config.dest_root = Some(root.to_string());
     }
 
-    pub fn load_entries<R: Read, P: AsRef<Path>>(workspace: P, entries_store: R) -> Result<Self> {
-        let entries: BTreeMap<String, String> = serde_json::from_reader(entries_store)?;
-        Ok(Self {
-            entries,
-            workspace: workspace.as_ref().to_path_buf(),
-        })
+    /// Changes case of dests derived by `resolve_dest`. Dests specified explicitly are
+    /// kept as they are.
+    pub fn set_dest_case(&mut self, case: DestCase) {
+        self.dest_case = case;
     }
 
-    pub fn save_entries<W: Write>(&self, entries_store: &mut W) -> Result<()> {
-        serde_json::to_writer_pretty(entries_store, &self.entries)?;
-        Ok(())
+    /// Attaches `tags` to entries created by following `link` calls.
+    pub fn set_link_tags(&mut self, tags: Vec<String>) {
+        self.link_tags = tags;
     }
 
-    pub fn contains<P: AsRef<Path>>(&self, src: P) -> bool {
-        self.entries.contains_key(&Self::strip_src(&src.as_ref()))
+    /// Sets what batch operations (`restore`, `unlink --tag` and linking multiple files)
+    /// do when an entry failed.
+    pub fn set_error_policy(&mut self, error_policy: ErrorPolicy) {
+        self.error_policy = error_policy;
     }
 
-    pub fn remove<P: AsRef<Path>>(&mut self, src: P) -> Result<()> {
-        self.entries
-            .remove(&Self::strip_src(src.as_ref()))
-            .ok_or_else(|| anyhow!("Entry not exists"))?;
-        Ok(())
+    /// Emits progress events of loop-based commands to `progress`.
+    pub fn set_progress(&mut self, progress: Progress) {
+        self.progress = progress;
     }
 
-    /// `dst` is relative path from workspace
-    pub fn add<P: AsRef<Path>>(&mut self, src: P, dst: &str) -> Result<()> {
-        let src = src.as_ref();
-        let src = Self::strip_src(src);
-        let entry = self.entries.entry(src);
-        if let btree_map::Entry::Occupied(_) = entry {
-            Err(anyhow!("Entry already exists"))
-        } else {
-            entry.or_insert_with(|| dst.to_string());
-            Ok(())
+    /// Makes following `link` calls create hard links instead of symlinks, for apps which
+    /// don't follow symlinks. The source and the workspace must be on the same filesystem.
+    pub fn set_link_hardlink(&mut self, hardlink: bool) {
+        self.link_hardlink = hardlink;
+    }
+
+    /// Applies `mode` to parent directories of dests which following `link` calls create.
+    /// Existing directories are not changed. Ignored except on unix.
+    pub fn set_link_dir_mode(&mut self, mode: Option<u32>) {
+        self.link_dir_mode = mode;
+    }
+
+    /// Makes `restore` link entries created by following `link` calls after `sources`.
+    pub fn set_link_after(&mut self, sources: &[PathBuf]) {
+        self.link_after = sources
+            .iter()
+            .map(|source| self.workspace.strip_home(source))
+            .collect();
+    }
+
+    /// Enables snapshots of files before destructive operations.
+    pub fn set_backup_dir(&mut self, backup_dir: PathBuf) {
+        self.backup_dir = Some(backup_dir);
+    }
+
+    /// Makes `restore` remember healthy entries in the cache directory and skip checking
+    /// them while mappings are unchanged. See `RestoreState`.
+    pub fn enable_restore_state(&mut self) {
+        let root = normalize_path(self.workspace.root());
+        let id = format!("{:x}", Sha256::digest(root.to_string_lossy().as_bytes()));
+        self.restore_state = dirs::cache_dir().map(|dir| {
+            dir.join("dotman")
+                .join(format!("restore-{}.json", &id[..16]))
+        });
+    }
+
+    /// Takes snapshot of `file` if backup directory is specified.
+    fn backup(&self, operation: &str, file: &Path) -> Result<()> {
+        if let Some(backup_dir) = &self.backup_dir {
+            if file.is_dir() {
+                warn!(
+                    "{} is a directory. Directories are not backed up.",
+                    file.to_string_lossy()
+                );
+                return Ok(());
+            }
+            let mut store = BackupStore::open(backup_dir)?;
+            let snapshot = store.take(operation, file)?;
+            println!("Backed up {} as {}", file.to_string_lossy(), snapshot.id);
         }
+        Ok(())
     }
 
-    /// 1. Normalize source path.
-    /// 1. Replace home directory to `~`
-    fn strip_src(src: &Path) -> String {
-        let src = normalize_path(src);
-        let home = dirs::home_dir().expect("Cannot retrieve home directory");
-        if let Ok(stripped) = src.strip_prefix(&home) {
-            format!(
-                "~{}{}",
-                std::path::MAIN_SEPARATOR,
-                stripped.to_string_lossy()
-            )
-        } else {
-            src.to_string_lossy().to_string()
+    fn oplog(&self) -> OpLog {
+        OpLog::new(self.workspace.oplog_path())
+    }
+
+    /// Appends `operation` to the operation log so that it can be undone. The operation
+    /// itself already succeeded, so failures are only logged.
+    fn record(&self, operation: Operation) {
+        if !self.record_operations {
+            return;
+        }
+        if let Err(err) = self.oplog().append(&operation) {
+            warn!("Failed to record '{}'! error: {}", operation, err);
         }
     }
-}
 
-/// Normalizes produced path.  
-///
-/// Notes:
-/// - This method don't follow symbolic links.  
-/// - This method treats `foo/bar` as `./foo/bar`.
-pub fn normalize_path<P: AsRef<Path>>(path: P) -> PathBuf {
-    let path = path.as_ref();
-    let mut result = PathBuf::new();
-    if let Some(comp) = path.components().next() {
-        if let Component::Normal(_) = comp {
-            result.push(env::current_dir().expect("Cannot retrieve current directory"))
+    /// Records unlink of `source` whose file was moved to `restore_at`.
+    fn record_unlink(&self, source: &Path, restore_at: &Path, dest: &str) {
+        self.record(Operation::Unlink {
+            source: self.workspace.strip_home(source),
+            dest: dest.to_string(),
+            to: Some(restore_at)
+                .filter(|restore_at| *restore_at != source)
+                .map(|source| self.workspace.strip_home(source)),
+        });
+    }
+
+    /// Appends the outcome of an operation to the audit log if it is configured.
+    fn audit(&self, operation: &str, source: &Path, dest: &str, result: &Result<()>) {
+        let path = match &self.config.audit_log {
+            Some(path) => self.workspace.expand_home(path),
+            None => return,
+        };
+        let source = normalize_path(source);
+        let entry = AuditEntry::new(operation, &source.to_string_lossy(), dest, result);
+        if let Err(err) = AuditLog::new(&path).append(&entry) {
+            warn!(
+                "Failed to write audit log: {} error: {}",
+                path.to_string_lossy(),
+                err
+            );
         }
     }
-    for comp in path.components() {
-        match comp {
-            Component::Prefix(_) | Component::RootDir | Component::Normal(_) => {
-                result.push(comp);
+
+    /// Fails with a friendly message if dotfiles folder is not a git repository or git
+    /// is disabled.
+    fn ensure_git_repo(&self) -> Result<()> {
+        if self.config.no_git {
+            return Err(ErrorKind::Git.error(
+                "Git is disabled by --no-git or no_git in config! Run git in the workspace directly.",
+            ));
+        }
+        if let Some(git_dir) = &self.config.git_dir {
+            let git_dir = self.workspace.expand_home(git_dir);
+            if !git_dir.is_dir() {
+                return Err(ErrorKind::Git.error(format!(
+                    "git_dir: {} in config does not exist!",
+                    git_dir.to_string_lossy()
+                )));
             }
-            Component::CurDir => {
-                result.push(env::current_dir().expect("Cannot retrieve current directory"));
+            return Ok(());
+        }
+        // `.git` is a file in worktrees and submodules
+        if fs::symlink_metadata(self.workspace.root().join(".git")).is_err() {
+            return Err(ErrorKind::Git.error(format!(
+                "Workspace: {} is not a git repository! Run 'dotman init' first.",
+                self.workspace.root().to_string_lossy()
+            )));
+        }
+        Ok(())
+    }
+
+    /// First argument is expanded if it is an alias in config.
+    pub fn git(&self, subcommands: &[String]) -> Result<()> {
+        self.ensure_git_repo()?;
+        self.run_git(&expand_git_alias(&self.config.git_aliases, subcommands))
+    }
+
+    /// Creates git command which runs in dotfiles folder, or in the bare repository
+    /// configured by `git_dir` and `git_work_tree`.
+    fn git_command(&self) -> Command {
+        let mut command = Command::new("git");
+        match &self.config.git_work_tree {
+            Some(work_tree) => {
+                let work_tree = self.workspace.expand_home(work_tree);
+                command
+                    .current_dir(&work_tree)
+                    .env("GIT_WORK_TREE", &work_tree);
             }
-            Component::ParentDir => {
-                result.pop();
+            None => {
+                command.current_dir(self.workspace.root());
             }
         }
+        if let Some(git_dir) = &self.config.git_dir {
+            command.env("GIT_DIR", self.workspace.expand_home(git_dir));
+        }
+        command
     }
-    result
-}
-
-#[cfg(test)]
-mod tests {
-    use crate::app::{self, FileMappings};
-    use std::env;
-    use std::path::{Path, PathBuf};
 
-    #[test]
-    fn normalize_current_dir() {
-        let actual = app::normalize_path("./Cargo.toml");
-        let expect = {
-            let mut tmp = PathBuf::new();
-            tmp.push(env::current_dir().unwrap());
-            tmp.push("Cargo.toml");
-            tmp
+    /// Executes git in dotfiles folder with inherited stdio. Aliases are not expanded.
+    fn run_git(&self, subcommands: &[String]) -> Result<()> {
+        debug!("Executing 'git {}'", subcommands.join("' '"));
+        let retries = if is_network_subcommand(subcommands) {
+            self.git_retries
+        } else {
+            0
         };
-        assert_eq!(actual, expect);
+        let status = if retries == 0 {
+            self.git_command()
+                .args(subcommands)
+                .stdin(Stdio::inherit())
+                .stdout(Stdio::inherit())
+                .stderr(Stdio::inherit())
+                .status()
+                .map_err(|err| {
+                    ErrorKind::Git.error(format!("Failed to execute process! error: {}", err))
+                })?
+        } else {
+            // stderr is captured to find network errors
+            retry_network(retries, GIT_RETRY_BASE_DELAY, || {
+                let output = self
+                    .git_command()
+                    .args(subcommands)
+                    .stdin(Stdio::inherit())
+                    .stdout(Stdio::inherit())
+                    .stderr(Stdio::piped())
+                    .output()
+                    .map_err(|err| {
+                        ErrorKind::Git.error(format!("Failed to execute process! error: {}", err))
+                    })?;
+                io::stderr().write_all(&output.stderr)?;
+                Ok(output)
+            })?
+            .status
+        };
+        if status.success() {
+            return Ok(());
+        }
+        println!();
+        if let Some(code) = status.code() {
+            println!("Process exited abnormally. Exited with code {}", code);
+        } else {
+            println!("Process terminated by signal");
+        }
+        Ok(())
     }
 
-    #[test]
-    fn normalize_dotdot_with_root() {
-        let actual = app::normalize_path("/foo/../foo");
-        let expect = Path::new("/foo");
-        assert_eq!(actual, expect);
+    /// `git_output` which retries network subcommands as configured by `set_git_retries`.
+    fn git_output_retrying<S: AsRef<OsStr>>(&self, args: &[S]) -> Result<Output> {
+        let args: Vec<String> = args
+            .iter()
+            .map(|arg| arg.as_ref().to_string_lossy().to_string())
+            .collect();
+        if !is_network_subcommand(&args) {
+            return self.git_output(&args);
+        }
+        retry_network(self.git_retries, GIT_RETRY_BASE_DELAY, || {
+            self.git_output(&args)
+        })
     }
 
-    #[test]
-    fn normalize_with_no_curdir_and_rootdir() {
-        let actual = app::normalize_path("foo/bar");
-        let expect = app::normalize_path("./foo/bar");
-        assert_eq!(actual, expect);
+    /// Executes git in dotfiles folder and captures its stdout and stderr.
+    pub fn git_output<S: AsRef<OsStr>>(&self, args: &[S]) -> Result<Output> {
+        self.git_output_with_env(args, &[])
     }
 
-    fn new_fm() -> FileMappings {
-        FileMappings::new(PathBuf::from("./test-workspace"))
+    /// `git_output` with additional environment variables.
+    fn git_output_with_env<S: AsRef<OsStr>>(
+        &self,
+        args: &[S],
+        envs: &[(&str, String)],
+    ) -> Result<Output> {
+        debug!("Executing git with captured output");
+        let output = self
+            .git_command()
+            .args(args)
+            .envs(envs.iter().map(|(key, value)| (key, value)))
+            .stdin(Stdio::null())
+            .output()?;
+        Ok(output)
     }
 
-    #[test]
-    fn contains_not_exists() {
-        let fm = new_fm();
-        assert!(!fm.contains("./Cargo.toml"));
+    /// Executes git with captured output and fails with its stderr if it exited abnormally.
+    fn git_checked<S: AsRef<OsStr>>(&self, args: &[S]) -> Result<Output> {
+        self.git_checked_with_env(args, &[])
     }
 
-    #[test]
-    fn contains_exists() {
-        let mut fm = new_fm();
-        fm.add("./Cargo.toml", "DestCargo.toml").unwrap();
-        assert!(fm.contains("./Cargo.toml"));
+    /// `git_checked` with additional environment variables.
+    fn git_checked_with_env<S: AsRef<OsStr>>(
+        &self,
+        args: &[S],
+        envs: &[(&str, String)],
+    ) -> Result<Output> {
+        let output = self.git_output_with_env(args, envs)?;
+        if !output.status.success() {
+            let args: Vec<String> = args
+                .iter()
+                .map(|arg| arg.as_ref().to_string_lossy().to_string())
+                .collect();
+            return Err(ErrorKind::Git.error(format!(
+                "Failed to execute 'git {}'! {}",
+                args.join(" "),
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+        Ok(output)
+    }
+
+    /// Commits all changes in dotfiles folder, and pushes them if `push`. The operation
+    /// log and the lock file are kept out of commits since they only matter to this
+    /// machine.
+    pub fn save(&self, message: &str, push: bool, set_upstream: bool) -> Result<()> {
+        self.ensure_git_repo()?;
+        let exclude = |path: PathBuf| format!(":(exclude){}", path.to_string_lossy());
+        self.git_checked(&[
+            "add",
+            "--all",
+            "--",
+            ":/",
+            &exclude(self.workspace.oplog_path()),
+            &exclude(self.workspace.lock_path()),
+        ])?;
+        // Checks staged changes only, since the excluded files are left untracked
+        let staged = self.git_output(&["diff", "--cached", "--quiet"])?;
+        match staged.status.code() {
+            Some(0) => println!("Nothing to save"),
+            Some(1) => {
+                let output =
+                    self.git_checked_with_env(&["commit", "-m", message], &self.author_env())?;
+                io::stdout().write_all(&output.stdout)?;
+            }
+            _ => {
+                return Err(ErrorKind::Git.error(format!(
+                    "Failed to execute 'git diff --cached --quiet'! {}",
+                    String::from_utf8_lossy(&staged.stderr).trim()
+                )))
+            }
+        }
+        if push {
+            self.push(set_upstream)?;
+        }
+        Ok(())
+    }
+
+    /// Environment variables which make commits use the identity in config, as both the
+    /// author and the committer. Git config decides the identity if they are not set.
+    fn author_env(&self) -> Vec<(&'static str, String)> {
+        let mut envs = Vec::new();
+        if let Some(name) = &self.config.git_author_name {
+            envs.push(("GIT_AUTHOR_NAME", name.clone()));
+            envs.push(("GIT_COMMITTER_NAME", name.clone()));
+        }
+        if let Some(email) = &self.config.git_author_email {
+            envs.push(("GIT_AUTHOR_EMAIL", email.clone()));
+            envs.push(("GIT_COMMITTER_EMAIL", email.clone()));
+        }
+        envs
+    }
+
+    /// Executes 'git push'. If current branch has no upstream, pushes it to `origin` and
+    /// sets upstream when `set_upstream`.
+    fn push(&self, set_upstream: bool) -> Result<()> {
+        let output = self.git_output_retrying(&["push"])?;
+        if output.status.success() {
+            io::stderr().write_all(&output.stderr)?;
+            return Ok(());
+        }
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        // Checked by exit status since messages of git may be localized
+        let has_upstream = self
+            .git_output(&["rev-parse", "--abbrev-ref", "--symbolic-full-name", "@{u}"])?
+            .status
+            .success();
+        if has_upstream {
+            return Err(
+                ErrorKind::Git.error(format!("Failed to execute 'git push'! {}", stderr.trim()))
+            );
+        }
+        if !set_upstream {
+            return Err(ErrorKind::Git.error(
+                "Current branch has no upstream branch! Use --set-upstream to execute 'git push -u origin HEAD'.",
+            ));
+        }
+        println!("Setting upstream to origin");
+        let output = self.git_output_retrying(&["push", "-u", "origin", "HEAD"])?;
+        io::stderr().write_all(&output.stderr)?;
+        if !output.status.success() {
+            return Err(ErrorKind::Git.error("Failed to execute 'git push -u origin HEAD'!"));
+        }
+        Ok(())
+    }
+
+    /// Same as `git` but prints captured output after the process exited.
+    pub fn git_captured(&self, subcommands: &[String]) -> Result<()> {
+        self.ensure_git_repo()?;
+        let subcommands = expand_git_alias(&self.config.git_aliases, subcommands);
+        let output = self.git_output_retrying(&subcommands)?;
+        io::stdout().write_all(&output.stdout)?;
+        io::stderr().write_all(&output.stderr)?;
+        if !output.status.success() {
+            return Err(match output.status.code() {
+                Some(code) => ErrorKind::Git.error(format!(
+                    "Process exited abnormally. Exited with code {}",
+                    code
+                )),
+                None => ErrorKind::Git.error("Process terminated by signal"),
+            });
+        }
+        Ok(())
+    }
+
+    /// Resolves `since` as a ref, or as a date if it is not a ref.
+    fn resolve_since(&self, since: &str) -> Result<Since> {
+        let output = self.git_output(&[
+            "rev-parse",
+            "--verify",
+            "--quiet",
+            &format!("{}^{{commit}}", since),
+        ])?;
+        if output.status.success() {
+            return Ok(Since::Ref(
+                String::from_utf8_lossy(&output.stdout).trim().to_string(),
+            ));
+        }
+        if NaiveDate::parse_from_str(since, "%Y-%m-%d").is_ok() {
+            return Ok(Since::Date(since.to_string()));
+        }
+        Err(ErrorKind::Git.error(format!(
+            "'{}' is neither a ref which resolves to a commit nor a date (YYYY-MM-DD)!",
+            since
+        )))
+    }
+
+    /// Executes 'git diff'. If `since` is specified, compares with it instead of the index.
+    pub fn diff(&self, since: Option<&str>) -> Result<()> {
+        self.ensure_git_repo()?;
+        let mut args = vec!["diff".to_string()];
+        match since.map(|since| self.resolve_since(since)).transpose()? {
+            Some(Since::Ref(commit)) => args.push(commit),
+            Some(Since::Date(date)) => {
+                let output =
+                    self.git_output(&["rev-list", "-1", &format!("--before={}", date), "HEAD"])?;
+                let commit = String::from_utf8_lossy(&output.stdout).trim().to_string();
+                if !output.status.success() || commit.is_empty() {
+                    return Err(
+                        ErrorKind::Git.error(format!("There are no commits before {}!", date))
+                    );
+                }
+                args.push(commit);
+            }
+            None => {}
+        }
+        self.run_git(&args)
+    }
+
+    /// Executes 'git log' for the dest of `source`.
+    pub fn history<P: AsRef<Path>>(&self, source: P, since: Option<&str>) -> Result<()> {
+        self.ensure_git_repo()?;
+        let dest = self.file_mappings()?.get(source)?.to_string();
+        let mut args = vec!["log".to_string()];
+        match since.map(|since| self.resolve_since(since)).transpose()? {
+            Some(Since::Ref(commit)) => args.push(format!("{}..HEAD", commit)),
+            Some(Since::Date(date)) => args.push(format!("--since={}", date)),
+            None => {}
+        }
+        args.push("--".to_string());
+        args.push(dest);
+        self.run_git(&args)
+    }
+
+    pub fn mappings(
+        &self,
+        print0: bool,
+        sort: MappingsSort,
+        filter: Option<&MappingFilter>,
+    ) -> Result<()> {
+        let entries = self.sorted_mappings(sort, filter)?;
+        if print0 {
+            let stdout = io::stdout();
+            write_mappings_print0(&entries, &mut stdout.lock())
+                .map_err(|err| anyhow!("Failed to write mappings! error: {}", err))?;
+            return Ok(());
+        }
+        let max_key_len = entries.iter().map(|(s, _)| s.width()).max().unwrap_or(0);
+        let max_value_len = entries.iter().map(|(_, s)| s.width()).max().unwrap_or(0);
+        let counter_len = entries.len().to_string().len();
+        println!("There are {} mapped files.", entries.len());
+        let header_footer = (0..(counter_len + max_key_len + max_value_len + 6))
+            .map(|_| "=")
+            .collect::<Vec<&str>>()
+            .join("");
+        println!("{}", header_footer);
+        for (counter, (dest, src)) in entries.iter().enumerate() {
+            println!(
+                "{:counter_len$}. {:key_len$} -> {:value_len$}",
+                counter + 1,
+                dest,
+                src,
+                counter_len = counter_len,
+                key_len = max_key_len,
+                value_len = max_value_len
+            );
+        }
+        println!("{}", header_footer);
+        Ok(())
+    }
+
+    /// Checks the mappings file without looking at links, e.g. in a pre-commit hook.
+    /// Prints nothing if it is valid. Otherwise prints each problem and fails.
+    pub fn validate_mappings(&self) -> Result<()> {
+        let path = self.workspace.store_path();
+        if !path.exists() {
+            return Ok(());
+        }
+        let problems = FileMappings::problems(BufReader::new(File::open(&path)?))
+            .unwrap_or_else(|err| vec![format!("Failed to parse mappings! error: {}", err)]);
+        if problems.is_empty() {
+            return Ok(());
+        }
+        for problem in &problems {
+            println!("{}", problem);
+        }
+        Err(anyhow!(
+            "Found {} problems in {}!",
+            problems.len(),
+            path.to_string_lossy()
+        ))
+    }
+
+    /// Rewrites the mappings file in the canonical form of `FileMappings::save_entries`,
+    /// e.g. after editing it by hand.
+    pub fn sort_paths(&mut self) -> Result<()> {
+        self.file_mappings_mut()?;
+        println!("Sorted mappings file.");
+        Ok(())
+    }
+
+    /// Writes mappings as JSON array of `{"source": ..., "dest": ...}`.
+    pub fn mappings_json<W: Write>(
+        &self,
+        sort: MappingsSort,
+        filter: Option<&MappingFilter>,
+        out: &mut W,
+    ) -> Result<()> {
+        #[derive(Serialize)]
+        struct Entry<'a> {
+            source: &'a str,
+            dest: &'a str,
+        }
+        let entries: Vec<Entry> = self
+            .sorted_mappings(sort, filter)?
+            .into_iter()
+            .map(|(source, dest)| Entry { source, dest })
+            .collect();
+        serde_json::to_writer_pretty(&mut *out, &entries)?;
+        writeln!(out)?;
+        out.flush()?;
+        Ok(())
+    }
+
+    /// Mappings matching `filter` in order of `sort`.
+    fn sorted_mappings(
+        &self,
+        sort: MappingsSort,
+        filter: Option<&MappingFilter>,
+    ) -> Result<Vec<(&str, &str)>> {
+        let mut entries: Vec<(&str, &str)> = self
+            .file_mappings()?
+            .as_map()
+            .iter()
+            .map(|(src, dest)| (src.as_str(), dest.as_str()))
+            .filter(|(src, dest)| filter.is_none_or(|filter| filter.matches(src, dest)))
+            .collect();
+        sort_mappings(&mut entries, sort, |src| {
+            fs::symlink_metadata(self.workspace.expand_home(src))
+                .and_then(|metadata| metadata.modified())
+                .ok()
+        });
+        Ok(entries)
+    }
+
+    pub fn status(&self, options: &StatusOptions) -> Result<()> {
+        if let Some(interval) = options.watch {
+            return self.watch_status(options, interval);
+        }
+        if options.translate {
+            return self.translated_git_status();
+        }
+        if let Some(StatusFormat::PorcelainV2) = options.format {
+            let file_mappings = self.file_mappings()?;
+            let report: Vec<_> = self
+                .health_report()?
+                .into_iter()
+                .filter(|(src, dest, _)| options.selects(file_mappings, src, dest))
+                .collect();
+            print!("{}", porcelain_v2(&report));
+            let broken = report
+                .iter()
+                .filter(|(_, _, health)| *health != Health::Healthy)
+                .count();
+            if options.fail_on_broken && broken != 0 {
+                return Err(
+                    ErrorKind::BrokenLinks.error(format!("{} mapped files are broken!", broken))
+                );
+            }
+            return Ok(());
+        }
+        if options.count_only {
+            let file_mappings = self.file_mappings()?;
+            let count = file_mappings
+                .as_map()
+                .iter()
+                .filter(|(src, dest)| options.selects(file_mappings, src, dest))
+                .count();
+            println!("{}", count);
+            return Ok(());
+        }
+        if options.verbose {
+            let file_mappings = self.file_mappings()?;
+            for target in self.link_targets()? {
+                let dest = match file_mappings.stored_dest(&target.source) {
+                    Some(dest) => dest,
+                    None => continue,
+                };
+                if !options.selects(file_mappings, &target.source, dest) {
+                    continue;
+                }
+                println!(
+                    "{}",
+                    target.format(&self.workspace, options.workspace_relative)
+                );
+            }
+        }
+        // Without git, health of mapped files is the only status to show. git status
+        // can't be filtered by tag or glob either.
+        if options.fail_on_broken
+            || self.config.no_git
+            || options.tag.is_some()
+            || options.filter.is_some()
+        {
+            let broken = self.print_health(options)?;
+            if options.fail_on_broken && broken != 0 {
+                return Err(
+                    ErrorKind::BrokenLinks.error(format!("{} mapped files are broken!", broken))
+                );
+            }
+            return Ok(());
+        }
+        self.git(&["status".to_string()])
+    }
+
+    /// Prints `git status --porcelain` with workspace paths replaced by their sources.
+    fn translated_git_status(&self) -> Result<()> {
+        self.ensure_git_repo()?;
+        let output = self.git_checked(&["status", "--porcelain", "-z"])?;
+        // Paths are relative to the work tree
+        let base = match &self.config.git_work_tree {
+            Some(work_tree) => self.workspace.expand_home(work_tree),
+            None => self.workspace.root().to_path_buf(),
+        };
+        let output = String::from_utf8_lossy(&output.stdout);
+        for line in translate_porcelain(&output, &base, &self.workspace, self.file_mappings()?) {
+            println!("{}", line);
+        }
+        Ok(())
+    }
+
+    /// Prints health of entries which have `tag`. Returns the number of broken entries.
+    fn print_health(&self, options: &StatusOptions) -> Result<usize> {
+        let file_mappings = self.file_mappings()?;
+        let report: Vec<_> = self
+            .health_report()?
+            .into_iter()
+            .filter(|(src, dest, _)| options.selects(file_mappings, src, dest))
+            .collect();
+        let broken = report
+            .iter()
+            .filter(|(_, _, health)| *health != Health::Healthy)
+            .count();
+        let max_health_len = report
+            .iter()
+            .map(|(_, _, health)| health.to_string().len())
+            .max()
+            .unwrap_or(0);
+        for (src, dest, health) in &report {
+            println!(
+                "{:health_len$} {} -> {}",
+                health.to_string(),
+                display_src(src, options.workspace_relative),
+                dest,
+                health_len = max_health_len
+            );
+        }
+        println!("{} of {} mapped files are broken.", broken, report.len());
+        Ok(broken)
+    }
+
+    /// Clears the screen and prints health every `interval` until Ctrl-C is pressed.
+    fn watch_status(&self, options: &StatusOptions, interval: Duration) -> Result<()> {
+        let running = stop_on_ctrl_c()?;
+        while running.load(Ordering::SeqCst) {
+            // Clear the screen and move the cursor to the top left
+            print!("\x1B[2J\x1B[H");
+            self.print_health(options)?;
+            println!(
+                "Refreshing every {} seconds. Press Ctrl-C to stop.",
+                interval.as_secs()
+            );
+            sleep_while_running(&running, interval);
+        }
+        Ok(())
+    }
+
+    /// Returns health of each entry as `(source, dest, health)`.
+    fn health_report(&self) -> Result<Vec<(String, String, Health)>> {
+        Ok(self
+            .file_mappings()?
+            .as_map()
+            .iter()
+            .map(|(src, dest)| {
+                let health = self.entry_health(src, dest);
+                (src.to_string(), dest.to_string(), health)
+            })
+            .collect())
+    }
+
+    /// Prints entries grouped by health, followed by the health score.
+    pub fn doctor(&self, json: bool) -> Result<()> {
+        let report = self.doctor_report()?;
+        if json {
+            println!("{}", serde_json::to_string_pretty(&report)?);
+            return Ok(());
+        }
+        for (health, entries) in &report.categories {
+            println!("{}: {}", health, entries.len());
+            if health == "healthy" {
+                continue;
+            }
+            for entry in entries {
+                println!("  {} -> {}", entry.source, entry.dest);
+            }
+        }
+        println!(
+            "Health score: {:.0}% ({} of {} entries are healthy)",
+            report.summary.health_score, report.summary.healthy, report.summary.total
+        );
+        Ok(())
+    }
+
+    /// Repairs broken entries by `fixes` and prints what was changed. Problems which
+    /// can't be repaired safely are listed afterwards.
+    pub fn doctor_fix(&mut self, fixes: &[DoctorFix]) -> Result<()> {
+        let mut batch = Batch::new(self.error_policy);
+        for (src, dest, health) in self.health_report()? {
+            let dest_exists = fs::symlink_metadata(self.workspace.dest_abs(&dest)).is_ok();
+            let fix = match health {
+                Health::Dangling => DoctorFix::Prune,
+                Health::Missing if !dest_exists => DoctorFix::Prune,
+                Health::Missing => DoctorFix::Recreate,
+                Health::Detached => DoctorFix::Relink,
+                Health::Healthy | Health::Repointed => continue,
+            };
+            if !fixes.contains(&fix) {
+                continue;
+            }
+            let result = match fix {
+                DoctorFix::Prune => self.prune_entry(&src, &dest),
+                DoctorFix::Recreate => {
+                    let rendered = self.file_mappings()?.stored_attributes(&src).rendered;
+                    let source = self.workspace.expand_home(&src);
+                    self.restore_entry(
+                        &src,
+                        &source,
+                        &self.workspace.dest_abs(&dest),
+                        rendered,
+                        false,
+                    )
+                }
+                DoctorFix::Relink => match self.relink_detached(&src, &dest) {
+                    Ok(false) => continue,
+                    Ok(true) => Ok(()),
+                    Err(err) => Err(err),
+                },
+            };
+            self.audit(
+                fix.name(),
+                &self.workspace.expand_home(&src),
+                &dest,
+                &result,
+            );
+            if result.is_ok() {
+                println!("{}: {} -> {}", fix, src, dest);
+            }
+            batch.record(&src, result)?;
+        }
+        println!("Fixed {} entries.", batch.succeeded());
+        let remaining: Vec<_> = self
+            .health_report()?
+            .into_iter()
+            .filter(|(_, _, health)| *health != Health::Healthy)
+            .collect();
+        if !remaining.is_empty() {
+            println!("Remaining problems:");
+            for (src, dest, health) in &remaining {
+                println!("  {} {} -> {}", health, src, dest);
+            }
+        }
+        batch.finish()
+    }
+
+    /// Removes entry whose dest does not exist. The dangling symlink is removed too.
+    fn prune_entry(&mut self, src: &str, dest: &str) -> Result<()> {
+        let source = self.workspace.expand_home(src);
+        if check_health(&source, &self.workspace.dest_abs(dest)) == Health::Dangling {
+            debug!("Removing dangling symlink: {}", source.to_string_lossy());
+            fs::remove_file(&source)?;
+        }
+        self.file_mappings_mut()?.remove(&source)?;
+        Ok(())
+    }
+
+    /// Replaces detached file at source with the link if it has the same contents as the
+    /// dest. Returns `false` without changes if contents differ, since either of them may
+    /// have the changes to keep. Rendered and hardlinked entries are left for `restore`.
+    fn relink_detached(&self, src: &str, dest: &str) -> Result<bool> {
+        let attributes = self.file_mappings()?.stored_attributes(src);
+        if attributes.rendered || attributes.hardlink {
+            return Ok(false);
+        }
+        let source = self.workspace.expand_home(src);
+        let dest_abs = self.workspace.dest_abs(dest);
+        let contents = fs::read(&source)?;
+        if !dest_abs.is_file() || fs::read(&dest_abs)? != contents {
+            return Ok(false);
+        }
+        self.backup("doctor", &source)?;
+        debug!("Removing detached file: {}", source.to_string_lossy());
+        fs::remove_file(&source)?;
+        if let Err(err) = self.create_link(&dest_abs, &source) {
+            fs::write(&source, contents)?;
+            return Err(err);
+        }
+        Ok(true)
+    }
+
+    fn doctor_report(&self) -> Result<DoctorReport> {
+        let mut categories: BTreeMap<String, Vec<DoctorEntry>> = Health::ALL
+            .iter()
+            .map(|health| (health.to_string(), Vec::new()))
+            .collect();
+        let report = self.health_report()?;
+        for (source, dest, health) in &report {
+            categories
+                .get_mut(&health.to_string())
+                .expect("Every health has a category")
+                .push(DoctorEntry {
+                    source: source.clone(),
+                    dest: dest.clone(),
+                });
+        }
+        let total = report.len();
+        let healthy = categories["healthy"].len();
+        Ok(DoctorReport {
+            version: DoctorReport::VERSION,
+            summary: DoctorSummary {
+                total,
+                healthy,
+                broken: total - healthy,
+                counts: categories
+                    .iter()
+                    .map(|(health, entries)| (health.clone(), entries.len()))
+                    .collect(),
+                health_score: if total == 0 {
+                    100.0
+                } else {
+                    healthy as f64 * 100.0 / total as f64
+                },
+            },
+            categories,
+        })
+    }
+
+    /// Returns actual target of the symlink of each entry.
+    fn link_targets(&self) -> Result<Vec<LinkTarget>> {
+        Ok(self
+            .health_report()?
+            .into_iter()
+            .map(|(src, dest, health)| {
+                let actual = fs::read_link(self.workspace.expand_home(&src)).ok();
+                LinkTarget {
+                    source: src,
+                    health,
+                    expected: self.workspace.dest_abs(dest),
+                    actual,
+                }
+            })
+            .collect())
+    }
+
+    /// `check_health` which treats a regular file at the source of rendered entry, and
+    /// a hard link of the dest at the source of hard-linked entry as healthy.
+    fn entry_health(&self, src: &str, dest: &str) -> Health {
+        let source = self.workspace.expand_home(src);
+        let dest_abs = self.workspace.dest_abs(dest);
+        let (rendered, hardlink) = self
+            .file_mappings()
+            .map(|file_mappings| {
+                let attributes = file_mappings.stored_attributes(src);
+                (attributes.rendered, attributes.hardlink)
+            })
+            .unwrap_or((false, false));
+        if !rendered && !hardlink {
+            return check_health(&source, &dest_abs);
+        }
+        match fs::symlink_metadata(&source) {
+            Ok(metadata) if metadata.is_file() && rendered => Health::Healthy,
+            Ok(metadata) if metadata.is_file() && !dest_abs.exists() => Health::Dangling,
+            Ok(metadata) if metadata.is_file() && is_same_file(&source, &dest_abs) => {
+                Health::Healthy
+            }
+            // Replaced by e.g. an editor which saves files by renaming
+            Ok(metadata) if metadata.is_file() => Health::Detached,
+            Ok(_) => Health::Repointed,
+            Err(_) => Health::Missing,
+        }
+    }
+
+    /// Prints path of the workspace file which `source` is linked to.
+    pub fn which<P: AsRef<Path>>(&self, source: P, print0: bool) -> Result<()> {
+        let dest_abs = self.workspace.dest_abs(self.file_mappings()?.get(source)?);
+        print_path(&dest_abs.to_string_lossy(), print0)
+    }
+
+    /// Returns `dest` if specified. Otherwise derives it from `source` if `mirror` or
+    /// `dest_from_source` in config is enabled.
+    ///
+    /// Dest is derived from the path relative to `prefix`, or home if `None`. Specifying
+    /// `prefix` implies `mirror`. `dest_root` in config is prepended in both cases. Derived
+    /// dests follow the case set by `set_dest_case`.
+    pub fn resolve_dest<P: AsRef<Path>>(
+        &self,
+        source: P,
+        dest: Option<&str>,
+        mirror: bool,
+        strip: Option<usize>,
+        prefix: Option<&Path>,
+    ) -> Result<String> {
+        if let Some(dest) = dest {
+            return self.prepend_dest_root(dest.to_string());
+        }
+        let dest = match prefix {
+            Some(prefix) => {
+                let source = normalize_path(source);
+                let prefix = normalize_path(prefix);
+                if !source.starts_with(&prefix) {
+                    return Err(ErrorKind::SourceInvalid.error(format!(
+                        "Source: {} does not start with prefix: {}!",
+                        source.to_string_lossy(),
+                        prefix.to_string_lossy()
+                    )));
+                }
+                mirror_dest(source, prefix)?
+            }
+            None if mirror || self.config.dest_from_source => {
+                let home =
+                    self.workspace.home()?.to_path_buf();
+                mirror_dest(source, home)?
+            }
+            None => {
+                return Err(ErrorKind::DestInvalid.error(
+                    "Dest is not specified! Specify dest, use --mirror or enable dest_from_source in config.",
+                ))
+            }
+        };
+        let dest = match strip {
+            Some(count) => strip_components(&dest, count)?,
+            None => dest,
+        };
+        let dest = match self.dest_case {
+            DestCase::Keep => dest,
+            DestCase::Lower => dest.to_lowercase(),
+        };
+        self.prepend_dest_root(dest)
+    }
+
+    /// Places `dest` under `dest_root` in config. `dest` must stay inside the root.
+    fn prepend_dest_root(&self, dest: String) -> Result<String> {
+        let root = match &self.config.dest_root {
+            Some(root) => root,
+            None => return Ok(dest),
+        };
+        validate_dest(root)
+            .map_err(|err| ErrorKind::Config.error(format!("Invalid dest_root! {}", err)))?;
+        validate_dest(&dest)?;
+        Ok(Path::new(root).join(dest).to_string_lossy().to_string())
+    }
+
+    /// If `dest` is an existing directory in the workspace, the file is placed in it
+    /// by the file name of `source`, as `mv` does.
+    pub fn link<P: AsRef<Path>>(
+        &mut self,
+        source: P,
+        dest: &str,
+        if_exists: IfExists,
+    ) -> Result<()> {
+        let source = source.as_ref();
+        let dest = self.dest_in_dir(source, dest);
+        self.link_path(source, &dest, if_exists, false)
+    }
+
+    /// Returns `dest/<file name of source>` if `dest` is an existing directory in the
+    /// workspace. Otherwise `dest` itself.
+    fn dest_in_dir(&self, source: &Path, dest: &str) -> String {
+        let is_dir = fs::symlink_metadata(self.workspace.dest_abs(dest))
+            .map(|metadata| metadata.is_dir())
+            .unwrap_or(false);
+        match source.file_name() {
+            Some(name) if is_dir && !dest.is_empty() => {
+                let dest = Path::new(dest).join(name).to_string_lossy().to_string();
+                debug!("Dest is a directory. Linking to '{}'", dest);
+                dest
+            }
+            _ => dest.to_string(),
+        }
+    }
+
+    /// Links each of `sources` to a dest derived from its path, as `--mirror` does.
+    /// Sources which get the same dest, e.g. by `strip`, are handled by `collision`.
+    ///
+    /// All dests are derived before linking, so a source outside `prefix` fails the
+    /// batch without making any changes.
+    pub fn link_batch(
+        &mut self,
+        sources: &[PathBuf],
+        prefix: Option<&Path>,
+        strip: Option<usize>,
+        if_exists: IfExists,
+        collision: DestCollision,
+    ) -> Result<()> {
+        let derived = sources
+            .iter()
+            .map(|source| self.resolve_dest(source, None, true, strip, prefix))
+            .collect::<Result<Vec<String>>>()?;
+        // Suffixed dests must not be used by existing entries either
+        let mapped: Vec<PathBuf> = if self.workspace.exists() {
+            self.file_mappings()?
+                .as_map()
+                .values()
+                .map(|dest| normalize_dest(dest))
+                .collect()
+        } else {
+            Vec::new()
+        };
+        let mut entries: Vec<(&PathBuf, String)> = Vec::new();
+        for (source, dest) in sources.iter().zip(derived) {
+            let owner = entries
+                .iter()
+                .find(|(_, other)| normalize_dest(other) == normalize_dest(&dest))
+                .map(|(owner, _)| owner.to_string_lossy().to_string());
+            let owner = match owner {
+                Some(owner) => owner,
+                None => {
+                    entries.push((source, dest));
+                    continue;
+                }
+            };
+            match collision {
+                DestCollision::Error => {
+                    return Err(ErrorKind::DestExists.error(format!(
+                        "Sources: {} and {} have the same dest: {}!",
+                        owner,
+                        source.to_string_lossy(),
+                        dest
+                    )))
+                }
+                DestCollision::Skip => warn!(
+                    "Dest: {} is already used by {}. Skipped {}.",
+                    dest,
+                    owner,
+                    source.to_string_lossy()
+                ),
+                DestCollision::Suffix => {
+                    let dest = (1..)
+                        .map(|n| suffix_dest(&dest, n))
+                        .find(|dest| {
+                            let used = entries
+                                .iter()
+                                .any(|(_, other)| normalize_dest(other) == normalize_dest(dest))
+                                || mapped.contains(&normalize_dest(dest));
+                            !used && fs::symlink_metadata(self.workspace.dest_abs(dest)).is_err()
+                        })
+                        .expect("Unused suffix is always found");
+                    entries.push((source, dest));
+                }
+            }
+        }
+        let mut batch = Batch::new(self.error_policy);
+        for (source, dest) in entries {
+            println!("{}", source.to_string_lossy());
+            let result = self.link(source, &dest, if_exists);
+            self.progress.result("link", source, &dest, &result);
+            batch.record(&source.to_string_lossy(), result)?;
+        }
+        batch.finish()
+    }
+
+    /// Links files in `source_dir` into `dest_dir` one by one.
+    ///
+    /// With `depth`, only entries in the top `depth` levels are managed individually.
+    /// Directories at level `depth` are linked as a whole, so mappings contain both
+    /// files and directories. e.g. with depth 1, `nvim/init.vim` is linked as a file and
+    /// `nvim/lua` as a directory. Symlinks in `source_dir` are skipped.
+    pub fn link_recursive<P: AsRef<Path>>(
+        &mut self,
+        source_dir: P,
+        dest_dir: &str,
+        depth: Option<usize>,
+        if_exists: IfExists,
+    ) -> Result<()> {
+        let source_dir = source_dir.as_ref();
+        if !fs::symlink_metadata(source_dir)?.is_dir() {
+            return Err(ErrorKind::SourceInvalid.error(format!(
+                "Source: {} is not a directory!",
+                source_dir.to_string_lossy()
+            )));
+        }
+        if depth == Some(0) {
+            return Err(ErrorKind::Other.error("Depth must be 1 or greater!"));
+        }
+        let entries = walk_dir(source_dir, WalkOptions::default(), |entry| {
+            depth != Some(entry.depth)
+        })?;
+        for entry in entries {
+            let descended = entry.file_type.is_dir() && depth != Some(entry.depth);
+            if descended {
+                continue;
+            }
+            if entry.file_type.is_dir() || entry.file_type.is_file() {
+                println!("{}", entry.path.to_string_lossy());
+                let dest = Path::new(dest_dir).join(&entry.relative);
+                let dest = dest.to_string_lossy();
+                let result = self.link_path(&entry.path, &dest, if_exists, true);
+                self.progress.result("link", &entry.path, &dest, &result);
+                result?;
+            } else {
+                warn!(
+                    "{} is a {}. Skipped.",
+                    entry.path.to_string_lossy(),
+                    describe_file_type(&entry.file_type)
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Directories are refused unless `allow_dir`.
+    fn link_path(
+        &mut self,
+        source: &Path,
+        dest: &str,
+        if_exists: IfExists,
+        allow_dir: bool,
+    ) -> Result<()> {
+        let result = self.link_path_unaudited(source, dest, if_exists, allow_dir);
+        self.audit("link", source, dest, &result);
+        result
+    }
+
+    fn link_path_unaudited(
+        &mut self,
+        source: &Path,
+        dest: &str,
+        if_exists: IfExists,
+        allow_dir: bool,
+    ) -> Result<()> {
+        ensure_utf8(source)?;
+        if !source.exists() {
+            return Err(ErrorKind::SourceMissing.error(format!(
+                "Source file: {} does not exist!",
+                source.to_string_lossy()
+            )));
+        }
+        let file_type = fs::symlink_metadata(source)?.file_type();
+        let linkable = file_type.is_file() || (allow_dir && file_type.is_dir());
+        if !linkable {
+            return Err(ErrorKind::SourceInvalid.error(format!(
+                "Source file: {} is a {}, not a regular file!",
+                source.to_string_lossy(),
+                describe_file_type(&file_type)
+            )));
+        }
+        if self.link_hardlink && !file_type.is_file() {
+            return Err(ErrorKind::SourceInvalid.error(format!(
+                "Source file: {} is a directory! Only regular files can be hard-linked.",
+                source.to_string_lossy()
+            )));
+        }
+        if self.workspace.is_inside(source) {
+            return Err(ErrorKind::SourceInvalid.error(format!(
+                "Source file: {} is inside the workspace! Files in the workspace can't be moved into it.",
+                source.to_string_lossy()
+            )));
+        }
+        // Moving the file away succeeds on some read-only mounts but the symlink can't be
+        // created in its place, so check before making any changes
+        let source_dir = normalize_path(source);
+        let source_dir = source_dir.parent().unwrap_or_else(|| Path::new("/"));
+        ensure_dir_writable(source_dir).map_err(|err| {
+            ErrorKind::SourceInvalid.error(format!(
+                "Directory: {} is not writable, so the symlink can't be created at {}! error: {}",
+                source_dir.to_string_lossy(),
+                source.to_string_lossy(),
+                err
+            ))
+        })?;
+        self.ensure_workspace()?;
+        let mut replaced_dest = None;
+        let mut replacing = false;
+        if self.file_mappings()?.contains(source) {
+            match if_exists {
+                IfExists::Skip => {
+                    println!("{} is already managed. Skipped.", source.to_string_lossy());
+                    return Ok(());
+                }
+                IfExists::Error => {
+                    return Err(ErrorKind::AlreadyManaged.error(format!(
+                        "File: {} is already managed by this tool!",
+                        source.to_string_lossy()
+                    )));
+                }
+                IfExists::Overwrite => {
+                    if fs::symlink_metadata(source)
+                        .map(|metadata| metadata.file_type().is_symlink())
+                        .unwrap_or(false)
+                    {
+                        return Err(ErrorKind::SourceInvalid.error(format!(
+                            "Source file: {} is a symlink! Unlink it first.",
+                            source.to_string_lossy()
+                        )));
+                    }
+                    replaced_dest = self.replaced_dest(source, dest)?;
+                    replacing = true;
+                }
+            }
+        }
+        let dest_abs = self.workspace.checked_dest_abs(dest)?;
+        if fs::symlink_metadata(&dest_abs).is_ok() {
+            match if_exists {
+                IfExists::Skip => {
+                    println!("{} already exists. Skipped.", dest_abs.to_string_lossy());
+                    return Ok(());
+                }
+                IfExists::Error => {
+                    return Err(ErrorKind::DestExists.error(format!(
+                        "Dest file: {} already exists!",
+                        dest_abs.to_string_lossy()
+                    )));
+                }
+                IfExists::Overwrite => {
+                    self.backup("link", &dest_abs).map_err(|err| {
+                        anyhow!(
+                            "Failed to backup {} error: {}",
+                            dest_abs.to_string_lossy(),
+                            err
+                        )
+                    })?;
+                    debug!("Overwriting '{}'", dest_abs.to_string_lossy());
+                }
+            }
+        }
+        if let Some(replaced_dest) = &replaced_dest {
+            self.backup("link", replaced_dest)?;
+        }
+        let replaced_entry = if replacing {
+            debug!("Removing existing entry...");
+            Some(self.file_mappings_mut()?.take_entry(source)?)
+        } else {
+            None
+        };
+        let mut created_dirs = CreatedDirs::default();
+        let moved = self.move_into_workspace(
+            source,
+            dest,
+            &dest_abs,
+            file_type.is_dir(),
+            &mut created_dirs,
+        );
+        if moved.is_err() {
+            created_dirs.rollback();
+            if let Some(entry) = replaced_entry {
+                debug!("Restoring the replaced entry...");
+                self.file_mappings_mut()?.put_entry(entry);
+            }
+        }
+        moved?;
+        if let Some(replaced_dest) = replaced_dest {
+            debug!("Removing '{}'", replaced_dest.to_string_lossy());
+            fs::remove_file(&replaced_dest)?;
+        }
+        if self.config.content_hashes && dest_abs.is_file() {
+            let hash = hash_file(&dest_abs)?;
+            self.file_mappings_mut()?.set_hash(source, Some(hash));
+        }
+        self.record(Operation::Link {
+            source: self.workspace.strip_home(source),
+            dest: dest.to_string(),
+        });
+        println!("Linked!");
+        Ok(())
+    }
+
+    /// Returns the workspace file of the existing entry of `source` which is replaced by
+    /// `dest`, to be removed after linking. Fails if it may have changes which would be
+    /// lost, i.e. it differs from `source` and the backup directory is not specified.
+    fn replaced_dest(&self, source: &Path, dest: &str) -> Result<Option<PathBuf>> {
+        let old_dest = self.file_mappings()?.get(source)?;
+        let old_dest_abs = self.workspace.dest_abs(old_dest);
+        if normalize_dest(old_dest) == normalize_dest(dest)
+            || fs::symlink_metadata(&old_dest_abs).is_err()
+        {
+            return Ok(None);
+        }
+        let removable = old_dest_abs.is_file()
+            && (self.backup_dir.is_some()
+                || (source.is_file() && fs::read(&old_dest_abs)? == fs::read(source)?));
+        if !removable {
+            return Err(ErrorKind::Conflict.error(format!(
+                "Dest: {} of the existing entry differs from {}! Specify --backup-dir to replace it anyway.",
+                old_dest_abs.to_string_lossy(),
+                source.to_string_lossy()
+            )));
+        }
+        Ok(Some(old_dest_abs))
+    }
+
+    /// Steps of `link` which move `source` to `dest_abs` and link it back. Directories
+    /// created for `dest_abs` are pushed to `created_dirs`, so the caller can remove them
+    /// if a step fails. The entry is added before linking since `create_link` reads its
+    /// attributes, and removed again if moving or linking fails.
+    fn move_into_workspace(
+        &mut self,
+        source: &Path,
+        dest: &str,
+        dest_abs: &Path,
+        is_dir: bool,
+        created_dirs: &mut CreatedDirs,
+    ) -> Result<()> {
+        if let Some(parent) = dest_abs.parent() {
+            debug!(
+                "Creating parent directories for '{}'",
+                dest_abs.to_string_lossy()
+            );
+            created_dirs
+                .create_all(parent, self.link_dir_mode)
+                .map_err(|err| {
+                    ErrorKind::Io.error(format!(
+                        "Failed to create directory: {} error: {}",
+                        parent.to_string_lossy(),
+                        err
+                    ))
+                })?;
+        }
+        self.backup("link", source).map_err(|err| {
+            anyhow!(
+                "Failed to backup {} error: {}",
+                source.to_string_lossy(),
+                err
+            )
+        })?;
+        debug!("Updating entries...");
+        let tags = self.link_tags.clone();
+        let after = self.link_after.clone();
+        let hardlink = self.link_hardlink;
+        let file_mappings = self.file_mappings_mut()?;
+        file_mappings.add(source, dest)?;
+        file_mappings.set_directory(source, is_dir);
+        file_mappings.set_hardlink(source, hardlink);
+        file_mappings.set_tags(source, tags);
+        file_mappings.set_after(source, after);
+        let linked = self.rename_and_link(source, dest_abs);
+        if linked.is_err() {
+            debug!("Removing the added entry...");
+            let _ = self.file_mappings_mut()?.remove(source);
+        }
+        linked
+    }
+
+    /// Moves `source` to `dest_abs` and links it back. `source` is moved back if linking
+    /// fails.
+    fn rename_and_link(&self, source: &Path, dest_abs: &Path) -> Result<()> {
+        debug!(
+            "Creating symbolic link from '{}' to '{}'",
+            source.to_string_lossy(),
+            dest_abs.to_string_lossy()
+        );
+        fs::rename(source, dest_abs).map_err(|err| {
+            ErrorKind::Io.error(format!(
+                "Failed to move {} into {} error: {}",
+                source.to_string_lossy(),
+                dest_abs.to_string_lossy(),
+                err
+            ))
+        })?;
+        self.create_link(dest_abs, source).map_err(|err| {
+            if let Err(err) = fs::rename(dest_abs, source) {
+                error!(
+                    "Failed to move {} back error: {}",
+                    dest_abs.to_string_lossy(),
+                    err
+                );
+            }
+            ErrorKind::Io.error(format!(
+                "Failed to create link! dest: '{}' source: '{}' error: {}",
+                source.to_string_lossy(),
+                dest_abs.to_string_lossy(),
+                err
+            ))
+        })
+    }
+
+    /// Same as `link` but writes the contents rendered with variables in config to
+    /// `source` instead of symlink. The workspace file is used as the template.
+    pub fn link_rendered<P: AsRef<Path>>(
+        &mut self,
+        source: P,
+        dest: &str,
+        if_exists: IfExists,
+    ) -> Result<()> {
+        let source = source.as_ref();
+        if source.is_file() {
+            // Fail before moving anything if the file is not a valid template
+            render_template(&fs::read_to_string(source)?, &self.config.variables)?;
+        }
+        let dest = &self.dest_in_dir(source, dest);
+        self.link(source, dest, if_exists)?;
+        let dest_abs = self.workspace.dest_abs(dest);
+        if self.file_mappings()?.get(source).ok() != Some(dest)
+            || check_health(source, &dest_abs) != Health::Healthy
+        {
+            // Skipped by `if_exists`
+            return Ok(());
+        }
+        self.materialize(&dest_abs, source)?;
+        self.file_mappings_mut()?.set_rendered(source, true);
+        println!("Rendered!");
+        Ok(())
+    }
+
+    /// Renders template `dest_abs` and writes it to `source`, replacing existing file.
+    fn materialize(&self, dest_abs: &Path, source: &Path) -> Result<()> {
+        let rendered = render_template(&fs::read_to_string(dest_abs)?, &self.config.variables)
+            .map_err(|err| {
+                anyhow!(
+                    "Failed to render {} error: {}",
+                    dest_abs.to_string_lossy(),
+                    err
+                )
+            })?;
+        let tmp = Self::temp_symlink_path(source);
+        fs::write(&tmp, rendered)?;
+        if let Err(err) = fs::rename(&tmp, source) {
+            let _ = fs::remove_file(&tmp);
+            return Err(err.into());
+        }
+        Ok(())
+    }
+
+    /// Writes contents of `reader` into the workspace as `dest` and creates symlink at
+    /// `link_at`. Used for generated files which don't exist before linking.
+    pub fn link_from_reader<R: Read, P: AsRef<Path>>(
+        &mut self,
+        reader: &mut R,
+        dest: &str,
+        link_at: P,
+        if_exists: IfExists,
+    ) -> Result<()> {
+        let link_at = link_at.as_ref();
+        if fs::symlink_metadata(link_at).is_ok() {
+            return Err(ErrorKind::SourceInvalid.error(format!(
+                "File: {} already exists!",
+                link_at.to_string_lossy()
+            )));
+        }
+        if let Some(parent) = link_at.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        debug!("Writing stdin into '{}'", link_at.to_string_lossy());
+        let written = File::create(link_at)
+            .map_err(anyhow::Error::from)
+            .and_then(|mut file| Ok(io::copy(reader, &mut file)?));
+        let result = written.and_then(|_| self.link(link_at, dest, if_exists));
+        let linked = fs::symlink_metadata(link_at)
+            .map(|metadata| metadata.file_type().is_symlink())
+            .unwrap_or(false);
+        if !linked {
+            debug!("Removing '{}'", link_at.to_string_lossy());
+            let _ = fs::remove_file(link_at);
+        }
+        result
+    }
+
+    /// Same as `link` but the symlink is created as `name` in the directory of `source`,
+    /// e.g. `config` for `config.example`. The entry is recorded for the symlink, so
+    /// `unlink` moves the file back as `name`.
+    pub fn link_named<P: AsRef<Path>>(
+        &mut self,
+        source: P,
+        name: &OsStr,
+        dest: &str,
+        if_exists: IfExists,
+    ) -> Result<()> {
+        let source = source.as_ref();
+        let mut components = Path::new(name).components();
+        let valid = matches!(
+            (components.next(), components.next()),
+            (Some(Component::Normal(_)), None)
+        );
+        if !valid {
+            return Err(ErrorKind::SourceInvalid.error(format!(
+                "Name: {} must be a file name without path separators!",
+                name.to_string_lossy()
+            )));
+        }
+        let link_at = source.with_file_name(name);
+        if link_at == source {
+            return self.link(source, dest, if_exists);
+        }
+        if !source.exists() {
+            return Err(ErrorKind::SourceMissing.error(format!(
+                "Source file: {} does not exist!",
+                source.to_string_lossy()
+            )));
+        }
+        if fs::symlink_metadata(&link_at).is_ok() {
+            return Err(ErrorKind::SourceInvalid.error(format!(
+                "File: {} already exists!",
+                link_at.to_string_lossy()
+            )));
+        }
+        debug!(
+            "Renaming '{}' to '{}'",
+            source.to_string_lossy(),
+            link_at.to_string_lossy()
+        );
+        fs::rename(source, &link_at)?;
+        let result = self.link(&link_at, dest, if_exists);
+        let linked = fs::symlink_metadata(&link_at)
+            .map(|metadata| metadata.file_type().is_symlink())
+            .unwrap_or(false);
+        if !linked {
+            debug!("Renaming '{}' back", link_at.to_string_lossy());
+            fs::rename(&link_at, source)?;
+        }
+        result
+    }
+
+    /// Creates symlink at `link` which points to `dest_abs` in `symlink_style` of config.
+    ///
+    /// The kind of symlink follows the recorded type of the entry of `link`. Entries
+    /// linked by older versions have no recorded type, so `dest_abs` is inspected.
+    /// Missing parent directories of `link` are created.
+    fn create_link(&self, dest_abs: &Path, link: &Path) -> Result<()> {
+        // e.g. `~/.config` of `~/.config/app` on a new machine
+        if let Some(parent) = link.parent() {
+            if !parent.as_os_str().is_empty() && !parent.exists() {
+                debug!(
+                    "Creating parent directories of '{}'",
+                    link.to_string_lossy()
+                );
+                fs::create_dir_all(parent)?;
+            }
+        }
+        let hardlink = self
+            .file_mappings()
+            .map(|file_mappings| file_mappings.is_hardlink(link))
+            .unwrap_or(false);
+        if hardlink {
+            return Self::create_hard_link(dest_abs, link);
+        }
+        let dir = self
+            .file_mappings()
+            .map(|file_mappings| file_mappings.is_directory(link))
+            .unwrap_or(false)
+            || dest_abs.is_dir();
+        let target = match self.config.symlink_style {
+            SymlinkStyle::Absolute => dest_abs.to_path_buf(),
+            SymlinkStyle::Relative => {
+                let link_dir = normalize_path(link);
+                let link_dir = link_dir.parent().unwrap_or_else(|| Path::new("/"));
+                relative_path(link_dir, &normalize_path(dest_abs))
+            }
+        };
+        Self::create_symlink(&target, link, dir)
+    }
+
+    /// Creates symlink at `dest` which points to `source`.
+    ///
+    /// The symlink is created at a temporary name in the same directory and then renamed
+    /// into place, so an existing file at `dest` is replaced atomically on unix.
+    ///
+    /// On Windows, `fs::rename` replaces an existing file but fails if `dest` is a
+    /// directory (including directory symlinks), and the replacement is not guaranteed
+    /// to be atomic.
+    ///
+    /// `dir` selects a directory symlink on Windows, where `source` can't be inspected
+    /// since it may be relative or not exist yet. Unix has one kind of symlink.
+    fn create_symlink(source: &Path, dest: &Path, dir: bool) -> Result<()> {
+        let tmp = Self::temp_symlink_path(dest);
+        if fs::symlink_metadata(&tmp).is_ok() {
+            debug!("Removing stale temporary file: {}", tmp.to_string_lossy());
+            fs::remove_file(&tmp)?;
+        }
+        Self::symlink(source, &tmp, dir)?;
+        if let Err(err) = fs::rename(&tmp, dest) {
+            let _ = fs::remove_file(&tmp);
+            return Err(err.into());
+        }
+        Ok(())
+    }
+
+    /// Creates hard link at `link` of `dest_abs`, replacing an existing file in the same
+    /// way as `create_symlink`. Fails if they are on different filesystems.
+    fn create_hard_link(dest_abs: &Path, link: &Path) -> Result<()> {
+        let tmp = Self::temp_symlink_path(link);
+        if fs::symlink_metadata(&tmp).is_ok() {
+            debug!("Removing stale temporary file: {}", tmp.to_string_lossy());
+            fs::remove_file(&tmp)?;
+        }
+        fs::hard_link(dest_abs, &tmp)?;
+        if let Err(err) = fs::rename(&tmp, link) {
+            let _ = fs::remove_file(&tmp);
+            return Err(err.into());
+        }
+        Ok(())
+    }
+
+    fn temp_symlink_path(dest: &Path) -> PathBuf {
+        let file_name = dest
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        dest.with_file_name(format!(".{}.dotman-tmp", file_name))
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    fn symlink(source: &Path, dest: &Path, _dir: bool) -> Result<()> {
+        std::os::unix::fs::symlink(source, dest)?;
+        Ok(())
+    }
+
+    /// Creating symlinks requires Developer Mode or the "Create symbolic links"
+    /// privilege (granted to administrators by default).
+    #[cfg(target_os = "windows")]
+    fn symlink(source: &Path, dest: &Path, dir: bool) -> Result<()> {
+        if dir {
+            std::os::windows::fs::symlink_dir(source, dest)?;
+        } else {
+            std::os::windows::fs::symlink_file(source, dest)?;
+        }
+        Ok(())
+    }
+
+    /// Unless `force`, refuses if the symlink does not point to the recorded dest.
+    pub fn unlink<P: AsRef<Path>>(&mut self, source: P, force: bool) -> Result<()> {
+        self.unlink_to(source, None, force)
+    }
+
+    /// Same as `unlink` but the file is restored at `to` instead of `source` if
+    /// specified. The symlink at `source` is removed.
+    pub fn unlink_to<P: AsRef<Path>>(
+        &mut self,
+        source: P,
+        to: Option<&Path>,
+        force: bool,
+    ) -> Result<()> {
+        let source = source.as_ref();
+        let dest = self
+            .file_mappings()
+            .ok()
+            .and_then(|file_mappings| file_mappings.get(source).ok())
+            .unwrap_or_default()
+            .to_string();
+        let result = self.unlink_with(source, to, force, |from, to| fs::rename(from, to));
+        self.audit("unlink", source, &dest, &result);
+        result
+    }
+
+    /// Removes the mapping of `source` but leaves the symlink and the workspace file as
+    /// they are. Refuses if the workspace file does not exist, unless `force`, since the
+    /// symlink would be left dangling. A note about the now-unmanaged symlink is written
+    /// to `out`.
+    pub fn unlink_keep<P: AsRef<Path>, W: Write>(
+        &mut self,
+        source: P,
+        force: bool,
+        out: &mut W,
+    ) -> Result<()> {
+        let source = source.as_ref();
+        let mapped_dest = self.file_mappings()?.get(source)?.to_string();
+        let dest_abs = self.workspace.dest_abs(&mapped_dest);
+        let result = if fs::symlink_metadata(&dest_abs).is_err() && !force {
+            Err(ErrorKind::DestInvalid.error(format!(
+                "Dest: {} does not exist! Symlink: {} would be left dangling. Use --force to unlink anyway.",
+                dest_abs.to_string_lossy(),
+                source.to_string_lossy()
+            )))
+        } else {
+            self.file_mappings_mut()
+                .and_then(|file_mappings| Ok(file_mappings.remove(source)?))
+        };
+        self.audit("unlink", source, &mapped_dest, &result);
+        result?;
+        self.record(Operation::Unlink {
+            source: self.workspace.strip_home(source),
+            dest: mapped_dest,
+            to: None,
+        });
+        if dest_abs.exists() {
+            writeln!(
+                out,
+                "Note: {} is no longer managed but still points to {}. It will dangle if the file is removed from the workspace.",
+                source.to_string_lossy(),
+                dest_abs.to_string_lossy()
+            )?;
+        } else {
+            writeln!(
+                out,
+                "Note: {} is no longer managed and points to {}, which does not exist.",
+                source.to_string_lossy(),
+                dest_abs.to_string_lossy()
+            )?;
+        }
+        println!("Unlinked!");
+        Ok(())
+    }
+
+    /// Same as `unlink_to` but if `source` is detached, i.e. the symlink was replaced by
+    /// a regular file (e.g. by an editor which saves by renaming), only removes the stale
+    /// entry since there is no symlink to remove. The file at `source` is kept as it is.
+    /// With `remove_dest`, the workspace copy of a detached entry is removed too after
+    /// taking a backup. Removing a detached entry is not recorded since `undo` could not
+    /// link the file back.
+    pub fn unlink_detached_ok<P: AsRef<Path>>(
+        &mut self,
+        source: P,
+        to: Option<&Path>,
+        force: bool,
+        remove_dest: bool,
+    ) -> Result<()> {
+        let source = source.as_ref();
+        let mapped_dest = self.file_mappings()?.get(source)?.to_string();
+        if self.entry_health(&self.workspace.strip_home(source), &mapped_dest) != Health::Detached {
+            return self.unlink_to(source, to, force);
+        }
+        let result = self.remove_detached(source, &mapped_dest, remove_dest);
+        self.audit("unlink", source, &mapped_dest, &result);
+        result?;
+        println!(
+            "Removed the entry of detached file: {}",
+            source.to_string_lossy()
+        );
+        Ok(())
+    }
+
+    fn remove_detached(&mut self, source: &Path, dest: &str, remove_dest: bool) -> Result<()> {
+        let dest_abs = self.workspace.dest_abs(dest);
+        if remove_dest && fs::symlink_metadata(&dest_abs).is_ok() {
+            self.backup("unlink", &dest_abs).map_err(|err| {
+                anyhow!(
+                    "Failed to backup {} error: {}",
+                    dest_abs.to_string_lossy(),
+                    err
+                )
+            })?;
+            debug!("Removing '{}'", dest_abs.to_string_lossy());
+            if dest_abs.is_dir() {
+                fs::remove_dir_all(&dest_abs)?;
+            } else {
+                fs::remove_file(&dest_abs)?;
+            }
+        }
+        self.file_mappings_mut()?.remove(source)?;
+        Ok(())
+    }
+
+    /// Unlinks all entries which have `tag`. Stops at the first failure.
+    pub fn unlink_tagged(&mut self, tag: &str, force: bool) -> Result<()> {
+        let file_mappings = self.file_mappings()?;
+        let sources: Vec<PathBuf> = file_mappings
+            .as_map()
+            .keys()
+            .filter(|src| file_mappings.has_tag(src, Some(tag)))
+            .map(|src| self.workspace.expand_home(src))
+            .collect();
+        if sources.is_empty() {
+            return Err(ErrorKind::NotManaged.error(format!("No entries have tag: {}!", tag)));
+        }
+        let mut batch = Batch::new(self.error_policy);
+        for source in sources {
+            println!("{}", source.to_string_lossy());
+            let result = self.unlink(&source, force);
+            batch.record(&source.to_string_lossy(), result)?;
+        }
+        batch.finish()
+    }
+
+    /// `unlink` which moves the file back by `rename`. Separated to test rollback.
+    fn unlink_with<F>(
+        &mut self,
+        source: &Path,
+        to: Option<&Path>,
+        force: bool,
+        rename: F,
+    ) -> Result<()>
+    where
+        F: FnOnce(&Path, &Path) -> io::Result<()>,
+    {
+        ensure_utf8(source)?;
+        if !source.exists() {
+            return Err(ErrorKind::SourceMissing.error(format!(
+                "Source file: {} does not exist!",
+                source.to_string_lossy()
+            )));
+        }
+        let mapped_dest = self.file_mappings()?.get(source)?.to_string();
+        let expected = self.workspace.dest_abs(&mapped_dest);
+        if let Some(to) = to {
+            if fs::symlink_metadata(to).is_ok() {
+                return Err(ErrorKind::DestExists
+                    .error(format!("File: {} already exists!", to.to_string_lossy())));
+            }
+            if let Some(parent) = to.parent() {
+                fs::create_dir_all(parent)?;
+            }
+        }
+        let restore_at = to.unwrap_or(source);
+        if self.file_mappings()?.is_rendered(source) {
+            return self.unlink_rendered(source, restore_at, &mapped_dest, rename);
+        }
+        if self.file_mappings()?.is_hardlink(source) {
+            return self.unlink_hardlinked(source, restore_at, &mapped_dest, rename);
+        }
+        let dest = read_link_abs(source).map_err(|err| {
+            ErrorKind::SourceInvalid.error(format!(
+                "Source file: {} is not a symlink! error: {} Use --detached-ok to remove the entry of a detached file.",
+                source.to_string_lossy(),
+                err
+            ))
+        })?;
+        if normalize_path(&dest) != normalize_path(&expected) {
+            if !force {
+                return Err(ErrorKind::SourceInvalid.error(format!(
+                    "Source file: {} points to {} but {} is expected! Use --force to unlink anyway.",
+                    source.to_string_lossy(),
+                    dest.to_string_lossy(),
+                    expected.to_string_lossy()
+                )));
+            }
+            warn!(
+                "Source file: {} points to {} but {} is expected",
+                source.to_string_lossy(),
+                dest.to_string_lossy(),
+                expected.to_string_lossy()
+            );
+        }
+        self.backup("unlink", &dest)
+            .map_err(|err| anyhow!("Failed to backup {} error: {}", dest.to_string_lossy(), err))?;
+        let raw_target = fs::read_link(source)?;
+        debug!("Removing symbolic link: {}", source.to_string_lossy());
+        fs::remove_file(source).map_err(|err| {
+            ErrorKind::Io.error(format!(
+                "Cannot remove symlink! {} error: {}",
+                source.to_string_lossy(),
+                err
+            ))
+        })?;
+        debug!(
+            "Renaming '{}' to '{}'",
+            dest.to_string_lossy(),
+            restore_at.to_string_lossy()
+        );
+        if let Err(err) = rename(&dest, restore_at) {
+            // Otherwise neither the symlink nor the file is at source
+            debug!("Restoring symbolic link: {}", source.to_string_lossy());
+            let rollback = Self::create_symlink(&raw_target, source, dest.is_dir())
+                .map(|_| "Symlink is restored.".to_string())
+                .unwrap_or_else(|err| format!("Failed to restore symlink! error: {}", err));
+            return Err(ErrorKind::Io.error(format!(
+                "Cannot move file {} into {} error: {} {}",
+                dest.to_string_lossy(),
+                restore_at.to_string_lossy(),
+                err,
+                rollback
+            )));
+        }
+        debug!("Updating entries...");
+        self.file_mappings_mut()?.remove(source)?;
+        self.record_unlink(source, restore_at, &mapped_dest);
+        println!("Unlinked!");
+        Ok(())
+    }
+
+    /// Replaces rendered file at `source` with the template. If `restore_at` is not
+    /// `source`, the template is moved there and the rendered file is removed.
+    fn unlink_rendered<F>(
+        &mut self,
+        source: &Path,
+        restore_at: &Path,
+        mapped_dest: &str,
+        rename: F,
+    ) -> Result<()>
+    where
+        F: FnOnce(&Path, &Path) -> io::Result<()>,
+    {
+        let dest_abs = self.workspace.dest_abs(mapped_dest);
+        if !fs::symlink_metadata(source)?.is_file() {
+            return Err(ErrorKind::SourceInvalid.error(format!(
+                "Source file: {} is rendered but is not a regular file!",
+                source.to_string_lossy()
+            )));
+        }
+        self.backup("unlink", source)?;
+        debug!(
+            "Moving template '{}' to '{}'",
+            dest_abs.to_string_lossy(),
+            restore_at.to_string_lossy()
+        );
+        rename(&dest_abs, restore_at).map_err(|err| {
+            ErrorKind::Io.error(format!(
+                "Cannot move file {} into {} error: {}",
+                dest_abs.to_string_lossy(),
+                restore_at.to_string_lossy(),
+                err
+            ))
+        })?;
+        if restore_at != source {
+            debug!("Removing rendered file: {}", source.to_string_lossy());
+            fs::remove_file(source)?;
+        }
+        self.file_mappings_mut()?.remove(source)?;
+        self.record_unlink(source, restore_at, mapped_dest);
+        println!("Unlinked!");
+        Ok(())
+    }
+
+    /// Removes the workspace file of hard-linked entry, so that the file at `source` is
+    /// the only one. The file is moved to `restore_at` if it is not `source`.
+    fn unlink_hardlinked<F>(
+        &mut self,
+        source: &Path,
+        restore_at: &Path,
+        mapped_dest: &str,
+        rename: F,
+    ) -> Result<()>
+    where
+        F: FnOnce(&Path, &Path) -> io::Result<()>,
+    {
+        let dest_abs = self.workspace.dest_abs(mapped_dest);
+        if self.entry_health(&self.workspace.strip_home(source), mapped_dest) != Health::Healthy {
+            return Err(ErrorKind::SourceInvalid.error(format!(
+                "Source file: {} is not a hard link of {}! Contents may differ.",
+                source.to_string_lossy(),
+                dest_abs.to_string_lossy()
+            )));
+        }
+        if restore_at != source {
+            debug!(
+                "Renaming '{}' to '{}'",
+                source.to_string_lossy(),
+                restore_at.to_string_lossy()
+            );
+            rename(source, restore_at).map_err(|err| {
+                ErrorKind::Io.error(format!(
+                    "Cannot move file {} into {} error: {}",
+                    source.to_string_lossy(),
+                    restore_at.to_string_lossy(),
+                    err
+                ))
+            })?;
+        }
+        debug!("Removing hard link: {}", dest_abs.to_string_lossy());
+        fs::remove_file(&dest_abs)?;
+        self.file_mappings_mut()?.remove(source)?;
+        self.record_unlink(source, restore_at, mapped_dest);
+        println!("Unlinked!");
+        Ok(())
+    }
+
+    /// Scaffolds the workspace as a git repository. Steps already done are skipped.
+    pub fn init(&self, gitignore: bool) -> Result<()> {
+        if self.workspace.exists() {
+            println!(
+                "Workspace already exists: {}",
+                self.workspace.root().to_string_lossy()
+            );
+        } else {
+            fs::create_dir_all(self.workspace.root())?;
+            println!(
+                "Created workspace: {}",
+                self.workspace.root().to_string_lossy()
+            );
+        }
+        if self.config.no_git {
+            debug!("Git is disabled. Skipped 'git init'");
+        } else if self.workspace.root().join(".git").exists() {
+            println!("Git repository already initialized");
+        } else {
+            debug!("Executing 'git init'");
+            let output = Command::new("git")
+                .current_dir(self.workspace.root())
+                .arg("init")
+                .output()?;
+            if !output.status.success() {
+                return Err(ErrorKind::Git.error(format!(
+                    "Failed to execute 'git init'! {}",
+                    String::from_utf8_lossy(&output.stderr).trim()
+                )));
+            }
+            println!("Initialized git repository");
+        }
+        if self.workspace.store_path().exists() {
+            println!("Mappings file already exists");
+        } else {
+            FileMappings::new().save_entries(&mut BufWriter::new(File::create(
+                self.workspace.store_path(),
+            )?))?;
+            println!(
+                "Created mappings file: {}",
+                self.workspace.store_path().to_string_lossy()
+            );
+        }
+        if gitignore {
+            let gitignore_path = self.workspace.root().join(".gitignore");
+            if gitignore_path.exists() {
+                println!(".gitignore already exists");
+            } else {
+                fs::write(&gitignore_path, DEFAULT_GITIGNORE)?;
+                println!("Created .gitignore");
+            }
+        }
+        if self.config.gitignore_internal_files {
+            self.gitignore_sync()?;
+        }
+        Ok(())
+    }
+
+    /// Adds internal files to `.gitignore` if `gitignore_internal_files` in config is
+    /// enabled. Otherwise removes them from `.gitignore`.
+    pub fn gitignore_sync(&self) -> Result<()> {
+        let gitignore_path = self.workspace.root().join(".gitignore");
+        let content = if gitignore_path.exists() {
+            fs::read_to_string(&gitignore_path)?
+        } else {
+            String::new()
+        };
+        let ignore = self.config.gitignore_internal_files;
+        let synced = sync_gitignore(&content, INTERNAL_FILE_NAMES, ignore);
+        if synced == content {
+            println!(".gitignore is up to date");
+            return Ok(());
+        }
+        fs::write(&gitignore_path, synced)?;
+        if ignore {
+            println!("Added internal files to .gitignore");
+        } else {
+            println!("Removed internal files from .gitignore");
+        }
+        Ok(())
+    }
+
+    /// Lists snapshots, or restores the snapshot specified by `id`.
+    pub fn restore_backup(&self, id: Option<&str>, prune: Option<usize>) -> Result<()> {
+        let backup_dir = self
+            .backup_dir
+            .as_ref()
+            .ok_or_else(|| anyhow!("Backup directory is not specified! Use --backup-dir"))?;
+        let mut store = BackupStore::open(backup_dir)?;
+        if let Some(keep) = prune {
+            let removed = store.prune(keep)?;
+            println!("Pruned {} snapshots.", removed);
+        }
+        match id {
+            Some(id) => {
+                let snapshot = store.restore(id)?;
+                println!(
+                    "Restored {} from {}",
+                    snapshot.original.to_string_lossy(),
+                    snapshot.id
+                );
+            }
+            None => {
+                println!("There are {} snapshots.", store.snapshots().len());
+                for snapshot in store.snapshots() {
+                    println!(
+                        "{} {} {:6} {}",
+                        snapshot.id,
+                        snapshot.created_at.to_rfc3339(),
+                        snapshot.operation,
+                        snapshot.original.to_string_lossy()
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Re-adopts detached files every `interval` until Ctrl-C is pressed.
+    pub fn watch(&mut self, interval: Duration, force: bool) -> Result<()> {
+        self.ensure_readopt_backed_up(force)?;
+        let running = stop_on_ctrl_c()?;
+        println!(
+            "Watching {} mapped files. Press Ctrl-C to stop.",
+            self.file_mappings()?.as_map().len()
+        );
+        while running.load(Ordering::SeqCst) {
+            self.readopt_detached(force)?;
+            sleep_while_running(&running, interval);
+        }
+        println!("Stopped watching.");
+        Ok(())
+    }
+
+    /// Moves detached files into the workspace and links them again.
+    /// Returns number of re-adopted files.
+    ///
+    /// Workspace copies are replaced, so the backup directory is required unless `force`.
+    pub fn readopt_detached(&self, force: bool) -> Result<usize> {
+        self.ensure_readopt_backed_up(force)?;
+        let mut count = 0;
+        for (src, dest) in self.file_mappings()?.as_map() {
+            let source = self.workspace.expand_home(src);
+            let dest_abs = self.workspace.dest_abs(dest);
+            if self.entry_health(src, dest) != Health::Detached {
+                continue;
+            }
+            debug!("Detected detached file: {}", source.to_string_lossy());
+            if let Err(err) = self.readopt(&source, &dest_abs) {
+                error!(
+                    "Failed to re-adopt {} error: {}",
+                    source.to_string_lossy(),
+                    err
+                );
+                continue;
+            }
+            println!("Re-adopted {}", src);
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    fn ensure_readopt_backed_up(&self, force: bool) -> Result<()> {
+        if self.backup_dir.is_none() && !force {
+            return Err(ErrorKind::Config.error(
+                "Detached files replace their copies in the workspace! Specify --backup-dir to keep them, or --force to replace them anyway.",
+            ));
+        }
+        Ok(())
+    }
+
+    fn readopt(&self, source: &Path, dest_abs: &Path) -> Result<()> {
+        if dest_abs.exists() {
+            self.backup("watch", dest_abs)?;
+        }
+        debug!(
+            "Renaming '{}' to '{}'",
+            source.to_string_lossy(),
+            dest_abs.to_string_lossy()
+        );
+        fs::rename(source, dest_abs)?;
+        self.create_link(dest_abs, source)
+    }
+
+    /// Prints configured repos. Current one is marked with `*`.
+    pub fn repos(&self) {
+        let repos = &self.config.repos;
+        println!("There are {} configured repos.", repos.len());
+        let max_name_len = repos.keys().map(|s| s.width()).max().unwrap_or(0);
+        for (name, path) in repos {
+            let mark = if self.workspace.expand_home(path) == self.workspace.root() {
+                "*"
+            } else {
+                " "
+            };
+            println!(
+                "{} {:name_len$} {}",
+                mark,
+                name,
+                path,
+                name_len = max_name_len
+            );
+        }
+    }
+
+    /// Applies modes in config to workspace files whose dest matches the pattern.
+    #[cfg(unix)]
+    pub fn fix_permissions(&self) -> Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let rules = self
+            .config
+            .permissions
+            .iter()
+            .map(|rule| {
+                let pattern = glob::Pattern::new(&rule.pattern)
+                    .map_err(|err| anyhow!("Invalid pattern: {} error: {}", rule.pattern, err))?;
+                Ok((pattern, rule.mode()?))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let mut changed = 0;
+        for dest in self.file_mappings()?.as_map().values() {
+            let mode = match rules.iter().find(|(pattern, _)| pattern.matches(dest)) {
+                Some((_, mode)) => *mode,
+                None => continue,
+            };
+            let dest_abs = self.workspace.dest_abs(dest);
+            let mut permissions = match fs::metadata(&dest_abs) {
+                Ok(metadata) => metadata.permissions(),
+                Err(err) => {
+                    error!(
+                        "Cannot read metadata of {} error: {}",
+                        dest_abs.to_string_lossy(),
+                        err
+                    );
+                    continue;
+                }
+            };
+            let current = permissions.mode() & 0o7777;
+            if current == mode {
+                continue;
+            }
+            permissions.set_mode(mode);
+            fs::set_permissions(&dest_abs, permissions)?;
+            println!("{}: {:04o} -> {:04o}", dest, current, mode);
+            changed += 1;
+        }
+        println!("Changed permissions of {} files.", changed);
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    pub fn fix_permissions(&self) -> Result<()> {
+        Err(anyhow!("fix-permissions is only supported on unix"))
+    }
+
+    /// Rewrites dests matching `from` with `to`, moving workspace files and recreating
+    /// symlinks. If any move fails, already moved files are moved back.
+    ///
+    /// A dest may be moved into another dest which is moved away, but dests moved in a
+    /// cycle are refused.
+    pub fn remap(&mut self, from: &str, to: &str, dry_run: bool) -> Result<()> {
+        let regex =
+            Regex::new(from).map_err(|err| anyhow!("Invalid regex: {} error: {}", from, err))?;
+        let map = self.file_mappings()?.as_map();
+        let mut moves = Vec::new();
+        for (src, dest) in map {
+            if !regex.is_match(dest) {
+                continue;
+            }
+            let new_dest = regex.replace(dest, to).to_string();
+            if &new_dest == dest {
+                continue;
+            }
+            validate_dest(&new_dest)?;
+            moves.push((src.to_string(), dest.to_string(), new_dest));
+        }
+        for (i, (_, _, new_dest)) in moves.iter().enumerate() {
+            let moved_away = |dest: &String| moves.iter().any(|(_, old, _)| old == dest);
+            let used_by_other = map
+                .values()
+                .any(|dest| dest == new_dest && !moved_away(dest));
+            let used_by_move = moves[..i].iter().any(|(_, _, other)| other == new_dest);
+            if used_by_other
+                || used_by_move
+                || (!moved_away(new_dest)
+                    && fs::symlink_metadata(self.workspace.dest_abs(new_dest)).is_ok())
+            {
+                return Err(
+                    ErrorKind::DestExists.error(format!("Dest: {} already exists!", new_dest))
+                );
+            }
+        }
+        // A dest is moved away before another dest is moved into it
+        let new_dests: BTreeMap<&str, &String> = moves
+            .iter()
+            .map(|(_, dest, new_dest)| (dest.as_str(), new_dest))
+            .collect();
+        let dests: Vec<String> = moves.iter().map(|(_, dest, _)| dest.clone()).collect();
+        let order = topo_sort(&dests, |dest| {
+            new_dests
+                .get(dest)
+                .map(|new_dest| std::slice::from_ref(*new_dest))
+                .unwrap_or(&[])
+        })
+        .map_err(|cycle| {
+            ErrorKind::Conflict.error(format!("Dests are moved in a cycle: {}", cycle.join(", ")))
+        })?;
+        let positions: BTreeMap<&str, usize> = order
+            .iter()
+            .enumerate()
+            .map(|(index, dest)| (dest.as_str(), index))
+            .collect();
+        moves.sort_by_key(|(_, dest, _)| positions[dest.as_str()]);
+        for (src, dest, new_dest) in &moves {
+            println!("{}: {} -> {}", src, dest, new_dest);
+        }
+        if dry_run {
+            println!("{} dests would be remapped.", moves.len());
+            return Ok(());
+        }
+        let moves: Vec<Move> = moves
+            .into_iter()
+            .map(|(source, dest, new_dest)| Move {
+                source,
+                dest,
+                new_dest,
+            })
+            .collect();
+        let result = self.apply_moves(&moves);
+        for entry in &moves {
+            self.audit(
+                "move",
+                &self.workspace.expand_home(&entry.source),
+                &entry.new_dest,
+                &result,
+            );
+        }
+        result?;
+        println!("Remapped {} dests.", moves.len());
+        self.record(Operation::Remap { moves });
+        Ok(())
+    }
+
+    /// Performs `moves` and updates entries. Moves already done are rolled back on failure.
+    fn apply_moves(&mut self, moves: &[Move]) -> Result<()> {
+        let mut done: Vec<&Move> = Vec::new();
+        for entry in moves {
+            if let Err(err) = self.move_dest(&entry.source, &entry.dest, &entry.new_dest) {
+                for done in done.into_iter().rev() {
+                    if let Err(err) = self.move_dest(&done.source, &done.new_dest, &done.dest) {
+                        error!("Failed to roll back {} error: {}", done.source, err);
+                    }
+                }
+                return Err(anyhow!(
+                    "Failed to move {} to {}! Rolled back. error: {}",
+                    entry.dest,
+                    entry.new_dest,
+                    err
+                ));
+            }
+            done.push(entry);
+        }
+        debug!("Updating entries...");
+        let file_mappings = self.file_mappings_mut()?;
+        for entry in moves {
+            file_mappings.set_dest(&entry.source, &entry.new_dest);
+        }
+        Ok(())
+    }
+
+    /// Reverts the newest operation in the operation log. Refuses if files or entries
+    /// were changed after the operation.
+    pub fn undo(&mut self) -> Result<()> {
+        let oplog = self.oplog();
+        let operation = oplog
+            .last()?
+            .ok_or_else(|| anyhow!("There are no operations to undo!"))?;
+        self.ensure_undoable(&operation).map_err(|err| {
+            ErrorKind::Conflict.error(format!("Cannot undo '{}'! {}", operation, err))
+        })?;
+        self.record_operations = false;
+        let result = match &operation {
+            Operation::Link { source, .. } => {
+                self.unlink(self.workspace.expand_home(source), false)
+            }
+            Operation::Unlink { source, dest, to } => self.undo_unlink(source, dest, to.as_deref()),
+            Operation::Remap { moves } => {
+                let reversed: Vec<Move> = moves
+                    .iter()
+                    .rev()
+                    .map(|entry| Move {
+                        source: entry.source.clone(),
+                        dest: entry.new_dest.clone(),
+                        new_dest: entry.dest.clone(),
+                    })
+                    .collect();
+                self.apply_moves(&reversed)
+            }
+        };
+        self.record_operations = true;
+        result?;
+        oplog.pop()?;
+        println!("Undid '{}'", operation);
+        Ok(())
+    }
+
+    /// Links `source` again. The file is moved back from `to` first if it was moved there.
+    fn undo_unlink(&mut self, source: &str, dest: &str, to: Option<&str>) -> Result<()> {
+        let source = self.workspace.expand_home(source);
+        let to = match to {
+            Some(to) => self.workspace.expand_home(to),
+            None => return self.link(&source, dest, IfExists::Error),
+        };
+        debug!(
+            "Renaming '{}' to '{}'",
+            to.to_string_lossy(),
+            source.to_string_lossy()
+        );
+        fs::rename(&to, &source)?;
+        let result = self.link(&source, dest, IfExists::Error);
+        let left = fs::symlink_metadata(&source).is_ok_and(|metadata| metadata.is_file());
+        if result.is_err() && left {
+            if let Err(err) = fs::rename(&source, &to) {
+                error!(
+                    "Failed to move {} back error: {}",
+                    source.to_string_lossy(),
+                    err
+                );
+            }
+        }
+        result
+    }
+
+    /// Fails if current state differs from the state just after `operation`.
+    fn ensure_undoable(&self, operation: &Operation) -> Result<()> {
+        let file_mappings = self.file_mappings()?;
+        match operation {
+            Operation::Link { source, dest } => {
+                if file_mappings.stored_dest(source) != Some(dest.as_str()) {
+                    return Err(anyhow!("{} is no longer mapped to {}.", source, dest));
+                }
+                let health = self.entry_health(source, dest);
+                if health != Health::Healthy {
+                    return Err(anyhow!("{} is {}.", source, health));
+                }
+            }
+            Operation::Unlink { source, dest, to } => {
+                if file_mappings.stored_dest(source).is_some() {
+                    return Err(anyhow!("{} is managed again.", source));
+                }
+                let file = to.as_ref().unwrap_or(source);
+                let is_file = fs::symlink_metadata(self.workspace.expand_home(file))
+                    .map(|metadata| metadata.is_file())
+                    .unwrap_or(false);
+                if !is_file {
+                    return Err(anyhow!("{} is no longer a regular file.", file));
+                }
+                if to.is_some() && fs::symlink_metadata(self.workspace.expand_home(source)).is_ok()
+                {
+                    return Err(anyhow!("{} already exists.", source));
+                }
+                if fs::symlink_metadata(self.workspace.dest_abs(dest)).is_ok() {
+                    return Err(anyhow!("Dest: {} already exists.", dest));
+                }
+            }
+            Operation::Remap { moves } => {
+                for entry in moves {
+                    if file_mappings.stored_dest(&entry.source) != Some(entry.new_dest.as_str()) {
+                        return Err(anyhow!(
+                            "{} is no longer mapped to {}.",
+                            entry.source,
+                            entry.new_dest
+                        ));
+                    }
+                    if fs::symlink_metadata(self.workspace.dest_abs(&entry.new_dest)).is_err() {
+                        return Err(anyhow!("Dest: {} does not exist.", entry.new_dest));
+                    }
+                    let moved_away = moves.iter().any(|other| other.new_dest == entry.dest);
+                    if !moved_away
+                        && fs::symlink_metadata(self.workspace.dest_abs(&entry.dest)).is_ok()
+                    {
+                        return Err(anyhow!("Dest: {} already exists.", entry.dest));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Moves workspace file from `dest` to `new_dest` and repoints the symlink of `src`.
+    fn move_dest(&self, src: &str, dest: &str, new_dest: &str) -> Result<()> {
+        let source = self.workspace.expand_home(src);
+        let dest_abs = self.workspace.dest_abs(dest);
+        let new_dest_abs = self.workspace.dest_abs(new_dest);
+        // Renaming keeps the hard link
+        let hardlink = self.file_mappings()?.stored_attributes(src).hardlink;
+        let relink = !hardlink && check_health(&source, &dest_abs) == Health::Healthy;
+        if let Some(parent) = new_dest_abs.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        debug!(
+            "Renaming '{}' to '{}'",
+            dest_abs.to_string_lossy(),
+            new_dest_abs.to_string_lossy()
+        );
+        fs::rename(&dest_abs, &new_dest_abs)?;
+        if relink {
+            if let Err(err) = self.create_link(&new_dest_abs, &source) {
+                fs::rename(&new_dest_abs, &dest_abs)?;
+                return Err(err);
+            }
+        } else if !hardlink {
+            warn!("{} is not linked. Symlink is not updated.", src);
+        }
+        Ok(())
+    }
+
+    /// Deletes workspace files which are not dest of any entry. Files of git and dotman
+    /// are never deleted. Asks for confirmation unless `yes`.
+    pub fn gc(&self, dry_run: bool, yes: bool) -> Result<()> {
+        let orphans = self.orphaned_files()?;
+        if orphans.is_empty() {
+            println!("There are no orphaned files.");
+            return Ok(());
+        }
+        for orphan in &orphans {
+            println!("{}", orphan.to_string_lossy());
+        }
+        if dry_run {
+            println!("{} files would be deleted.", orphans.len());
+            return Ok(());
+        }
+        if !yes && !confirm(&format!("Delete {} files?", orphans.len()))? {
+            println!("Aborted.");
+            return Ok(());
+        }
+        for orphan in &orphans {
+            let path = self.workspace.dest_abs(orphan);
+            debug!("Removing '{}'", path.to_string_lossy());
+            fs::remove_file(&path)?;
+            // Remove directories which became empty
+            for dir in path.ancestors().skip(1) {
+                if !self.workspace.is_inside(dir) || fs::remove_dir(dir).is_err() {
+                    break;
+                }
+            }
+        }
+        println!("Deleted {} files.", orphans.len());
+        Ok(())
+    }
+
+    /// Reports dests whose content differs from the hash recorded on `link`. With `fix`,
+    /// the recorded hash of each changed dest is updated after confirmation.
+    pub fn verify(&mut self, fix: bool, yes: bool) -> Result<()> {
+        let mut changed = Vec::new();
+        for (src, dest) in self.file_mappings()?.as_map() {
+            let recorded = match self.file_mappings()?.hash(src) {
+                Some(hash) => hash,
+                None => continue,
+            };
+            let dest_abs = self.workspace.dest_abs(dest);
+            if !dest_abs.is_file() {
+                warn!("Dest: {} is not a file. Skipped {}.", dest, src);
+                continue;
+            }
+            let current = hash_file(&dest_abs)?;
+            if current != recorded {
+                println!("changed: {} -> {}", src, dest);
+                changed.push((src.to_string(), current));
+            }
+        }
+        if changed.is_empty() {
+            println!("All recorded hashes match.");
+            return Ok(());
+        }
+        if !fix {
+            return Err(ErrorKind::Conflict.error(format!(
+                "{} dests were changed! Run 'dotman verify --fix' to accept the changes.",
+                changed.len()
+            )));
+        }
+        let mut updated = 0;
+        for (src, current) in changed {
+            if yes || confirm(&format!("Update hash of {}?", src))? {
+                let source = self.workspace.expand_home(&src);
+                self.file_mappings_mut()?.set_hash(source, Some(current));
+                updated += 1;
+            }
+        }
+        println!("Updated {} hashes.", updated);
+        Ok(())
+    }
+
+    /// Returns workspace-relative paths of files which are not dest of any entry nor in a
+    /// directory dest.
+    fn orphaned_files(&self) -> Result<Vec<PathBuf>> {
+        let dests: Vec<PathBuf> = self
+            .file_mappings()?
+            .as_map()
+            .values()
+            .map(|dest| normalize_dest(dest))
+            .collect();
+        let skipped = |entry: &WalkEntry| {
+            let name = entry.relative.to_string_lossy();
+            let is_root = entry.depth == 1;
+            (is_root
+                && (GIT_FILE_NAMES.contains(&name.as_ref())
+                    || INTERNAL_FILE_NAMES.contains(&name.as_ref())
+                    || is_overlay_file_name(&name)))
+                || dests.iter().any(|dest| entry.relative.starts_with(dest))
+        };
+        let entries = walk_dir(self.workspace.root(), WalkOptions::default(), |entry| {
+            !skipped(entry)
+        })?;
+        Ok(entries
+            .into_iter()
+            .filter(|entry| !entry.file_type.is_dir() && !skipped(entry))
+            .map(|entry| entry.relative)
+            .collect())
+    }
+
+    /// Creates symlinks of entries whose source does not exist, e.g. on a new machine.
+    /// With `RestoreMode::Reconcile`, detached and repointed sources are also replaced
+    /// with the symlinks of their dests.
+    ///
+    /// Sources which exist as real files are skipped unless `force` or reconciling. They
+    /// are moved aside to `<source>.dotman-backup` and replaced with symlinks then.
+    /// Detached sources of hardlinked entries are always skipped.
+    ///
+    /// With `tag`, only entries which have the tag are restored.
+    ///
+    /// Entries are restored after the entries they depend on by `link --after`.
+    ///
+    /// If the restore state is enabled, entries which were healthy on the last run are
+    /// only checked to still be symlinks to their dests until mappings change, unless
+    /// `full`. It takes one `read_link` instead of three syscalls per entry. Reconciling
+    /// always checks every entry.
+    pub fn restore(
+        &self,
+        force: bool,
+        tag: Option<&str>,
+        full: bool,
+        mode: RestoreMode,
+    ) -> Result<()> {
+        let file_mappings = self.file_mappings()?;
+        let mappings_hash = match &self.restore_state {
+            Some(_) => {
+                let mut hash = hash_file(&self.workspace.store_path()).unwrap_or_default();
+                if let Some(profile) = &self.profile {
+                    let overlay = self.workspace.overlay_path(profile);
+                    hash.push_str(&hash_file(&overlay).unwrap_or_default());
+                }
+                hash
+            }
+            None => String::new(),
+        };
+        let known_healthy = match &self.restore_state {
+            Some(path) if !full && mode == RestoreMode::LinkMissingOnly => {
+                RestoreState::load(path, &mappings_hash).healthy
+            }
+            _ => BTreeSet::new(),
+        };
+        let mut report: Vec<(String, String, Health)> = file_mappings
+            .as_map()
+            .iter()
+            .map(|(src, dest)| {
+                let known = known_healthy.contains(src)
+                    && !file_mappings.stored_attributes(src).rendered
+                    && read_link_abs(&self.workspace.expand_home(src)).is_ok_and(|target| {
+                        normalize_path(target) == normalize_path(self.workspace.dest_abs(dest))
+                    });
+                let health = if known {
+                    Health::Healthy
+                } else {
+                    self.entry_health(src, dest)
+                };
+                (src.to_string(), dest.to_string(), health)
+            })
+            .collect();
+        let mut healthy: BTreeSet<String> = report
+            .iter()
+            .filter(|(src, _, health)| {
+                *health == Health::Healthy && !file_mappings.stored_attributes(src).rendered
+            })
+            .map(|(src, _, _)| src.clone())
+            .collect();
+        let sources: Vec<String> = report.iter().map(|(src, _, _)| src.clone()).collect();
+        let order = topo_sort(&sources, |src| &file_mappings.stored_attributes(src).after)
+            .map_err(|cycle| {
+                ErrorKind::Conflict.error(format!(
+                    "Dependencies of entries form a cycle: {}",
+                    cycle.join(", ")
+                ))
+            })?;
+        let positions: BTreeMap<&str, usize> = order
+            .iter()
+            .enumerate()
+            .map(|(index, src)| (src.as_str(), index))
+            .collect();
+        report.sort_by_key(|(src, _, _)| positions.get(src.as_str()).copied());
+        let mut batch = Batch::new(self.error_policy);
+        for (src, dest, health) in report {
+            if !self.file_mappings()?.has_tag(&src, tag) {
+                continue;
+            }
+            // Rendered files are regenerated since variables may have been changed
+            let attributes = self.file_mappings()?.stored_attributes(&src);
+            let rendered = attributes.rendered;
+            let replace = match mode.step(health, attributes, force) {
+                Some(RestoreStep::Create) => false,
+                Some(RestoreStep::MoveAsideAndCreate) => true,
+                None => continue,
+            };
+            let source = self.workspace.expand_home(&src);
+            let dest_abs = self.workspace.dest_abs(&dest);
+            if !dest_abs.exists() {
+                warn!("Dest: {} does not exist. Skipped {}.", dest, src);
+                self.progress.skipped("restore", &source, &dest);
+                continue;
+            }
+            let result = self.restore_entry(&src, &source, &dest_abs, rendered, replace);
+            self.audit("restore", &source, &dest, &result);
+            self.progress.result("restore", &source, &dest, &result);
+            if result.is_ok() && !rendered {
+                healthy.insert(src.clone());
+            }
+            batch.record(&src, result)?;
+        }
+        println!("Restored {} files.", batch.succeeded());
+        if let Some(path) = &self.restore_state {
+            let state = RestoreState {
+                mappings: mappings_hash,
+                healthy,
+            };
+            if let Err(err) = state.save(path) {
+                warn!(
+                    "Failed to save restore state: {} error: {}",
+                    path.to_string_lossy(),
+                    err
+                );
+            }
+        }
+        batch.finish()
+    }
+
+    /// Creates the symlink or the rendered file of an entry. The file at `source` is
+    /// moved aside first if `replace`.
+    fn restore_entry(
+        &self,
+        src: &str,
+        source: &Path,
+        dest_abs: &Path,
+        rendered: bool,
+        replace: bool,
+    ) -> Result<()> {
+        if replace {
+            self.move_aside(source)?;
+        }
+        if let Some(parent) = source.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        debug!("Restoring '{}'", source.to_string_lossy());
+        if rendered {
+            self.materialize(dest_abs, source)
+        } else {
+            self.create_link(dest_abs, source)
+        }
+        .map_err(|err| ErrorKind::Io.error(format!("Failed to restore {} error: {}", src, err)))
+    }
+
+    /// Moves existing file at `source` to `<source>.dotman-backup`. It is also snapshotted
+    /// if backup directory is specified.
+    fn move_aside(&self, source: &Path) -> Result<()> {
+        let mut aside = source.as_os_str().to_owned();
+        aside.push(".dotman-backup");
+        let aside = PathBuf::from(aside);
+        if fs::symlink_metadata(&aside).is_ok() {
+            return Err(ErrorKind::DestExists.error(format!(
+                "Cannot move {} aside! {} already exists.",
+                source.to_string_lossy(),
+                aside.to_string_lossy()
+            )));
+        }
+        self.backup("restore", source)?;
+        debug!(
+            "Moving '{}' to '{}'",
+            source.to_string_lossy(),
+            aside.to_string_lossy()
+        );
+        fs::rename(source, &aside)?;
+        println!(
+            "Moved {} to {}",
+            source.to_string_lossy(),
+            aside.to_string_lossy()
+        );
+        Ok(())
+    }
+
+    /// Makes `alias` another path of the managed `source`, so that e.g. `which` and
+    /// `unlink` find the entry by either path. Removes `alias` if `source` is `None`.
+    pub fn alias<P: AsRef<Path>, A: AsRef<Path>>(
+        &mut self,
+        source: Option<P>,
+        alias: A,
+    ) -> Result<()> {
+        let file_mappings = self.file_mappings_mut()?;
+        match source {
+            Some(source) => {
+                file_mappings.add_alias(source, &alias)?;
+                println!("Added alias: {}", self.workspace.strip_home(alias.as_ref()));
+            }
+            None => {
+                let src = file_mappings.remove_alias(&alias)?;
+                println!(
+                    "Removed alias: {} of {}",
+                    self.workspace.strip_home(alias.as_ref()),
+                    src
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Rewrites the mappings file without entries whose dest is already used by
+    /// another source. The first source in sorted order keeps the dest.
+    pub fn repair(&mut self) -> Result<()> {
+        let path = self.workspace.store_path();
+        let (mut file_mappings, duplicates) =
+            FileMappings::load_entries_repairing(BufReader::new(File::open(&path)?))?;
+        file_mappings.set_case_insensitive(self.case_insensitive);
+        for (src, err) in &duplicates {
+            println!("Removed {}. {}", src, err);
+        }
+        self.file_mappings = OnceCell::from(file_mappings);
+        if duplicates.is_empty() {
+            println!("There are no duplicate dests.");
+        } else {
+            self.file_mappings_dirty = true;
+            println!("Removed {} entries.", duplicates.len());
+        }
+        Ok(())
+    }
+
+    /// Imports mappings from another mappings file or workspace.
+    ///
+    /// Without `merge`, importing is only allowed while no mappings exist.
+    pub fn import<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+        merge: bool,
+        policy: Option<ConflictPolicy>,
+        json: bool,
+    ) -> Result<()> {
+        let path = path.as_ref();
+        let path = if path.is_dir() {
+            path.join(FILE_MAPPINGS_FILE_NAME)
+        } else {
+            path.to_path_buf()
+        };
+        let file = File::open(&path)?;
+        self.import_from_reader(
+            BufReader::new(file),
+            &path.to_string_lossy(),
+            merge,
+            policy,
+            json,
+        )
+    }
+
+    /// Same as `import` but reads mappings from `reader`, e.g. stdin. `name` is used in
+    /// messages.
+    pub fn import_from_reader<R: Read>(
+        &mut self,
+        reader: R,
+        name: &str,
+        merge: bool,
+        policy: Option<ConflictPolicy>,
+        json: bool,
+    ) -> Result<()> {
+        self.ensure_workspace()?;
+        if !merge && !self.file_mappings()?.as_map().is_empty() {
+            return Err(anyhow!(
+                "Mappings already exist! Use --merge to combine them with '{}'",
+                name
+            ));
+        }
+        let theirs = FileMappings::load_entries(reader)?;
+        let result = merge_mappings(self.file_mappings()?.as_map(), theirs.as_map());
+        if json {
+            println!("{}", serde_json::to_string_pretty(&result)?);
+        } else {
+            result.print();
+        }
+        if !result.conflicts.is_empty() {
+            match policy {
+                None => {
+                    return Err(ErrorKind::Conflict.error(format!(
+                        "{} conflicting entries! Specify --prefer-theirs, --prefer-ours or --fail",
+                        result.conflicts.len()
+                    )))
+                }
+                Some(ConflictPolicy::Fail) => {
+                    return Err(ErrorKind::Conflict.error(format!(
+                        "Aborted due to {} conflicting entries",
+                        result.conflicts.len()
+                    )))
+                }
+                Some(_) => {}
+            }
+        }
+        debug!("Updating entries...");
+        self.file_mappings_mut()?
+            .apply_merge(
+                &result,
+                &theirs,
+                policy.unwrap_or(ConflictPolicy::PreferOurs),
+            )
+            .map_err(|err| {
+                ErrorKind::Conflict.error(format!("Entries of '{}' can't be merged! {}", name, err))
+            })?;
+        // Keeps the JSON report the only output
+        if !json {
+            println!("Imported!");
+        }
+        Ok(())
+    }
+
+    /// Adds entries for symlinks under `root` (home if `None`) which point into the
+    /// workspace, e.g. created by hand before using dotman. Only prints them if `dry_run`.
+    pub fn reconcile(&mut self, root: Option<&Path>, dry_run: bool) -> Result<()> {
+        let root = match root {
+            Some(root) => normalize_path(root),
+            None => self.workspace.home()?.to_path_buf(),
+        };
+        let options = WalkOptions {
+            skip_unreadable: true,
+            ..WalkOptions::default()
+        };
+        let workspace_root = normalize_path(self.workspace.root());
+        let entries = walk_dir(&root, options, |entry| {
+            normalize_path(&entry.path) != workspace_root
+        })?;
+        let mut found = 0;
+        for entry in entries.iter().filter(|entry| entry.file_type.is_symlink()) {
+            let target = match read_link_abs(&entry.path) {
+                Ok(target) => target,
+                Err(_) => continue,
+            };
+            let dest = match self.workspace.relative(&target) {
+                Some(dest) if dest != Path::new("") => dest.to_string_lossy().to_string(),
+                _ => continue,
+            };
+            let file_mappings = self.file_mappings()?;
+            if file_mappings.contains(&entry.path) {
+                debug!("Already managed: {}", entry.path.to_string_lossy());
+                continue;
+            }
+            if let Some((other, _)) = file_mappings
+                .as_map()
+                .iter()
+                .find(|(_, other)| normalize_dest(other) == normalize_dest(&dest))
+            {
+                warn!(
+                    "Dest: {} is already used by {}. Skipped {}.",
+                    dest,
+                    other,
+                    entry.path.to_string_lossy()
+                );
+                continue;
+            }
+            println!("{} -> {}", self.workspace.strip_home(&entry.path), dest);
+            found += 1;
+            if dry_run {
+                continue;
+            }
+            let file_mappings = self.file_mappings_mut()?;
+            file_mappings.add(&entry.path, &dest)?;
+            file_mappings.set_directory(&entry.path, target.is_dir());
+        }
+        if dry_run {
+            println!("{} entries would be added.", found);
+        } else {
+            println!("Added {} entries.", found);
+        }
+        Ok(())
+    }
+
+    /// Writes mappings in `format`. `ExportFormat::Json` is the format of the mappings
+    /// file, which `import` can read.
+    pub fn export<W: Write>(&self, out: &mut W, format: ExportFormat) -> Result<()> {
+        match format {
+            ExportFormat::Json => {
+                self.file_mappings()?.save_entries(out)?;
+            }
+            ExportFormat::Shell => self.export_shell(out)?,
+        }
+        out.flush()?;
+        Ok(())
+    }
+
+    /// Writes `dotman link` commands which recreate the entries when the sources are
+    /// real files. Directory entries can't be recreated by `link`, so they are written
+    /// as comments.
+    fn export_shell<W: Write>(&self, out: &mut W) -> Result<()> {
+        let file_mappings = self.file_mappings()?;
+        writeln!(out, "#!/bin/sh")?;
+        writeln!(out, "set -e")?;
+        for (src, dest) in file_mappings.as_map() {
+            let attributes = file_mappings.stored_attributes(src);
+            if attributes.directory {
+                writeln!(out, "# Directory: {} -> {}", src, dest)?;
+                continue;
+            }
+            let mut command = vec![
+                "dotman".to_string(),
+                "link".to_string(),
+                quote_src(src),
+                shell_quote(dest),
+            ];
+            if attributes.rendered {
+                command.push("--render".to_string());
+            }
+            if attributes.hardlink {
+                command.push("--hardlink".to_string());
+            }
+            for tag in &attributes.tags {
+                command.push("--tag".to_string());
+                command.push(shell_quote(tag));
+            }
+            for after in &attributes.after {
+                command.push("--after".to_string());
+                command.push(quote_src(after));
+            }
+            writeln!(out, "{}", command.join(" "))?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for App {
+    /// Fallback of `App::finish`. Errors are only logged since they can't be reported here.
+    fn drop(&mut self) {
+        if let Err(err) = self.save_mappings() {
+            error!(
+                "Failed to save mappings: {}! error: {}",
+                self.workspace.store_path().to_string_lossy(),
+                err
+            );
+        }
+    }
+}
+
+/// Root directory of dotfiles and paths in it.
+///
+/// Dests are paths relative to the root and must not escape it. Sources are stored
+/// with home directory replaced by `~`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Workspace {
+    root: PathBuf,
+    /// Home directory which `~` of sources refers to. `None` if it can't be retrieved.
+    home: Option<PathBuf>,
+}
+
+impl Workspace {
+    pub fn new(root: PathBuf) -> Self {
+        Self {
+            root,
+            home: dirs::home_dir(),
+        }
+    }
+
+    /// Replaces home directory of the current user, e.g. with a temporary directory.
+    #[cfg(test)]
+    pub fn set_home(&mut self, home: PathBuf) {
+        self.home = Some(home);
+    }
+
+    pub fn home(&self) -> Result<&Path> {
+        self.home
+            .as_deref()
+            .ok_or_else(|| anyhow!("Cannot retrieve home directory"))
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn exists(&self) -> bool {
+        self.root.exists()
+    }
+
+    /// Fails unless the root is an absolute path of an existing directory.
+    pub fn check(&self) -> Result<()> {
+        let root = self.root.to_string_lossy();
+        if !self.root.is_absolute() {
+            return Err(
+                ErrorKind::Config.error(format!("Workspace: {} is not an absolute path!", root))
+            );
+        }
+        match fs::metadata(&self.root) {
+            Ok(metadata) if metadata.is_dir() => Ok(()),
+            Ok(_) => Err(ErrorKind::Config.error(format!(
+                "Workspace: {} is not a directory!",
+                root
+            ))),
+            Err(err) => Err(ErrorKind::Config.error(format!(
+                "Workspace: {} does not exist! Create it with 'dotman init' or fix the config. error: {}",
+                root, err
+            ))),
+        }
+    }
+
+    /// Absolute path of `dest`. Use `checked_dest_abs` for dests from users.
+    pub fn dest_abs<P: AsRef<Path>>(&self, dest: P) -> PathBuf {
+        self.root.join(dest)
+    }
+
+    /// Same as `dest_abs` but fails if `dest` is absolute or escapes the workspace.
+    pub fn checked_dest_abs(&self, dest: &str) -> Result<PathBuf> {
+        validate_dest(dest)?;
+        Ok(self.dest_abs(dest))
+    }
+
+    /// Whether `path` is below the root. The root itself is not inside.
+    pub fn is_inside<P: AsRef<Path>>(&self, path: P) -> bool {
+        self.relative(path)
+            .map(|relative| relative != Path::new(""))
+            .unwrap_or(false)
+    }
+
+    /// Returns `path` relative to the root, or `None` if it is not in the workspace.
+    ///
+    /// The root is kept as configured, so links point into it by that path. Paths that
+    /// reach the workspace through a symlinked directory are resolved with
+    /// `fs::canonicalize` before comparing. The last component of `path` is not
+    /// resolved since it may be a link managed by dotman.
+    pub fn relative<P: AsRef<Path>>(&self, path: P) -> Option<PathBuf> {
+        let path = normalize_path(path);
+        if let Ok(relative) = path.strip_prefix(normalize_path(&self.root)) {
+            return Some(relative.to_path_buf());
+        }
+        let root = fs::canonicalize(&self.root).ok()?;
+        let path = match (path.parent(), path.file_name()) {
+            (Some(parent), Some(name)) => fs::canonicalize(parent).ok()?.join(name),
+            _ => fs::canonicalize(&path).ok()?,
+        };
+        path.strip_prefix(root).ok().map(Path::to_path_buf)
+    }
+
+    /// Path of the mappings file.
+    pub fn store_path(&self) -> PathBuf {
+        self.root.join(FILE_MAPPINGS_FILE_NAME)
+    }
+
+    /// Path of the mappings overlay of `profile`. See `App::set_profile`.
+    pub fn overlay_path(&self, profile: &str) -> PathBuf {
+        self.root.join(format!(".file_mappings.{}.json", profile))
+    }
+
+    /// Path of the lock file. See `Lock`.
+    pub fn lock_path(&self) -> PathBuf {
+        self.root.join(LOCK_FILE_NAME)
+    }
+
+    pub fn oplog_path(&self) -> PathBuf {
+        self.root.join(OPLOG_FILE_NAME)
+    }
+
+    /// Normalizes `path` and replaces home directory with `~`. Sources are stored in
+    /// this form.
+    pub fn strip_home<P: AsRef<Path>>(&self, path: P) -> String {
+        strip_home(self.home.as_deref(), path.as_ref())
+    }
+
+    /// Reverse of `strip_home`. Replaces leading `~` with home directory.
+    pub fn expand_home(&self, src: &str) -> PathBuf {
+        expand_home(self.home.as_deref(), src)
+    }
+}
+
+/// See `Workspace::strip_home`.
+fn strip_home(home: Option<&Path>, path: &Path) -> String {
+    let path = normalize_path(path);
+    match home.and_then(|home| path.strip_prefix(home).ok()) {
+        Some(stripped) => format!(
+            "~{}{}",
+            std::path::MAIN_SEPARATOR,
+            stripped.to_string_lossy()
+        ),
+        None => path.to_string_lossy().to_string(),
+    }
+}
+
+/// See `Workspace::expand_home`.
+fn expand_home(home: Option<&Path>, src: &str) -> PathBuf {
+    let prefix = format!("~{}", std::path::MAIN_SEPARATOR);
+    match src.strip_prefix(&prefix) {
+        Some(stripped) => home.expect("Cannot retrieve home directory").join(stripped),
+        None => PathBuf::from(src),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MappingError {
+    /// Source is already mapped
+    AlreadyExists(String),
+    /// Source is not mapped
+    NotFound(String),
+    /// Dest is already used by other source
+    DuplicateDest { dest: String, source: String },
+}
+
+impl fmt::Display for MappingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MappingError::AlreadyExists(src) => write!(f, "File: {} is already managed!", src),
+            MappingError::NotFound(src) => {
+                write!(f, "File: {} is not managed by this tool!", src)
+            }
+            MappingError::DuplicateDest { dest, source } => {
+                write!(f, "Dest: {} is already used by {}!", dest, source)
+            }
+        }
+    }
+}
+
+impl std::error::Error for MappingError {}
+
+#[derive(Debug)]
+struct FileMappings {
+    entries: BTreeMap<String, String>,
+    /// Attributes of entries which have any. Keyed by the same keys as `entries`.
+    attributes: BTreeMap<String, EntryAttributes>,
+    /// Compare sources case-insensitively. Stored keys keep their original case.
+    case_insensitive: bool,
+    /// Home directory which sources are stored relative to. See `Workspace::strip_home`.
+    home: Option<PathBuf>,
+}
+
+impl FileMappings {
+    pub fn new() -> Self {
+        Self {
+            entries: BTreeMap::new(),
+            attributes: BTreeMap::new(),
+            case_insensitive: false,
+            home: dirs::home_dir(),
+        }
+    }
+
+    pub fn set_case_insensitive(&mut self, case_insensitive: bool) {
+        self.case_insensitive = case_insensitive;
+    }
+
+    pub fn set_home(&mut self, home: Option<PathBuf>) {
+        self.home = home;
+    }
+
+    fn strip_home(&self, src: &Path) -> String {
+        strip_home(self.home.as_deref(), src)
+    }
+
+    pub fn as_map(&self) -> &BTreeMap<String, String> {
+        &self.entries
+    }
+
+    /// Fails if two sources share a dest, e.g. in a hand-edited file. See
+    /// `load_entries_repairing`.
+    pub fn load_entries<R: Read>(entries_store: R) -> Result<Self> {
+        let (file_mappings, duplicates) = Self::load_entries_repairing(entries_store)?;
+        if let Some((src, err)) = duplicates.first() {
+            return Err(ErrorKind::Conflict.error(format!(
+                "Source: {} has the same dest as another source. {} Run 'dotman repair' to keep only the first source.",
+                src, err
+            )));
+        }
+        Ok(file_mappings)
+    }
+
+    /// Returns problems of stored entries: empty sources, dests which are not relative
+    /// paths in the workspace and dests used by multiple sources. Fails only if the
+    /// entries can't be parsed.
+    pub fn problems<R: Read>(entries_store: R) -> Result<Vec<String>> {
+        let (file_mappings, duplicates) = Self::load_entries_repairing(entries_store)?;
+        let mut problems = Vec::new();
+        for (src, dest) in &file_mappings.entries {
+            if src.trim().is_empty() {
+                problems.push(format!("Source of dest: {} is empty!", dest));
+            }
+            if let Err(err) = validate_dest(dest) {
+                problems.push(format!("{}: {}", src, err));
+            }
+        }
+        for (src, err) in duplicates {
+            problems.push(format!("{}: {}", src, err));
+        }
+        Ok(problems)
+    }
+
+    /// Loads entries of `base` and replaces them with entries of `overlay` which have
+    /// the same sources. Fails if two sources share a dest after merging.
+    pub fn load_layered<R: Read, O: Read>(base: R, overlay: O) -> Result<Self> {
+        let mut file_mappings = Self::load_entries_unchecked(base)?;
+        let overlay = Self::load_entries_unchecked(overlay)?;
+        for (src, dest) in overlay.entries {
+            file_mappings.attributes.remove(&src);
+            file_mappings.entries.insert(src, dest);
+        }
+        file_mappings.attributes.extend(overlay.attributes);
+        let (file_mappings, duplicates) = Self::repaired(file_mappings);
+        if let Some((src, err)) = duplicates.first() {
+            return Err(ErrorKind::Conflict.error(format!(
+                "Source: {} in the overlay has the same dest as another source. {}",
+                src, err
+            )));
+        }
+        Ok(file_mappings)
+    }
+
+    /// Splits entries loaded from `base` and `overlay` into the new contents of them, the
+    /// reverse of `load_layered`. `base` keeps its entries, including the ones replaced
+    /// by `overlay`, and only loses the entries of its own which no longer exist. The new
+    /// overlay has the entries which differ from `base`.
+    pub fn split(&self, base: &FileMappings, overlay: &FileMappings) -> (Self, Self) {
+        let mut kept = Self::new();
+        for (src, dest) in &base.entries {
+            if !self.entries.contains_key(src) && !overlay.entries.contains_key(src) {
+                continue;
+            }
+            kept.entries.insert(src.to_string(), dest.to_string());
+            if let Some(attributes) = base.attributes.get(src) {
+                kept.attributes.insert(src.to_string(), attributes.clone());
+            }
+        }
+        let mut changed = Self::new();
+        for (src, dest) in &self.entries {
+            let attributes = self.attributes.get(src);
+            if base.entries.get(src) == Some(dest) && base.attributes.get(src) == attributes {
+                continue;
+            }
+            changed.entries.insert(src.to_string(), dest.to_string());
+            if let Some(attributes) = attributes {
+                changed
+                    .attributes
+                    .insert(src.to_string(), attributes.clone());
+            }
+        }
+        (kept, changed)
+    }
+
+    /// Loads entries keeping only the first source, in sorted order, of each dest.
+    /// Returns sources of the dropped entries alongside.
+    pub fn load_entries_repairing<R: Read>(
+        entries_store: R,
+    ) -> Result<(Self, Vec<(String, MappingError)>)> {
+        Ok(Self::repaired(Self::load_entries_unchecked(entries_store)?))
+    }
+
+    fn repaired(file_mappings: Self) -> (Self, Vec<(String, MappingError)>) {
+        let mut kept = Self::new();
+        let mut owners: BTreeMap<PathBuf, &str> = BTreeMap::new();
+        let mut duplicates = Vec::new();
+        for (src, dest) in &file_mappings.entries {
+            if let Some(owner) = owners.get(&normalize_dest(dest)) {
+                let err = MappingError::DuplicateDest {
+                    dest: dest.to_string(),
+                    source: owner.to_string(),
+                };
+                duplicates.push((src.to_string(), err));
+                continue;
+            }
+            owners.insert(normalize_dest(dest), src);
+            kept.entries.insert(src.to_string(), dest.to_string());
+            if let Some(attributes) = file_mappings.attributes.get(src) {
+                kept.attributes.insert(src.to_string(), attributes.clone());
+            }
+        }
+        (kept, duplicates)
+    }
+
+    fn load_entries_unchecked<R: Read>(entries_store: R) -> Result<Self> {
+        let stored: BTreeMap<String, StoredEntry> = serde_json::from_reader(entries_store)?;
+        let mut file_mappings = Self::new();
+        for (src, entry) in stored {
+            match entry {
+                StoredEntry::Dest(dest) => {
+                    file_mappings.entries.insert(src, dest);
+                }
+                StoredEntry::Detailed { dest, attributes } => {
+                    file_mappings.entries.insert(src.clone(), dest);
+                    if attributes != EntryAttributes::default() {
+                        file_mappings.attributes.insert(src, attributes);
+                    }
+                }
+            }
+        }
+        Ok(file_mappings)
+    }
+
+    /// Entries without attributes are saved as plain dest strings, same as older versions.
+    ///
+    /// The output depends only on the entries, not on the order they were added in, so
+    /// that committing the mappings file produces minimal diffs. Sources are sorted,
+    /// fields follow `EntryAttributes`, path lists are sorted and the file ends with a
+    /// newline.
+    pub fn save_entries<W: Write>(&self, entries_store: &mut W) -> Result<()> {
+        let stored: BTreeMap<&str, StoredEntry> = self
+            .entries
+            .iter()
+            .map(|(src, dest)| {
+                let entry = match self.attributes.get(src) {
+                    Some(attributes) => StoredEntry::Detailed {
+                        dest: dest.to_string(),
+                        attributes: attributes.canonical(),
+                    },
+                    None => StoredEntry::Dest(dest.to_string()),
+                };
+                (src.as_str(), entry)
+            })
+            .collect();
+        serde_json::to_writer_pretty(&mut *entries_store, &stored)?;
+        writeln!(entries_store)?;
+        Ok(())
+    }
+
+    /// Attributes of the stored key `key`, e.g. a key of `as_map`. Unlike the accessors
+    /// which take paths, `key` is not normalized, so keys under home (`~/...`) are found.
+    fn stored_attributes(&self, key: &str) -> &EntryAttributes {
+        self.attributes.get(key).unwrap_or(&NO_ATTRIBUTES)
+    }
+
+    /// Dest of the stored key `key`, e.g. a source in the operation log. See
+    /// `stored_attributes`.
+    fn stored_dest(&self, key: &str) -> Option<&str> {
+        self.find_key(key)
+            .and_then(|key| self.entries.get(&key))
+            .map(String::as_str)
+    }
+
+    pub fn is_rendered<P: AsRef<Path>>(&self, src: P) -> bool {
+        self.find_key(&self.strip_home(src.as_ref()))
+            .and_then(|key| self.attributes.get(&key))
+            .map(|attributes| attributes.rendered)
+            .unwrap_or(false)
+    }
+
+    pub fn set_rendered<P: AsRef<Path>>(&mut self, src: P, rendered: bool) {
+        self.update_attributes(src.as_ref(), |attributes| attributes.rendered = rendered);
+    }
+
+    pub fn is_directory<P: AsRef<Path>>(&self, src: P) -> bool {
+        self.find_key(&self.strip_home(src.as_ref()))
+            .and_then(|key| self.attributes.get(&key))
+            .map(|attributes| attributes.directory)
+            .unwrap_or(false)
+    }
+
+    pub fn set_directory<P: AsRef<Path>>(&mut self, src: P, directory: bool) {
+        self.update_attributes(src.as_ref(), |attributes| attributes.directory = directory);
+    }
+
+    pub fn is_hardlink<P: AsRef<Path>>(&self, src: P) -> bool {
+        self.find_key(&self.strip_home(src.as_ref()))
+            .and_then(|key| self.attributes.get(&key))
+            .map(|attributes| attributes.hardlink)
+            .unwrap_or(false)
+    }
+
+    pub fn set_hardlink<P: AsRef<Path>>(&mut self, src: P, hardlink: bool) {
+        self.update_attributes(src.as_ref(), |attributes| attributes.hardlink = hardlink);
+    }
+
+    /// Recorded hash of the entry of the stored key `key`.
+    pub fn hash(&self, key: &str) -> Option<&str> {
+        self.stored_attributes(key).hash.as_deref()
+    }
+
+    /// Whether entry of the stored key `key` has `tag`. Any entry matches if `tag` is
+    /// `None`.
+    pub fn has_tag(&self, key: &str, tag: Option<&str>) -> bool {
+        match tag {
+            Some(tag) => self
+                .stored_attributes(key)
+                .tags
+                .iter()
+                .any(|other| other == tag),
+            None => true,
+        }
+    }
+
+    /// Duplicated tags are removed.
+    pub fn set_tags<P: AsRef<Path>>(&mut self, src: P, mut tags: Vec<String>) {
+        tags.sort();
+        tags.dedup();
+        self.update_attributes(src.as_ref(), |attributes| attributes.tags = tags);
+    }
+
+    pub fn set_after<P: AsRef<Path>>(&mut self, src: P, after: Vec<String>) {
+        self.update_attributes(src.as_ref(), |attributes| attributes.after = after);
+    }
+
+    pub fn set_hash<P: AsRef<Path>>(&mut self, src: P, hash: Option<String>) {
+        self.update_attributes(src.as_ref(), |attributes| attributes.hash = hash);
+    }
+
+    /// Applies `update` to attributes of `src`. Entries whose attributes become default
+    /// are saved as plain dest strings again.
+    fn update_attributes<F: FnOnce(&mut EntryAttributes)>(&mut self, src: &Path, update: F) {
+        if let Some(key) = self.find_key(&self.strip_home(src)) {
+            self.update_stored_attributes(key, update);
+        }
+    }
+
+    /// Same as `update_attributes` but by the stored key. See `stored_attributes`.
+    fn update_stored_attributes<F: FnOnce(&mut EntryAttributes)>(
+        &mut self,
+        key: String,
+        update: F,
+    ) {
+        let attributes = self.attributes.entry(key.clone()).or_default();
+        update(attributes);
+        if *attributes == EntryAttributes::default() {
+            self.attributes.remove(&key);
+        }
+    }
+
+    pub fn contains<P: AsRef<Path>>(&self, src: P) -> bool {
+        self.find_key(&self.strip_home(src.as_ref())).is_some()
+    }
+
+    /// Returns dest of `src`.
+    pub fn get<P: AsRef<Path>>(&self, src: P) -> Result<&str, MappingError> {
+        let src = self.strip_home(src.as_ref());
+        self.find_key(&src)
+            .and_then(|key| self.entries.get(&key))
+            .map(String::as_str)
+            .ok_or(MappingError::NotFound(src))
+    }
+
+    pub fn remove<P: AsRef<Path>>(&mut self, src: P) -> Result<(), MappingError> {
+        self.take_entry(src).map(|_| ())
+    }
+
+    /// Removes the entry of `src` and returns its stored key, dest and attributes, so that
+    /// it can be put back by `put_entry`.
+    pub fn take_entry<P: AsRef<Path>>(&mut self, src: P) -> Result<TakenEntry, MappingError> {
+        let src = self.strip_home(src.as_ref());
+        let key = self.find_key(&src).ok_or(MappingError::NotFound(src))?;
+        let dest = self.entries.remove(&key).unwrap_or_default();
+        let attributes = self.attributes.remove(&key).unwrap_or_default();
+        Ok((key, dest, attributes))
+    }
+
+    /// Reverse of `take_entry`.
+    pub fn put_entry(&mut self, (key, dest, attributes): TakenEntry) {
+        self.entries.insert(key.clone(), dest);
+        self.update_stored_attributes(key, |stored| *stored = attributes);
+    }
+
+    /// Returns stored key which is equal to `src`, or has `src` as an alias, under
+    /// current case sensitivity.
+    fn find_key(&self, src: &str) -> Option<String> {
+        let key = if self.case_insensitive {
+            let src = src.to_lowercase();
+            self.entries.keys().find(|key| key.to_lowercase() == src)
+        } else {
+            self.entries.get_key_value(src).map(|(key, _)| key)
+        };
+        key.or_else(|| {
+            self.attributes
+                .iter()
+                .find(|(_, attributes)| {
+                    attributes
+                        .aliases
+                        .iter()
+                        .any(|alias| self.same_source(alias, src))
+                })
+                .map(|(key, _)| key)
+        })
+        .cloned()
+    }
+
+    fn same_source(&self, a: &str, b: &str) -> bool {
+        if self.case_insensitive {
+            a.to_lowercase() == b.to_lowercase()
+        } else {
+            a == b
+        }
+    }
+
+    /// Makes `alias` refer to the entry of `src`. Fails if `alias` already refers to an
+    /// entry.
+    pub fn add_alias<P: AsRef<Path>, A: AsRef<Path>>(
+        &mut self,
+        src: P,
+        alias: A,
+    ) -> Result<(), MappingError> {
+        let src = self.strip_home(src.as_ref());
+        let key = self
+            .find_key(&src)
+            .ok_or_else(|| MappingError::NotFound(src.clone()))?;
+        let alias = self.strip_home(alias.as_ref());
+        if let Some(other) = self.find_key(&alias) {
+            return Err(MappingError::AlreadyExists(other));
+        }
+        self.update_stored_attributes(key, |attributes| attributes.aliases.push(alias));
+        Ok(())
+    }
+
+    /// Returns the source which `alias` referred to.
+    pub fn remove_alias<A: AsRef<Path>>(&mut self, alias: A) -> Result<String, MappingError> {
+        let alias = self.strip_home(alias.as_ref());
+        let key = match self.find_key(&alias) {
+            Some(key)
+                if self
+                    .stored_attributes(&key)
+                    .aliases
+                    .iter()
+                    .any(|other| self.same_source(other, &alias)) =>
+            {
+                key
+            }
+            _ => return Err(MappingError::NotFound(alias)),
+        };
+        let case_insensitive = self.case_insensitive;
+        self.update_stored_attributes(key.clone(), |attributes| {
+            attributes.aliases.retain(|other| {
+                if case_insensitive {
+                    other.to_lowercase() != alias.to_lowercase()
+                } else {
+                    *other != alias
+                }
+            })
+        });
+        Ok(key)
+    }
+
+    /// Replaces dest of the stored key `src`.
+    pub fn set_dest(&mut self, src: &str, dest: &str) {
+        self.entries.insert(src.to_string(), dest.to_string());
+    }
+
+    /// Applies `result` of `merge_mappings` against `theirs` to entries. Entries taken from
+    /// `theirs` keep their attributes. Fails without changing entries if two sources would
+    /// share a dest.
+    pub fn apply_merge(
+        &mut self,
+        result: &MergeResult,
+        theirs: &FileMappings,
+        policy: ConflictPolicy,
+    ) -> Result<(), MappingError> {
+        let mut taken: Vec<&String> = result.new.keys().collect();
+        if let ConflictPolicy::PreferTheirs = policy {
+            taken.extend(result.conflicts.keys());
+        }
+        let mut merged = self.entries.clone();
+        for src in &taken {
+            if let Some(dest) = theirs.entries.get(*src) {
+                merged.insert(src.to_string(), dest.to_string());
+            }
+        }
+        let mut sources: BTreeMap<&str, &str> = BTreeMap::new();
+        for (src, dest) in &merged {
+            if let Some(other) = sources.insert(dest, src) {
+                return Err(MappingError::DuplicateDest {
+                    dest: dest.to_string(),
+                    source: other.to_string(),
+                });
+            }
+        }
+        self.entries = merged;
+        for src in taken {
+            match theirs.attributes.get(src) {
+                Some(attributes) => self.attributes.insert(src.to_string(), attributes.clone()),
+                None => self.attributes.remove(src),
+            };
+        }
+        Ok(())
+    }
+
+    /// `dst` is relative path from workspace
+    pub fn add<P: AsRef<Path>>(&mut self, src: P, dst: &str) -> Result<(), MappingError> {
+        let src = src.as_ref();
+        let src = self.strip_home(src);
+        if let Some(key) = self.find_key(&src) {
+            return Err(MappingError::AlreadyExists(key));
+        }
+        if let Some((other, _)) = self.entries.iter().find(|(_, dest)| *dest == dst) {
+            return Err(MappingError::DuplicateDest {
+                dest: dst.to_string(),
+                source: other.to_string(),
+            });
+        }
+        self.entries.insert(src, dst.to_string());
+        Ok(())
+    }
+}
+
+/// Stored key, dest and attributes of an entry removed by `FileMappings::take_entry`.
+type TakenEntry = (String, String, EntryAttributes);
+
+/// Attributes of entry other than dest.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct EntryAttributes {
+    /// Source is a file rendered from the template at dest instead of symlink
+    #[serde(default, skip_serializing_if = "is_false")]
+    rendered: bool,
+    /// SHA-256 of dest when it was linked or verified. See `content_hashes` in config.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    hash: Option<String>,
+    /// Dest is a directory. Windows needs a different kind of symlink for directories.
+    #[serde(default, skip_serializing_if = "is_false")]
+    directory: bool,
+    /// Free-form labels to filter entries by. e.g. `shell`, `laptop`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    tags: Vec<String>,
+    /// Sources which must be restored before this entry
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    after: Vec<String>,
+    /// Source is a hard link of dest instead of symlink
+    #[serde(default, skip_serializing_if = "is_false")]
+    hardlink: bool,
+    /// Other paths which refer to the source, e.g. through a symlinked parent. Found by
+    /// lookups as if they were the source.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    aliases: Vec<String>,
+}
+
+impl EntryAttributes {
+    /// Copy whose lists are sorted and deduplicated. Their order has no meaning.
+    fn canonical(&self) -> Self {
+        let sorted = |list: &[String]| {
+            let mut list = list.to_vec();
+            list.sort();
+            list.dedup();
+            list
+        };
+        Self {
+            tags: sorted(&self.tags),
+            after: sorted(&self.after),
+            aliases: sorted(&self.aliases),
+            ..self.clone()
+        }
+    }
+}
+
+/// Attributes of entries which have none.
+static NO_ATTRIBUTES: EntryAttributes = EntryAttributes {
+    rendered: false,
+    hash: None,
+    directory: false,
+    tags: Vec::new(),
+    after: Vec::new(),
+    hardlink: false,
+    aliases: Vec::new(),
+};
+
+fn is_false(value: &bool) -> bool {
+    !*value
+}
+
+/// Entry in the mappings file.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+enum StoredEntry {
+    Dest(String),
+    Detailed {
+        dest: String,
+        #[serde(flatten)]
+        attributes: EntryAttributes,
+    },
+}
+
+/// Replaces `{{name}}` in `template` with values of `variables`. Spaces around the name
+/// are allowed. Fails if a variable is not defined.
+pub fn render_template(template: &str, variables: &BTreeMap<String, String>) -> Result<String> {
+    let mut result = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        result.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let end = after
+            .find("}}")
+            .ok_or_else(|| anyhow!("Placeholder is not closed: {}", &rest[start..]))?;
+        let name = after[..end].trim();
+        let value = variables
+            .get(name)
+            .ok_or_else(|| anyhow!("Variable: {} is not defined!", name))?;
+        result.push_str(value);
+        rest = &after[end + 2..];
+    }
+    result.push_str(rest);
+    Ok(result)
+}
+
+fn is_network_subcommand(args: &[String]) -> bool {
+    matches!(
+        args.first().map(String::as_str),
+        Some("pull") | Some("push") | Some("fetch") | Some("clone") | Some("ls-remote")
+    )
+}
+
+/// Whether git failed by a network error judging by its stderr. Other failures (e.g.
+/// merge conflicts) are not worth retrying.
+fn is_network_failure(stderr: &str) -> bool {
+    const PATTERNS: &[&str] = &[
+        "could not resolve host",
+        "connection timed out",
+        "connection refused",
+        "connection reset",
+        "failed to connect",
+        "operation timed out",
+        "the remote end hung up unexpectedly",
+        "early eof",
+        "rpc failed",
+        "temporary failure in name resolution",
+        "network is unreachable",
+    ];
+    let stderr = stderr.to_lowercase();
+    PATTERNS.iter().any(|pattern| stderr.contains(pattern))
+}
+
+/// Executes `run` until it succeeds, fails by non-network error, or is retried `retries`
+/// times. Waits `base_delay`, then twice as long before each subsequent retry, up to
+/// `GIT_RETRY_MAX_DELAY`.
+fn retry_network<F>(retries: u32, base_delay: Duration, mut run: F) -> Result<Output>
+where
+    F: FnMut() -> Result<Output>,
+{
+    let mut attempt = 0;
+    loop {
+        let output = run()?;
+        if output.status.success()
+            || attempt >= retries
+            || !is_network_failure(&String::from_utf8_lossy(&output.stderr))
+        {
+            return Ok(output);
+        }
+        let delay = retry_delay(base_delay, attempt);
+        attempt += 1;
+        warn!(
+            "Network error occurred. Retrying in {:?} ({}/{})",
+            delay, attempt, retries
+        );
+        thread::sleep(delay);
+    }
+}
+
+/// Delay before retry number `attempt`, counted from 0.
+fn retry_delay(base_delay: Duration, attempt: u32) -> Duration {
+    2u32.checked_pow(attempt)
+        .and_then(|factor| base_delay.checked_mul(factor))
+        .map_or(GIT_RETRY_MAX_DELAY, |delay| delay.min(GIT_RETRY_MAX_DELAY))
+}
+
+/// Replaces first argument with the whitespace-separated arguments of the alias.
+///
+/// Aliases take precedence over git subcommands of the same name.
+pub fn expand_git_alias(aliases: &BTreeMap<String, String>, args: &[String]) -> Vec<String> {
+    match args.split_first() {
+        Some((first, rest)) => match aliases.get(first) {
+            Some(alias) => {
+                debug!("Expanding git alias '{}' to '{}'", first, alias);
+                alias
+                    .split_whitespace()
+                    .map(str::to_string)
+                    .chain(rest.iter().cloned())
+                    .collect()
+            }
+            None => args.to_vec(),
+        },
+        None => Vec::new(),
+    }
+}
+
+/// State of a mapped source.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Health {
+    /// Symlink which points to the dest
+    Healthy,
+    /// Source was replaced with a real file
+    Detached,
+    /// Symlink which points to a file that does not exist
+    Dangling,
+    /// Symlink which points to other than the dest
+    Repointed,
+    /// Source does not exist
+    Missing,
+}
+
+impl Health {
+    pub const ALL: [Health; 5] = [
+        Health::Healthy,
+        Health::Detached,
+        Health::Dangling,
+        Health::Repointed,
+        Health::Missing,
+    ];
+}
+
+impl fmt::Display for Health {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Health::Healthy => "healthy",
+            Health::Detached => "detached",
+            Health::Dangling => "dangling",
+            Health::Repointed => "repointed",
+            Health::Missing => "missing",
+        })
+    }
+}
+
+pub fn check_health(source: &Path, dest_abs: &Path) -> Health {
+    let metadata = match fs::symlink_metadata(source) {
+        Ok(metadata) => metadata,
+        Err(_) => return Health::Missing,
+    };
+    if !metadata.file_type().is_symlink() {
+        return Health::Detached;
+    }
+    match read_link_abs(source) {
+        Ok(target) if normalize_path(&target) != normalize_path(dest_abs) => Health::Repointed,
+        Ok(_) if !dest_abs.exists() => Health::Dangling,
+        Ok(_) => Health::Healthy,
+        Err(_) => Health::Missing,
+    }
+}
+
+/// Writes `file_mappings` to `path`. The file is replaced by rename so that readers
+/// never see a partially written file.
+fn write_mappings_file(path: &Path, file_mappings: &FileMappings) -> Result<()> {
+    let file_name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let tmp = path.with_file_name(format!("{}.tmp", file_name));
+    let written = File::create(&tmp)
+        .map_err(anyhow::Error::from)
+        .and_then(|file| {
+            let mut writer = BufWriter::new(file);
+            file_mappings.save_entries(&mut writer)?;
+            writer
+                .into_inner()
+                .map_err(|err| err.into_error())?
+                .sync_all()?;
+            Ok(fs::rename(&tmp, path)?)
+        });
+    if let Err(err) = written {
+        let _ = fs::remove_file(&tmp);
+        return Err(err);
+    }
+    Ok(())
+}
+
+/// Whether `name` is of a mappings overlay, e.g. `.file_mappings.laptop.json`.
+fn is_overlay_file_name(name: &str) -> bool {
+    name.starts_with(".file_mappings.")
+        && name.ends_with(".json")
+        && name != FILE_MAPPINGS_FILE_NAME
+}
+
+/// Directories created during one operation, newest last. Used to remove only the
+/// directories dotman created if the operation fails.
+#[derive(Debug, Default)]
+struct CreatedDirs {
+    dirs: Vec<PathBuf>,
+}
+
+impl CreatedDirs {
+    /// `fs::create_dir_all` which applies `mode` to the created directories and
+    /// remembers them. Directories which already exist are kept as they are.
+    fn create_all(&mut self, dir: &Path, mode: Option<u32>) -> io::Result<()> {
+        let mut missing: Vec<&Path> = dir
+            .ancestors()
+            .take_while(|dir| !dir.as_os_str().is_empty() && !dir.exists())
+            .collect();
+        missing.reverse();
+        for dir in missing {
+            match fs::create_dir(dir) {
+                Ok(_) => self.dirs.push(dir.to_path_buf()),
+                // Created by another process meanwhile
+                Err(err) if err.kind() == io::ErrorKind::AlreadyExists && dir.is_dir() => continue,
+                Err(err) => return Err(err),
+            }
+            if let Some(mode) = mode {
+                set_dir_mode(dir, mode)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes the created directories, deepest first. Directories which are no longer
+    /// empty are kept.
+    fn rollback(self) {
+        for dir in self.dirs.iter().rev() {
+            debug!("Removing created directory: {}", dir.to_string_lossy());
+            if let Err(err) = fs::remove_dir(dir) {
+                warn!(
+                    "Failed to remove directory: {} error: {}",
+                    dir.to_string_lossy(),
+                    err
+                );
+            }
+        }
+    }
+}
+
+/// Set after creation since the mode of `DirBuilder` is masked by umask.
+#[cfg(unix)]
+fn set_dir_mode(dir: &Path, mode: u32) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    fs::set_permissions(dir, fs::Permissions::from_mode(mode))
+}
+
+/// Modes are not supported except on unix.
+#[cfg(not(unix))]
+fn set_dir_mode(_dir: &Path, _mode: u32) -> io::Result<()> {
+    Ok(())
+}
+
+/// Whether `a` and `b` are hard links of the same file.
+#[cfg(unix)]
+fn is_same_file(a: &Path, b: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    match (fs::metadata(a), fs::metadata(b)) {
+        (Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+        _ => false,
+    }
+}
+
+/// Whether `a` and `b` are hard links of the same file. File identity is not available
+/// on stable Rust here, so files with the same contents are treated as the same.
+#[cfg(not(unix))]
+fn is_same_file(a: &Path, b: &Path) -> bool {
+    match (fs::read(a), fs::read(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+/// Reads target of symlink at `link`. Relative target is resolved against the directory
+/// of `link`.
+pub fn read_link_abs(link: &Path) -> io::Result<PathBuf> {
+    let target = fs::read_link(link)?;
+    if target.is_absolute() {
+        return Ok(target);
+    }
+    Ok(normalize_path(link)
+        .parent()
+        .map(|parent| normalize_path(parent.join(&target)))
+        .unwrap_or(target))
+}
+
+/// Returns path of `to` relative to directory `from`. Both must be absolute and normalized.
+///
+/// Computed lexically, so the result is wrong if `from` contains symlinked directories
+/// which are not symlinked in `to`.
+pub fn relative_path(from: &Path, to: &Path) -> PathBuf {
+    let from: Vec<Component> = from.components().collect();
+    let to: Vec<Component> = to.components().collect();
+    let common = from
+        .iter()
+        .zip(&to)
+        .take_while(|(from, to)| from == to)
+        .count();
+    let mut result = PathBuf::new();
+    for _ in common..from.len() {
+        result.push("..");
+    }
+    for comp in &to[common..] {
+        result.push(comp);
+    }
+    result
+}
+
+/// Formats stored `src` for display. If `home_relative`, leading `~/` is removed.
+pub fn display_src(src: &str, home_relative: bool) -> String {
+    let prefix = format!("~{}", std::path::MAIN_SEPARATOR);
+    match src.strip_prefix(&prefix) {
+        Some(stripped) if home_relative => stripped.to_string(),
+        _ => src.to_string(),
+    }
+}
+
+/// Replaces leading `~` with home directory of the current user. See
+/// `Workspace::expand_home`.
+pub fn expand_src(src: &str) -> PathBuf {
+    expand_home(dirs::home_dir().as_deref(), src)
+}
+
+/// Expands leading `~` or `~user` of a path given by the user, e.g. `~root/.bashrc`.
+/// Other paths are returned as is.
+///
+/// Fails if the user does not exist. `~user` is supported only on unix.
+pub fn expand_user<P: AsRef<Path>>(path: P) -> Result<PathBuf> {
+    let path = path.as_ref();
+    let mut components = path.components();
+    let name = match components.next() {
+        Some(Component::Normal(first)) => {
+            match first.to_str().and_then(|first| first.strip_prefix('~')) {
+                Some(name) => name.to_string(),
+                None => return Ok(path.to_path_buf()),
+            }
+        }
+        _ => return Ok(path.to_path_buf()),
+    };
+    let home = if name.is_empty() {
+        dirs::home_dir().ok_or_else(|| anyhow!("Cannot retrieve home directory"))?
+    } else {
+        user_home(&name)?
+    };
+    let rest = components.as_path();
+    if rest.as_os_str().is_empty() {
+        return Ok(home);
+    }
+    Ok(home.join(rest))
+}
+
+#[cfg(unix)]
+fn user_home(name: &str) -> Result<PathBuf> {
+    nix::unistd::User::from_name(name)
+        .map_err(|err| anyhow!("Failed to look up user: {} error: {}", name, err))?
+        .map(|user| user.dir)
+        .ok_or_else(|| ErrorKind::SourceInvalid.error(format!("User: {} does not exist!", name)))
+}
+
+#[cfg(not(unix))]
+fn user_home(name: &str) -> Result<PathBuf> {
+    Err(ErrorKind::SourceInvalid.error(format!(
+        "~{} can't be expanded! ~user is supported only on unix.",
+        name
+    )))
+}
+
+/// Options of `walk_dir`.
+#[derive(Debug, Clone, Copy)]
+pub struct WalkOptions {
+    /// Directories at this depth are not descended. Entries directly in the root are at
+    /// depth 1.
+    pub max_depth: usize,
+    /// Descends into symlinked directories. Each directory is descended at most once, so
+    /// symlink cycles don't cause infinite recursion.
+    pub follow_symlinks: bool,
+    /// Warns and skips directories which can't be read instead of failing
+    pub skip_unreadable: bool,
+}
+
+impl Default for WalkOptions {
+    fn default() -> Self {
+        Self {
+            max_depth: MAX_WALK_DEPTH,
+            follow_symlinks: false,
+            skip_unreadable: false,
+        }
+    }
+}
+
+/// Entry found by `walk_dir`.
+#[derive(Debug)]
+pub struct WalkEntry {
+    pub path: PathBuf,
+    /// Path relative to the root
+    pub relative: PathBuf,
+    pub depth: usize,
+    /// Type of the entry itself. Symlinks are not followed.
+    pub file_type: FileType,
+}
+
+/// Lists entries under `root` depth-first, in sorted order. A directory is listed
+/// before its contents and is descended only if `descend` returns `true` for it.
+///
+/// Directories at `max_depth` are not descended and a warning is printed.
+pub fn walk_dir<F>(root: &Path, options: WalkOptions, mut descend: F) -> Result<Vec<WalkEntry>>
+where
+    F: FnMut(&WalkEntry) -> bool,
+{
+    let mut visited = Vec::new();
+    if options.follow_symlinks {
+        visited.push(fs::canonicalize(root)?);
+    }
+    let mut entries = Vec::new();
+    walk_dir_into(
+        root,
+        Path::new(""),
+        1,
+        options,
+        &mut visited,
+        &mut descend,
+        &mut entries,
+    )?;
+    Ok(entries)
+}
+
+fn walk_dir_into<F>(
+    dir: &Path,
+    relative: &Path,
+    depth: usize,
+    options: WalkOptions,
+    visited: &mut Vec<PathBuf>,
+    descend: &mut F,
+    entries: &mut Vec<WalkEntry>,
+) -> Result<()>
+where
+    F: FnMut(&WalkEntry) -> bool,
+{
+    let dir_entries = fs::read_dir(dir).and_then(|entries| entries.collect::<io::Result<Vec<_>>>());
+    let mut dir_entries = match dir_entries {
+        Ok(dir_entries) => dir_entries,
+        Err(err) if options.skip_unreadable => {
+            warn!("Cannot read {} error: {}", dir.to_string_lossy(), err);
+            return Ok(());
+        }
+        Err(err) => return Err(err.into()),
+    };
+    dir_entries.sort_by_key(|entry| entry.file_name());
+    for dir_entry in dir_entries {
+        let entry = WalkEntry {
+            path: dir_entry.path(),
+            relative: relative.join(dir_entry.file_name()),
+            depth,
+            file_type: dir_entry.file_type()?,
+        };
+        let is_dir = entry.file_type.is_dir()
+            || (options.follow_symlinks && entry.file_type.is_symlink() && entry.path.is_dir());
+        let descended = is_dir && descend(&entry);
+        let (path, entry_relative) = (entry.path.clone(), entry.relative.clone());
+        entries.push(entry);
+        if !descended {
+            continue;
+        }
+        if depth >= options.max_depth {
+            warn!(
+                "{} is nested deeper than {} levels. Skipped its contents.",
+                path.to_string_lossy(),
+                options.max_depth
+            );
+            continue;
+        }
+        if options.follow_symlinks {
+            let canonical = fs::canonicalize(&path)?;
+            if visited.contains(&canonical) {
+                debug!("Already visited: {}", path.to_string_lossy());
+                continue;
+            }
+            visited.push(canonical);
+        }
+        walk_dir_into(
+            &path,
+            &entry_relative,
+            depth + 1,
+            options,
+            visited,
+            descend,
+            entries,
+        )?;
+    }
+    Ok(())
+}
+
+/// Quotes `s` for POSIX shells if it contains characters other than safe ones.
+pub fn shell_quote(s: &str) -> String {
+    let safe = |c: char| c.is_ascii_alphanumeric() || "_-+=./,:@%".contains(c);
+    if !s.is_empty() && s.chars().all(safe) {
+        return s.to_string();
+    }
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+/// `shell_quote` which keeps leading `~/` of a stored source unquoted, so that the
+/// shell expands it to home directory.
+fn quote_src(src: &str) -> String {
+    let prefix = format!("~{}", std::path::MAIN_SEPARATOR);
+    match src.strip_prefix(&prefix) {
+        Some(rest) => format!("{}{}", prefix, shell_quote(rest)),
+        None => shell_quote(src),
+    }
+}
+
+/// Returns a flag which becomes `false` when Ctrl-C is pressed.
+fn stop_on_ctrl_c() -> Result<Arc<AtomicBool>> {
+    let running = Arc::new(AtomicBool::new(true));
+    {
+        let running = Arc::clone(&running);
+        ctrlc::set_handler(move || running.store(false, Ordering::SeqCst))?;
+    }
+    Ok(running)
+}
+
+/// Sleeps for `duration`, returning early when `running` becomes `false`.
+fn sleep_while_running(running: &AtomicBool, duration: Duration) {
+    let started = Instant::now();
+    while running.load(Ordering::SeqCst) && started.elapsed() < duration {
+        thread::sleep(Duration::from_millis(100));
+    }
+}
+
+/// Returns whether `source` should be expanded by `expand_glob`.
+pub fn is_glob(source: &str) -> bool {
+    source.contains(['*', '?', '['])
+}
+
+/// Returns regular files matching `pattern` in sorted order. Leading `~` is expanded.
+/// Directories and symlinks (e.g. already linked files) are skipped.
+pub fn expand_glob(pattern: &str) -> Result<Vec<PathBuf>> {
+    let pattern = expand_src(pattern);
+    let paths = glob::glob(&pattern.to_string_lossy())
+        .map_err(|err| ErrorKind::SourceInvalid.error(format!("Invalid glob: {}", err)))?;
+    let mut sources = Vec::new();
+    for path in paths {
+        let path = path.map_err(|err| anyhow!("Failed to expand glob! error: {}", err))?;
+        if fs::symlink_metadata(&path)?.is_file() {
+            sources.push(path);
+        }
+    }
+    if sources.is_empty() {
+        return Err(ErrorKind::SourceMissing
+            .error(format!("No files match {}!", pattern.to_string_lossy())));
+    }
+    Ok(sources)
+}
+
+/// Returns human readable name of `file_type`.
+pub fn describe_file_type(file_type: &FileType) -> &'static str {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::FileTypeExt;
+
+        if file_type.is_fifo() {
+            return "FIFO";
+        }
+        if file_type.is_socket() {
+            return "socket";
+        }
+        if file_type.is_block_device() {
+            return "block device";
+        }
+        if file_type.is_char_device() {
+            return "character device";
+        }
+    }
+    if file_type.is_symlink() {
+        "symlink"
+    } else if file_type.is_dir() {
+        "directory"
+    } else if file_type.is_file() {
+        "regular file"
+    } else {
+        "special file"
+    }
+}
+
+/// Fails if a file can't be created in `dir`. Directories without any write permission bits
+/// are refused even for root. Nothing is created in `dir` to check it.
+fn ensure_dir_writable(dir: &Path) -> io::Result<()> {
+    if fs::metadata(dir)?.permissions().readonly() {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "directory is read-only",
+        ));
+    }
+    // Also fails on read-only mounts
+    #[cfg(unix)]
+    nix::unistd::access(dir, nix::unistd::AccessFlags::W_OK)?;
+    Ok(())
+}
+
+/// Refuses paths which can't be stored in mappings without lossy conversion.
+pub fn ensure_utf8(path: &Path) -> Result<()> {
+    if normalize_path(path).to_str().is_none() {
+        return Err(ErrorKind::SourceInvalid.error(format!(
+            "Path: {} contains non-UTF-8 characters! It can't be managed by this tool.",
+            path.to_string_lossy()
+        )));
+    }
+    Ok(())
+}
+
+/// Checks that `dest` is relative to the workspace and stays inside it.
+pub fn validate_dest(dest: &str) -> Result<()> {
+    let path = Path::new(dest);
+    if dest.starts_with('~') || path.has_root() || path.is_absolute() {
+        return Err(ErrorKind::DestInvalid.error(format!(
+            "Dest: {} must be a path relative to the workspace!",
+            dest
+        )));
+    }
+    let mut depth = 0usize;
+    for comp in Path::new(dest).components() {
+        match comp {
+            Component::Normal(_) => depth += 1,
+            Component::CurDir => {}
+            Component::ParentDir if depth > 0 => depth -= 1,
+            _ => {
+                return Err(
+                    ErrorKind::DestInvalid.error(format!("Dest: {} escapes the workspace!", dest))
+                )
+            }
+        }
+    }
+    if depth == 0 {
+        return Err(
+            ErrorKind::DestInvalid.error(format!("Dest: '{}' does not point to a file!", dest))
+        );
+    }
+    Ok(())
+}
+
+/// Derives dest from the home-relative path of `source`.
+///
+/// Leading `.` of the first component is stripped, so `~/.config/app/config` becomes
+/// `config/app/config`.
+pub fn mirror_dest<P: AsRef<Path>, H: AsRef<Path>>(source: P, home: H) -> Result<String> {
+    let source = normalize_path(source);
+    let relative = source.strip_prefix(home.as_ref()).map_err(|_| {
+        anyhow!(
+            "Source file: {} is not in home directory!",
+            source.to_string_lossy()
+        )
+    })?;
+    let mut components = relative.components();
+    let first = components
+        .next()
+        .ok_or_else(|| anyhow!("Cannot derive dest from home directory itself!"))?
+        .as_os_str()
+        .to_string_lossy()
+        .to_string();
+    let mut dest = PathBuf::from(match first.strip_prefix('.') {
+        Some(stripped) if !stripped.is_empty() => stripped,
+        _ => &first,
+    });
+    if components.as_path() != Path::new("") {
+        dest.push(components.as_path());
+    }
+    let dest = dest.to_string_lossy().to_string();
+    validate_dest(&dest)?;
+    Ok(dest)
+}
+
+/// Removes `count` leading components from derived `dest`.
+pub fn strip_components(dest: &str, count: usize) -> Result<String> {
+    let components: Vec<Component> = Path::new(dest).components().collect();
+    if count >= components.len() {
+        return Err(anyhow!(
+            "Cannot strip {} components from '{}' which has only {} components!",
+            count,
+            dest,
+            components.len()
+        ));
+    }
+    let stripped: PathBuf = components[count..].iter().collect();
+    let stripped = stripped.to_string_lossy().to_string();
+    validate_dest(&stripped)?;
+    Ok(stripped)
+}
+
+/// Sets extension of the file name of `dest` to `extension`. Leading `.` of `extension`
+/// is ignored. A dot file without extension (e.g. `.bashrc`) gets it appended.
+pub fn set_dest_extension(dest: &str, extension: &str) -> Result<String> {
+    let extension = extension.trim_start_matches('.');
+    if extension.is_empty() || extension.contains(['/', std::path::MAIN_SEPARATOR]) {
+        return Err(
+            ErrorKind::DestInvalid.error(format!("Extension: '{}' is not valid!", extension))
+        );
+    }
+    let mut path = PathBuf::from(dest);
+    path.set_extension(extension);
+    let dest = path.to_string_lossy().to_string();
+    validate_dest(&dest)?;
+    Ok(dest)
+}
+
+/// Orders `(source, dest)` entries. Entries whose `linked_at` is unknown come last when
+/// sorted by date. Ties keep the order of source.
+fn sort_mappings<F>(entries: &mut [(&str, &str)], sort: MappingsSort, linked_at: F)
+where
+    F: Fn(&str) -> Option<SystemTime>,
+{
+    match sort {
+        MappingsSort::Source => entries.sort_by(|a, b| a.0.cmp(b.0)),
+        MappingsSort::Dest => entries.sort_by(|a, b| a.1.cmp(b.1).then(a.0.cmp(b.0))),
+        MappingsSort::Date => {
+            entries.sort_by(|a, b| a.0.cmp(b.0));
+            entries.sort_by_cached_key(|(src, _)| {
+                let linked_at = linked_at(src);
+                (linked_at.is_none(), linked_at)
+            });
+        }
+    }
+}
+
+/// Adds `names` to `.gitignore` content if `ignore`, otherwise removes them. Other lines
+/// are kept as is.
+fn sync_gitignore(content: &str, names: &[&str], ignore: bool) -> String {
+    let matches = |line: &str, name: &str| {
+        let line = line.trim();
+        line == name || line.strip_prefix('/') == Some(name)
+    };
+    if !ignore {
+        return content
+            .lines()
+            .filter(|line| !names.iter().any(|name| matches(line, name)))
+            .map(|line| format!("{}\n", line))
+            .collect();
+    }
+    let mut result = content.to_string();
+    for name in names {
+        if content.lines().any(|line| matches(line, name)) {
+            continue;
+        }
+        if !result.is_empty() && !result.ends_with('\n') {
+            result.push('\n');
+        }
+        result.push_str(name);
+        result.push('\n');
+    }
+    result
+}
+
+/// Replaces paths in `git status --porcelain -z` output with the sources they are linked
+/// from, and returns lines of the output without `-z`. Paths are relative to `base`.
+/// Paths which are not dests, nor in directory dests, are kept with a `(not mapped)`
+/// marker.
+fn translate_porcelain(
+    output: &str,
+    base: &Path,
+    workspace: &Workspace,
+    file_mappings: &FileMappings,
+) -> Vec<String> {
+    let translate = |path: &str| {
+        let relative = match workspace.relative(base.join(path)) {
+            Some(relative) => relative,
+            None => return format!("{} (not mapped)", path),
+        };
+        file_mappings
+            .as_map()
+            .iter()
+            .find_map(|(src, dest)| {
+                let rest = relative.strip_prefix(normalize_dest(dest)).ok()?;
+                Some(if rest == Path::new("") {
+                    src.to_string()
+                } else {
+                    Path::new(src).join(rest).to_string_lossy().to_string()
+                })
+            })
+            .unwrap_or_else(|| format!("{} (not mapped)", path))
+    };
+    let mut lines = Vec::new();
+    let mut records = output.split('\0').filter(|record| !record.is_empty());
+    while let Some(record) = records.next() {
+        if record.len() < 4 {
+            lines.push(record.to_string());
+            continue;
+        }
+        let (status, path) = record.split_at(3);
+        // Renames and copies are followed by a record of the original path
+        let from = if status.contains(['R', 'C']) {
+            records.next()
+        } else {
+            None
+        };
+        let paths = match from {
+            Some(from) => format!("{} -> {}", translate(from), translate(path)),
+            None => translate(path),
+        };
+        lines.push(format!("{}{}", status, paths));
+    }
+    lines
+}
+
+/// Inserts `-n` before the extension of `dest`. e.g. `app/a.conf` to `app/a-1.conf`.
+fn suffix_dest(dest: &str, n: usize) -> String {
+    let path = Path::new(dest);
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(extension) => format!("{}-{}.{}", stem, n, extension.to_string_lossy()),
+        None => format!("{}-{}", stem, n),
+    };
+    path.with_file_name(name).to_string_lossy().to_string()
+}
+
+/// Removes `.` components and trailing separators so that dests can be compared by path.
+fn normalize_dest(dest: &str) -> PathBuf {
+    Path::new(dest)
+        .components()
+        .filter(|comp| *comp != Component::CurDir)
+        .collect()
+}
+
+/// Orders `nodes` so that each node comes after the nodes returned by `deps`. Nodes
+/// which are ready at the same time keep their order in `nodes`. Dependencies not in
+/// `nodes` are ignored.
+///
+/// Returns nodes in or depending on a cycle as `Err`.
+fn topo_sort<'a, F>(nodes: &[String], deps: F) -> Result<Vec<String>, Vec<String>>
+where
+    F: Fn(&str) -> &'a [String],
+{
+    let indices: BTreeMap<&str, usize> = nodes
+        .iter()
+        .enumerate()
+        .map(|(index, node)| (node.as_str(), index))
+        .collect();
+    // Number of unsorted dependencies of each node, and nodes depending on each node
+    let mut unsorted_deps = vec![0usize; nodes.len()];
+    let mut dependents: Vec<Vec<usize>> = vec![Vec::new(); nodes.len()];
+    for (index, node) in nodes.iter().enumerate() {
+        for dep in deps(node) {
+            match indices.get(dep.as_str()) {
+                Some(&dep) if dep != index => {
+                    unsorted_deps[index] += 1;
+                    dependents[dep].push(index);
+                }
+                _ => {}
+            }
+        }
+    }
+    // Always takes the first ready node in `nodes` order
+    let mut ready: BTreeSet<usize> = (0..nodes.len())
+        .filter(|&index| unsorted_deps[index] == 0)
+        .collect();
+    let mut sorted: Vec<String> = Vec::with_capacity(nodes.len());
+    while let Some(index) = ready.pop_first() {
+        sorted.push(nodes[index].clone());
+        for &dependent in &dependents[index] {
+            unsorted_deps[dependent] -= 1;
+            if unsorted_deps[dependent] == 0 {
+                ready.insert(dependent);
+            }
+        }
+    }
+    if sorted.len() < nodes.len() {
+        return Err(nodes
+            .iter()
+            .zip(&unsorted_deps)
+            .filter(|(_, count)| **count != 0)
+            .map(|(node, _)| node.clone())
+            .collect());
+    }
+    Ok(sorted)
+}
+
+/// Asks yes/no `question` on stdin. Anything other than `y` or `yes` means no.
+fn confirm(question: &str) -> Result<bool> {
+    print!("{} [y/N] ", question);
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
+
+/// Returns SHA-256 of contents of `path` in lowercase hex.
+fn hash_file(path: &Path) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Formats `(source, dest, health)` entries for `status --format=porcelain=v2`.
+///
+/// The format is a stable contract for scripts. It won't change in later versions; a
+/// changed format gets a new version. Each line is a record of a type followed by
+/// space-separated `key=value` fields:
+///
+/// ```text
+/// # dotman status porcelain=v2
+/// entry health=healthy source=~/.vimrc dest=vim/vimrc
+/// entry health=missing source=~/My%20Notes dest=notes
+/// summary total=2 broken=1
+/// ```
+///
+/// - The first line is the version marker.
+/// - `health` is one of `healthy`, `detached`, `dangling`, `repointed` and `missing`.
+/// - `source` is the source as stored in mappings and `dest` is relative to the workspace.
+///   In paths, `%`, space, `=` and control characters including newline and NUL are
+///   encoded as `%XX` of their bytes, so records never contain them literally.
+/// - Scripts must ignore record types and fields which they don't know.
+pub fn porcelain_v2(entries: &[(String, String, Health)]) -> String {
+    let encode = |path: &str| {
+        let mut encoded = Vec::with_capacity(path.len());
+        for byte in path.bytes() {
+            match byte {
+                b'%' | b' ' | b'=' | 0x00..=0x1f | 0x7f => {
+                    encoded.extend(format!("%{:02X}", byte).bytes())
+                }
+                _ => encoded.push(byte),
+            }
+        }
+        // Only ASCII bytes are replaced, so it is still UTF-8
+        String::from_utf8(encoded).expect("Encoded path is UTF-8")
+    };
+    let mut out = String::from("# dotman status porcelain=v2\n");
+    for (src, dest, health) in entries {
+        out.push_str(&format!(
+            "entry health={} source={} dest={}\n",
+            health,
+            encode(src),
+            encode(dest)
+        ));
+    }
+    let broken = entries
+        .iter()
+        .filter(|(_, _, health)| *health != Health::Healthy)
+        .count();
+    out.push_str(&format!(
+        "summary total={} broken={}\n",
+        entries.len(),
+        broken
+    ));
+    out
+}
+
+/// Commit message of `save`. With `timestamp`, `now` is added to `message` in ISO 8601,
+/// or the message is `dotman: update <now>` if `message` is not specified.
+pub fn commit_message(
+    message: Option<&str>,
+    timestamp: Option<TimestampPosition>,
+    now: DateTime<Utc>,
+) -> String {
+    let now = now.format("%Y-%m-%dT%H:%M:%SZ");
+    match (message, timestamp) {
+        (Some(message), None) => message.to_string(),
+        (None, None) => DEFAULT_COMMIT_MESSAGE.to_string(),
+        (None, Some(_)) => format!("dotman: update {}", now),
+        (Some(message), Some(TimestampPosition::Append)) => format!("{} {}", message, now),
+        (Some(message), Some(TimestampPosition::Prepend)) => format!("{} {}", now, message),
+    }
+}
+
+/// Writes into file at `path` by `write` through `BufWriter`. Parent directories are
+/// created. Prints the number of written bytes.
+pub fn write_output<F>(path: &Path, write: F) -> Result<()>
+where
+    F: FnOnce(&mut BufWriter<File>) -> Result<()>,
+{
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut writer = BufWriter::new(File::create(path).map_err(|err| {
+        ErrorKind::Io.error(format!(
+            "Failed to create {} error: {}",
+            path.to_string_lossy(),
+            err
+        ))
+    })?);
+    write(&mut writer)?;
+    writer.flush()?;
+    println!(
+        "Wrote {} bytes to {}",
+        fs::metadata(path)?.len(),
+        path.to_string_lossy()
+    );
+    Ok(())
+}
+
+/// Prints single path terminated by newline, or by NUL if `print0`.
+pub fn print_path(path: &str, print0: bool) -> Result<()> {
+    let stdout = io::stdout();
+    write_path(path, print0, &mut stdout.lock())
+        .map_err(|err| anyhow!("Failed to write path! error: {}", err))
+}
+
+fn write_path<W: Write>(path: &str, print0: bool, out: &mut W) -> io::Result<()> {
+    out.write_all(path.as_bytes())?;
+    out.write_all(if print0 { b"\0" } else { b"\n" })?;
+    out.flush()
+}
+
+/// Writes each mapping as NUL-terminated `dest\0src\0` record.
+fn write_mappings_print0<W: Write>(entries: &[(&str, &str)], out: &mut W) -> io::Result<()> {
+    for (src, dest) in entries {
+        out.write_all(dest.as_bytes())?;
+        out.write_all(b"\0")?;
+        out.write_all(src.as_bytes())?;
+        out.write_all(b"\0")?;
+    }
+    out.flush()
+}
+
+/// Starting point of `diff` and `history`.
+#[derive(Debug, PartialEq, Eq)]
+enum Since {
+    /// Full hash of the commit
+    Ref(String),
+    /// `YYYY-MM-DD`
+    Date(String),
+}
+
+/// Options of `App::status`.
+#[derive(Debug, Default, Clone)]
+pub struct StatusOptions {
+    /// Prints only the number of mapped files
+    pub count_only: bool,
+    /// Prints health of each entry and fails if any of them is broken
+    pub fail_on_broken: bool,
+    /// Prints actual target of each symlink alongside the expected dest
+    pub verbose: bool,
+    /// Shows sources relative to home without `~` and dests relative to the workspace
+    pub workspace_relative: bool,
+    /// Shows only entries which have the tag
+    pub tag: Option<String>,
+    /// Redraws health of entries at this interval until Ctrl-C is pressed
+    pub watch: Option<Duration>,
+    /// Prints `git status` with paths of sources instead of workspace paths
+    pub translate: bool,
+    /// Shows only entries which match the glob
+    pub filter: Option<MappingFilter>,
+    /// Prints health in the format for scripts instead of `git status`
+    pub format: Option<StatusFormat>,
+}
+
+/// Format of `status --format`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StatusFormat {
+    /// See `porcelain_v2`
+    PorcelainV2,
+}
+
+impl FromStr for StatusFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "porcelain=v2" => Ok(StatusFormat::PorcelainV2),
+            _ => Err(anyhow!("Unknown status format: {}", s)),
+        }
+    }
+}
+
+impl StatusOptions {
+    /// Whether the entry passes both `tag` and `filter`.
+    fn selects(&self, file_mappings: &FileMappings, src: &str, dest: &str) -> bool {
+        file_mappings.has_tag(src, self.tag.as_deref())
+            && self
+                .filter
+                .as_ref()
+                .is_none_or(|filter| filter.matches(src, dest))
+    }
+}
+
+/// Field of a mapping which `MappingFilter` matches against.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FilterField {
+    /// Stored source. e.g. `~/.config/nvim/init.vim`
+    Source,
+    Dest,
+    /// Either source or dest
+    Any,
+}
+
+impl FromStr for FilterField {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "source" => Ok(FilterField::Source),
+            "dest" => Ok(FilterField::Dest),
+            "any" => Ok(FilterField::Any),
+            _ => Err(anyhow!("Unknown filter field: {}", s)),
+        }
+    }
+}
+
+/// Glob which selects mappings by source or dest. e.g. `nvim/*`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MappingFilter {
+    pattern: glob::Pattern,
+    field: FilterField,
+}
+
+impl MappingFilter {
+    pub fn new(pattern: &str, field: FilterField) -> Result<Self> {
+        let pattern = glob::Pattern::new(pattern)
+            .map_err(|err| anyhow!("Invalid glob: {} error: {}", pattern, err))?;
+        Ok(Self { pattern, field })
+    }
+
+    pub fn matches(&self, src: &str, dest: &str) -> bool {
+        match self.field {
+            FilterField::Source => self.pattern.matches(src),
+            FilterField::Dest => self.pattern.matches(dest),
+            FilterField::Any => self.pattern.matches(src) || self.pattern.matches(dest),
+        }
+    }
+}
+
+/// Output of `doctor --json`.
+///
+/// ```json
+/// {
+///   "version": 1,
+///   "summary": {
+///     "total": 2, "healthy": 1, "broken": 1, "health_score": 50.0,
+///     "counts": {"dangling": 0, "detached": 1, "healthy": 1, "missing": 0, "repointed": 0}
+///   },
+///   "categories": {
+///     "detached": [{"source": "~/.vimrc", "dest": "vimrc"}],
+///     "healthy": [{"source": "~/.zshrc", "dest": "zshrc"}],
+///     ...
+///   }
+/// }
+/// ```
+///
+/// Every health is present in `counts` and `categories` even if no entry has it.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DoctorReport {
+    /// Incremented on incompatible changes of this structure
+    pub version: u32,
+    pub summary: DoctorSummary,
+    /// Entries keyed by health
+    pub categories: BTreeMap<String, Vec<DoctorEntry>>,
+}
+
+impl DoctorReport {
+    pub const VERSION: u32 = 1;
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DoctorSummary {
+    pub total: usize,
+    pub healthy: usize,
+    pub broken: usize,
+    pub counts: BTreeMap<String, usize>,
+    /// Percentage of healthy entries. 100 if there is no entry.
+    pub health_score: f64,
+}
+
+/// Kind of repair of `doctor --fix`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DoctorFix {
+    /// Removes entries whose dest does not exist, with their dangling symlinks
+    Prune,
+    /// Replaces detached sources with links if their contents equal the dests
+    Relink,
+    /// Creates links of entries whose source does not exist
+    Recreate,
+}
+
+impl DoctorFix {
+    pub const ALL: [DoctorFix; 3] = [DoctorFix::Prune, DoctorFix::Relink, DoctorFix::Recreate];
+
+    /// Name of the fix in `--fix` and the audit log.
+    fn name(self) -> &'static str {
+        match self {
+            DoctorFix::Prune => "prune",
+            DoctorFix::Relink => "relink",
+            DoctorFix::Recreate => "recreate",
+        }
+    }
+}
+
+impl fmt::Display for DoctorFix {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            DoctorFix::Prune => "pruned",
+            DoctorFix::Relink => "relinked",
+            DoctorFix::Recreate => "recreated",
+        })
+    }
+}
+
+impl FromStr for DoctorFix {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        DoctorFix::ALL
+            .iter()
+            .copied()
+            .find(|fix| fix.name() == s)
+            .ok_or_else(|| anyhow!("Unknown fix: {}", s))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DoctorEntry {
+    pub source: String,
+    pub dest: String,
+}
+
+/// Actual and expected target of the symlink of an entry.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LinkTarget {
+    pub source: String,
+    pub health: Health,
+    pub expected: PathBuf,
+    /// Result of `fs::read_link`. `None` if source is not a symlink.
+    pub actual: Option<PathBuf>,
+}
+
+impl LinkTarget {
+    /// Whether the symlink points to other than the expected dest.
+    pub fn differs(&self) -> bool {
+        self.health == Health::Repointed
+    }
+
+    /// Formats as lines. If `workspace_relative`, paths in `workspace` are shown relative
+    /// to it and source is shown by `display_src`.
+    pub fn format(&self, workspace: &Workspace, workspace_relative: bool) -> String {
+        let display = |path: &Path| match workspace.relative(path) {
+            Some(relative) if workspace_relative => relative.to_string_lossy().to_string(),
+            _ => path.to_string_lossy().to_string(),
+        };
+        let actual = match &self.actual {
+            Some(actual) if self.differs() => format!("{} <- differs", display(actual)),
+            Some(actual) => display(actual),
+            None => "(not a symlink)".to_string(),
+        };
+        format!(
+            "{} {}\n    expected: {}\n    actual:   {}",
+            self.health,
+            display_src(&self.source, workspace_relative),
+            display(&self.expected),
+            actual
+        )
+    }
+}
+
+/// Format of `App::export`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    Json,
+    /// Shell script of `dotman link` commands
+    Shell,
+}
+
+impl FromStr for ExportFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "json" => Ok(ExportFormat::Json),
+            "shell" => Ok(ExportFormat::Shell),
+            _ => Err(anyhow!("Unknown format: {}", s)),
+        }
+    }
+}
+
+/// What `App::link_batch` does when sources get the same dest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DestCollision {
+    /// Fails before linking any source
+    Error,
+    /// Links only the first source
+    Skip,
+    /// Appends `-1`, `-2`, ... to the file name of later dests
+    Suffix,
+}
+
+impl FromStr for DestCollision {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "error" => Ok(DestCollision::Error),
+            "skip" => Ok(DestCollision::Skip),
+            "suffix" => Ok(DestCollision::Suffix),
+            _ => Err(anyhow!("Unknown collision policy: {}", s)),
+        }
+    }
+}
+
+/// Which entries `App::restore` creates the symlinks of.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RestoreMode {
+    /// Only entries whose source does not exist
+    LinkMissingOnly,
+    /// Also entries whose source is detached or points to another file
+    Reconcile,
+}
+
+/// What `App::restore` does to one entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RestoreStep {
+    Create,
+    /// The file at the source is moved to `<source>.dotman-backup` first
+    MoveAsideAndCreate,
+}
+
+impl RestoreMode {
+    /// Returns what to do to an entry in `health`, or `None` to leave it. Rendered
+    /// entries are always regenerated since variables may have been changed. `force`
+    /// replaces detached sources in either mode, except of rendered and hardlinked
+    /// entries whose sources are real files anyway.
+    fn step(
+        self,
+        health: Health,
+        attributes: &EntryAttributes,
+        force: bool,
+    ) -> Option<RestoreStep> {
+        let replaceable = !attributes.rendered && !attributes.hardlink;
+        match health {
+            Health::Missing => Some(RestoreStep::Create),
+            Health::Healthy if attributes.rendered => Some(RestoreStep::Create),
+            Health::Detached if replaceable && (force || self == RestoreMode::Reconcile) => {
+                Some(RestoreStep::MoveAsideAndCreate)
+            }
+            // The symlink is replaced atomically and nothing is lost
+            Health::Repointed if self == RestoreMode::Reconcile => Some(RestoreStep::Create),
+            _ => None,
+        }
+    }
+}
+
+/// Case of dests derived from source paths.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DestCase {
+    Keep,
+    /// e.g. for repos which are checked out on case-insensitive file systems
+    Lower,
+}
+
+impl FromStr for DestCase {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "keep" => Ok(DestCase::Keep),
+            "lower" => Ok(DestCase::Lower),
+            _ => Err(anyhow!("Unknown dest case: {}", s)),
+        }
+    }
+}
+
+/// Where `save --timestamp` puts the timestamp in the commit message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimestampPosition {
+    Append,
+    Prepend,
+}
+
+impl FromStr for TimestampPosition {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "append" => Ok(TimestampPosition::Append),
+            "prepend" => Ok(TimestampPosition::Prepend),
+            _ => Err(anyhow!("Unknown timestamp position: {}", s)),
+        }
+    }
+}
+
+/// Order of `App::mappings`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MappingsSort {
+    Source,
+    Dest,
+    /// Time when the symlink was created
+    Date,
+}
+
+impl FromStr for MappingsSort {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "source" => Ok(MappingsSort::Source),
+            "dest" => Ok(MappingsSort::Dest),
+            "date" => Ok(MappingsSort::Date),
+            _ => Err(anyhow!("Unknown order: {}", s)),
+        }
+    }
+}
+
+/// What `App::link` does when the entry or the dest file already exists.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IfExists {
+    /// Does nothing and succeeds
+    Skip,
+    /// Fails
+    Error,
+    /// Replaces existing entry and dest file
+    Overwrite,
+}
+
+impl FromStr for IfExists {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "skip" => Ok(IfExists::Skip),
+            "error" => Ok(IfExists::Error),
+            "overwrite" => Ok(IfExists::Overwrite),
+            _ => Err(anyhow!("Unknown policy: {}", s)),
+        }
+    }
+}
+
+/// What batch operations do when an entry failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorPolicy {
+    /// Processes remaining entries and fails at the end
+    ContinueOnError,
+    /// Returns the error of the first failed entry immediately
+    FailFast,
+}
+
+/// Results of entries of a batch operation.
+#[derive(Debug)]
+struct Batch {
+    policy: ErrorPolicy,
+    succeeded: usize,
+    /// `(entry, error)` of failed entries
+    failures: Vec<(String, anyhow::Error)>,
+}
+
+impl Batch {
+    fn new(policy: ErrorPolicy) -> Self {
+        Self {
+            policy,
+            succeeded: 0,
+            failures: Vec::new(),
+        }
+    }
+
+    /// Records result of `entry`. Returns the error if the batch should be aborted.
+    fn record(&mut self, entry: &str, result: Result<()>) -> Result<()> {
+        match result {
+            Ok(_) => self.succeeded += 1,
+            Err(err) if self.policy == ErrorPolicy::FailFast => return Err(err),
+            Err(err) => {
+                error!("Failed to process {} error: {}", entry, err);
+                self.failures.push((entry.to_string(), err));
+            }
+        }
+        Ok(())
+    }
+
+    fn succeeded(&self) -> usize {
+        self.succeeded
+    }
+
+    /// Fails if any entry failed. The kind of error is of the first failure.
+    fn finish(self) -> Result<()> {
+        let (entry, err) = match self.failures.first() {
+            Some(failure) => failure,
+            None => return Ok(()),
+        };
+        Err(ErrorKind::of(err).error(format!(
+            "{} of {} entries failed! First failure: {} error: {}",
+            self.failures.len(),
+            self.failures.len() + self.succeeded,
+            entry,
+            err
+        )))
+    }
+}
+
+/// How to resolve entries which have same source but different dest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    PreferTheirs,
+    PreferOurs,
+    Fail,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct MergeConflict {
+    pub ours: String,
+    pub theirs: String,
+}
+
+/// Result of `merge_mappings`. Keys are sources.
+#[derive(Debug, Default, PartialEq, Eq, Serialize)]
+pub struct MergeResult {
+    pub new: BTreeMap<String, String>,
+    pub identical: BTreeMap<String, String>,
+    pub conflicts: BTreeMap<String, MergeConflict>,
+}
+
+impl MergeResult {
+    pub fn print(&self) {
+        println!(
+            "{} new, {} identical, {} conflicting entries.",
+            self.new.len(),
+            self.identical.len(),
+            self.conflicts.len()
+        );
+        for (src, dst) in &self.new {
+            println!("  new       {} -> {}", src, dst);
+        }
+        for (src, dst) in &self.identical {
+            println!("  identical {} -> {}", src, dst);
+        }
+        for (src, conflict) in &self.conflicts {
+            println!(
+                "  conflict  {} -> ours: {} theirs: {}",
+                src, conflict.ours, conflict.theirs
+            );
+        }
+    }
+}
+
+/// Classifies each entry of `theirs` as new, identical or conflicting against `ours`.
+pub fn merge_mappings(
+    ours: &BTreeMap<String, String>,
+    theirs: &BTreeMap<String, String>,
+) -> MergeResult {
+    let mut result = MergeResult::default();
+    for (src, their_dst) in theirs {
+        match ours.get(src) {
+            None => {
+                result.new.insert(src.to_string(), their_dst.to_string());
+            }
+            Some(our_dst) if our_dst == their_dst => {
+                result
+                    .identical
+                    .insert(src.to_string(), their_dst.to_string());
+            }
+            Some(our_dst) => {
+                result.conflicts.insert(
+                    src.to_string(),
+                    MergeConflict {
+                        ours: our_dst.to_string(),
+                        theirs: their_dst.to_string(),
+                    },
+                );
+            }
+        }
+    }
+    result
+}
+
+/// Normalizes produced path.  
+///
+/// Notes:
+/// - This method don't follow symbolic links.  
+/// - This method treats `foo/bar` as `./foo/bar`.
+pub fn normalize_path<P: AsRef<Path>>(path: P) -> PathBuf {
+    let path = path.as_ref();
+    let mut result = PathBuf::new();
+    if let Some(Component::Normal(_)) = path.components().next() {
+        result.push(env::current_dir().expect("Cannot retrieve current directory"))
+    }
+    for comp in path.components() {
+        match comp {
+            Component::Prefix(_) | Component::RootDir | Component::Normal(_) => {
+                result.push(comp);
+            }
+            Component::CurDir => {
+                result.push(env::current_dir().expect("Cannot retrieve current directory"));
+            }
+            Component::ParentDir => {
+                result.pop();
+            }
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::app::{
+        self, App, ConflictPolicy, DestCase, DestCollision, DoctorFix, ErrorPolicy, ExportFormat,
+        FileMappings, FilterField, Health, IfExists, LinkTarget, MappingError, MappingFilter,
+        MappingsSort, MergeConflict, RestoreMode, Since, StatusOptions, TimestampPosition,
+        WalkOptions, Workspace,
+    };
+    use crate::backup::BackupStore;
+    use crate::config::{Config, SymlinkStyle};
+    use crate::error::{self, ErrorKind};
+    use crate::progress::Progress;
+    use chrono::{TimeZone, Utc};
+    use std::cell::RefCell;
+    use std::collections::BTreeMap;
+    use std::env;
+    use std::ffi::OsStr;
+    use std::fs;
+    use std::io::{self, Write};
+    use std::path::{Path, PathBuf};
+    use std::rc::Rc;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn normalize_current_dir() {
+        let actual = app::normalize_path("./Cargo.toml");
+        let expect = {
+            let mut tmp = PathBuf::new();
+            tmp.push(env::current_dir().unwrap());
+            tmp.push("Cargo.toml");
+            tmp
+        };
+        assert_eq!(actual, expect);
+    }
+
+    #[test]
+    fn normalize_dotdot_with_root() {
+        let actual = app::normalize_path("/foo/../foo");
+        let expect = Path::new("/foo");
+        assert_eq!(actual, expect);
+    }
+
+    #[test]
+    fn normalize_with_no_curdir_and_rootdir() {
+        let actual = app::normalize_path("foo/bar");
+        let expect = app::normalize_path("./foo/bar");
+        assert_eq!(actual, expect);
+    }
+
+    fn new_fm() -> FileMappings {
+        FileMappings::new()
+    }
+
+    #[test]
+    fn contains_not_exists() {
+        let fm = new_fm();
+        assert!(!fm.contains("./Cargo.toml"));
+    }
+
+    #[test]
+    fn contains_exists() {
+        let mut fm = new_fm();
+        fm.add("./Cargo.toml", "DestCargo.toml").unwrap();
+        assert!(fm.contains("./Cargo.toml"));
         assert!(fm.contains({
             let mut tmp = PathBuf::new();
             tmp.push(env::current_dir().unwrap());
@@ -402,15 +5673,3339 @@ mod tests {
     }
 
     #[test]
-    fn remove_fail() {
-        let mut fm = new_fm();
-        assert!(fm.remove("./Cargo.toml").is_err());
+    fn remove_fail() {
+        let mut fm = new_fm();
+        assert!(matches!(
+            fm.remove("./Cargo.toml"),
+            Err(MappingError::NotFound(_))
+        ));
+    }
+
+    #[test]
+    fn remove_success() {
+        let mut fm = new_fm();
+        fm.add("./Cargo.toml", "DestCargo.toml").unwrap();
+        assert!(fm.remove(Path::new("./Cargo.toml")).is_ok());
+    }
+
+    fn map(entries: &[(&str, &str)]) -> BTreeMap<String, String> {
+        entries
+            .iter()
+            .map(|(src, dst)| (src.to_string(), dst.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn merge_new() {
+        let result = app::merge_mappings(&map(&[]), &map(&[("~/.vimrc", "vimrc")]));
+        assert_eq!(result.new, map(&[("~/.vimrc", "vimrc")]));
+        assert!(result.identical.is_empty());
+        assert!(result.conflicts.is_empty());
+    }
+
+    #[test]
+    fn merge_identical() {
+        let entries = map(&[("~/.vimrc", "vimrc")]);
+        let result = app::merge_mappings(&entries, &entries);
+        assert!(result.new.is_empty());
+        assert_eq!(result.identical, entries);
+        assert!(result.conflicts.is_empty());
+    }
+
+    #[test]
+    fn merge_conflict() {
+        let result = app::merge_mappings(
+            &map(&[("~/.vimrc", "vimrc")]),
+            &map(&[("~/.vimrc", "vim/vimrc")]),
+        );
+        assert!(result.new.is_empty());
+        assert!(result.identical.is_empty());
+        assert_eq!(
+            result.conflicts.get("~/.vimrc"),
+            Some(&MergeConflict {
+                ours: "vimrc".to_string(),
+                theirs: "vim/vimrc".to_string(),
+            })
+        );
+    }
+
+    #[test]
+    fn merge_json() {
+        let result = app::merge_mappings(
+            &map(&[("~/.bashrc", "bashrc"), ("~/.vimrc", "vimrc")]),
+            &map(&[("~/.bashrc", "bashrc"), ("~/.vimrc", "vim/vimrc")]),
+        );
+        let json = serde_json::to_value(&result).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "new": {},
+                "identical": { "~/.bashrc": "bashrc" },
+                "conflicts": { "~/.vimrc": { "ours": "vimrc", "theirs": "vim/vimrc" } },
+            })
+        );
+    }
+
+    #[test]
+    fn apply_merge_policies() {
+        let ours = map(&[("~/.vimrc", "vimrc")]);
+        let theirs = map(&[("~/.bashrc", "bashrc"), ("~/.vimrc", "vim/vimrc")]);
+        let result = app::merge_mappings(&ours, &theirs);
+        let mut their_fm = new_fm();
+        their_fm.entries = theirs.clone();
+
+        let mut fm = new_fm();
+        fm.entries = ours.clone();
+        fm.apply_merge(&result, &their_fm, ConflictPolicy::PreferOurs)
+            .unwrap();
+        assert_eq!(
+            fm.as_map(),
+            &map(&[("~/.bashrc", "bashrc"), ("~/.vimrc", "vimrc")])
+        );
+
+        let mut fm = new_fm();
+        fm.entries = ours;
+        fm.apply_merge(&result, &their_fm, ConflictPolicy::PreferTheirs)
+            .unwrap();
+        assert_eq!(fm.as_map(), &theirs);
+    }
+
+    #[test]
+    fn write_path_terminators() {
+        let mut out = Vec::new();
+        app::write_path("dir/new\nline", true, &mut out).unwrap();
+        assert_eq!(out, b"dir/new\nline\0");
+        let mut out = Vec::new();
+        app::write_path("dir/file", false, &mut out).unwrap();
+        assert_eq!(out, b"dir/file\n");
+    }
+
+    #[test]
+    fn print0_with_space() {
+        let mut out = Vec::new();
+        app::write_mappings_print0(&[("~/my file", "dir/my file")], &mut out).unwrap();
+        assert_eq!(out, b"dir/my file\0~/my file\0");
+    }
+
+    #[test]
+    fn load_layered_overlay_wins() {
+        let base = r#"{"~/.vimrc": "vimrc", "~/.zshrc": {"dest": "zshrc", "tags": ["shell"]}}"#;
+        let overlay = r#"{"~/.zshrc": "laptop/zshrc", "~/.gitconfig": "gitconfig"}"#;
+        let file_mappings =
+            FileMappings::load_layered(base.as_bytes(), overlay.as_bytes()).unwrap();
+        let entries = file_mappings.as_map();
+        assert_eq!(entries["~/.vimrc"], "vimrc");
+        assert_eq!(entries["~/.zshrc"], "laptop/zshrc");
+        assert_eq!(entries["~/.gitconfig"], "gitconfig");
+        assert!(!file_mappings.attributes.contains_key("~/.zshrc"));
+
+        let (kept, split) = file_mappings.split(
+            &FileMappings::load_entries(base.as_bytes()).unwrap(),
+            &FileMappings::load_entries(overlay.as_bytes()).unwrap(),
+        );
+        assert_eq!(
+            kept.as_map().keys().collect::<Vec<_>>(),
+            vec!["~/.vimrc", "~/.zshrc"]
+        );
+        assert_eq!(kept.as_map()["~/.zshrc"], "zshrc");
+        assert!(kept.stored_attributes("~/.zshrc").tags == ["shell"]);
+        assert_eq!(
+            split.as_map().keys().collect::<Vec<_>>(),
+            vec!["~/.gitconfig", "~/.zshrc"]
+        );
+
+        let conflicting = r#"{"~/.gitconfig": "vimrc"}"#;
+        assert!(FileMappings::load_layered(base.as_bytes(), conflicting.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn profile_saves_new_entries_into_overlay() {
+        let dir = tempfile::tempdir().unwrap();
+        let (app, _) = linked_fixture(dir.path(), &["vimrc"]);
+        app.finish().unwrap();
+        let mut app = App::with_workspace(dir.path().join("dotfiles")).unwrap();
+        app.set_profile("laptop").unwrap();
+        let source = dir.path().join("zshrc");
+        fs::write(&source, "zshrc").unwrap();
+        app.link(&source, "zshrc", IfExists::Error).unwrap();
+        app.finish().unwrap();
+
+        let base = fs::read_to_string(dir.path().join("dotfiles/.file_mappings.json")).unwrap();
+        let overlay =
+            fs::read_to_string(dir.path().join("dotfiles/.file_mappings.laptop.json")).unwrap();
+        assert!(base.contains("vimrc") && !base.contains("zshrc"));
+        assert!(overlay.contains("zshrc") && !overlay.contains("vimrc"));
+        let app = App::with_workspace(dir.path().join("dotfiles")).unwrap();
+        assert_eq!(app.file_mappings().unwrap().as_map().len(), 1);
+        assert!(App::with_workspace(dir.path().join("dotfiles"))
+            .unwrap()
+            .set_profile("../x")
+            .is_err());
+    }
+
+    #[test]
+    fn profile_keeps_shadowed_base_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let (app, sources) = linked_fixture(dir.path(), &["vimrc", "zshrc"]);
+        app.finish().unwrap();
+        let base_path = dir.path().join("dotfiles/.file_mappings.json");
+        let base = fs::read_to_string(&base_path).unwrap();
+        let load = |profile: Option<&str>| {
+            let mut app = App::with_workspace(dir.path().join("dotfiles")).unwrap();
+            if let Some(profile) = profile {
+                app.set_profile(profile).unwrap();
+            }
+            app
+        };
+        let mut app = load(Some("laptop"));
+        app.file_mappings_mut()
+            .unwrap()
+            .set_tags(&sources[1], vec!["laptop".to_string()]);
+        app.finish().unwrap();
+        assert_eq!(fs::read_to_string(&base_path).unwrap(), base);
+
+        // Saving again keeps the replaced entry of the mappings file
+        let mut app = load(Some("laptop"));
+        app.file_mappings_mut().unwrap();
+        app.finish().unwrap();
+        assert_eq!(fs::read_to_string(&base_path).unwrap(), base);
+        let app = load(Some("laptop"));
+        let zshrc = app.workspace.strip_home(&sources[1]);
+        assert!(app.file_mappings().unwrap().has_tag(&zshrc, Some("laptop")));
+        let app = load(None);
+        assert!(!app.file_mappings().unwrap().has_tag(&zshrc, Some("laptop")));
+
+        // Only unlinking an entry of the mappings file itself removes it
+        let mut app = load(Some("laptop"));
+        app.unlink(&sources[0], false).unwrap();
+        app.finish().unwrap();
+        let app = load(None);
+        assert!(!app.file_mappings().unwrap().contains(&sources[0]));
+        assert!(app.file_mappings().unwrap().contains(&sources[1]));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn failed_link_removes_created_dirs() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut app = App::with_workspace(dir.path().join("dotfiles")).unwrap();
+        fs::create_dir_all(dir.path().join("dotfiles/existing")).unwrap();
+        let source = dir.path().join("vimrc");
+        fs::write(&source, "vimrc").unwrap();
+        // Creating `new` succeeds but the next directory fails
+        let dest = format!("existing/new/{}/vimrc", "a".repeat(300));
+        assert!(app.link(&source, &dest, IfExists::Error).is_err());
+        assert!(dir.path().join("dotfiles/existing").is_dir());
+        assert!(!dir.path().join("dotfiles/existing/new").exists());
+        assert_eq!(fs::read_to_string(&source).unwrap(), "vimrc");
+        assert!(!app.file_mappings().unwrap().contains(&source));
+    }
+
+    #[test]
+    fn failed_link_removes_added_entry() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut app = App::with_workspace(dir.path().join("dotfiles")).unwrap();
+        fs::create_dir_all(dir.path().join("dotfiles/vimrc")).unwrap();
+        fs::write(dir.path().join("dotfiles/vimrc/keep"), "").unwrap();
+        let source = dir.path().join("vimrc");
+        fs::write(&source, "vimrc").unwrap();
+        // The file can't be moved onto the non-empty directory
+        assert!(app
+            .link_path(&source, "vimrc", IfExists::Overwrite, false)
+            .is_err());
+        assert_eq!(fs::read_to_string(&source).unwrap(), "vimrc");
+        assert!(!app.file_mappings().unwrap().contains(&source));
+        assert!(dir.path().join("dotfiles/vimrc/keep").is_file());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn create_link_of_dir_creates_source_parents() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut app = App::with_workspace(dir.path().join("dotfiles")).unwrap();
+        let dest_abs = dir.path().join("dotfiles/app");
+        fs::create_dir_all(&dest_abs).unwrap();
+        fs::write(dest_abs.join("settings.toml"), "").unwrap();
+        let source = dir.path().join("home/.config/app");
+        app.file_mappings_mut()
+            .unwrap()
+            .add(&source, "app")
+            .unwrap();
+        app.file_mappings_mut()
+            .unwrap()
+            .set_directory(&source, true);
+        app.create_link(&dest_abs, &source).unwrap();
+        assert_eq!(fs::read_link(&source).unwrap(), dest_abs);
+        assert!(source.join("settings.toml").is_file());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn create_symlink_replaces_existing() {
+        let dir = tempfile::tempdir().unwrap();
+        let old_target = dir.path().join("old");
+        let new_target = dir.path().join("new");
+        let link = dir.path().join("link");
+        App::create_symlink(&old_target, &link, false).unwrap();
+        App::create_symlink(&new_target, &link, false).unwrap();
+        assert_eq!(fs::read_link(&link).unwrap(), new_target);
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn init_is_idempotent() {
+        let dir = tempfile::tempdir().unwrap();
+        let workspace = dir.path().join("dotfiles");
+        let app = App::with_workspace(workspace.clone()).unwrap();
+        app.init(true).unwrap();
+        assert!(workspace.join(".git").is_dir());
+        assert!(workspace.join(".file_mappings.json").is_file());
+        assert!(workspace.join(".gitignore").is_file());
+        fs::write(workspace.join(".gitignore"), "custom\n").unwrap();
+        app.init(true).unwrap();
+        assert_eq!(
+            fs::read_to_string(workspace.join(".gitignore")).unwrap(),
+            "custom\n"
+        );
+    }
+
+    #[test]
+    fn mirror_dest_in_home() {
+        let dest = app::mirror_dest("/home/user/.vimrc", "/home/user").unwrap();
+        assert_eq!(dest, "vimrc");
+    }
+
+    #[test]
+    fn mirror_dest_nested() {
+        let dest = app::mirror_dest("/home/user/.config/app/config", "/home/user").unwrap();
+        assert_eq!(dest, "config/app/config");
+        let dest = app::mirror_dest("/home/user/bin/script", "/home/user").unwrap();
+        assert_eq!(dest, "bin/script");
+    }
+
+    #[test]
+    fn mirror_dest_outside_home() {
+        assert!(app::mirror_dest("/etc/hosts", "/home/user").is_err());
+        assert!(app::mirror_dest("/home/user", "/home/user").is_err());
+    }
+
+    #[test]
+    fn validate_dest_escape() {
+        assert!(app::validate_dest("vim/vimrc").is_ok());
+        assert!(app::validate_dest("vim/../vimrc").is_ok());
+        assert!(app::validate_dest("../vimrc").is_err());
+        assert!(app::validate_dest("vim/../../vimrc").is_err());
+        assert!(app::validate_dest("vim/..").is_err());
+    }
+
+    #[test]
+    fn git_output_captures_stdout() {
+        let dir = tempfile::tempdir().unwrap();
+        let app = App::with_workspace(dir.path().to_path_buf()).unwrap();
+        let output = app.git_output(&["--version"]).unwrap();
+        assert!(output.status.success());
+        assert!(String::from_utf8_lossy(&output.stdout).starts_with("git version"));
+    }
+
+    #[test]
+    fn link_into_existing_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut app = App::with_workspace(dir.path().join("dotfiles")).unwrap();
+        fs::create_dir_all(dir.path().join("dotfiles/vim")).unwrap();
+        let source = dir.path().join("vimrc");
+        fs::write(&source, "vimrc").unwrap();
+        app.link(&source, "vim", IfExists::Error).unwrap();
+        assert_eq!(
+            app.file_mappings().unwrap().get(&source).unwrap(),
+            "vim/vimrc"
+        );
+        assert_eq!(
+            fs::read_to_string(dir.path().join("dotfiles/vim/vimrc")).unwrap(),
+            "vimrc"
+        );
+        assert!(fs::symlink_metadata(&source)
+            .unwrap()
+            .file_type()
+            .is_symlink());
+    }
+
+    #[test]
+    fn link_refuses_self_link() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut app = App::with_workspace(dir.path().to_path_buf()).unwrap();
+        let source = dir.path().join("vimrc");
+        fs::write(&source, "set number").unwrap();
+        assert!(app.link(&source, "vimrc", IfExists::Error).is_err());
+        assert!(fs::symlink_metadata(&source).unwrap().file_type().is_file());
+        assert!(!app.file_mappings().unwrap().contains(&source));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn link_applies_dir_mode() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut app = App::with_workspace(dir.path().join("dotfiles")).unwrap();
+        fs::create_dir_all(dir.path().join("dotfiles/ssh")).unwrap();
+        fs::set_permissions(
+            dir.path().join("dotfiles/ssh"),
+            fs::Permissions::from_mode(0o755),
+        )
+        .unwrap();
+        app.set_link_dir_mode(Some(0o700));
+        let source = dir.path().join("config");
+        fs::write(&source, "Host *").unwrap();
+        app.link(&source, "ssh/hosts/work/config", IfExists::Error)
+            .unwrap();
+        let mode = |path: &str| {
+            fs::metadata(dir.path().join(path))
+                .unwrap()
+                .permissions()
+                .mode()
+                & 0o777
+        };
+        assert_eq!(mode("dotfiles/ssh"), 0o755);
+        assert_eq!(mode("dotfiles/ssh/hosts"), 0o700);
+        assert_eq!(mode("dotfiles/ssh/hosts/work"), 0o700);
+    }
+
+    #[test]
+    fn dest_root_is_prepended() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut app = App::with_workspace(dir.path().join("dotfiles")).unwrap();
+        app.config.dest_root = Some("home".to_string());
+        let source = dir.path().join("vimrc");
+        fs::write(&source, "set number").unwrap();
+        let dest = app
+            .resolve_dest(&source, Some("vim/vimrc"), false, None, None)
+            .unwrap();
+        assert_eq!(dest, "home/vim/vimrc");
+        let err = app
+            .resolve_dest(&source, Some("../vimrc"), false, None, None)
+            .unwrap_err();
+        assert_eq!(ErrorKind::of(&err), ErrorKind::DestInvalid);
+        app.set_home(dir.path().join("home"));
+        let home_source = dir.path().join("home/.config/git/config");
+        assert_eq!(
+            app.resolve_dest(&home_source, None, true, Some(1), None)
+                .unwrap(),
+            "home/git/config"
+        );
+
+        app.link(&source, &dest, IfExists::Error).unwrap();
+        assert_eq!(
+            app.file_mappings().unwrap().get(&source).unwrap(),
+            "home/vim/vimrc"
+        );
+        assert_eq!(
+            fs::read_to_string(dir.path().join("dotfiles/home/vim/vimrc")).unwrap(),
+            "set number"
+        );
+        assert_eq!(
+            app.entry_health(&app.workspace.strip_home(&source), &dest),
+            Health::Healthy
+        );
+
+        app.config.dest_root = Some("../outside".to_string());
+        let err = app
+            .resolve_dest(&source, Some("vimrc"), false, None, None)
+            .unwrap_err();
+        assert_eq!(ErrorKind::of(&err), ErrorKind::Config);
+    }
+
+    #[test]
+    fn link_and_unlink_take_backups() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut app = App::with_workspace(dir.path().join("dotfiles")).unwrap();
+        app.set_backup_dir(dir.path().join("backups"));
+        let source = dir.path().join("vimrc");
+        fs::write(&source, "set number").unwrap();
+        app.link(&source, "vimrc", IfExists::Error).unwrap();
+        app.unlink(&source, false).unwrap();
+        let store = BackupStore::open(dir.path().join("backups")).unwrap();
+        let operations: Vec<&str> = store
+            .snapshots()
+            .iter()
+            .map(|snapshot| snapshot.operation.as_str())
+            .collect();
+        assert_eq!(operations, vec!["link", "unlink"]);
+    }
+
+    #[test]
+    fn case_insensitive_dedup() {
+        let mut fm = new_fm();
+        fm.set_case_insensitive(true);
+        fm.add("/home/user/.Bashrc", "bashrc").unwrap();
+        assert!(fm.contains("/home/user/.bashrc"));
+        assert_eq!(
+            fm.add("/home/user/.bashrc", "bashrc2"),
+            Err(MappingError::AlreadyExists(
+                "/home/user/.Bashrc".to_string()
+            ))
+        );
+        assert_eq!(fm.as_map(), &map(&[("/home/user/.Bashrc", "bashrc")]));
+        fm.remove("/home/user/.BASHRC").unwrap();
+        assert!(fm.as_map().is_empty());
+    }
+
+    #[test]
+    fn case_sensitive_distinct() {
+        let mut fm = new_fm();
+        fm.add("/home/user/.Bashrc", "bashrc").unwrap();
+        assert!(!fm.contains("/home/user/.bashrc"));
+        assert!(fm.add("/home/user/.bashrc", "bashrc2").is_ok());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn health_states() {
+        let dir = tempfile::tempdir().unwrap();
+        let dest = dir.path().join("dest");
+        let source = dir.path().join("source");
+        assert_eq!(app::check_health(&source, &dest), Health::Missing);
+        std::os::unix::fs::symlink(&dest, &source).unwrap();
+        assert_eq!(app::check_health(&source, &dest), Health::Dangling);
+        fs::write(&dest, "").unwrap();
+        assert_eq!(app::check_health(&source, &dest), Health::Healthy);
+        assert_eq!(
+            app::check_health(&source, &dir.path().join("other")),
+            Health::Repointed
+        );
+        fs::remove_file(&source).unwrap();
+        fs::write(&source, "").unwrap();
+        assert_eq!(app::check_health(&source, &dest), Health::Detached);
+    }
+
+    #[test]
+    fn readopt_detached_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut app = App::with_workspace(dir.path().join("dotfiles")).unwrap();
+        let source = dir.path().join("vimrc");
+        fs::write(&source, "set number").unwrap();
+        app.link(&source, "vimrc", IfExists::Error).unwrap();
+        let err = app.readopt_detached(false).unwrap_err();
+        assert_eq!(ErrorKind::of(&err), ErrorKind::Config);
+        app.set_backup_dir(dir.path().join("backups"));
+        assert_eq!(app.readopt_detached(false).unwrap(), 0);
+
+        fs::remove_file(&source).unwrap();
+        fs::write(&source, "set nonumber").unwrap();
+        assert_eq!(app.readopt_detached(false).unwrap(), 1);
+        let store = BackupStore::open(dir.path().join("backups")).unwrap();
+        assert_eq!(store.snapshots().len(), 1);
+        assert_eq!(store.snapshots()[0].operation, "watch");
+        assert!(fs::symlink_metadata(&source)
+            .unwrap()
+            .file_type()
+            .is_symlink());
+        assert_eq!(
+            fs::read_to_string(dir.path().join("dotfiles").join("vimrc")).unwrap(),
+            "set nonumber"
+        );
+    }
+
+    #[test]
+    fn strip_components_levels() {
+        let dest = "projects/dotfiles/vim/vimrc";
+        assert_eq!(app::strip_components(dest, 0).unwrap(), dest);
+        assert_eq!(
+            app::strip_components(dest, 1).unwrap(),
+            "dotfiles/vim/vimrc"
+        );
+        assert_eq!(app::strip_components(dest, 2).unwrap(), "vim/vimrc");
+        assert_eq!(app::strip_components(dest, 3).unwrap(), "vimrc");
+    }
+
+    #[test]
+    fn strip_components_everything() {
+        assert!(app::strip_components("vim/vimrc", 2).is_err());
+        assert!(app::strip_components("vim/vimrc", 3).is_err());
+    }
+
+    #[test]
+    fn add_duplicate_dest() {
+        let mut fm = new_fm();
+        fm.add("/home/user/.vimrc", "vimrc").unwrap();
+        assert_eq!(
+            fm.add("/home/user/.vimrc.local", "vimrc"),
+            Err(MappingError::DuplicateDest {
+                dest: "vimrc".to_string(),
+                source: "/home/user/.vimrc".to_string(),
+            })
+        );
+    }
+
+    #[test]
+    fn get_entry() {
+        let mut fm = new_fm();
+        assert_eq!(
+            fm.get("/home/user/.vimrc"),
+            Err(MappingError::NotFound("/home/user/.vimrc".to_string()))
+        );
+        fm.add("/home/user/.vimrc", "vimrc").unwrap();
+        assert_eq!(fm.get("/home/user/.vimrc"), Ok("vimrc"));
+    }
+
+    #[test]
+    fn link_if_exists_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut app = App::with_workspace(dir.path().join("dotfiles")).unwrap();
+        fs::create_dir(dir.path().join("dotfiles")).unwrap();
+        fs::write(dir.path().join("dotfiles/vimrc"), "old").unwrap();
+        let source = dir.path().join("vimrc");
+        fs::write(&source, "new").unwrap();
+        let err = app.link(&source, "vimrc", IfExists::Error).unwrap_err();
+        assert_eq!(ErrorKind::of(&err), ErrorKind::DestExists);
+        assert_eq!(fs::read_to_string(&source).unwrap(), "new");
+        assert!(!app.file_mappings().unwrap().contains(&source));
+        assert_eq!(
+            fs::read_to_string(dir.path().join("dotfiles").join("vimrc")).unwrap(),
+            "old"
+        );
+    }
+
+    #[test]
+    fn link_if_exists_skip() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut app = App::with_workspace(dir.path().join("dotfiles")).unwrap();
+        fs::create_dir(dir.path().join("dotfiles")).unwrap();
+        fs::write(dir.path().join("dotfiles/vimrc"), "old").unwrap();
+        let source = dir.path().join("vimrc");
+        fs::write(&source, "new").unwrap();
+        app.link(&source, "vimrc", IfExists::Skip).unwrap();
+        assert!(!fs::symlink_metadata(&source)
+            .unwrap()
+            .file_type()
+            .is_symlink());
+        assert!(!app.file_mappings().unwrap().contains(&source));
+
+        app.file_mappings_mut()
+            .unwrap()
+            .add(&source, "other")
+            .unwrap();
+        app.link(&source, "vimrc2", IfExists::Skip).unwrap();
+        assert_eq!(app.file_mappings().unwrap().get(&source), Ok("other"));
+    }
+
+    #[test]
+    fn link_if_exists_overwrite() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut app = App::with_workspace(dir.path().join("dotfiles")).unwrap();
+        fs::create_dir(dir.path().join("dotfiles")).unwrap();
+        fs::write(dir.path().join("dotfiles/vimrc"), "old").unwrap();
+        let source = dir.path().join("vimrc");
+        fs::write(&source, "new").unwrap();
+        app.file_mappings_mut()
+            .unwrap()
+            .add(&source, "other")
+            .unwrap();
+        app.link(&source, "vimrc", IfExists::Overwrite).unwrap();
+        assert!(fs::symlink_metadata(&source)
+            .unwrap()
+            .file_type()
+            .is_symlink());
+        assert_eq!(app.file_mappings().unwrap().get(&source), Ok("vimrc"));
+        assert_eq!(fs::read_to_string(&source).unwrap(), "new");
+    }
+
+    #[test]
+    fn link_if_exists_overwrite_replaced_dest() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut app = App::with_workspace(dir.path().join("dotfiles")).unwrap();
+        fs::create_dir(dir.path().join("dotfiles")).unwrap();
+        fs::write(dir.path().join("dotfiles/vimrc"), "old").unwrap();
+        let source = dir.path().join("vimrc");
+        fs::write(&source, "new").unwrap();
+        let other = dir.path().join("dotfiles/other");
+        fs::write(&other, "changed").unwrap();
+        app.file_mappings_mut()
+            .unwrap()
+            .add(&source, "other")
+            .unwrap();
+        let err = app.link(&source, "vimrc", IfExists::Overwrite).unwrap_err();
+        assert_eq!(ErrorKind::of(&err), ErrorKind::Conflict);
+        assert_eq!(fs::read_to_string(&other).unwrap(), "changed");
+        assert_eq!(app.file_mappings().unwrap().get(&source), Ok("other"));
+
+        // The same contents as the source
+        fs::write(&other, "new").unwrap();
+        app.link(&source, "vimrc", IfExists::Overwrite).unwrap();
+        assert!(!other.exists());
+        assert_eq!(fs::read_to_string(&source).unwrap(), "new");
+    }
+
+    #[test]
+    fn link_if_exists_overwrite_keeps_entry_on_failure() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut app = App::with_workspace(dir.path().join("dotfiles")).unwrap();
+        fs::create_dir(dir.path().join("dotfiles")).unwrap();
+        fs::write(dir.path().join("dotfiles/vimrc"), "old").unwrap();
+        let source = dir.path().join("vimrc");
+        fs::write(&source, "new").unwrap();
+        app.set_backup_dir(dir.path().join("backups"));
+        fs::write(dir.path().join("dotfiles/other"), "changed").unwrap();
+        app.file_mappings_mut()
+            .unwrap()
+            .add(&source, "other")
+            .unwrap();
+        app.file_mappings_mut()
+            .unwrap()
+            .set_tags(&source, vec!["work".to_string()]);
+        let err = app
+            .link(&source, "../escape", IfExists::Overwrite)
+            .unwrap_err();
+        assert_eq!(ErrorKind::of(&err), ErrorKind::DestInvalid);
+        assert_eq!(app.file_mappings().unwrap().get(&source), Ok("other"));
+
+        // Fails after the entry is taken out
+        fs::create_dir(dir.path().join("dotfiles/dir")).unwrap();
+        fs::write(dir.path().join("dotfiles/dir/keep"), "").unwrap();
+        assert!(app
+            .link_path(&source, "dir", IfExists::Overwrite, false)
+            .is_err());
+        let file_mappings = app.file_mappings().unwrap();
+        assert_eq!(file_mappings.get(&source), Ok("other"));
+        let key = app.workspace.strip_home(&source);
+        assert!(file_mappings.has_tag(&key, Some("work")));
+        assert_eq!(
+            fs::read_to_string(dir.path().join("dotfiles/other")).unwrap(),
+            "changed"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn fix_permissions_by_pattern() {
+        use crate::config::PermissionRule;
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let workspace = dir.path().join("dotfiles");
+        let mut app = App::with_workspace(workspace.clone()).unwrap();
+        app.config.permissions.push(PermissionRule {
+            pattern: "ssh/*".to_string(),
+            mode: "0600".to_string(),
+        });
+        fs::create_dir_all(workspace.join("ssh")).unwrap();
+        for dest in &["ssh/config", "vimrc"] {
+            fs::write(workspace.join(dest), "").unwrap();
+            fs::set_permissions(workspace.join(dest), fs::Permissions::from_mode(0o644)).unwrap();
+            app.file_mappings_mut()
+                .unwrap()
+                .add(dir.path().join(dest), dest)
+                .unwrap();
+        }
+        app.fix_permissions().unwrap();
+        let mode = |dest: &str| {
+            fs::metadata(workspace.join(dest))
+                .unwrap()
+                .permissions()
+                .mode()
+                & 0o7777
+        };
+        assert_eq!(mode("ssh/config"), 0o600);
+        assert_eq!(mode("vimrc"), 0o644);
+    }
+
+    #[test]
+    fn link_missing_source_json_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut app = App::with_workspace(dir.path().join("dotfiles")).unwrap();
+        let source = dir.path().join("missing");
+        let err = app.link(&source, "missing", IfExists::Error).unwrap_err();
+        let json: serde_json::Value = serde_json::from_str(&error::to_json(&err)).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "error": format!("Source file: {} does not exist!", source.to_string_lossy()),
+                "kind": "SourceMissing",
+            })
+        );
+        assert_eq!(ErrorKind::of(&err).exit_code(), 3);
+    }
+
+    #[test]
+    fn validate_dest_absolute() {
+        let err = app::validate_dest("/etc/foo").unwrap_err();
+        assert_eq!(ErrorKind::of(&err), ErrorKind::DestInvalid);
+        assert!(err.to_string().contains("relative to the workspace"));
+    }
+
+    #[test]
+    fn validate_dest_home_prefixed() {
+        let err = app::validate_dest("~/foo").unwrap_err();
+        assert_eq!(ErrorKind::of(&err), ErrorKind::DestInvalid);
+        assert!(app::validate_dest("~").is_err());
+        assert!(app::validate_dest("foo/~bar").is_ok());
+    }
+
+    #[test]
+    fn mappings_loaded_lazily() {
+        let dir = tempfile::tempdir().unwrap();
+        let workspace = dir.path().join("dotfiles");
+        fs::create_dir_all(&workspace).unwrap();
+        fs::write(workspace.join(".file_mappings.json"), "broken").unwrap();
+        let app = App::with_workspace(workspace.clone()).unwrap();
+        drop(app);
+        assert_eq!(
+            fs::read_to_string(workspace.join(".file_mappings.json")).unwrap(),
+            "broken"
+        );
+
+        fs::write(workspace.join(".file_mappings.json"), "{}").unwrap();
+        let app = App::with_workspace(workspace.clone()).unwrap();
+        app.mappings(false, MappingsSort::Source, None).unwrap();
+        fs::remove_file(workspace.join(".file_mappings.json")).unwrap();
+        drop(app);
+        assert!(!workspace.join(".file_mappings.json").exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn unlink_refuses_repointed_symlink() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut app = App::with_workspace(dir.path().join("dotfiles")).unwrap();
+        let source = dir.path().join("vimrc");
+        fs::write(&source, "set number").unwrap();
+        app.link(&source, "vimrc", IfExists::Error).unwrap();
+        let other = dir.path().join("other");
+        fs::write(&other, "other").unwrap();
+        fs::remove_file(&source).unwrap();
+        std::os::unix::fs::symlink(&other, &source).unwrap();
+
+        let err = app.unlink(&source, false).unwrap_err();
+        assert_eq!(ErrorKind::of(&err), ErrorKind::SourceInvalid);
+        assert_eq!(fs::read_to_string(&other).unwrap(), "other");
+        assert!(app.file_mappings().unwrap().contains(&source));
+
+        app.unlink(&source, true).unwrap();
+        assert_eq!(fs::read_to_string(&source).unwrap(), "other");
+        assert!(!other.exists());
+    }
+
+    #[test]
+    fn git_alias_expansion() {
+        let aliases = map(&[("lg", "log --oneline --graph"), ("status", "status -s")]);
+        let args = |args: &[&str]| args.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        assert_eq!(
+            app::expand_git_alias(&aliases, &args(&["lg", "-n", "3"])),
+            args(&["log", "--oneline", "--graph", "-n", "3"])
+        );
+        assert_eq!(
+            app::expand_git_alias(&aliases, &args(&["status"])),
+            args(&["status", "-s"])
+        );
+        assert_eq!(
+            app::expand_git_alias(&aliases, &args(&["log", "lg"])),
+            args(&["log", "lg"])
+        );
+        assert!(app::expand_git_alias(&aliases, &[]).is_empty());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn link_refuses_non_utf8_source() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut app = App::with_workspace(dir.path().join("dotfiles")).unwrap();
+        let source = dir.path().join(OsStr::from_bytes(b"invalid-\xff.conf"));
+        fs::write(&source, "").unwrap();
+        let err = app
+            .link(&source, "invalid.conf", IfExists::Error)
+            .unwrap_err();
+        assert_eq!(ErrorKind::of(&err), ErrorKind::SourceInvalid);
+        assert!(err.to_string().contains("non-UTF-8"));
+        assert!(fs::symlink_metadata(&source).unwrap().file_type().is_file());
+    }
+
+    /// Links `names` in `dir` to `old/<name>` and returns the app and the sources.
+    fn linked_fixture(dir: &Path, names: &[&str]) -> (App, Vec<PathBuf>) {
+        let mut app = App::with_workspace(dir.join("dotfiles")).unwrap();
+        let sources = names
+            .iter()
+            .map(|name| {
+                let source = dir.join(name);
+                fs::write(&source, *name).unwrap();
+                app.link(&source, &format!("old/{}", name), IfExists::Error)
+                    .unwrap();
+                source
+            })
+            .collect();
+        (app, sources)
+    }
+
+    #[test]
+    fn remap_moves_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut app, sources) = linked_fixture(dir.path(), &["a", "b"]);
+        app.remap("^old/", "new/", true).unwrap();
+        assert_eq!(app.file_mappings().unwrap().get(&sources[0]), Ok("old/a"));
+
+        app.remap("^old/", "new/", false).unwrap();
+        for (source, name) in sources.iter().zip(&["a", "b"]) {
+            let dest = format!("new/{}", name);
+            assert_eq!(app.file_mappings().unwrap().get(source), Ok(dest.as_str()));
+            assert_eq!(
+                fs::read_link(source).unwrap(),
+                dir.path().join("dotfiles").join(&dest)
+            );
+            assert_eq!(fs::read_to_string(source).unwrap(), *name);
+        }
+    }
+
+    #[test]
+    fn remap_rolls_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut app, sources) = linked_fixture(dir.path(), &["a", "b"]);
+        // Parent of the second new dest is a file, so the second move fails
+        fs::write(dir.path().join("dotfiles").join("new-b"), "").unwrap();
+        assert!(app.remap("^old/(.*)$", "new-$1/file", false).is_err());
+        for (source, name) in sources.iter().zip(&["a", "b"]) {
+            let dest = format!("old/{}", name);
+            assert_eq!(app.file_mappings().unwrap().get(source), Ok(dest.as_str()));
+            assert_eq!(
+                fs::read_link(source).unwrap(),
+                dir.path().join("dotfiles").join(&dest)
+            );
+        }
+    }
+
+    #[test]
+    fn remap_orders_chained_moves() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut app, sources) = linked_fixture(dir.path(), &["x", "x.bak"]);
+        app.remap("^old/x", "old/x.bak", false).unwrap();
+        let dotfiles = dir.path().join("dotfiles");
+        assert_eq!(fs::read_to_string(dotfiles.join("old/x.bak")).unwrap(), "x");
+        assert_eq!(
+            fs::read_to_string(dotfiles.join("old/x.bak.bak")).unwrap(),
+            "x.bak"
+        );
+        assert!(!dotfiles.join("old/x").exists());
+        assert_eq!(fs::read_to_string(&sources[1]).unwrap(), "x.bak");
+
+        app.undo().unwrap();
+        assert_eq!(fs::read_to_string(dotfiles.join("old/x")).unwrap(), "x");
+        assert_eq!(
+            fs::read_to_string(dotfiles.join("old/x.bak")).unwrap(),
+            "x.bak"
+        );
+        assert!(!dotfiles.join("old/x.bak.bak").exists());
+
+        fs::create_dir(dir.path().join("swap")).unwrap();
+        let (mut app, sources) = linked_fixture(&dir.path().join("swap"), &["ab", "ba"]);
+        let err = app.remap("^old/(.)(.)$", "old/$2$1", false).unwrap_err();
+        assert_eq!(ErrorKind::of(&err), ErrorKind::Conflict);
+        assert_eq!(fs::read_to_string(&sources[0]).unwrap(), "ab");
+    }
+
+    #[test]
+    fn remap_refuses_collision() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut app, _) = linked_fixture(dir.path(), &["a", "b"]);
+        let err = app.remap("^old/.*$", "same", false).unwrap_err();
+        assert_eq!(ErrorKind::of(&err), ErrorKind::DestExists);
+    }
+
+    #[test]
+    fn git_requires_repository() {
+        let dir = tempfile::tempdir().unwrap();
+        let app = App::with_workspace(dir.path().to_path_buf()).unwrap();
+        let err = app.git(&["status".to_string()]).unwrap_err();
+        assert_eq!(ErrorKind::of(&err), ErrorKind::Git);
+        assert!(err.to_string().contains("dotman init"));
+        assert!(app.status(&StatusOptions::default()).is_err());
+        assert!(app.git_captured(&["status".to_string()]).is_err());
+    }
+
+    #[test]
+    fn resolve_dest_from_source_config() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut app = App::with_workspace(dir.path().to_path_buf()).unwrap();
+        app.set_home(dir.path().join("home"));
+        let source = dir.path().join("home/.config/git/config");
+        assert_eq!(
+            app.resolve_dest(&source, Some("git"), false, None, None)
+                .unwrap(),
+            "git"
+        );
+        let err = app
+            .resolve_dest(&source, None, false, None, None)
+            .unwrap_err();
+        assert_eq!(ErrorKind::of(&err), ErrorKind::DestInvalid);
+        app.config.dest_from_source = true;
+        assert_eq!(
+            app.resolve_dest(&source, None, false, None, None).unwrap(),
+            "config/git/config"
+        );
+        assert_eq!(
+            app.resolve_dest(&source, None, false, Some(1), None)
+                .unwrap(),
+            "git/config"
+        );
+    }
+
+    #[test]
+    fn derived_dest_lower_case() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut app = App::with_workspace(dir.path().join("dotfiles")).unwrap();
+        app.set_dest_case(DestCase::Lower);
+        let home = dir.path().join("home");
+        let source = home.join("Library/MyApp/Settings.JSON");
+        fs::create_dir_all(source.parent().unwrap()).unwrap();
+        fs::write(&source, "{}").unwrap();
+        let dest = app
+            .resolve_dest(&source, None, true, None, Some(&home))
+            .unwrap();
+        assert_eq!(dest, "library/myapp/settings.json");
+        assert_eq!(
+            app.resolve_dest(&source, Some("MyApp"), false, None, None)
+                .unwrap(),
+            "MyApp"
+        );
+        app.link(&source, &dest, IfExists::Error).unwrap();
+        assert!(fs::symlink_metadata(&source)
+            .unwrap()
+            .file_type()
+            .is_symlink());
+        assert!(dir
+            .path()
+            .join("dotfiles/library/myapp/settings.json")
+            .is_file());
+    }
+
+    #[test]
+    fn status_fail_on_broken() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut app, sources) = linked_fixture(dir.path(), &["a"]);
+        let options = StatusOptions {
+            fail_on_broken: true,
+            ..StatusOptions::default()
+        };
+        app.status(&options).unwrap();
+
+        app.file_mappings_mut()
+            .unwrap()
+            .add(dir.path().join("missing"), "old/missing")
+            .unwrap();
+        let err = app.status(&options).unwrap_err();
+        assert_eq!(ErrorKind::of(&err), ErrorKind::BrokenLinks);
+        assert!(fs::read_link(&sources[0]).is_ok());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn link_refuses_fifo() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut app = App::with_workspace(dir.path().join("dotfiles")).unwrap();
+        let source = dir.path().join("fifo");
+        let status = std::process::Command::new("mkfifo")
+            .arg(&source)
+            .status()
+            .unwrap();
+        assert!(status.success());
+        let err = app.link(&source, "fifo", IfExists::Error).unwrap_err();
+        assert_eq!(ErrorKind::of(&err), ErrorKind::SourceInvalid);
+        assert!(err.to_string().contains("is a FIFO"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn link_refuses_symlink_and_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut app = App::with_workspace(dir.path().join("dotfiles")).unwrap();
+        let target = dir.path().join("target");
+        fs::write(&target, "").unwrap();
+        let source = dir.path().join("symlink");
+        std::os::unix::fs::symlink(&target, &source).unwrap();
+        let err = app.link(&source, "symlink", IfExists::Error).unwrap_err();
+        assert!(err.to_string().contains("is a symlink"));
+        let err = app.link(dir.path(), "dir", IfExists::Error).unwrap_err();
+        assert!(err.to_string().contains("is a directory"));
+    }
+
+    #[test]
+    fn resolve_since_ref_or_date() {
+        let dir = tempfile::tempdir().unwrap();
+        let app = App::with_workspace(dir.path().join("dotfiles")).unwrap();
+        app.init(false).unwrap();
+        let output = app
+            .git_output(&[
+                "-c",
+                "user.name=dotman",
+                "-c",
+                "user.email=dotman@example.com",
+                "commit",
+                "--allow-empty",
+                "-m",
+                "initial",
+            ])
+            .unwrap();
+        assert!(output.status.success());
+        assert!(matches!(app.resolve_since("HEAD").unwrap(), Since::Ref(_)));
+        assert_eq!(
+            app.resolve_since("2024-01-01").unwrap(),
+            Since::Date("2024-01-01".to_string())
+        );
+        let err = app.resolve_since("no-such-ref").unwrap_err();
+        assert_eq!(ErrorKind::of(&err), ErrorKind::Git);
+    }
+
+    fn sort_fixture() -> Vec<(&'static str, &'static str)> {
+        vec![
+            ("~/.bashrc", "shell/bashrc"),
+            ("~/.vimrc", "vim/vimrc"),
+            ("~/.zshrc", "shell/zshrc"),
+        ]
+    }
+
+    fn sources(entries: &[(&str, &str)]) -> Vec<String> {
+        entries.iter().map(|(src, _)| src.to_string()).collect()
+    }
+
+    #[test]
+    fn sort_mappings_by_source() {
+        let mut entries = sort_fixture();
+        entries.reverse();
+        app::sort_mappings(&mut entries, MappingsSort::Source, |_| None);
+        assert_eq!(sources(&entries), ["~/.bashrc", "~/.vimrc", "~/.zshrc"]);
+    }
+
+    #[test]
+    fn sort_mappings_by_dest() {
+        let mut entries = sort_fixture();
+        app::sort_mappings(&mut entries, MappingsSort::Dest, |_| None);
+        assert_eq!(sources(&entries), ["~/.bashrc", "~/.zshrc", "~/.vimrc"]);
+    }
+
+    #[test]
+    fn sort_mappings_by_date() {
+        let mut entries = sort_fixture();
+        app::sort_mappings(&mut entries, MappingsSort::Date, |src| match src {
+            "~/.vimrc" => Some(UNIX_EPOCH + Duration::from_secs(10)),
+            "~/.zshrc" => Some(UNIX_EPOCH + Duration::from_secs(5)),
+            _ => None,
+        });
+        assert_eq!(sources(&entries), ["~/.zshrc", "~/.vimrc", "~/.bashrc"]);
+    }
+
+    #[test]
+    fn finish_reports_save_failure() {
+        let dir = tempfile::tempdir().unwrap();
+        let workspace = dir.path().join("dotfiles");
+        let mut app = App::with_workspace(workspace.clone()).unwrap();
+        let source = dir.path().join("vimrc");
+        fs::write(&source, "").unwrap();
+        app.link(&source, "vimrc", IfExists::Error).unwrap();
+        fs::create_dir(workspace.join(".file_mappings.json")).unwrap();
+        assert!(app.finish().is_err());
+    }
+
+    #[test]
+    fn undo_link() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut app = App::with_workspace(dir.path().join("dotfiles")).unwrap();
+        let source = dir.path().join("vimrc");
+        fs::write(&source, "set number").unwrap();
+        app.link(&source, "vim/vimrc", IfExists::Error).unwrap();
+        app.undo().unwrap();
+        assert!(fs::symlink_metadata(&source).unwrap().is_file());
+        assert!(!app.file_mappings().unwrap().contains(&source));
+        assert!(app.undo().is_err());
+    }
+
+    #[test]
+    fn undo_unlink() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut app = App::with_workspace(dir.path().join("dotfiles")).unwrap();
+        let source = dir.path().join("vimrc");
+        fs::write(&source, "set number").unwrap();
+        app.link(&source, "vim/vimrc", IfExists::Error).unwrap();
+        app.unlink(&source, false).unwrap();
+        app.undo().unwrap();
+        assert_eq!(
+            app.file_mappings().unwrap().get(&source).unwrap(),
+            "vim/vimrc"
+        );
+        assert_eq!(
+            fs::read_link(&source).unwrap(),
+            dir.path().join("dotfiles/vim/vimrc")
+        );
+    }
+
+    #[test]
+    fn undo_unlink_to() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut app = App::with_workspace(dir.path().join("dotfiles")).unwrap();
+        let source = dir.path().join("vimrc");
+        fs::write(&source, "set number").unwrap();
+        let to = dir.path().join("vimrc.old");
+        app.link(&source, "vim/vimrc", IfExists::Error).unwrap();
+        app.unlink_to(&source, Some(&to), false).unwrap();
+        assert!(fs::symlink_metadata(&source).is_err());
+        app.undo().unwrap();
+        assert!(fs::symlink_metadata(&to).is_err());
+        assert_eq!(
+            fs::read_link(&source).unwrap(),
+            dir.path().join("dotfiles/vim/vimrc")
+        );
+    }
+
+    #[test]
+    fn undo_entries_under_home() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut app = App::with_workspace(dir.path().join("dotfiles")).unwrap();
+        app.set_home(dir.path().to_path_buf());
+        let source = dir.path().join(".vimrc");
+        fs::write(&source, "set number").unwrap();
+
+        app.link(&source, "vimrc", IfExists::Error).unwrap();
+        app.undo().unwrap();
+        assert!(fs::symlink_metadata(&source).unwrap().is_file());
+        assert!(!app.file_mappings().unwrap().contains(&source));
+
+        app.link(&source, "vimrc", IfExists::Error).unwrap();
+        app.unlink(&source, false).unwrap();
+        app.undo().unwrap();
+        assert_eq!(app.file_mappings().unwrap().get(&source), Ok("vimrc"));
+
+        app.remap("^vimrc$", "vim/vimrc", false).unwrap();
+        app.undo().unwrap();
+        assert_eq!(app.file_mappings().unwrap().get(&source), Ok("vimrc"));
+        assert_eq!(
+            fs::read_link(&source).unwrap(),
+            dir.path().join("dotfiles/vimrc")
+        );
+    }
+
+    #[test]
+    fn undo_refuses_changed_state() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut app = App::with_workspace(dir.path().join("dotfiles")).unwrap();
+        let source = dir.path().join("vimrc");
+        fs::write(&source, "set number").unwrap();
+        app.link(&source, "vim/vimrc", IfExists::Error).unwrap();
+        fs::remove_file(&source).unwrap();
+        fs::write(&source, "replaced").unwrap();
+        let err = app.undo().unwrap_err();
+        assert_eq!(ErrorKind::of(&err), ErrorKind::Conflict);
+        assert!(app.oplog().last().unwrap().is_some());
+    }
+
+    #[test]
+    fn link_from_stdin() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut app = App::with_workspace(dir.path().join("dotfiles")).unwrap();
+        let link_at = dir.path().join("config/generated.conf");
+        let mut stdin: &[u8] = b"generated = true\n";
+        app.link_from_reader(&mut stdin, "gen/generated.conf", &link_at, IfExists::Error)
+            .unwrap();
+        let dest_abs = dir.path().join("dotfiles/gen/generated.conf");
+        assert_eq!(fs::read_to_string(&dest_abs).unwrap(), "generated = true\n");
+        assert_eq!(fs::read_link(&link_at).unwrap(), dest_abs);
+        assert_eq!(
+            app.file_mappings().unwrap().get(&link_at).unwrap(),
+            "gen/generated.conf"
+        );
+    }
+
+    #[test]
+    fn link_from_stdin_cleans_up_on_failure() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut app = App::with_workspace(dir.path().join("dotfiles")).unwrap();
+        let link_at = dir.path().join("generated.conf");
+        let mut stdin: &[u8] = b"generated = true\n";
+        assert!(app
+            .link_from_reader(&mut stdin, "../escape", &link_at, IfExists::Error)
+            .is_err());
+        assert!(fs::symlink_metadata(&link_at).is_err());
+    }
+
+    #[test]
+    fn relative_path_between_dirs() {
+        assert_eq!(
+            app::relative_path(
+                Path::new("/home/user"),
+                Path::new("/home/user/dotfiles/vimrc")
+            ),
+            PathBuf::from("dotfiles/vimrc")
+        );
+        assert_eq!(
+            app::relative_path(
+                Path::new("/home/user/.config/nvim"),
+                Path::new("/home/user/dotfiles/init.vim")
+            ),
+            PathBuf::from("../../dotfiles/init.vim")
+        );
+    }
+
+    fn styled_app(dir: &Path, style: SymlinkStyle) -> App {
+        let config = Config {
+            workspace: Some(dir.join("dotfiles").to_string_lossy().to_string()),
+            symlink_style: style,
+            ..Config::default()
+        };
+        App::new(config, None).unwrap()
+    }
+
+    #[test]
+    fn link_with_absolute_style() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut app = styled_app(dir.path(), SymlinkStyle::Absolute);
+        let source = dir.path().join("vimrc");
+        fs::write(&source, "").unwrap();
+        app.link(&source, "vim/vimrc", IfExists::Error).unwrap();
+        assert_eq!(
+            fs::read_link(&source).unwrap(),
+            dir.path().join("dotfiles/vim/vimrc")
+        );
+    }
+
+    #[test]
+    fn link_and_restore_with_relative_style() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut app = styled_app(dir.path(), SymlinkStyle::Relative);
+        let source = dir.path().join("vimrc");
+        fs::write(&source, "").unwrap();
+        app.link(&source, "vim/vimrc", IfExists::Error).unwrap();
+        assert_eq!(
+            fs::read_link(&source).unwrap(),
+            PathBuf::from("dotfiles/vim/vimrc")
+        );
+        assert_eq!(app.health_report().unwrap()[0].2, Health::Healthy);
+        fs::remove_file(&source).unwrap();
+        app.restore(false, None, false, RestoreMode::LinkMissingOnly)
+            .unwrap();
+        assert_eq!(
+            fs::read_link(&source).unwrap(),
+            PathBuf::from("dotfiles/vim/vimrc")
+        );
+        app.unlink(&source, false).unwrap();
+        assert!(fs::symlink_metadata(&source).unwrap().is_file());
+    }
+
+    #[test]
+    fn unlink_rolls_back_on_rename_failure() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut app = App::with_workspace(dir.path().join("dotfiles")).unwrap();
+        let source = dir.path().join("vimrc");
+        fs::write(&source, "set number").unwrap();
+        app.link(&source, "vimrc", IfExists::Error).unwrap();
+        let err = app
+            .unlink_with(&source, None, false, |_, _| {
+                Err(io::Error::other("cross-device link"))
+            })
+            .unwrap_err();
+        assert_eq!(ErrorKind::of(&err), ErrorKind::Io);
+        assert_eq!(
+            fs::read_link(&source).unwrap(),
+            dir.path().join("dotfiles/vimrc")
+        );
+        assert!(app.file_mappings().unwrap().contains(&source));
+    }
+
+    #[test]
+    fn sync_gitignore_is_idempotent() {
+        let names = &[".file_mappings.json", ".dotman_operations.jsonl"];
+        let added = app::sync_gitignore("*.swp", names, true);
+        assert_eq!(
+            added,
+            "*.swp\n.file_mappings.json\n.dotman_operations.jsonl\n"
+        );
+        assert_eq!(app::sync_gitignore(&added, names, true), added);
+        let removed = app::sync_gitignore(&added, names, false);
+        assert_eq!(removed, "*.swp\n");
+        assert_eq!(app::sync_gitignore(&removed, names, false), removed);
+    }
+
+    fn variables() -> BTreeMap<String, String> {
+        let mut variables = BTreeMap::new();
+        variables.insert("name".to_string(), "kuro".to_string());
+        variables.insert("email".to_string(), "kuro@example.com".to_string());
+        variables
+    }
+
+    #[test]
+    fn render_template_variables() {
+        let rendered = app::render_template(
+            "[user]\nname = {{name}}\nemail = {{ email }}\n",
+            &variables(),
+        )
+        .unwrap();
+        assert_eq!(rendered, "[user]\nname = kuro\nemail = kuro@example.com\n");
+        assert!(app::render_template("{{undefined}}", &variables()).is_err());
+        assert!(app::render_template("{{name", &variables()).is_err());
+    }
+
+    #[test]
+    fn save_entries_is_byte_stable() {
+        let mut fm = new_fm();
+        fm.add("/tmp/zshrc", "zshrc").unwrap();
+        fm.add("/tmp/vimrc", "vimrc").unwrap();
+        fm.set_after(
+            "/tmp/vimrc",
+            vec!["/tmp/zshrc".to_string(), "/tmp/bashrc".to_string()],
+        );
+        fm.set_tags(
+            "/tmp/vimrc",
+            vec!["shell".to_string(), "editor".to_string()],
+        );
+        fm.set_hash("/tmp/vimrc", Some("abc".to_string()));
+        let mut first = Vec::new();
+        fm.save_entries(&mut first).unwrap();
+
+        let mut other = new_fm();
+        other.add("/tmp/vimrc", "vimrc").unwrap();
+        other.set_hash("/tmp/vimrc", Some("abc".to_string()));
+        other.set_tags(
+            "/tmp/vimrc",
+            vec!["editor".to_string(), "shell".to_string()],
+        );
+        other.set_after(
+            "/tmp/vimrc",
+            vec!["/tmp/bashrc".to_string(), "/tmp/zshrc".to_string()],
+        );
+        other.add("/tmp/zshrc", "zshrc").unwrap();
+        let mut second = Vec::new();
+        other.save_entries(&mut second).unwrap();
+        assert_eq!(first, second);
+
+        let mut reloaded = Vec::new();
+        FileMappings::load_entries(&first[..])
+            .unwrap()
+            .save_entries(&mut reloaded)
+            .unwrap();
+        assert_eq!(first, reloaded);
+        let text = String::from_utf8(first).unwrap();
+        assert!(text.ends_with("}\n"));
+        assert!(text.find("\"hash\"").unwrap() < text.find("\"tags\"").unwrap());
+        assert!(text.find("/tmp/bashrc").unwrap() < text.find("/tmp/zshrc\"\n").unwrap());
+    }
+
+    #[test]
+    fn rendered_attribute_round_trip() {
+        let mut fm = new_fm();
+        fm.add("/tmp/gitconfig", "gitconfig").unwrap();
+        fm.add("/tmp/vimrc", "vimrc").unwrap();
+        fm.set_rendered("/tmp/gitconfig", true);
+        let mut out = Vec::new();
+        fm.save_entries(&mut out).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "/tmp/gitconfig": {"dest": "gitconfig", "rendered": true},
+                "/tmp/vimrc": "vimrc",
+            })
+        );
+        let loaded = FileMappings::load_entries(&out[..]).unwrap();
+        assert!(loaded.is_rendered("/tmp/gitconfig"));
+        assert!(!loaded.is_rendered("/tmp/vimrc"));
+    }
+
+    #[test]
+    fn link_rendered_and_restore() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            workspace: Some(dir.path().join("dotfiles").to_string_lossy().to_string()),
+            variables: variables(),
+            ..Config::default()
+        };
+        let mut app = App::new(config, None).unwrap();
+        let source = dir.path().join("gitconfig");
+        fs::write(&source, "name = {{name}}\n").unwrap();
+        app.link_rendered(&source, "gitconfig", IfExists::Error)
+            .unwrap();
+        assert!(fs::symlink_metadata(&source).unwrap().is_file());
+        assert_eq!(fs::read_to_string(&source).unwrap(), "name = kuro\n");
+        assert_eq!(
+            fs::read_to_string(dir.path().join("dotfiles/gitconfig")).unwrap(),
+            "name = {{name}}\n"
+        );
+        assert_eq!(app.health_report().unwrap()[0].2, Health::Healthy);
+        assert_eq!(app.readopt_detached(true).unwrap(), 0);
+
+        app.config
+            .variables
+            .insert("name".to_string(), "kuro46".to_string());
+        app.restore(false, None, false, RestoreMode::LinkMissingOnly)
+            .unwrap();
+        assert_eq!(fs::read_to_string(&source).unwrap(), "name = kuro46\n");
+    }
+
+    #[test]
+    fn rendered_entry_under_home() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            workspace: Some(dir.path().join("dotfiles").to_string_lossy().to_string()),
+            variables: variables(),
+            ..Config::default()
+        };
+        let mut app = App::new(config, None).unwrap();
+        app.set_home(dir.path().to_path_buf());
+        let source = dir.path().join("gitconfig");
+        fs::write(&source, "name = {{name}}\n").unwrap();
+        app.link_rendered(&source, "gitconfig", IfExists::Error)
+            .unwrap();
+        let report = app.health_report().unwrap();
+        assert!(report[0].0.starts_with('~'));
+        assert_eq!(report[0].2, Health::Healthy);
+
+        app.config
+            .variables
+            .insert("name".to_string(), "kuro46".to_string());
+        app.restore(false, None, false, RestoreMode::LinkMissingOnly)
+            .unwrap();
+        assert_eq!(fs::read_to_string(&source).unwrap(), "name = kuro46\n");
+    }
+
+    #[test]
+    fn save_push_sets_upstream() {
+        let dir = tempfile::tempdir().unwrap();
+        let remote = dir.path().join("remote.git");
+        let output = std::process::Command::new("git")
+            .args(["init", "--bare"])
+            .arg(&remote)
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        let app = App::with_workspace(dir.path().join("dotfiles")).unwrap();
+        app.init(false).unwrap();
+        for args in &[
+            vec!["config", "user.name", "dotman"],
+            vec!["config", "user.email", "dotman@example.com"],
+            vec!["config", "push.autoSetupRemote", "false"],
+            vec!["remote", "add", "origin", remote.to_str().unwrap()],
+        ] {
+            app.git_checked(args).unwrap();
+        }
+        fs::write(dir.path().join("dotfiles/vimrc"), "set number").unwrap();
+
+        let err = app.save("Add vimrc", true, false).unwrap_err();
+        assert_eq!(ErrorKind::of(&err), ErrorKind::Git);
+        assert!(err.to_string().contains("--set-upstream"));
+        app.save("Add vimrc", true, true).unwrap();
+        let upstream = app
+            .git_checked(&["rev-parse", "--abbrev-ref", "@{upstream}"])
+            .unwrap();
+        assert!(String::from_utf8_lossy(&upstream.stdout).starts_with("origin/"));
+    }
+
+    #[test]
+    fn save_uses_configured_author() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut app = App::with_workspace(dir.path().join("dotfiles")).unwrap();
+        assert!(app.author_env().is_empty());
+        app.config.git_author_name = Some("Dotfiles Bot".to_string());
+        app.config.git_author_email = Some("bot@example.com".to_string());
+        assert_eq!(
+            app.author_env(),
+            vec![
+                ("GIT_AUTHOR_NAME", "Dotfiles Bot".to_string()),
+                ("GIT_COMMITTER_NAME", "Dotfiles Bot".to_string()),
+                ("GIT_AUTHOR_EMAIL", "bot@example.com".to_string()),
+                ("GIT_COMMITTER_EMAIL", "bot@example.com".to_string()),
+            ]
+        );
+        app.init(false).unwrap();
+        fs::write(dir.path().join("dotfiles/vimrc"), "set number").unwrap();
+        app.save("Add vimrc", false, false).unwrap();
+        let log = app
+            .git_checked(&["log", "-1", "--format=%an <%ae> %cn <%ce>"])
+            .unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&log.stdout).trim(),
+            "Dotfiles Bot <bot@example.com> Dotfiles Bot <bot@example.com>"
+        );
+    }
+
+    #[test]
+    fn save_excludes_operation_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut app, _) = linked_fixture(dir.path(), &["vimrc"]);
+        app.config.git_author_name = Some("Dotfiles Bot".to_string());
+        app.config.git_author_email = Some("bot@example.com".to_string());
+        app.init(false).unwrap();
+        app.save_mappings().unwrap();
+        assert!(app.workspace.oplog_path().is_file());
+        app.save("Add vimrc", false, false).unwrap();
+        let files = app.git_checked(&["ls-files"]).unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&files.stdout),
+            ".file_mappings.json\nold/vimrc\n"
+        );
+
+        // The untracked operation log is not a change to save
+        app.save("Nothing", false, false).unwrap();
+        let log = app.git_checked(&["log", "--format=%s"]).unwrap();
+        assert_eq!(String::from_utf8_lossy(&log.stdout), "Add vimrc\n");
+    }
+
+    #[test]
+    fn porcelain_v2_bytes() {
+        let entries = vec![
+            (
+                "~/.vimrc".to_string(),
+                "vim/vimrc".to_string(),
+                Health::Healthy,
+            ),
+            (
+                "~/My Notes/メモ=b%\n".to_string(),
+                "notes/a".to_string(),
+                Health::Dangling,
+            ),
+        ];
+        assert_eq!(
+            app::porcelain_v2(&entries),
+            "# dotman status porcelain=v2\n\
+             entry health=healthy source=~/.vimrc dest=vim/vimrc\n\
+             entry health=dangling source=~/My%20Notes/メモ%3Db%25%0A dest=notes/a\n\
+             summary total=2 broken=1\n"
+        );
+    }
+
+    #[test]
+    fn commit_message_with_timestamp() {
+        let now = Utc.with_ymd_and_hms(2024, 6, 1, 12, 0, 0).unwrap();
+        assert_eq!(
+            app::commit_message(None, Some(TimestampPosition::Append), now),
+            "dotman: update 2024-06-01T12:00:00Z"
+        );
+        assert_eq!(
+            app::commit_message(Some("Add vimrc"), Some(TimestampPosition::Prepend), now),
+            "2024-06-01T12:00:00Z Add vimrc"
+        );
+        assert_eq!(
+            app::commit_message(Some("Add vimrc"), Some(TimestampPosition::Append), now),
+            "Add vimrc 2024-06-01T12:00:00Z"
+        );
+        assert_eq!(app::commit_message(None, None, now), "Update dotfiles");
+    }
+
+    fn tree_fixture(dir: &Path) -> PathBuf {
+        let source = dir.join("nvim");
+        fs::create_dir_all(source.join("lua/plugins")).unwrap();
+        fs::write(source.join("init.vim"), "").unwrap();
+        fs::write(source.join("lua/options.lua"), "").unwrap();
+        fs::write(source.join("lua/plugins/lsp.lua"), "").unwrap();
+        source
+    }
+
+    fn sorted_entries(app: &App) -> Vec<(String, String)> {
+        app.file_mappings()
+            .unwrap()
+            .as_map()
+            .iter()
+            .map(|(src, dest)| (src.to_string(), dest.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn link_recursive_depth_1() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = tree_fixture(dir.path());
+        let mut app = App::with_workspace(dir.path().join("dotfiles")).unwrap();
+        app.link_recursive(&source, "nvim", Some(1), IfExists::Error)
+            .unwrap();
+        let src = |name: &str| source.join(name).to_string_lossy().to_string();
+        assert_eq!(
+            sorted_entries(&app),
+            vec![
+                (src("init.vim"), "nvim/init.vim".to_string()),
+                (src("lua"), "nvim/lua".to_string()),
+            ]
+        );
+        assert!(fs::symlink_metadata(source.join("lua"))
+            .unwrap()
+            .file_type()
+            .is_symlink());
+        assert!(source.join("lua/plugins/lsp.lua").is_file());
+        let file_mappings = app.file_mappings().unwrap();
+        assert!(file_mappings.is_directory(source.join("lua")));
+        assert!(!file_mappings.is_directory(source.join("init.vim")));
+    }
+
+    #[test]
+    fn link_recursive_depth_2() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = tree_fixture(dir.path());
+        let mut app = App::with_workspace(dir.path().join("dotfiles")).unwrap();
+        app.link_recursive(&source, "nvim", Some(2), IfExists::Error)
+            .unwrap();
+        let src = |name: &str| source.join(name).to_string_lossy().to_string();
+        assert_eq!(
+            sorted_entries(&app),
+            vec![
+                (src("init.vim"), "nvim/init.vim".to_string()),
+                (src("lua/options.lua"), "nvim/lua/options.lua".to_string()),
+                (src("lua/plugins"), "nvim/lua/plugins".to_string()),
+            ]
+        );
+        assert!(fs::symlink_metadata(source.join("lua")).unwrap().is_dir());
+        assert_eq!(
+            app.health_report()
+                .unwrap()
+                .iter()
+                .filter(|(_, _, health)| *health == Health::Healthy)
+                .count(),
+            3
+        );
+    }
+
+    #[test]
+    fn link_targets_show_repointed() {
+        let dir = tempfile::tempdir().unwrap();
+        let (app, sources) = linked_fixture(dir.path(), &["vimrc", "zshrc"]);
+        let elsewhere = dir.path().join("elsewhere");
+        fs::write(&elsewhere, "").unwrap();
+        App::create_symlink(&elsewhere, &sources[1], false).unwrap();
+        let targets = app.link_targets().unwrap();
+        assert!(!targets[0].differs());
+        assert_eq!(targets[0].actual.as_ref(), Some(&targets[0].expected));
+        assert!(targets[1].differs());
+        assert_eq!(targets[1].actual, Some(elsewhere));
+        assert_eq!(targets[1].expected, dir.path().join("dotfiles/old/zshrc"));
+    }
+
+    fn link_and_unlink_named(name: &str, dest: &str) {
+        let dir = tempfile::tempdir().unwrap();
+        let mut app = App::with_workspace(dir.path().join("dot files")).unwrap();
+        let source = dir.path().join(name);
+        fs::write(&source, name).unwrap();
+        app.link(&source, dest, IfExists::Error).unwrap();
+        let key = source.to_string_lossy().to_string();
+        assert_eq!(app.file_mappings().unwrap().as_map()[&key], dest);
+        let dest_abs = dir.path().join("dot files").join(dest);
+        assert_eq!(fs::read_link(&source).unwrap(), dest_abs);
+        assert_eq!(fs::read_to_string(&dest_abs).unwrap(), name);
+        app.unlink(&source, false).unwrap();
+        assert_eq!(fs::read_to_string(&source).unwrap(), name);
+        assert!(!app.file_mappings().unwrap().contains(&source));
+    }
+
+    #[test]
+    fn link_path_with_spaces() {
+        link_and_unlink_named("my config file.conf", "app/my config file.conf");
+    }
+
+    #[test]
+    fn link_path_with_unicode() {
+        link_and_unlink_named("設定 ファイル.conf", "アプリ/café.conf");
+    }
+
+    #[test]
+    fn workspace_relative_format() {
+        assert_eq!(app::display_src("~/.vimrc", true), ".vimrc");
+        assert_eq!(app::display_src("~/.vimrc", false), "~/.vimrc");
+        assert_eq!(app::display_src("/etc/hosts", true), "/etc/hosts");
+        let target = LinkTarget {
+            source: "~/.vimrc".to_string(),
+            health: Health::Repointed,
+            expected: PathBuf::from("/home/user/dotfiles/vim/vimrc"),
+            actual: Some(PathBuf::from("/home/user/dotfiles/vimrc")),
+        };
+        let workspace = Workspace::new(PathBuf::from("/home/user/dotfiles"));
+        assert_eq!(
+            target.format(&workspace, true),
+            "repointed .vimrc\n    expected: vim/vimrc\n    actual:   vimrc <- differs"
+        );
+        assert_eq!(
+            target.format(&workspace, false),
+            "repointed ~/.vimrc\n    expected: /home/user/dotfiles/vim/vimrc\n    actual:   /home/user/dotfiles/vimrc <- differs"
+        );
+    }
+
+    #[cfg(unix)]
+    fn fake_output(code: i32, stderr: &str) -> std::process::Output {
+        use std::os::unix::process::ExitStatusExt;
+
+        std::process::Output {
+            status: std::process::ExitStatus::from_raw(code << 8),
+            stdout: Vec::new(),
+            stderr: stderr.as_bytes().to_vec(),
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn retry_network_until_success() {
+        let mut outputs = vec![
+            fake_output(0, ""),
+            fake_output(
+                128,
+                "fatal: unable to access: Could not resolve host: example.com",
+            ),
+        ];
+        let mut calls = 0;
+        let output = app::retry_network(3, Duration::from_millis(1), || {
+            calls += 1;
+            Ok(outputs.pop().unwrap())
+        })
+        .unwrap();
+        assert!(output.status.success());
+        assert_eq!(calls, 2);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn retry_network_skips_other_failures() {
+        let mut calls = 0;
+        let output = app::retry_network(3, Duration::from_millis(1), || {
+            calls += 1;
+            Ok(fake_output(
+                1,
+                "CONFLICT (content): Merge conflict in vimrc",
+            ))
+        })
+        .unwrap();
+        assert!(!output.status.success());
+        assert_eq!(calls, 1);
+        let mut calls = 0;
+        app::retry_network(2, Duration::from_millis(1), || {
+            calls += 1;
+            Ok(fake_output(128, "fatal: Connection refused"))
+        })
+        .unwrap();
+        assert_eq!(calls, 3);
+    }
+
+    #[test]
+    fn retry_delay_is_capped() {
+        let base = Duration::from_secs(1);
+        assert_eq!(app::retry_delay(base, 0), base);
+        assert_eq!(app::retry_delay(base, 3), Duration::from_secs(8));
+        assert_eq!(app::retry_delay(base, 6), Duration::from_secs(60));
+        assert_eq!(app::retry_delay(base, 40), Duration::from_secs(60));
+        assert_eq!(app::retry_delay(Duration::MAX, 1), Duration::from_secs(60));
+    }
+
+    #[test]
+    fn set_dest_extension_cases() {
+        assert_eq!(
+            app::set_dest_extension("bashrc", "sh").unwrap(),
+            "bashrc.sh"
+        );
+        assert_eq!(
+            app::set_dest_extension("shell/.bashrc", ".sh").unwrap(),
+            "shell/.bashrc.sh"
+        );
+        assert_eq!(
+            app::set_dest_extension("conf/app.conf", "toml").unwrap(),
+            "conf/app.toml"
+        );
+        assert!(app::set_dest_extension("bashrc", "").is_err());
+        assert!(app::set_dest_extension("bashrc", "a/b").is_err());
+    }
+
+    #[test]
+    fn link_with_dest_extension() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut app = App::with_workspace(dir.path().join("dotfiles")).unwrap();
+        let source = dir.path().join(".bashrc");
+        fs::write(&source, "alias ll='ls -l'").unwrap();
+        let dest = app::set_dest_extension("bashrc", "sh").unwrap();
+        app.link(&source, &dest, IfExists::Error).unwrap();
+        assert_eq!(
+            fs::read_link(&source).unwrap(),
+            dir.path().join("dotfiles/bashrc.sh")
+        );
+        app.unlink(&source, false).unwrap();
+        assert_eq!(fs::read_to_string(&source).unwrap(), "alias ll='ls -l'");
+        assert!(!dir.path().join("dotfiles/bashrc.sh").exists());
+    }
+
+    #[test]
+    fn gc_removes_only_orphans() {
+        let dir = tempfile::tempdir().unwrap();
+        let workspace = dir.path().join("dotfiles");
+        let (app, _) = linked_fixture(dir.path(), &["vimrc"]);
+        app.init(true).unwrap();
+        app.oplog()
+            .append(&crate::oplog::Operation::Link {
+                source: "~/.vimrc".to_string(),
+                dest: "old/vimrc".to_string(),
+            })
+            .unwrap();
+        fs::create_dir_all(workspace.join("stale/deep")).unwrap();
+        fs::write(workspace.join("stale/deep/zshrc"), "").unwrap();
+        fs::write(workspace.join("old/vimrc.orig"), "").unwrap();
+
+        app.gc(true, false).unwrap();
+        assert!(workspace.join("stale/deep/zshrc").exists());
+        assert_eq!(
+            app.orphaned_files().unwrap(),
+            vec![
+                PathBuf::from("old/vimrc.orig"),
+                PathBuf::from("stale/deep/zshrc")
+            ]
+        );
+        app.gc(false, true).unwrap();
+        assert!(!workspace.join("stale").exists());
+        assert!(!workspace.join("old/vimrc.orig").exists());
+        assert!(workspace.join("old/vimrc").is_file());
+        assert!(workspace.join(".git").is_dir());
+        assert!(workspace.join(".gitignore").is_file());
+        assert!(workspace.join(".file_mappings.json").is_file());
+        assert!(workspace.join(".dotman_operations.jsonl").is_file());
+    }
+
+    #[test]
+    fn read_only_commands_keep_missing_workspace() {
+        let dir = tempfile::tempdir().unwrap();
+        let workspace = dir.path().join("dotfiles");
+        let app = App::with_workspace(workspace.clone()).unwrap();
+        let options = StatusOptions {
+            count_only: true,
+            ..StatusOptions::default()
+        };
+        let err = app.status(&options).unwrap_err();
+        assert_eq!(ErrorKind::of(&err), ErrorKind::Config);
+        assert!(app.status(&StatusOptions::default()).is_err());
+        assert!(app.mappings(false, MappingsSort::Source, None).is_err());
+        drop(app);
+        assert!(!workspace.exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn link_refuses_read_only_source_dir() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut app = App::with_workspace(dir.path().join("dotfiles")).unwrap();
+        let read_only = dir.path().join("read-only");
+        fs::create_dir(&read_only).unwrap();
+        let source = read_only.join("vimrc");
+        fs::write(&source, "set number").unwrap();
+        fs::set_permissions(&read_only, fs::Permissions::from_mode(0o555)).unwrap();
+        let result = app.link(&source, "vimrc", IfExists::Error);
+        fs::set_permissions(&read_only, fs::Permissions::from_mode(0o755)).unwrap();
+        let err = result.unwrap_err();
+        assert_eq!(ErrorKind::of(&err), ErrorKind::SourceInvalid);
+        assert!(err.to_string().contains("is not writable"));
+        assert!(fs::symlink_metadata(&source).unwrap().is_file());
+        assert!(!dir.path().join("dotfiles").exists());
+    }
+
+    #[test]
+    fn link_batch_with_prefix() {
+        let dir = tempfile::tempdir().unwrap();
+        let config_dir = dir.path().join(".config");
+        fs::create_dir_all(config_dir.join("nvim")).unwrap();
+        fs::create_dir_all(config_dir.join("git")).unwrap();
+        fs::write(config_dir.join("nvim/init.vim"), "set number").unwrap();
+        fs::write(config_dir.join("git/config"), "[user]").unwrap();
+        let mut app = App::with_workspace(dir.path().join("dotfiles")).unwrap();
+        let sources = app::expand_glob(&format!("{}/**/*", config_dir.to_string_lossy())).unwrap();
+        assert_eq!(
+            sources,
+            vec![
+                config_dir.join("git/config"),
+                config_dir.join("nvim/init.vim")
+            ]
+        );
+        app.link_batch(
+            &sources,
+            Some(&config_dir),
+            None,
+            IfExists::Error,
+            DestCollision::Error,
+        )
+        .unwrap();
+        assert_eq!(
+            app.file_mappings()
+                .unwrap()
+                .get(config_dir.join("nvim/init.vim"))
+                .unwrap(),
+            "nvim/init.vim"
+        );
+        assert_eq!(
+            app.file_mappings()
+                .unwrap()
+                .get(config_dir.join("git/config"))
+                .unwrap(),
+            "git/config"
+        );
+        // Linked files are symlinks now, so they are no longer matched
+        assert!(app::expand_glob(&format!("{}/**/*", config_dir.to_string_lossy())).is_err());
+    }
+
+    #[test]
+    fn link_batch_refuses_source_outside_prefix() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join(".config")).unwrap();
+        fs::write(dir.path().join(".config/a.conf"), "a").unwrap();
+        fs::write(dir.path().join("b.conf"), "b").unwrap();
+        let mut app = App::with_workspace(dir.path().join("dotfiles")).unwrap();
+        let sources =
+            app::expand_glob(&format!("{}/**/*.conf", dir.path().to_string_lossy())).unwrap();
+        let err = app
+            .link_batch(
+                &sources,
+                Some(&dir.path().join(".config")),
+                None,
+                IfExists::Error,
+                DestCollision::Error,
+            )
+            .unwrap_err();
+        assert_eq!(ErrorKind::of(&err), ErrorKind::SourceInvalid);
+        // Nothing is linked when any source is outside the prefix
+        assert!(fs::symlink_metadata(dir.path().join(".config/a.conf"))
+            .unwrap()
+            .is_file());
+        assert!(!dir.path().join("dotfiles").exists());
+    }
+
+    #[test]
+    fn link_appends_audit_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("vimrc");
+        fs::write(&source, "set number").unwrap();
+        let audit_log = dir.path().join("audit.jsonl");
+        let mut app = App::with_workspace(dir.path().join("dotfiles")).unwrap();
+        app.config.audit_log = Some(audit_log.to_string_lossy().to_string());
+        app.link(&source, "vimrc", IfExists::Error).unwrap();
+        assert!(app.link(&source, "vimrc", IfExists::Error).is_err());
+        let lines: Vec<serde_json::Value> = fs::read_to_string(&audit_log)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["operation"], "link");
+        assert_eq!(lines[0]["source"], source.to_string_lossy().as_ref());
+        assert_eq!(lines[0]["dest"], "vimrc");
+        assert_eq!(lines[0]["outcome"], "success");
+        assert!(lines[0]["timestamp"].is_string());
+        assert_eq!(lines[1]["outcome"], "failure");
+        assert!(lines[1]["error"].is_string());
+    }
+
+    #[test]
+    fn restore_force_replaces_existing_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let (app, sources) = linked_fixture(dir.path(), &["vimrc"]);
+        let source = &sources[0];
+        fs::remove_file(source).unwrap();
+        fs::write(source, "local").unwrap();
+
+        app.restore(false, None, false, RestoreMode::LinkMissingOnly)
+            .unwrap();
+        assert!(fs::symlink_metadata(source).unwrap().is_file());
+
+        app.restore(true, None, false, RestoreMode::LinkMissingOnly)
+            .unwrap();
+        assert_eq!(app.health_report().unwrap()[0].2, Health::Healthy);
+        assert_eq!(
+            fs::read_to_string(dir.path().join("vimrc.dotman-backup")).unwrap(),
+            "local"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn restore_link_missing_only() {
+        let dir = tempfile::tempdir().unwrap();
+        let (app, sources) = linked_fixture(dir.path(), &["a", "b", "c"]);
+        // `a` is missing, `b` is detached and `c` points to another file
+        fs::remove_file(&sources[0]).unwrap();
+        fs::remove_file(&sources[1]).unwrap();
+        fs::write(&sources[1], "local").unwrap();
+        fs::remove_file(&sources[2]).unwrap();
+        std::os::unix::fs::symlink(&sources[1], &sources[2]).unwrap();
+        app.restore(false, None, false, RestoreMode::LinkMissingOnly)
+            .unwrap();
+        let health: Vec<Health> = app
+            .health_report()
+            .unwrap()
+            .into_iter()
+            .map(|(_, _, health)| health)
+            .collect();
+        assert_eq!(
+            health,
+            vec![Health::Healthy, Health::Detached, Health::Repointed]
+        );
+        assert_eq!(fs::read_to_string(&sources[1]).unwrap(), "local");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn restore_reconcile() {
+        let dir = tempfile::tempdir().unwrap();
+        let (app, sources) = linked_fixture(dir.path(), &["a", "b", "c"]);
+        // `a` is missing, `b` is detached and `c` points to another file
+        fs::remove_file(&sources[0]).unwrap();
+        fs::remove_file(&sources[1]).unwrap();
+        fs::write(&sources[1], "local").unwrap();
+        fs::remove_file(&sources[2]).unwrap();
+        std::os::unix::fs::symlink(&sources[1], &sources[2]).unwrap();
+        app.restore(false, None, false, RestoreMode::Reconcile)
+            .unwrap();
+        assert!(app
+            .health_report()
+            .unwrap()
+            .iter()
+            .all(|(_, _, health)| *health == Health::Healthy));
+        assert_eq!(fs::read_to_string(&sources[2]).unwrap(), "c");
+        assert_eq!(
+            fs::read_to_string(dir.path().join("b.dotman-backup")).unwrap(),
+            "local"
+        );
+    }
+
+    #[test]
+    fn restore_reconcile_keeps_detached_hardlink() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("vimrc");
+        fs::write(&source, "set number").unwrap();
+        let mut app = App::with_workspace(dir.path().join("dotfiles")).unwrap();
+        app.set_link_hardlink(true);
+        app.link(&source, "vimrc", IfExists::Error).unwrap();
+        fs::remove_file(&source).unwrap();
+        fs::write(&source, "local").unwrap();
+
+        app.restore(true, None, false, RestoreMode::Reconcile)
+            .unwrap();
+        assert_eq!(app.health_report().unwrap()[0].2, Health::Detached);
+        assert_eq!(fs::read_to_string(&source).unwrap(), "local");
+        assert!(!dir.path().join("vimrc.dotman-backup").exists());
+    }
+
+    #[test]
+    fn verify_entry_under_home() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("vimrc");
+        fs::write(&source, "set number").unwrap();
+        let mut app = App::with_workspace(dir.path().join("dotfiles")).unwrap();
+        app.set_home(dir.path().to_path_buf());
+        app.config.content_hashes = true;
+        app.link(&source, "vimrc", IfExists::Error).unwrap();
+        app.verify(false, false).unwrap();
+        fs::write(&source, "set nonumber").unwrap();
+        let err = app.verify(false, false).unwrap_err();
+        assert_eq!(ErrorKind::of(&err), ErrorKind::Conflict);
+    }
+
+    #[test]
+    fn verify_fix_updates_hash() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("vimrc");
+        fs::write(&source, "set number").unwrap();
+        let mut app = App::with_workspace(dir.path().join("dotfiles")).unwrap();
+        app.config.content_hashes = true;
+        app.link(&source, "vimrc", IfExists::Error).unwrap();
+        let recorded = app
+            .file_mappings()
+            .unwrap()
+            .hash(&app.workspace.strip_home(&source))
+            .unwrap()
+            .to_string();
+        app.verify(false, false).unwrap();
+
+        fs::write(&source, "set nonumber").unwrap();
+        let err = app.verify(false, false).unwrap_err();
+        assert_eq!(ErrorKind::of(&err), ErrorKind::Conflict);
+        app.verify(true, true).unwrap();
+        let updated = app
+            .file_mappings()
+            .unwrap()
+            .hash(&app.workspace.strip_home(&source))
+            .unwrap()
+            .to_string();
+        assert_ne!(updated, recorded);
+        assert_eq!(
+            updated,
+            app::hash_file(&dir.path().join("dotfiles/vimrc")).unwrap()
+        );
+        app.verify(false, false).unwrap();
+    }
+
+    #[test]
+    fn export_and_import_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut app, sources) = linked_fixture(dir.path(), &["a", "b"]);
+        let file_mappings = app.file_mappings_mut().unwrap();
+        file_mappings.set_tags(&sources[0], vec!["work".to_string()]);
+        file_mappings.set_hardlink(&sources[0], true);
+        file_mappings.set_after(&sources[1], vec![sources[0].to_string_lossy().to_string()]);
+        file_mappings.set_hash(&sources[1], Some("hash".to_string()));
+        let mut exported = Vec::new();
+        app.export(&mut exported, ExportFormat::Json).unwrap();
+
+        let mut other = App::with_workspace(dir.path().join("other")).unwrap();
+        other
+            .import_from_reader(exported.as_slice(), "stdin", true, None, false)
+            .unwrap();
+        assert_eq!(
+            other.file_mappings().unwrap().as_map(),
+            app.file_mappings().unwrap().as_map()
+        );
+        assert_eq!(
+            other.file_mappings().unwrap().attributes,
+            app.file_mappings().unwrap().attributes
+        );
+        assert_eq!(other.file_mappings().unwrap().attributes.len(), 2);
+        assert_eq!(other.file_mappings().unwrap().get(&sources[1]), Ok("old/b"));
+    }
+
+    #[test]
+    fn import_refuses_shared_dests() {
+        fn import(
+            app: &mut App,
+            entries: &str,
+            policy: Option<ConflictPolicy>,
+        ) -> anyhow::Result<()> {
+            app.import_from_reader(entries.as_bytes(), "stdin", true, policy, false)
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let mut app = App::with_workspace(dir.path().join("dotfiles")).unwrap();
+        import(&mut app, r#"{"/home/user/.vimrc": "vimrc"}"#, None).unwrap();
+        let err = import(&mut app, r#"{"/home/user/.zshrc": "vimrc"}"#, None).unwrap_err();
+        assert_eq!(ErrorKind::of(&err), ErrorKind::Conflict);
+
+        let swapped = r#"{"/home/user/.vimrc": "zshrc", "/home/user/.zshrc": "vimrc"}"#;
+        let err = import(&mut app, swapped, Some(ConflictPolicy::PreferOurs)).unwrap_err();
+        assert_eq!(ErrorKind::of(&err), ErrorKind::Conflict);
+        assert_eq!(
+            app.file_mappings().unwrap().as_map(),
+            &map(&[("/home/user/.vimrc", "vimrc")])
+        );
+
+        // The dest is moved away by the preferred entry
+        import(&mut app, swapped, Some(ConflictPolicy::PreferTheirs)).unwrap();
+        assert_eq!(
+            app.file_mappings().unwrap().as_map(),
+            &map(&[
+                ("/home/user/.vimrc", "zshrc"),
+                ("/home/user/.zshrc", "vimrc")
+            ])
+        );
     }
 
     #[test]
-    fn remove_success() {
-        let mut fm = new_fm();
-        fm.add("./Cargo.toml", "DestCargo.toml").unwrap();
-        assert!(fm.remove(&Path::new("./Cargo.toml")).is_ok());
+    fn link_and_unlink_without_git() {
+        let dir = tempfile::tempdir().unwrap();
+        let workspace = dir.path().join("dotfiles");
+        let mut app = App::with_workspace(workspace.clone()).unwrap();
+        app.set_no_git(true);
+        app.init(false).unwrap();
+        assert!(!workspace.join(".git").exists());
+
+        let source = dir.path().join("vimrc");
+        fs::write(&source, "set number").unwrap();
+        app.link(&source, "vimrc", IfExists::Error).unwrap();
+        app.status(&StatusOptions::default()).unwrap();
+        fs::remove_file(&source).unwrap();
+        app.restore(false, None, false, RestoreMode::LinkMissingOnly)
+            .unwrap();
+        assert_eq!(app.health_report().unwrap()[0].2, Health::Healthy);
+        app.unlink(&source, false).unwrap();
+        assert_eq!(fs::read_to_string(&source).unwrap(), "set number");
+        assert!(!workspace.join(".git").exists());
+
+        let err = app.git(&["status".to_string()]).unwrap_err();
+        assert_eq!(ErrorKind::of(&err), ErrorKind::Git);
+    }
+
+    #[test]
+    fn workspace_dest_abs() {
+        let workspace = Workspace::new(PathBuf::from("/home/user/dotfiles"));
+        assert_eq!(
+            workspace.dest_abs("vim/vimrc"),
+            PathBuf::from("/home/user/dotfiles/vim/vimrc")
+        );
+        assert_eq!(
+            workspace.checked_dest_abs("vim/vimrc").unwrap(),
+            PathBuf::from("/home/user/dotfiles/vim/vimrc")
+        );
+        let err = workspace.checked_dest_abs("../vimrc").unwrap_err();
+        assert_eq!(ErrorKind::of(&err), ErrorKind::DestInvalid);
+        assert!(workspace.checked_dest_abs("/etc/hosts").is_err());
+    }
+
+    #[test]
+    fn workspace_is_inside() {
+        let workspace = Workspace::new(PathBuf::from("/home/user/dotfiles"));
+        assert!(workspace.is_inside("/home/user/dotfiles/vimrc"));
+        assert!(workspace.is_inside("/home/user/dotfiles/vim/../vimrc"));
+        assert!(!workspace.is_inside("/home/user/dotfiles"));
+        assert!(!workspace.is_inside("/home/user/dotfiles/../.vimrc"));
+        assert!(!workspace.is_inside("/home/user/dotfiles-old/vimrc"));
+        assert_eq!(
+            workspace.relative("/home/user/dotfiles/vim/vimrc"),
+            Some(PathBuf::from("vim/vimrc"))
+        );
+        assert_eq!(workspace.relative("/etc/hosts"), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn workspace_relative_through_symlinked_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let real = dir.path().join("real");
+        let linked = dir.path().join("linked");
+        fs::create_dir_all(real.join("vim")).unwrap();
+        std::os::unix::fs::symlink(&real, &linked).unwrap();
+
+        let workspace = Workspace::new(linked.clone());
+        assert_eq!(
+            workspace.relative(real.join("vim/vimrc")),
+            Some(PathBuf::from("vim/vimrc"))
+        );
+        assert!(workspace.is_inside(real.join("vimrc")));
+        assert!(!workspace.is_inside(&real));
+        assert!(!workspace.is_inside(dir.path().join("vimrc")));
+
+        let workspace = Workspace::new(real);
+        assert_eq!(
+            workspace.relative(linked.join("vim/vimrc")),
+            Some(PathBuf::from("vim/vimrc"))
+        );
+        assert!(!workspace.is_inside(linked.join("missing/vimrc")));
+        assert_eq!(workspace.root(), dir.path().join("real"));
+    }
+
+    #[test]
+    fn workspace_internal_paths() {
+        let workspace = Workspace::new(PathBuf::from("/home/user/dotfiles"));
+        assert_eq!(
+            workspace.store_path(),
+            PathBuf::from("/home/user/dotfiles/.file_mappings.json")
+        );
+        assert_eq!(
+            workspace.oplog_path(),
+            PathBuf::from("/home/user/dotfiles/.dotman_operations.jsonl")
+        );
+        assert_eq!(workspace.root(), Path::new("/home/user/dotfiles"));
+    }
+
+    #[test]
+    fn workspace_home_substitution() {
+        let mut workspace = Workspace::new(PathBuf::from("/home/user/dotfiles"));
+        workspace.set_home(PathBuf::from("/home/user"));
+        let home = PathBuf::from("/home/user");
+        let stripped = workspace.strip_home(home.join(".vimrc"));
+        assert_eq!(stripped, format!("~{}.vimrc", std::path::MAIN_SEPARATOR));
+        assert_eq!(workspace.expand_home(&stripped), home.join(".vimrc"));
+        assert_eq!(workspace.strip_home("/etc/hosts"), "/etc/hosts");
+        assert_eq!(
+            workspace.expand_home("/etc/hosts"),
+            PathBuf::from("/etc/hosts")
+        );
+        assert_eq!(
+            workspace.strip_home("/home/user-old/.vimrc"),
+            "/home/user-old/.vimrc"
+        );
+    }
+
+    #[test]
+    fn tags_of_entries_under_home() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut app = App::with_workspace(dir.path().join("dotfiles")).unwrap();
+        app.set_home(dir.path().to_path_buf());
+        let sources: Vec<PathBuf> = ["bashrc", "vimrc"]
+            .iter()
+            .map(|name| dir.path().join(name))
+            .collect();
+        for (source, tag) in sources.iter().zip(&["shell", "editor"]) {
+            fs::write(source, "").unwrap();
+            app.set_link_tags(vec![tag.to_string()]);
+            let name = source.file_name().unwrap().to_string_lossy().to_string();
+            app.link(source, &name, IfExists::Error).unwrap();
+        }
+        let options = StatusOptions {
+            tag: Some("shell".to_string()),
+            ..StatusOptions::default()
+        };
+        let file_mappings = app.file_mappings().unwrap();
+        let selected: Vec<&String> = file_mappings
+            .as_map()
+            .iter()
+            .filter(|(src, dest)| options.selects(file_mappings, src, dest))
+            .map(|(src, _)| src)
+            .collect();
+        assert_eq!(selected, vec![&app.workspace.strip_home(&sources[0])]);
+        app.status(&StatusOptions {
+            verbose: true,
+            tag: Some("shell".to_string()),
+            ..StatusOptions::default()
+        })
+        .unwrap();
+
+        for source in &sources {
+            fs::remove_file(source).unwrap();
+        }
+        app.restore(false, Some("shell"), false, RestoreMode::LinkMissingOnly)
+            .unwrap();
+        assert!(fs::symlink_metadata(&sources[0]).is_ok());
+        assert!(fs::symlink_metadata(&sources[1]).is_err());
+        app.unlink_tagged("shell", false).unwrap();
+        assert!(fs::symlink_metadata(&sources[0]).unwrap().is_file());
+    }
+
+    #[test]
+    fn restore_by_tag() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut app = App::with_workspace(dir.path().join("dotfiles")).unwrap();
+        let sources: Vec<PathBuf> = ["bashrc", "zshrc", "vimrc"]
+            .iter()
+            .map(|name| dir.path().join(name))
+            .collect();
+        let tags = [vec!["shell", "laptop"], vec!["shell"], vec![]];
+        for (source, tags) in sources.iter().zip(&tags) {
+            fs::write(source, "").unwrap();
+            app.set_link_tags(tags.iter().map(|tag| tag.to_string()).collect());
+            let name = source.file_name().unwrap().to_string_lossy().to_string();
+            app.link(source, &name, IfExists::Error).unwrap();
+        }
+        for source in &sources {
+            fs::remove_file(source).unwrap();
+        }
+
+        app.restore(false, Some("laptop"), false, RestoreMode::LinkMissingOnly)
+            .unwrap();
+        assert!(fs::symlink_metadata(&sources[0]).is_ok());
+        assert!(fs::symlink_metadata(&sources[1]).is_err());
+        app.restore(false, Some("shell"), false, RestoreMode::LinkMissingOnly)
+            .unwrap();
+        assert!(fs::symlink_metadata(&sources[1]).is_ok());
+        assert!(fs::symlink_metadata(&sources[2]).is_err());
+
+        app.unlink_tagged("shell", false).unwrap();
+        assert!(fs::symlink_metadata(&sources[0]).unwrap().is_file());
+        assert!(fs::symlink_metadata(&sources[1]).unwrap().is_file());
+        assert!(app
+            .file_mappings()
+            .unwrap()
+            .contains(dir.path().join("vimrc")));
+        assert!(app.unlink_tagged("shell", false).is_err());
+    }
+
+    fn deps<'a>(edges: &'a BTreeMap<String, Vec<String>>) -> impl Fn(&str) -> &'a [String] {
+        move |node| edges.get(node).map(Vec::as_slice).unwrap_or(&[])
+    }
+
+    #[test]
+    fn topo_sort_chain() {
+        let nodes: Vec<String> = ["a", "b", "c", "d"].iter().map(|s| s.to_string()).collect();
+        let mut edges = BTreeMap::new();
+        edges.insert("a".to_string(), vec!["c".to_string()]);
+        edges.insert("c".to_string(), vec!["d".to_string()]);
+        edges.insert("b".to_string(), vec!["unknown".to_string()]);
+        assert_eq!(
+            app::topo_sort(&nodes, deps(&edges)).unwrap(),
+            vec!["b", "d", "c", "a"]
+        );
+    }
+
+    #[test]
+    fn topo_sort_cycle() {
+        let nodes: Vec<String> = ["a", "b", "c", "d"].iter().map(|s| s.to_string()).collect();
+        let mut edges = BTreeMap::new();
+        edges.insert("a".to_string(), vec!["b".to_string()]);
+        edges.insert("b".to_string(), vec!["c".to_string()]);
+        edges.insert("c".to_string(), vec!["a".to_string()]);
+        assert_eq!(
+            app::topo_sort(&nodes, deps(&edges)).unwrap_err(),
+            vec!["a", "b", "c"]
+        );
+    }
+
+    #[test]
+    fn restore_follows_after() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut app = App::with_workspace(dir.path().join("dotfiles")).unwrap();
+        let a = dir.path().join("a");
+        let b = dir.path().join("b");
+        fs::write(&a, "").unwrap();
+        fs::write(&b, "").unwrap();
+        app.set_link_after(std::slice::from_ref(&b));
+        app.link(&a, "a", IfExists::Error).unwrap();
+        app.set_link_after(std::slice::from_ref(&a));
+        app.link(&b, "b", IfExists::Error).unwrap();
+        fs::remove_file(&a).unwrap();
+        let err = app
+            .restore(false, None, false, RestoreMode::LinkMissingOnly)
+            .unwrap_err();
+        assert_eq!(ErrorKind::of(&err), ErrorKind::Conflict);
+        assert!(fs::symlink_metadata(&a).is_err());
+
+        app.file_mappings_mut().unwrap().set_after(&b, Vec::new());
+        app.restore(false, None, false, RestoreMode::LinkMissingOnly)
+            .unwrap();
+        assert!(fs::symlink_metadata(&a).is_ok());
+    }
+
+    #[test]
+    fn restore_follows_after_under_home() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut app = App::with_workspace(dir.path().join("dotfiles")).unwrap();
+        app.set_home(dir.path().to_path_buf());
+        let a = dir.path().join("a");
+        let b = dir.path().join("b");
+        fs::write(&a, "").unwrap();
+        fs::write(&b, "").unwrap();
+        app.set_link_after(std::slice::from_ref(&b));
+        app.link(&a, "a", IfExists::Error).unwrap();
+        app.set_link_after(std::slice::from_ref(&a));
+        app.link(&b, "b", IfExists::Error).unwrap();
+        fs::remove_file(&a).unwrap();
+        let err = app
+            .restore(false, None, false, RestoreMode::LinkMissingOnly)
+            .unwrap_err();
+        assert_eq!(ErrorKind::of(&err), ErrorKind::Conflict);
+    }
+
+    #[test]
+    fn mappings_json_to_output() {
+        let dir = tempfile::tempdir().unwrap();
+        let (app, sources) = linked_fixture(dir.path(), &["a", "b"]);
+        let output = dir.path().join("out/mappings.json");
+        app::write_output(&output, |writer| {
+            app.mappings_json(MappingsSort::Source, None, writer)
+        })
+        .unwrap();
+        let json: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(&output).unwrap()).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!([
+                {"source": sources[0].to_string_lossy(), "dest": "old/a"},
+                {"source": sources[1].to_string_lossy(), "dest": "old/b"},
+            ])
+        );
+    }
+
+    #[test]
+    fn duplicate_dest_on_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let workspace = dir.path().join("dotfiles");
+        fs::create_dir(&workspace).unwrap();
+        fs::write(
+            workspace.join(".file_mappings.json"),
+            r#"{"/a": "vimrc", "/b": "./vimrc", "/c": {"dest": "zshrc", "rendered": true}}"#,
+        )
+        .unwrap();
+        let app = App::with_workspace(workspace.clone()).unwrap();
+        let err = app.file_mappings().unwrap_err();
+        assert_eq!(ErrorKind::of(&err), ErrorKind::Conflict);
+        assert!(err.to_string().contains("dotman repair"));
+
+        let mut app = App::with_workspace(workspace.clone()).unwrap();
+        app.repair().unwrap();
+        app.finish().unwrap();
+        let app = App::with_workspace(workspace).unwrap();
+        let file_mappings = app.file_mappings().unwrap();
+        assert_eq!(
+            file_mappings.as_map(),
+            &map(&[("/a", "vimrc"), ("/c", "zshrc")])
+        );
+        assert!(file_mappings.is_rendered("/c"));
+    }
+
+    #[test]
+    fn git_command_for_bare_repository() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut app = App::with_workspace(dir.path().join("dotfiles")).unwrap();
+        let command = app.git_command();
+        assert_eq!(
+            command.get_current_dir(),
+            Some(dir.path().join("dotfiles").as_path())
+        );
+        assert_eq!(command.get_envs().count(), 0);
+
+        app.config.git_dir = Some(dir.path().join("bare").to_string_lossy().to_string());
+        app.config.git_work_tree = Some(dir.path().to_string_lossy().to_string());
+        let command = app.git_command();
+        assert_eq!(command.get_current_dir(), Some(dir.path()));
+        let envs: BTreeMap<&OsStr, Option<&OsStr>> = command.get_envs().collect();
+        assert_eq!(
+            envs[OsStr::new("GIT_DIR")],
+            Some(dir.path().join("bare").as_os_str())
+        );
+        assert_eq!(
+            envs[OsStr::new("GIT_WORK_TREE")],
+            Some(dir.path().as_os_str())
+        );
+        let err = app.git(&["status".to_string()]).unwrap_err();
+        assert_eq!(ErrorKind::of(&err), ErrorKind::Git);
+    }
+
+    #[test]
+    fn unlink_to_other_location() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut app, sources) = linked_fixture(dir.path(), &["vimrc"]);
+        let to = dir.path().join("backup/vimrc");
+        app.unlink_to(&sources[0], Some(&to), false).unwrap();
+        assert_eq!(fs::read_to_string(&to).unwrap(), "vimrc");
+        assert!(fs::symlink_metadata(&sources[0]).is_err());
+        assert!(!dir.path().join("dotfiles/old/vimrc").exists());
+        assert!(!app.file_mappings().unwrap().contains(&sources[0]));
+    }
+
+    #[test]
+    fn unlink_keep_leaves_symlink() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut app, sources) = linked_fixture(dir.path(), &["vimrc", "zshrc"]);
+        let mut out = Vec::new();
+        app.unlink_keep(&sources[0], false, &mut out).unwrap();
+        let dest = dir.path().join("dotfiles/old/vimrc");
+        assert_eq!(fs::read_link(&sources[0]).unwrap(), dest);
+        assert!(!app.file_mappings().unwrap().contains(&sources[0]));
+        let note = String::from_utf8(out).unwrap();
+        assert!(note.contains("no longer managed"));
+        assert!(note.contains(&*dest.to_string_lossy()));
+
+        fs::remove_file(dir.path().join("dotfiles/old/zshrc")).unwrap();
+        let mut out = Vec::new();
+        let err = app.unlink_keep(&sources[1], false, &mut out).unwrap_err();
+        assert_eq!(ErrorKind::of(&err), ErrorKind::DestInvalid);
+        assert!(app.file_mappings().unwrap().contains(&sources[1]));
+        app.unlink_keep(&sources[1], true, &mut out).unwrap();
+        assert!(String::from_utf8(out).unwrap().contains("does not exist"));
+    }
+
+    #[test]
+    fn unlink_detached_removes_stale_entry() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut app, sources) = linked_fixture(dir.path(), &["vimrc", "zshrc"]);
+        for source in &sources {
+            fs::remove_file(source).unwrap();
+            fs::write(source, "local").unwrap();
+        }
+        let err = app.unlink(&sources[0], false).unwrap_err();
+        assert_eq!(ErrorKind::of(&err), ErrorKind::SourceInvalid);
+
+        app.unlink_detached_ok(&sources[0], None, false, false)
+            .unwrap();
+        assert!(!app.file_mappings().unwrap().contains(&sources[0]));
+        assert_eq!(fs::read_to_string(&sources[0]).unwrap(), "local");
+        assert!(dir.path().join("dotfiles/old/vimrc").exists());
+
+        app.unlink_detached_ok(&sources[1], None, false, true)
+            .unwrap();
+        assert!(!app.file_mappings().unwrap().contains(&sources[1]));
+        assert_eq!(fs::read_to_string(&sources[1]).unwrap(), "local");
+        assert!(!dir.path().join("dotfiles/old/zshrc").exists());
+        assert!(matches!(
+            app.oplog().last().unwrap(),
+            Some(crate::oplog::Operation::Link { .. })
+        ));
+    }
+
+    #[test]
+    fn unlink_detached_ok_hardlink_under_home() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("vimrc");
+        fs::write(&source, "set number").unwrap();
+        let mut app = App::with_workspace(dir.path().join("dotfiles")).unwrap();
+        app.set_home(dir.path().to_path_buf());
+        app.set_link_hardlink(true);
+        app.link(&source, "vimrc", IfExists::Error).unwrap();
+
+        // Healthy, so it is unlinked as usual instead of dropping the entry
+        app.unlink_detached_ok(&source, None, false, false).unwrap();
+        assert!(!app.file_mappings().unwrap().contains(&source));
+        assert_eq!(fs::read_to_string(&source).unwrap(), "set number");
+        assert!(!app.workspace.dest_abs("vimrc").exists());
+        assert!(matches!(
+            app.oplog().last().unwrap(),
+            Some(crate::oplog::Operation::Unlink { .. })
+        ));
+    }
+
+    #[test]
+    fn mappings_filter_by_dest_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut app, sources) = linked_fixture(dir.path(), &["vimrc", "init.vim"]);
+        let file_mappings = app.file_mappings_mut().unwrap();
+        file_mappings.remove(&sources[1]).unwrap();
+        file_mappings.add(&sources[1], "nvim/init.vim").unwrap();
+        let init = app.workspace.strip_home(&sources[1]);
+
+        let filter = MappingFilter::new("nvim/*", FilterField::Dest).unwrap();
+        let entries = app
+            .sorted_mappings(MappingsSort::Source, Some(&filter))
+            .unwrap();
+        assert_eq!(entries, vec![(init.as_str(), "nvim/init.vim")]);
+        let filter = MappingFilter::new("nvim/*", FilterField::Source).unwrap();
+        assert!(app
+            .sorted_mappings(MappingsSort::Source, Some(&filter))
+            .unwrap()
+            .is_empty());
+        let filter = MappingFilter::new("*vim*", FilterField::Any).unwrap();
+        assert_eq!(
+            app.sorted_mappings(MappingsSort::Source, Some(&filter))
+                .unwrap()
+                .len(),
+            2
+        );
+        assert!(MappingFilter::new("[", FilterField::Any).is_err());
+    }
+
+    #[test]
+    fn read_while_locked_sees_saved_mappings() {
+        let dir = tempfile::tempdir().unwrap();
+        let (writer, sources) = linked_fixture(dir.path(), &["vimrc"]);
+        writer.finish().unwrap();
+        let mut writer = App::with_workspace(dir.path().join("dotfiles")).unwrap();
+        writer
+            .file_mappings_mut()
+            .unwrap()
+            .add(dir.path().join("zshrc"), "zshrc")
+            .unwrap();
+        assert!(dir.path().join("dotfiles/.dotman.lock").exists());
+
+        let reader = App::with_workspace(dir.path().join("dotfiles")).unwrap();
+        let mappings = reader.sorted_mappings(MappingsSort::Source, None).unwrap();
+        assert_eq!(mappings.len(), 1);
+        assert!(reader.file_mappings().unwrap().contains(&sources[0]));
+        let mut other_writer = App::with_workspace(dir.path().join("dotfiles")).unwrap();
+        let err = other_writer.file_mappings_mut().unwrap_err();
+        assert_eq!(ErrorKind::of(&err), ErrorKind::Conflict);
+
+        writer.finish().unwrap();
+        assert!(!dir.path().join("dotfiles/.dotman.lock").exists());
+        assert!(!dir.path().join("dotfiles/.file_mappings.json.tmp").exists());
+        let reader = App::with_workspace(dir.path().join("dotfiles")).unwrap();
+        assert_eq!(reader.file_mappings().unwrap().as_map().len(), 2);
+        other_writer.file_mappings_mut().unwrap();
+    }
+
+    #[test]
+    fn validate_mappings_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let (app, _) = linked_fixture(dir.path(), &["vimrc", "zshrc"]);
+        app.finish().unwrap();
+        let app = App::with_workspace(dir.path().join("dotfiles")).unwrap();
+        app.validate_mappings().unwrap();
+
+        let dirty = r#"{
+            "": "empty",
+            "~/.a": "/etc/a",
+            "~/.b": "../b",
+            "~/.c": "shared",
+            "~/.d": "./shared"
+        }"#;
+        let problems = FileMappings::problems(dirty.as_bytes()).unwrap();
+        assert_eq!(problems.len(), 4);
+        assert!(problems[0].contains("empty"));
+        assert!(problems[1].starts_with("~/.a: "));
+        assert!(problems[2].contains("escapes the workspace"));
+        assert!(problems[3].starts_with("~/.d: "));
+        fs::write(dir.path().join("dotfiles/.file_mappings.json"), dirty).unwrap();
+        let err = app.validate_mappings().unwrap_err();
+        assert!(err.to_string().contains("Found 4 problems"));
+        fs::write(dir.path().join("dotfiles/.file_mappings.json"), "{").unwrap();
+        assert!(app.validate_mappings().is_err());
+    }
+
+    /// Writer whose contents can be read after it is moved into `Progress`
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn restore_emits_progress() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut app, sources) = linked_fixture(dir.path(), &["a", "b"]);
+        let buffer = SharedBuffer::default();
+        app.set_progress(Progress::new(Box::new(buffer.clone())));
+        for source in &sources {
+            fs::remove_file(source).unwrap();
+        }
+        fs::remove_file(app.workspace.dest_abs("old/b")).unwrap();
+
+        app.restore(false, None, false, RestoreMode::LinkMissingOnly)
+            .unwrap();
+        let output = String::from_utf8(buffer.0.borrow().clone()).unwrap();
+        let events: Vec<serde_json::Value> = output
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(
+            events,
+            vec![
+                serde_json::json!({
+                    "event": "restore",
+                    "source": sources[0].to_string_lossy(),
+                    "dest": "old/a",
+                    "status": "ok",
+                }),
+                serde_json::json!({
+                    "event": "restore",
+                    "source": sources[1].to_string_lossy(),
+                    "dest": "old/b",
+                    "status": "skipped",
+                }),
+            ]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn doctor_fix_repairs_selected_problems() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut app, sources) = linked_fixture(dir.path(), &["a", "b", "c", "d", "e", "f"]);
+        // b: detached with the same contents, c: detached with local changes
+        fs::remove_file(&sources[1]).unwrap();
+        fs::write(&sources[1], "b").unwrap();
+        fs::remove_file(&sources[2]).unwrap();
+        fs::write(&sources[2], "local").unwrap();
+        // d: missing, e: dangling, f: repointed
+        fs::remove_file(&sources[3]).unwrap();
+        fs::remove_file(app.workspace.dest_abs("old/e")).unwrap();
+        fs::remove_file(&sources[5]).unwrap();
+        std::os::unix::fs::symlink(dir.path().join("other"), &sources[5]).unwrap();
+
+        app.doctor_fix(&[DoctorFix::Prune, DoctorFix::Relink])
+            .unwrap();
+        let health = |app: &App, index: usize, name: &str| {
+            app.entry_health(
+                &app.workspace.strip_home(&sources[index]),
+                &format!("old/{}", name),
+            )
+        };
+        assert_eq!(health(&app, 1, "b"), Health::Healthy);
+        assert_eq!(health(&app, 2, "c"), Health::Detached);
+        assert_eq!(fs::read_to_string(&sources[2]).unwrap(), "local");
+        assert_eq!(health(&app, 3, "d"), Health::Missing);
+        assert!(!app.file_mappings().unwrap().contains(&sources[4]));
+        assert!(fs::symlink_metadata(&sources[4]).is_err());
+        assert_eq!(health(&app, 5, "f"), Health::Repointed);
+
+        app.doctor_fix(&DoctorFix::ALL).unwrap();
+        assert_eq!(health(&app, 3, "d"), Health::Healthy);
+        assert_eq!(health(&app, 2, "c"), Health::Detached);
+        assert_eq!(app.file_mappings().unwrap().as_map().len(), 5);
+    }
+
+    #[test]
+    fn doctor_fix_entries_under_home() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            workspace: Some(dir.path().join("dotfiles").to_string_lossy().to_string()),
+            variables: variables(),
+            audit_log: Some(dir.path().join("audit.jsonl").to_string_lossy().to_string()),
+            ..Config::default()
+        };
+        let mut app = App::new(config, None).unwrap();
+        app.set_home(dir.path().to_path_buf());
+        let gitconfig = dir.path().join("gitconfig");
+        let vimrc = dir.path().join("vimrc");
+        fs::write(&gitconfig, "name = {{name}}\n").unwrap();
+        fs::write(&vimrc, "set number").unwrap();
+        app.link_rendered(&gitconfig, "gitconfig", IfExists::Error)
+            .unwrap();
+        app.set_link_hardlink(true);
+        app.link(&vimrc, "vimrc", IfExists::Error).unwrap();
+        fs::remove_file(&gitconfig).unwrap();
+        // Detached copy of the hardlinked file, which must not become a symlink
+        fs::remove_file(&vimrc).unwrap();
+        fs::write(&vimrc, "set number").unwrap();
+
+        app.doctor_fix(&DoctorFix::ALL).unwrap();
+        assert_eq!(fs::read_to_string(&gitconfig).unwrap(), "name = kuro\n");
+        assert!(fs::symlink_metadata(&vimrc).unwrap().is_file());
+        assert!(!app::is_same_file(&vimrc, &app.workspace.dest_abs("vimrc")));
+        let lines: Vec<serde_json::Value> = fs::read_to_string(dir.path().join("audit.jsonl"))
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        let fixes: Vec<_> = lines
+            .iter()
+            .filter(|line| line["operation"] != "link")
+            .collect();
+        assert_eq!(fixes.len(), 1);
+        assert_eq!(fixes[0]["operation"], "recreate");
+        assert_eq!(fixes[0]["dest"], "gitconfig");
+        assert_eq!(fixes[0]["outcome"], "success");
+    }
+
+    #[test]
+    fn restore_state_rechecks_known_healthy_links() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut app, sources) = linked_fixture(dir.path(), &["a", "b"]);
+        app.file_mappings_dirty = true;
+        app.save_mappings().unwrap();
+        app.restore_state = Some(dir.path().join("cache/restore.json"));
+        app.restore(false, None, false, RestoreMode::LinkMissingOnly)
+            .unwrap();
+        assert!(dir.path().join("cache/restore.json").is_file());
+
+        // Symlinks removed or replaced since the last run are noticed
+        fs::remove_file(&sources[0]).unwrap();
+        fs::remove_file(&sources[1]).unwrap();
+        fs::write(&sources[1], "local").unwrap();
+        app.restore(false, None, false, RestoreMode::LinkMissingOnly)
+            .unwrap();
+        assert!(fs::read_link(&sources[0]).is_ok());
+        assert_eq!(fs::read_to_string(&sources[1]).unwrap(), "local");
+        app.restore(true, None, false, RestoreMode::LinkMissingOnly)
+            .unwrap();
+        assert!(fs::read_link(&sources[1]).is_ok());
+
+        // Changed mappings invalidate the state
+        fs::remove_file(&sources[1]).unwrap();
+        app.file_mappings_mut()
+            .unwrap()
+            .set_tags(&sources[0], vec!["shell".to_string()]);
+        app.save_mappings().unwrap();
+        app.restore(false, None, false, RestoreMode::LinkMissingOnly)
+            .unwrap();
+        assert!(fs::read_link(&sources[1]).is_ok());
+    }
+
+    #[test]
+    fn doctor_json_structure() {
+        let dir = tempfile::tempdir().unwrap();
+        let (app, sources) = linked_fixture(dir.path(), &["a", "b", "c", "d"]);
+        fs::remove_file(&sources[1]).unwrap();
+        fs::write(&sources[1], "local").unwrap();
+        fs::remove_file(&sources[2]).unwrap();
+        fs::remove_file(app.workspace.dest_abs("old/d")).unwrap();
+
+        let report = serde_json::to_value(app.doctor_report().unwrap()).unwrap();
+        let entry = |index: usize, name: &str| {
+            serde_json::json!({
+                "source": sources[index].to_string_lossy(),
+                "dest": format!("old/{}", name),
+            })
+        };
+        assert_eq!(
+            report,
+            serde_json::json!({
+                "version": 1,
+                "summary": {
+                    "total": 4,
+                    "healthy": 1,
+                    "broken": 3,
+                    "counts": {
+                        "dangling": 1,
+                        "detached": 1,
+                        "healthy": 1,
+                        "missing": 1,
+                        "repointed": 0,
+                    },
+                    "health_score": 25.0,
+                },
+                "categories": {
+                    "dangling": [entry(3, "d")],
+                    "detached": [entry(1, "b")],
+                    "healthy": [entry(0, "a")],
+                    "missing": [entry(2, "c")],
+                    "repointed": [],
+                },
+            })
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn expand_user_home() {
+        use nix::unistd::{Uid, User};
+
+        let user = User::from_uid(Uid::current()).unwrap().unwrap();
+        assert_eq!(
+            app::expand_user(format!("~{}/.bashrc", user.name)).unwrap(),
+            user.dir.join(".bashrc")
+        );
+        assert_eq!(
+            app::expand_user(format!("~{}", user.name)).unwrap(),
+            user.dir
+        );
+        assert_eq!(
+            app::expand_user("/etc/~hosts").unwrap(),
+            PathBuf::from("/etc/~hosts")
+        );
+        let err = app::expand_user("~no-such-user-for-dotman/.bashrc").unwrap_err();
+        assert_eq!(ErrorKind::of(&err), ErrorKind::SourceInvalid);
+    }
+
+    #[test]
+    fn restore_continues_on_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut app = App::with_workspace(dir.path().join("dotfiles")).unwrap();
+        fs::create_dir_all(dir.path().join("b")).unwrap();
+        let sources: Vec<PathBuf> = ["a", "b/x", "c"]
+            .iter()
+            .map(|name| dir.path().join(name))
+            .collect();
+        for (source, dest) in sources.iter().zip(&["a", "x", "c"]) {
+            fs::write(source, "").unwrap();
+            app.link(source, dest, IfExists::Error).unwrap();
+            fs::remove_file(source).unwrap();
+        }
+        // Restoring `b/x` fails since `b` is replaced with a file
+        fs::remove_dir(dir.path().join("b")).unwrap();
+        fs::write(dir.path().join("b"), "").unwrap();
+        let err = app
+            .restore(false, None, false, RestoreMode::LinkMissingOnly)
+            .unwrap_err();
+        assert_eq!(ErrorKind::of(&err), ErrorKind::Io);
+        assert!(err.to_string().starts_with("1 of 3 entries failed!"));
+        assert!(fs::symlink_metadata(&sources[0]).is_ok());
+        assert!(fs::symlink_metadata(&sources[2]).is_ok());
+    }
+
+    #[test]
+    fn restore_fails_fast() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut app = App::with_workspace(dir.path().join("dotfiles")).unwrap();
+        fs::create_dir_all(dir.path().join("b")).unwrap();
+        let sources: Vec<PathBuf> = ["a", "b/x", "c"]
+            .iter()
+            .map(|name| dir.path().join(name))
+            .collect();
+        for (source, dest) in sources.iter().zip(&["a", "x", "c"]) {
+            fs::write(source, "").unwrap();
+            app.link(source, dest, IfExists::Error).unwrap();
+            fs::remove_file(source).unwrap();
+        }
+        // Restoring `b/x` fails since `b` is replaced with a file
+        fs::remove_dir(dir.path().join("b")).unwrap();
+        fs::write(dir.path().join("b"), "").unwrap();
+        app.set_error_policy(ErrorPolicy::FailFast);
+        let err = app
+            .restore(false, None, false, RestoreMode::LinkMissingOnly)
+            .unwrap_err();
+        assert_eq!(ErrorKind::of(&err), ErrorKind::Io);
+        assert!(!err.to_string().contains("entries failed"));
+        assert!(fs::symlink_metadata(&sources[0]).is_ok());
+        assert!(fs::symlink_metadata(&sources[2]).is_err());
+    }
+
+    #[test]
+    fn find_by_alias() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut app, sources) = linked_fixture(dir.path(), &["vimrc"]);
+        let alias = dir.path().join("link/vimrc");
+        app.alias(Some(&sources[0]), &alias).unwrap();
+        let file_mappings = app.file_mappings().unwrap();
+        assert!(file_mappings.contains(&sources[0]));
+        assert!(file_mappings.contains(&alias));
+        assert_eq!(file_mappings.get(&sources[0]), Ok("old/vimrc"));
+        assert_eq!(file_mappings.get(&alias), Ok("old/vimrc"));
+        assert_eq!(
+            file_mappings
+                .stored_attributes(&app.workspace.strip_home(&sources[0]))
+                .aliases,
+            [alias.to_string_lossy().to_string()]
+        );
+        assert!(app.alias(Some(&sources[0]), &sources[0]).is_err());
+
+        app.alias(None::<&Path>, &alias).unwrap();
+        assert!(!app.file_mappings().unwrap().contains(&alias));
+        assert!(app.alias(None::<&Path>, &sources[0]).is_err());
+    }
+
+    #[test]
+    fn alias_of_source_under_home() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut app = App::with_workspace(dir.path().join("dotfiles")).unwrap();
+        app.set_home(dir.path().to_path_buf());
+        let source = dir.path().join("vimrc");
+        fs::write(&source, "vimrc").unwrap();
+        app.link(&source, "old/vimrc", IfExists::Error).unwrap();
+        let alias = dir.path().join("link/vimrc");
+        app.alias(Some(&source), &alias).unwrap();
+        assert_eq!(app.file_mappings().unwrap().get(&alias), Ok("old/vimrc"));
+        app.which(&alias, false).unwrap();
+
+        app.alias(None::<&Path>, &alias).unwrap();
+        assert!(!app.file_mappings().unwrap().contains(&alias));
+    }
+
+    #[test]
+    fn hardlink_entry_under_home() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("vimrc");
+        fs::write(&source, "set number").unwrap();
+        let mut app = App::with_workspace(dir.path().join("dotfiles")).unwrap();
+        app.set_home(dir.path().to_path_buf());
+        app.set_link_hardlink(true);
+        app.link(&source, "vimrc", IfExists::Error).unwrap();
+        assert_eq!(app.health_report().unwrap()[0].2, Health::Healthy);
+        app.remap("^vimrc$", "vim/vimrc", false).unwrap();
+        assert_eq!(app.health_report().unwrap()[0].2, Health::Healthy);
+        assert_eq!(app.readopt_detached(true).unwrap(), 0);
+    }
+
+    #[test]
+    fn link_hardlink_and_unlink() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("vimrc");
+        fs::write(&source, "set number").unwrap();
+        let mut app = App::with_workspace(dir.path().join("dotfiles")).unwrap();
+        app.set_link_hardlink(true);
+        app.link(&source, "vimrc", IfExists::Error).unwrap();
+        let dest_abs = app.workspace.dest_abs("vimrc");
+        assert!(fs::symlink_metadata(&source).unwrap().is_file());
+        assert_eq!(app.health_report().unwrap()[0].2, Health::Healthy);
+        fs::write(&dest_abs, "set nonumber").unwrap();
+        assert_eq!(fs::read_to_string(&source).unwrap(), "set nonumber");
+
+        fs::remove_file(&source).unwrap();
+        app.restore(false, None, false, RestoreMode::LinkMissingOnly)
+            .unwrap();
+        assert!(fs::symlink_metadata(&source).unwrap().is_file());
+        assert_eq!(app.health_report().unwrap()[0].2, Health::Healthy);
+
+        app.unlink(&source, false).unwrap();
+        assert_eq!(fs::read_to_string(&source).unwrap(), "set nonumber");
+        assert!(!dest_abs.exists());
+        assert!(!app.file_mappings().unwrap().contains(&source));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn walk_dir_avoids_symlink_cycle() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("a/b/c")).unwrap();
+        fs::write(dir.path().join("a/file"), "").unwrap();
+        std::os::unix::fs::symlink(dir.path(), dir.path().join("a/loop")).unwrap();
+        let relatives = |options: WalkOptions| -> Vec<PathBuf> {
+            app::walk_dir(dir.path(), options, |_| true)
+                .unwrap()
+                .into_iter()
+                .map(|entry| entry.relative)
+                .collect()
+        };
+        let expected: Vec<PathBuf> = ["a", "a/b", "a/b/c", "a/file", "a/loop"]
+            .iter()
+            .map(PathBuf::from)
+            .collect();
+        assert_eq!(relatives(WalkOptions::default()), expected);
+        let follow = WalkOptions {
+            follow_symlinks: true,
+            ..WalkOptions::default()
+        };
+        assert_eq!(relatives(follow), expected);
+        let shallow = WalkOptions {
+            max_depth: 2,
+            ..WalkOptions::default()
+        };
+        assert_eq!(
+            relatives(shallow),
+            ["a", "a/b", "a/file", "a/loop"]
+                .iter()
+                .map(PathBuf::from)
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn export_shell_commands() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut app = App::with_workspace(dir.path().join("dotfiles")).unwrap();
+        let plain = dir.path().join("vimrc");
+        let spaced = dir.path().join("my app's.conf");
+        fs::write(&plain, "").unwrap();
+        fs::write(&spaced, "").unwrap();
+        app.link(&plain, "vimrc", IfExists::Error).unwrap();
+        app.set_link_tags(vec!["work".to_string()]);
+        app.link(&spaced, "app/my app.conf", IfExists::Error)
+            .unwrap();
+
+        let mut exported = Vec::new();
+        app.export(&mut exported, ExportFormat::Shell).unwrap();
+        let root = dir.path().to_string_lossy();
+        assert_eq!(
+            String::from_utf8(exported).unwrap(),
+            format!(
+                "#!/bin/sh\nset -e\n\
+                 dotman link '{root}/my app'\\''s.conf' 'app/my app.conf' --tag work\n\
+                 dotman link {root}/vimrc vimrc\n",
+                root = root
+            )
+        );
+        assert_eq!(app::shell_quote("~/.vimrc"), "'~/.vimrc'");
+    }
+
+    #[test]
+    fn export_shell_entries_under_home() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut app = App::with_workspace(dir.path().join("dotfiles")).unwrap();
+        app.set_home(dir.path().to_path_buf());
+        let source = tree_fixture(dir.path());
+        app.set_link_tags(vec!["work".to_string()]);
+        app.link_recursive(&source, "nvim", Some(1), IfExists::Error)
+            .unwrap();
+
+        let mut exported = Vec::new();
+        app.export(&mut exported, ExportFormat::Shell).unwrap();
+        let root = "~";
+        assert_eq!(
+            String::from_utf8(exported).unwrap(),
+            format!(
+                "#!/bin/sh\nset -e\n\
+                 dotman link {root}/nvim/init.vim nvim/init.vim --tag work\n\
+                 # Directory: {root}/nvim/lua -> nvim/lua\n",
+                root = root
+            )
+        );
+    }
+
+    #[test]
+    fn link_refuses_source_inside_workspace() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut app = App::with_workspace(dir.path().join("dotfiles")).unwrap();
+        let source = dir.path().join("dotfiles/vim/vimrc");
+        fs::create_dir_all(source.parent().unwrap()).unwrap();
+        fs::write(&source, "set number").unwrap();
+        let err = app.link(&source, "vimrc", IfExists::Error).unwrap_err();
+        assert_eq!(ErrorKind::of(&err), ErrorKind::SourceInvalid);
+        assert!(fs::symlink_metadata(&source).unwrap().is_file());
+        assert!(!dir.path().join("dotfiles/vimrc").exists());
+        assert!(!app.file_mappings().unwrap().contains(&source));
+    }
+
+    #[test]
+    fn link_batch_suffixes_colliding_dests() {
+        let dir = tempfile::tempdir().unwrap();
+        let sources: Vec<PathBuf> = ["a/app.conf", "b/app.conf", "c/app.conf"]
+            .iter()
+            .map(|name| dir.path().join(name))
+            .collect();
+        for source in &sources {
+            fs::create_dir_all(source.parent().unwrap()).unwrap();
+            fs::write(source, "").unwrap();
+        }
+        let mut app = App::with_workspace(dir.path().join("dotfiles")).unwrap();
+        let err = app
+            .link_batch(
+                &sources,
+                Some(dir.path()),
+                Some(1),
+                IfExists::Error,
+                DestCollision::Error,
+            )
+            .unwrap_err();
+        assert_eq!(ErrorKind::of(&err), ErrorKind::DestExists);
+        assert!(!dir.path().join("dotfiles").exists());
+
+        app.link_batch(
+            &sources,
+            Some(dir.path()),
+            Some(1),
+            IfExists::Error,
+            DestCollision::Suffix,
+        )
+        .unwrap();
+        let file_mappings = app.file_mappings().unwrap();
+        assert_eq!(file_mappings.get(&sources[0]), Ok("app.conf"));
+        assert_eq!(file_mappings.get(&sources[1]), Ok("app-1.conf"));
+        assert_eq!(file_mappings.get(&sources[2]), Ok("app-2.conf"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn reconcile_adds_existing_symlinks() {
+        let dir = tempfile::tempdir().unwrap();
+        let workspace = dir.path().join("dotfiles");
+        let home = dir.path().join("home");
+        fs::create_dir_all(workspace.join("vim")).unwrap();
+        fs::create_dir_all(&home).unwrap();
+        fs::write(workspace.join("vim/vimrc"), "").unwrap();
+        fs::write(dir.path().join("other"), "").unwrap();
+        std::os::unix::fs::symlink(workspace.join("vim/vimrc"), home.join(".vimrc")).unwrap();
+        std::os::unix::fs::symlink(dir.path().join("other"), home.join(".other")).unwrap();
+        let mut app = App::with_workspace(workspace).unwrap();
+
+        app.reconcile(Some(&home), true).unwrap();
+        assert!(app.file_mappings().unwrap().as_map().is_empty());
+        app.reconcile(Some(&home), false).unwrap();
+        let file_mappings = app.file_mappings().unwrap();
+        assert_eq!(file_mappings.as_map().len(), 1);
+        assert_eq!(file_mappings.get(home.join(".vimrc")), Ok("vim/vimrc"));
+        assert_eq!(app.health_report().unwrap()[0].2, Health::Healthy);
+    }
+
+    #[test]
+    fn workspace_check() {
+        let dir = tempfile::tempdir().unwrap();
+        let check = |workspace: &Path| {
+            let config = Config {
+                workspace: Some(workspace.to_string_lossy().to_string()),
+                workspace_check: true,
+                ..Config::default()
+            };
+            App::new(config, None).map(|_| ())
+        };
+        check(dir.path()).unwrap();
+        let err = check(&dir.path().join("missing")).unwrap_err();
+        assert_eq!(ErrorKind::of(&err), ErrorKind::Config);
+        assert!(err.to_string().contains("does not exist"));
+        fs::write(dir.path().join("file"), "").unwrap();
+        let err = check(&dir.path().join("file")).unwrap_err();
+        assert!(err.to_string().contains("is not a directory"));
+        assert!(check(Path::new("dotfiles")).is_err());
+    }
+
+    #[test]
+    fn translate_porcelain_paths() {
+        let workspace = Workspace::new(PathBuf::from("/home/user/dotfiles"));
+        let file_mappings = FileMappings::load_entries(
+            r#"{"~/.vimrc": "vimrc", "~/.config/nvim": "nvim", "~/.zshrc": "zshrc",
+                "~/a -> b": "a -> b"}"#
+                .as_bytes(),
+        )
+        .unwrap();
+        let output =
+            " M vimrc\0?? nvim/lua/init.lua\0R  shell/zshrc\0zshrc\0?? README.md\0 M a -> b\0";
+        assert_eq!(
+            app::translate_porcelain(output, workspace.root(), &workspace, &file_mappings),
+            vec![
+                " M ~/.vimrc",
+                "?? ~/.config/nvim/lua/init.lua",
+                "R  ~/.zshrc -> shell/zshrc (not mapped)",
+                "?? README.md (not mapped)",
+                " M ~/a -> b",
+            ]
+        );
+    }
+
+    #[test]
+    fn link_named_and_unlink() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("config.example");
+        let link = dir.path().join("config");
+        fs::write(&source, "Host *").unwrap();
+        let mut app = App::with_workspace(dir.path().join("dotfiles")).unwrap();
+        let err = app
+            .link_named(
+                &source,
+                OsStr::new("ssh/config"),
+                "ssh/config",
+                IfExists::Error,
+            )
+            .unwrap_err();
+        assert_eq!(ErrorKind::of(&err), ErrorKind::SourceInvalid);
+
+        app.link_named(&source, OsStr::new("config"), "ssh/config", IfExists::Error)
+            .unwrap();
+        assert!(fs::symlink_metadata(&link)
+            .unwrap()
+            .file_type()
+            .is_symlink());
+        assert!(fs::symlink_metadata(&source).is_err());
+        assert_eq!(app.file_mappings().unwrap().get(&link), Ok("ssh/config"));
+
+        app.unlink(&link, false).unwrap();
+        assert_eq!(fs::read_to_string(&link).unwrap(), "Host *");
+        assert!(!app.file_mappings().unwrap().contains(&link));
     }
 }
@@ -0,0 +1,68 @@
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+    Success,
+    Failure,
+}
+
+/// One line of the audit log.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub timestamp: DateTime<Utc>,
+    /// e.g. `link`
+    pub operation: String,
+    pub source: String,
+    pub dest: String,
+    pub outcome: Outcome,
+    /// Message of the error if `outcome` is `failure`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl AuditEntry {
+    pub fn new(operation: &str, source: &str, dest: &str, result: &Result<()>) -> Self {
+        Self {
+            timestamp: Utc::now(),
+            operation: operation.to_string(),
+            source: source.to_string(),
+            dest: dest.to_string(),
+            outcome: match result {
+                Ok(_) => Outcome::Success,
+                Err(_) => Outcome::Failure,
+            },
+            error: result.as_ref().err().map(|err| err.to_string()),
+        }
+    }
+}
+
+/// Append-only log of mutating commands for auditing. One JSON object per line.
+///
+/// Unlike the operation log, dotman never reads this file.
+#[derive(Debug)]
+pub struct AuditLog {
+    path: PathBuf,
+}
+
+impl AuditLog {
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+        }
+    }
+
+    pub fn append(&self, entry: &AuditEntry) -> Result<()> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        writeln!(file, "{}", serde_json::to_string(entry)?)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,188 @@
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+
+const INDEX_FILE_NAME: &str = "index.json";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub id: String,
+    pub created_at: DateTime<Utc>,
+    /// Operation which took this snapshot. e.g. `link`
+    pub operation: String,
+    /// Path which the file was at when the snapshot was taken
+    pub original: PathBuf,
+}
+
+/// Directory which stores snapshots of files before destructive operations.
+///
+/// Layout:
+/// - `index.json`: list of snapshots
+/// - `<id>/<file name>`: copy of the file
+#[derive(Debug)]
+pub struct BackupStore {
+    dir: PathBuf,
+    snapshots: Vec<Snapshot>,
+}
+
+impl BackupStore {
+    pub fn open<P: AsRef<Path>>(dir: P) -> Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        let index_path = dir.join(INDEX_FILE_NAME);
+        let snapshots = if index_path.exists() {
+            serde_json::from_reader(BufReader::new(File::open(&index_path)?))?
+        } else {
+            Vec::new()
+        };
+        Ok(Self { dir, snapshots })
+    }
+
+    pub fn snapshots(&self) -> &[Snapshot] {
+        &self.snapshots
+    }
+
+    pub fn get(&self, id: &str) -> Option<&Snapshot> {
+        self.snapshots.iter().find(|snapshot| snapshot.id == id)
+    }
+
+    /// Copies `file` into the store and records it to the index.
+    pub fn take<P: AsRef<Path>>(&mut self, operation: &str, file: P) -> Result<&Snapshot> {
+        let file = file.as_ref();
+        let created_at = Utc::now();
+        let id = self.new_id(&created_at);
+        let file_name = file
+            .file_name()
+            .ok_or_else(|| anyhow!("Cannot backup {}", file.to_string_lossy()))?;
+        let snapshot_dir = self.dir.join(&id);
+        fs::create_dir_all(&snapshot_dir)?;
+        debug!(
+            "Copying '{}' into '{}'",
+            file.to_string_lossy(),
+            snapshot_dir.to_string_lossy()
+        );
+        fs::copy(file, snapshot_dir.join(file_name))?;
+        self.snapshots.push(Snapshot {
+            id,
+            created_at,
+            operation: operation.to_string(),
+            original: file.to_path_buf(),
+        });
+        self.save_index()?;
+        Ok(self.snapshots.last().unwrap())
+    }
+
+    /// Copies file of the snapshot back to its original path.
+    pub fn restore(&self, id: &str) -> Result<&Snapshot> {
+        let snapshot = self
+            .get(id)
+            .ok_or_else(|| anyhow!("Snapshot: {} does not exist!", id))?;
+        if fs::symlink_metadata(&snapshot.original).is_ok() {
+            return Err(anyhow!(
+                "File: {} already exists! Move it away before restoring.",
+                snapshot.original.to_string_lossy()
+            ));
+        }
+        if let Some(parent) = snapshot.original.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::copy(self.snapshot_file(snapshot)?, &snapshot.original)?;
+        Ok(snapshot)
+    }
+
+    /// Removes all but the newest `keep` snapshots. Returns number of removed snapshots.
+    pub fn prune(&mut self, keep: usize) -> Result<usize> {
+        if self.snapshots.len() <= keep {
+            return Ok(0);
+        }
+        let removed: Vec<Snapshot> = self
+            .snapshots
+            .drain(..self.snapshots.len() - keep)
+            .collect();
+        for snapshot in &removed {
+            let snapshot_dir = self.dir.join(&snapshot.id);
+            debug!("Removing snapshot: {}", snapshot_dir.to_string_lossy());
+            if snapshot_dir.exists() {
+                fs::remove_dir_all(snapshot_dir)?;
+            }
+        }
+        self.save_index()?;
+        Ok(removed.len())
+    }
+
+    fn snapshot_file(&self, snapshot: &Snapshot) -> Result<PathBuf> {
+        let file_name = snapshot
+            .original
+            .file_name()
+            .ok_or_else(|| anyhow!("Snapshot: {} is broken!", snapshot.id))?;
+        Ok(self.dir.join(&snapshot.id).join(file_name))
+    }
+
+    fn new_id(&self, created_at: &DateTime<Utc>) -> String {
+        let base = created_at.format("%Y%m%dT%H%M%S").to_string();
+        let mut id = base.clone();
+        let mut counter = 1;
+        while self.get(&id).is_some() {
+            id = format!("{}-{}", base, counter);
+            counter += 1;
+        }
+        id
+    }
+
+    fn save_index(&self) -> Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        serde_json::to_writer_pretty(
+            BufWriter::new(File::create(self.dir.join(INDEX_FILE_NAME))?),
+            &self.snapshots,
+        )?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backup::BackupStore;
+    use std::fs;
+
+    #[test]
+    fn take_and_restore() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("vimrc");
+        fs::write(&file, "set number").unwrap();
+        let mut store = BackupStore::open(dir.path().join("backups")).unwrap();
+        let id = store.take("link", &file).unwrap().id.clone();
+        fs::remove_file(&file).unwrap();
+
+        let store = BackupStore::open(dir.path().join("backups")).unwrap();
+        assert_eq!(store.snapshots().len(), 1);
+        store.restore(&id).unwrap();
+        assert_eq!(fs::read_to_string(&file).unwrap(), "set number");
+    }
+
+    #[test]
+    fn restore_refuses_existing_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("vimrc");
+        fs::write(&file, "set number").unwrap();
+        let mut store = BackupStore::open(dir.path().join("backups")).unwrap();
+        let id = store.take("link", &file).unwrap().id.clone();
+        assert!(store.restore(&id).is_err());
+    }
+
+    #[test]
+    fn prune_keeps_newest() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("vimrc");
+        fs::write(&file, "set number").unwrap();
+        let mut store = BackupStore::open(dir.path().join("backups")).unwrap();
+        let ids: Vec<String> = (0..3)
+            .map(|_| store.take("link", &file).unwrap().id.clone())
+            .collect();
+        assert_eq!(store.prune(1).unwrap(), 2);
+        assert_eq!(store.snapshots().len(), 1);
+        assert_eq!(store.snapshots()[0].id, ids[2]);
+        assert!(!dir.path().join("backups").join(&ids[0]).exists());
+    }
+}
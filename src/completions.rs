@@ -0,0 +1,67 @@
+use anyhow::Result;
+use clap::Shell;
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+
+/// Detects shell from the value of `$SHELL`. e.g. `/usr/bin/zsh`
+pub fn detect_shell(shell_env: Option<&OsStr>) -> Result<Shell> {
+    let shell_env = shell_env.ok_or_else(|| anyhow!("$SHELL is not set! Use --shell."))?;
+    let name = Path::new(shell_env)
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    name.parse().map_err(|_| {
+        anyhow!(
+            "Shell: {} is not supported! Use --shell.",
+            shell_env.to_string_lossy()
+        )
+    })
+}
+
+/// Returns conventional path of the completion script of `shell` for a user.
+pub fn install_path(shell: Shell, home: &Path) -> Result<PathBuf> {
+    match shell {
+        Shell::Bash => Ok(home.join(".local/share/bash-completion/completions/dotman")),
+        // Not searched by default. Must be added to `fpath`.
+        Shell::Zsh => Ok(home.join(".zsh/completions/_dotman")),
+        Shell::Fish => Ok(home.join(".config/fish/completions/dotman.fish")),
+        _ => {
+            let shell = shell.to_string().to_lowercase();
+            Err(anyhow!(
+                "There is no conventional location for {}! Redirect the output of 'dotman completions --shell {}' instead.",
+                shell,
+                shell
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::completions;
+    use clap::Shell;
+    use std::ffi::OsStr;
+    use std::path::{Path, PathBuf};
+
+    #[test]
+    fn detect_from_shell_env() {
+        let shell = completions::detect_shell(Some(OsStr::new("/usr/bin/zsh"))).unwrap();
+        assert!(matches!(shell, Shell::Zsh));
+        assert!(completions::detect_shell(Some(OsStr::new("/bin/tcsh"))).is_err());
+        assert!(completions::detect_shell(None).is_err());
+    }
+
+    #[test]
+    fn install_paths() {
+        let home = Path::new("/home/user");
+        assert_eq!(
+            completions::install_path(Shell::Zsh, home).unwrap(),
+            PathBuf::from("/home/user/.zsh/completions/_dotman")
+        );
+        assert_eq!(
+            completions::install_path(Shell::Fish, home).unwrap(),
+            PathBuf::from("/home/user/.config/fish/completions/dotman.fish")
+        );
+        assert!(completions::install_path(Shell::PowerShell, home).is_err());
+    }
+}
@@ -0,0 +1,460 @@
+use crate::app;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const CONFIG_ENV: &str = "DOTMAN_CONFIG";
+/// Key of config files to merge before the file itself
+const INCLUDE_KEY: &str = "include";
+/// Keys which can be read and written by `config get` and `config set`
+pub const EDITABLE_KEYS: &[&str] = &[
+    "workspace",
+    "default_repo",
+    "symlink_style",
+    "dest_from_source",
+    "gitignore_internal_files",
+    "dest_root",
+];
+
+/// Contents of `~/.dotman.toml`. Every key is optional.
+///
+/// `include = ["base.toml"]` merges other config files, relative to the including file,
+/// before its own keys. Tables are merged key by key and other values are overridden.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// Workspace used when no repo is selected
+    pub workspace: Option<String>,
+    /// Repo used when `--repo` is not specified
+    pub default_repo: Option<String>,
+    /// Named workspaces
+    pub repos: BTreeMap<String, String>,
+    /// Derive dest from the home-relative path of source when `link` has no dest,
+    /// as if `--mirror` was specified
+    pub dest_from_source: bool,
+    /// Adds files written by dotman (e.g. `.file_mappings.json`) to `.gitignore` of the
+    /// workspace on `init` and `gitignore-sync`
+    pub gitignore_internal_files: bool,
+    /// Directory in the workspace which dests of `link` are placed under. e.g. with
+    /// `home`, `link ~/.vimrc vimrc` maps to `home/vimrc`. Mappings store the prefixed
+    /// dest. `link --dest-root` overrides this.
+    pub dest_root: Option<String>,
+    /// Whether symlinks point to dests by absolute or relative path
+    pub symlink_style: SymlinkStyle,
+    /// Values of `{{name}}` placeholders in rendered files
+    pub variables: BTreeMap<String, String>,
+    /// How many times network git operations (e.g. `pull`) are retried. `--retries`
+    /// overrides this.
+    pub git_retries: u32,
+    /// Aliases expanded by `git` command. e.g. `lg = "log --oneline --graph"`.
+    /// An alias takes precedence over the git subcommand of the same name.
+    pub git_aliases: BTreeMap<String, String>,
+    /// Author and committer name of commits by `save`. Git config is used if unset.
+    pub git_author_name: Option<String>,
+    /// Author and committer email of commits by `save`. Git config is used if unset.
+    pub git_author_email: Option<String>,
+    /// Modes applied by `fix-permissions`. First matching rule wins.
+    pub permissions: Vec<PermissionRule>,
+    /// File which `link`, `unlink`, `remap` and `restore` append JSON lines to
+    pub audit_log: Option<String>,
+    /// Records SHA-256 of dests on `link` so that `verify` can detect changes
+    pub content_hashes: bool,
+    /// `GIT_DIR` of git commands, for a bare repository whose work tree is e.g. home
+    /// (`git --git-dir=~/.dotfiles --work-tree=~`). The workspace is not required to be
+    /// a git repository then. Files tracked this way are not linked by dotman, but both
+    /// styles can share the bare repository if the workspace is inside the work tree.
+    pub git_dir: Option<String>,
+    /// `GIT_WORK_TREE` of git commands. Git runs in this directory instead of the
+    /// workspace. Defaults to the workspace.
+    pub git_work_tree: Option<String>,
+    /// Fails every command if the workspace is not an absolute path of an existing
+    /// directory, instead of failing later with confusing errors. `--workspace-check`
+    /// enables this too.
+    pub workspace_check: bool,
+    /// Manages symlinks without git. `init` skips `git init`, `status` prints health of
+    /// mapped files and git commands fail. `--no-git` overrides this.
+    pub no_git: bool,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SymlinkStyle {
+    #[default]
+    Absolute,
+    /// Relative to the directory of the symlink. Survives moving home and workspace together.
+    Relative,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PermissionRule {
+    /// Glob matched against dest paths. e.g. `ssh/*`
+    pub pattern: String,
+    /// Octal mode. e.g. `0600`
+    pub mode: String,
+}
+
+impl PermissionRule {
+    pub fn mode(&self) -> Result<u32> {
+        u32::from_str_radix(&self.mode, 8)
+            .map_err(|err| anyhow!("Invalid mode: {} error: {}", self.mode, err))
+    }
+}
+
+impl Config {
+    /// Path of the config file. Can be overridden by `DOTMAN_CONFIG`.
+    pub fn path() -> Result<PathBuf> {
+        if let Some(path) = env::var_os(CONFIG_ENV) {
+            return Ok(PathBuf::from(path));
+        }
+        Ok(dirs::home_dir()
+            .ok_or_else(|| anyhow!("Cannot retrieve home directory"))?
+            .join(".dotman.toml"))
+    }
+
+    pub fn load() -> Result<Self> {
+        let path = Self::path()?;
+        if !path.exists() {
+            debug!("Config: {} does not exist", path.to_string_lossy());
+            return Ok(Self::default());
+        }
+        Self::load_from(&path)
+    }
+
+    pub fn load_from(path: &Path) -> Result<Self> {
+        load_value(path, &mut Vec::new())?
+            .try_into()
+            .map_err(|err| anyhow!("Failed to parse {}! error: {}", path.to_string_lossy(), err))
+    }
+
+    /// Returns value of `key` formatted as in config file. `None` if it is not set.
+    pub fn get(&self, key: &str) -> Result<Option<String>> {
+        Ok(match key {
+            "workspace" => self.workspace.clone(),
+            "default_repo" => self.default_repo.clone(),
+            "dest_root" => self.dest_root.clone(),
+            "symlink_style" => Some(
+                match self.symlink_style {
+                    SymlinkStyle::Absolute => "absolute",
+                    SymlinkStyle::Relative => "relative",
+                }
+                .to_string(),
+            ),
+            "dest_from_source" => Some(self.dest_from_source.to_string()),
+            "gitignore_internal_files" => Some(self.gitignore_internal_files.to_string()),
+            _ => return Err(unknown_key(key)),
+        })
+    }
+
+    /// Sets `key` in config file at `path`. Comments, formatting and other keys are kept.
+    pub fn set_in_file(path: &Path, key: &str, value: &str) -> Result<()> {
+        let content = if path.exists() {
+            fs::read_to_string(path)?
+        } else {
+            String::new()
+        };
+        let mut document: toml_edit::DocumentMut = content
+            .parse()
+            .map_err(|err| anyhow!("Failed to parse {}! error: {}", path.to_string_lossy(), err))?;
+        document[key] = match key {
+            "workspace" | "default_repo" | "dest_root" => toml_edit::value(value),
+            "symlink_style" => match value {
+                "absolute" | "relative" => toml_edit::value(value),
+                _ => return Err(anyhow!("symlink_style must be 'absolute' or 'relative'!")),
+            },
+            "dest_from_source" | "gitignore_internal_files" => toml_edit::value(
+                value
+                    .parse::<bool>()
+                    .map_err(|_| anyhow!("{} must be 'true' or 'false'!", key))?,
+            ),
+            _ => return Err(unknown_key(key)),
+        };
+        let content = document.to_string();
+        // Refuse to write a file which can't be loaded
+        toml::from_str::<Config>(&content)
+            .map_err(|err| anyhow!("Invalid value for {}! error: {}", key, err))?;
+        fs::write(path, content)?;
+        Ok(())
+    }
+
+    /// Resolves workspace in order of `repo`, `default_repo`, `workspace` and `~/dotfiles`.
+    pub fn resolve_workspace(&self, repo: Option<&str>) -> Result<PathBuf> {
+        Ok(self.resolve_workspace_origin(repo)?.0)
+    }
+
+    /// Same as `resolve_workspace` but also returns which setting selected the workspace.
+    pub fn resolve_workspace_origin(
+        &self,
+        repo: Option<&str>,
+    ) -> Result<(PathBuf, WorkspaceOrigin)> {
+        let selected = match repo {
+            Some(name) => Some((name, WorkspaceOrigin::Repo(name.to_string()))),
+            None => self
+                .default_repo
+                .as_deref()
+                .map(|name| (name, WorkspaceOrigin::DefaultRepo(name.to_string()))),
+        };
+        if let Some((name, origin)) = selected {
+            let path = self
+                .repos
+                .get(name)
+                .ok_or_else(|| anyhow!("Repo: {} is not configured!", name))?;
+            return Ok((app::expand_src(path), origin));
+        }
+        if let Some(workspace) = &self.workspace {
+            return Ok((app::expand_src(workspace), WorkspaceOrigin::Workspace));
+        }
+        let home = dirs::home_dir().ok_or_else(|| anyhow!("Cannot retrieve home directory"))?;
+        Ok((home.join("dotfiles"), WorkspaceOrigin::Default))
+    }
+}
+
+/// Setting which selected the workspace. See `Config::resolve_workspace`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WorkspaceOrigin {
+    /// `--repo`
+    Repo(String),
+    DefaultRepo(String),
+    Workspace,
+    /// `~/dotfiles`
+    Default,
+}
+
+impl fmt::Display for WorkspaceOrigin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WorkspaceOrigin::Repo(name) => write!(f, "repo '{}' selected by --repo", name),
+            WorkspaceOrigin::DefaultRepo(name) => {
+                write!(f, "repo '{}' selected by default_repo in config", name)
+            }
+            WorkspaceOrigin::Workspace => f.write_str("workspace in config"),
+            WorkspaceOrigin::Default => f.write_str("default"),
+        }
+    }
+}
+
+/// Loads config file at `path` with its includes resolved. `stack` is the chain of files
+/// which are including `path`, to detect cycles.
+fn load_value(path: &Path, stack: &mut Vec<PathBuf>) -> Result<toml::Value> {
+    debug!("Loading config: {}", path.to_string_lossy());
+    let canonical = fs::canonicalize(path)
+        .map_err(|err| anyhow!("Failed to read {}! error: {}", path.to_string_lossy(), err))?;
+    if let Some(start) = stack.iter().position(|other| *other == canonical) {
+        let cycle: Vec<String> = stack[start..]
+            .iter()
+            .chain(std::iter::once(&canonical))
+            .map(|path| path.to_string_lossy().to_string())
+            .collect();
+        return Err(anyhow!(
+            "Config files include each other: {}",
+            cycle.join(" -> ")
+        ));
+    }
+    let content = fs::read_to_string(path)?;
+    let mut value: toml::Value = toml::from_str(&content)
+        .map_err(|err| anyhow!("Failed to parse {}! error: {}", path.to_string_lossy(), err))?;
+    let includes: Vec<String> = value
+        .as_table_mut()
+        .and_then(|table| table.remove(INCLUDE_KEY))
+        .map(toml::Value::try_into)
+        .transpose()
+        .map_err(|err| {
+            anyhow!(
+                "{} of {} must be an array of paths! error: {}",
+                INCLUDE_KEY,
+                path.to_string_lossy(),
+                err
+            )
+        })?
+        .unwrap_or_default();
+    let dir = path.parent().unwrap_or_else(|| Path::new(""));
+    let mut merged = toml::Value::Table(toml::value::Table::new());
+    stack.push(canonical);
+    for include in includes {
+        let included = load_value(&dir.join(app::expand_src(&include)), stack)?;
+        merge(&mut merged, included);
+    }
+    stack.pop();
+    merge(&mut merged, value);
+    Ok(merged)
+}
+
+/// Merges `overrides` into `base`. Tables are merged recursively and other values of
+/// `overrides` replace ones of `base`.
+fn merge(base: &mut toml::Value, overrides: toml::Value) {
+    match (base, overrides) {
+        (toml::Value::Table(base), toml::Value::Table(overrides)) => {
+            for (key, value) in overrides {
+                match base.get_mut(&key) {
+                    Some(existing) => merge(existing, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, overrides) => *base = overrides,
+    }
+}
+
+fn unknown_key(key: &str) -> anyhow::Error {
+    anyhow!(
+        "Unknown key: {}! Available keys: {}",
+        key,
+        EDITABLE_KEYS.join(", ")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{Config, SymlinkStyle, WorkspaceOrigin};
+    use std::fs;
+    use std::path::PathBuf;
+
+    fn parse(content: &str) -> Config {
+        toml::from_str(content).unwrap()
+    }
+
+    #[test]
+    fn resolve_selected_repo() {
+        let config = parse(
+            r#"
+            default_repo = "personal"
+            [repos]
+            personal = "/home/user/dotfiles"
+            work = "/home/user/work-dotfiles"
+            "#,
+        );
+        assert_eq!(
+            config.resolve_workspace(Some("work")).unwrap(),
+            PathBuf::from("/home/user/work-dotfiles")
+        );
+        assert_eq!(
+            config.resolve_workspace(None).unwrap(),
+            PathBuf::from("/home/user/dotfiles")
+        );
+        assert!(config.resolve_workspace(Some("unknown")).is_err());
+    }
+
+    #[test]
+    fn resolve_workspace_key() {
+        let config = parse(r#"workspace = "/srv/dotfiles""#);
+        assert_eq!(
+            config.resolve_workspace(None).unwrap(),
+            PathBuf::from("/srv/dotfiles")
+        );
+    }
+
+    #[test]
+    fn resolve_workspace_precedence() {
+        let config = parse(
+            r#"
+            workspace = "/srv/dotfiles"
+            default_repo = "personal"
+            [repos]
+            personal = "/home/user/dotfiles"
+            work = "/home/user/work-dotfiles"
+            "#,
+        );
+        assert_eq!(
+            config.resolve_workspace_origin(Some("work")).unwrap(),
+            (
+                PathBuf::from("/home/user/work-dotfiles"),
+                WorkspaceOrigin::Repo("work".to_string())
+            )
+        );
+        assert_eq!(
+            config.resolve_workspace_origin(None).unwrap(),
+            (
+                PathBuf::from("/home/user/dotfiles"),
+                WorkspaceOrigin::DefaultRepo("personal".to_string())
+            )
+        );
+        let config = parse(r#"workspace = "/srv/dotfiles""#);
+        assert_eq!(
+            config.resolve_workspace_origin(None).unwrap(),
+            (PathBuf::from("/srv/dotfiles"), WorkspaceOrigin::Workspace)
+        );
+        assert_eq!(
+            Config::default().resolve_workspace_origin(None).unwrap().1,
+            WorkspaceOrigin::Default
+        );
+    }
+
+    #[test]
+    fn parse_permissions() {
+        let config = parse(
+            r#"
+            [[permissions]]
+            pattern = "ssh/*"
+            mode = "0600"
+            "#,
+        );
+        assert_eq!(config.permissions[0].pattern, "ssh/*");
+        assert_eq!(config.permissions[0].mode().unwrap(), 0o600);
+    }
+
+    #[test]
+    fn parse_symlink_style() {
+        assert_eq!(Config::default().symlink_style, SymlinkStyle::Absolute);
+        let config = parse(r#"symlink_style = "relative""#);
+        assert_eq!(config.symlink_style, SymlinkStyle::Relative);
+    }
+
+    #[test]
+    fn resolve_default() {
+        let workspace = Config::default().resolve_workspace(None).unwrap();
+        assert!(workspace.ends_with("dotfiles"));
+    }
+
+    #[test]
+    fn set_keeps_other_keys() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("dotman.toml");
+        let content = "# my config\nworkspace = \"~/dotfiles\"\nunknown_key = 1\n\n[repos]\nwork = \"~/work\"\n";
+        fs::write(&path, content).unwrap();
+        Config::set_in_file(&path, "symlink_style", "relative").unwrap();
+        Config::set_in_file(&path, "workspace", "~/dots").unwrap();
+        let written = fs::read_to_string(&path).unwrap();
+        assert!(written.starts_with("# my config\n"));
+        assert!(written.contains("unknown_key = 1"));
+        let config = Config::load_from(&path).unwrap();
+        assert_eq!(config.get("symlink_style").unwrap().unwrap(), "relative");
+        assert_eq!(config.get("workspace").unwrap().unwrap(), "~/dots");
+        assert_eq!(config.repos["work"], "~/work");
+        assert!(Config::set_in_file(&path, "symlink_style", "weird").is_err());
+        assert!(Config::set_in_file(&path, "unknown", "1").is_err());
+    }
+
+    #[test]
+    fn load_merges_includes() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(
+            dir.path().join("base.toml"),
+            "workspace = \"~/base\"\ngit_retries = 2\n\n[variables]\nname = \"base\"\nemail = \"base@example.com\"\n",
+        )
+        .unwrap();
+        let path = dir.path().join("dotman.toml");
+        fs::write(
+            &path,
+            "include = [\"base.toml\"]\nworkspace = \"~/main\"\n\n[variables]\nname = \"main\"\n",
+        )
+        .unwrap();
+        let config = Config::load_from(&path).unwrap();
+        assert_eq!(config.workspace.as_deref(), Some("~/main"));
+        assert_eq!(config.git_retries, 2);
+        assert_eq!(config.variables["name"], "main");
+        assert_eq!(config.variables["email"], "base@example.com");
+
+        fs::write(
+            dir.path().join("base.toml"),
+            "include = [\"dotman.toml\"]\n",
+        )
+        .unwrap();
+        let err = Config::load_from(&path).unwrap_err();
+        assert!(err.to_string().contains("include each other"));
+    }
+}
@@ -0,0 +1,125 @@
+use crate::app::MappingError;
+use serde::Serialize;
+use std::fmt;
+use std::io;
+
+/// Category of failure. Each kind has its own exit code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum ErrorKind {
+    Other,
+    SourceMissing,
+    SourceInvalid,
+    DestInvalid,
+    DestExists,
+    AlreadyManaged,
+    NotManaged,
+    Conflict,
+    Io,
+    Git,
+    Config,
+    BrokenLinks,
+}
+
+impl ErrorKind {
+    pub fn exit_code(self) -> i32 {
+        match self {
+            ErrorKind::Other => 1,
+            ErrorKind::SourceMissing => 3,
+            ErrorKind::SourceInvalid => 4,
+            ErrorKind::DestInvalid => 5,
+            ErrorKind::DestExists => 6,
+            ErrorKind::AlreadyManaged => 7,
+            ErrorKind::NotManaged => 8,
+            ErrorKind::Conflict => 9,
+            ErrorKind::Io => 10,
+            ErrorKind::Git => 11,
+            ErrorKind::Config => 12,
+            ErrorKind::BrokenLinks => 13,
+        }
+    }
+
+    /// Creates error of this kind.
+    pub fn error<S: Into<String>>(self, message: S) -> anyhow::Error {
+        Error {
+            kind: self,
+            message: message.into(),
+        }
+        .into()
+    }
+
+    /// Finds kind of `err`. Errors which are not created by `ErrorKind::error` are
+    /// classified by their type.
+    pub fn of(err: &anyhow::Error) -> Self {
+        if let Some(err) = err.downcast_ref::<Error>() {
+            return err.kind;
+        }
+        if let Some(err) = err.downcast_ref::<MappingError>() {
+            return match err {
+                MappingError::AlreadyExists(_) => ErrorKind::AlreadyManaged,
+                MappingError::NotFound(_) => ErrorKind::NotManaged,
+                MappingError::DuplicateDest { .. } => ErrorKind::DestExists,
+            };
+        }
+        if err.downcast_ref::<io::Error>().is_some() {
+            return ErrorKind::Io;
+        }
+        ErrorKind::Other
+    }
+}
+
+#[derive(Debug)]
+pub struct Error {
+    kind: ErrorKind,
+    message: String,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for Error {}
+
+#[derive(Debug, Serialize)]
+struct JsonError {
+    error: String,
+    kind: ErrorKind,
+}
+
+/// Formats `err` as `{"error": "...", "kind": "..."}`.
+pub fn to_json(err: &anyhow::Error) -> String {
+    serde_json::to_string(&JsonError {
+        error: err.to_string(),
+        kind: ErrorKind::of(err),
+    })
+    .expect("Failed to serialize error")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::app::MappingError;
+    use crate::error::{self, ErrorKind};
+
+    #[test]
+    fn kind_of_errors() {
+        let err = ErrorKind::SourceMissing.error("missing");
+        assert_eq!(ErrorKind::of(&err), ErrorKind::SourceMissing);
+        let err = anyhow::Error::from(MappingError::NotFound("~/.vimrc".to_string()));
+        assert_eq!(ErrorKind::of(&err), ErrorKind::NotManaged);
+        assert_eq!(ErrorKind::of(&anyhow!("other")), ErrorKind::Other);
+    }
+
+    #[test]
+    fn json_shape() {
+        let err = ErrorKind::DestInvalid.error("Dest: ../vimrc escapes the workspace!");
+        let json: serde_json::Value = serde_json::from_str(&error::to_json(&err)).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "error": "Dest: ../vimrc escapes the workspace!",
+                "kind": "DestInvalid",
+            })
+        );
+    }
+}
@@ -0,0 +1,73 @@
+use crate::error::ErrorKind;
+use anyhow::Result;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// Exclusive lock of a workspace, held while mappings may be modified.
+///
+/// Writers create the lock file before modifying mappings and remove it when they are
+/// done, so at most one instance writes at a time. Readers (e.g. `status`, `mappings`)
+/// never take the lock and are not blocked by writers. The mappings file is replaced by
+/// rename on save, so readers see either the old or the new file and never a partially
+/// written one.
+#[derive(Debug)]
+pub struct Lock {
+    path: PathBuf,
+}
+
+impl Lock {
+    /// Fails with `Conflict` if another instance holds the lock.
+    pub fn acquire<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        debug!("Acquiring lock: {}", path.to_string_lossy());
+        match OpenOptions::new().write(true).create_new(true).open(path) {
+            Ok(mut file) => {
+                writeln!(file, "{}", std::process::id())?;
+                Ok(Self {
+                    path: path.to_path_buf(),
+                })
+            }
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {
+                let holder = fs::read_to_string(path).unwrap_or_default();
+                Err(ErrorKind::Conflict.error(format!(
+                    "Another dotman (pid {}) is modifying the workspace! Remove {} if no other dotman is running.",
+                    holder.trim(),
+                    path.to_string_lossy()
+                )))
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        debug!("Releasing lock: {}", self.path.to_string_lossy());
+        if let Err(err) = fs::remove_file(&self.path) {
+            warn!(
+                "Failed to remove lock: {} error: {}",
+                self.path.to_string_lossy(),
+                err
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::ErrorKind;
+    use crate::lock::Lock;
+
+    #[test]
+    fn acquire_is_exclusive() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(".dotman.lock");
+        let lock = Lock::acquire(&path).unwrap();
+        let err = Lock::acquire(&path).unwrap_err();
+        assert_eq!(ErrorKind::of(&err), ErrorKind::Conflict);
+        drop(lock);
+        assert!(!path.exists());
+        Lock::acquire(&path).unwrap();
+    }
+}
@@ -6,57 +6,1423 @@ extern crate log;
 extern crate clap;
 
 mod app;
+mod audit;
+mod backup;
+mod completions;
+mod config;
+mod error;
+mod lock;
+mod oplog;
+mod progress;
+mod state;
+mod version;
 
-use app::App;
-use clap::{App as ClapApp, AppSettings, Arg, SubCommand};
+use app::{
+    App, ConflictPolicy, DestCase, DestCollision, DoctorFix, ErrorPolicy, ExportFormat,
+    FilterField, IfExists, MappingFilter, MappingsSort, RestoreMode, StatusFormat, StatusOptions,
+    TimestampPosition,
+};
+use clap::{App as ClapApp, AppSettings, Arg, ArgGroup, Shell, SubCommand};
+use config::Config;
+use error::ErrorKind;
+use progress::Progress;
+use std::env;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+use std::time::Duration;
 use std::vec::Vec;
 
 fn main() {
     pretty_env_logger::init();
-    let m = ClapApp::new("dotman")
+    let m = build_cli().get_matches();
+    let json = m.is_present("json");
+    if let Some(sub_m) = m.subcommand_matches("completions") {
+        if let Err(err) = completions_command(sub_m) {
+            exit_with_error(&err, json);
+        }
+        return;
+    }
+    if let Some(sub_m) = m.subcommand_matches("version") {
+        if sub_m.is_present("json") {
+            match serde_json::to_string(&version::BUILD_INFO) {
+                Ok(info) => println!("{}", info),
+                Err(err) => exit_with_error(&err.into(), json),
+            }
+        } else {
+            println!("{}", version::BUILD_INFO);
+        }
+        return;
+    }
+    if let Some(sub_m) = m.subcommand_matches("config") {
+        // Handled before creating App so that a broken config can be fixed
+        if let Err(err) = config_command(sub_m) {
+            exit_with_error(&ErrorKind::Config.error(err.to_string()), json);
+        }
+        return;
+    }
+    if m.is_present("print-workspace") {
+        if let Err(err) = print_workspace(m.value_of("repo")) {
+            exit_with_error(&ErrorKind::Config.error(err.to_string()), json);
+        }
+        return;
+    }
+    let app = Config::load()
+        .and_then(|mut config| {
+            config.workspace_check |= m.is_present("workspace-check");
+            App::new(config, m.value_of("repo"))
+        })
+        .map_err(|err| ErrorKind::Config.error(err.to_string()));
+    let mut app = match app {
+        Ok(app) => app,
+        Err(err) => exit_with_error(&err, json),
+    };
+    if m.is_present("case-insensitive") {
+        app.set_case_insensitive(true);
+    }
+    if m.is_present("no-git") {
+        app.set_no_git(true);
+    }
+    if m.is_present("fail-fast") {
+        app.set_error_policy(ErrorPolicy::FailFast);
+    }
+    if m.is_present("progress-json") {
+        match progress_out(m.value_of("progress-fd")) {
+            Ok(out) => app.set_progress(Progress::new(out)),
+            Err(err) => exit_with_error(&err, json),
+        }
+    }
+    if let Some(retries) = m.value_of("retries") {
+        match retries.parse() {
+            Ok(retries) => app.set_git_retries(retries),
+            Err(err) => exit_with_error(&anyhow!("Invalid value for --retries: {}", err), json),
+        }
+    }
+    if let Some(profile) = m.value_of("profile") {
+        if let Err(err) = app.set_profile(profile) {
+            exit_with_error(&err, json);
+        }
+    }
+    if let Some(backup_dir) = m.value_of("backup-dir") {
+        app.set_backup_dir(app::normalize_path(backup_dir));
+    }
+    let result = match m.subcommand_name().unwrap_or("status") {
+        "mappings" => {
+            let sub_m = m.subcommand().1.unwrap();
+            let sort = value_t!(sub_m, "sort", MappingsSort).unwrap();
+            if sub_m.is_present("validate") {
+                app.validate_mappings()
+            } else if sub_m.is_present("sort-paths") {
+                app.sort_paths()
+            } else {
+                mapping_filter(sub_m).and_then(|filter| {
+                    let filter = filter.as_ref();
+                    match (sub_m.is_present("json"), sub_m.value_of_os("output")) {
+                        (true, Some(output)) => app::write_output(Path::new(output), |writer| {
+                            app.mappings_json(sort, filter, writer)
+                        }),
+                        (true, None) => {
+                            let stdout = io::stdout();
+                            app.mappings_json(sort, filter, &mut stdout.lock())
+                        }
+                        (false, _) => app.mappings(sub_m.is_present("print0"), sort, filter),
+                    }
+                })
+            }
+        }
+        "status" => {
+            let options = match m.subcommand_matches("status") {
+                Some(sub_m) => sub_m
+                    .value_of("interval")
+                    .unwrap()
+                    .parse()
+                    .map_err(|err| anyhow!("Invalid value for --interval: {}", err))
+                    .map(|interval| StatusOptions {
+                        count_only: sub_m.is_present("count-only"),
+                        fail_on_broken: sub_m.is_present("fail-on-broken"),
+                        verbose: sub_m.is_present("verbose"),
+                        workspace_relative: sub_m.is_present("workspace-relative"),
+                        tag: sub_m.value_of("tag").map(str::to_string),
+                        watch: Some(Duration::from_secs(interval))
+                            .filter(|_| sub_m.is_present("watch")),
+                        translate: sub_m.is_present("translate"),
+                        filter: None,
+                        format: sub_m
+                            .value_of("format")
+                            .map(|_| value_t!(sub_m, "format", StatusFormat).unwrap()),
+                    })
+                    .and_then(|options| {
+                        Ok(StatusOptions {
+                            filter: mapping_filter(sub_m)?,
+                            ..options
+                        })
+                    }),
+                None => Ok(StatusOptions::default()),
+            };
+            options.and_then(|options| app.status(&options))
+        }
+        "init" => {
+            let sub_m = m.subcommand().1.unwrap();
+            app.init(sub_m.is_present("gitignore"))
+        }
+        "restore" => {
+            let sub_m = m.subcommand().1.unwrap();
+            app.enable_restore_state();
+            app.restore(
+                sub_m.is_present("force"),
+                sub_m.value_of("tag"),
+                sub_m.is_present("full"),
+                if sub_m.is_present("reconcile") {
+                    RestoreMode::Reconcile
+                } else {
+                    RestoreMode::LinkMissingOnly
+                },
+            )
+        }
+        "restore-backup" => {
+            let sub_m = m.subcommand().1.unwrap();
+            match sub_m.value_of("prune").map(str::parse).transpose() {
+                Ok(prune) => app.restore_backup(sub_m.value_of("id"), prune),
+                Err(err) => Err(anyhow!("Invalid value for --prune: {}", err)),
+            }
+        }
+        "git" => {
+            let sub_m = m.subcommand().1.unwrap();
+            let args = sub_m.values_of_lossy("args").unwrap_or_else(Vec::new);
+            if sub_m.is_present("capture") {
+                app.git_captured(&args)
+            } else {
+                app.git(&args)
+            }
+        }
+        "diff" => {
+            let sub_m = m.subcommand().1.unwrap();
+            app.diff(sub_m.value_of("since"))
+        }
+        "history" => {
+            let sub_m = m.subcommand().1.unwrap();
+            source_arg(sub_m).and_then(|source| app.history(source, sub_m.value_of("since")))
+        }
+        "save" => {
+            let sub_m = m.subcommand().1.unwrap();
+            // `--timestamp` alone appends the timestamp
+            let timestamp = match sub_m.value_of("timestamp") {
+                Some(_) => Some(value_t!(sub_m, "timestamp", TimestampPosition).unwrap()),
+                None if sub_m.is_present("timestamp") => Some(TimestampPosition::Append),
+                None => None,
+            };
+            let message =
+                app::commit_message(sub_m.value_of("message"), timestamp, chrono::Utc::now());
+            app.save(
+                &message,
+                sub_m.is_present("push"),
+                sub_m.is_present("set-upstream"),
+            )
+        }
+        "unlink" => {
+            let sub_m = m.subcommand().1.unwrap();
+            match sub_m.value_of("tag") {
+                Some(tag) => app.unlink_tagged(tag, sub_m.is_present("force")),
+                None if sub_m.is_present("keep") => source_arg(sub_m).and_then(|source| {
+                    app.unlink_keep(source, sub_m.is_present("force"), &mut io::stderr())
+                }),
+                None if sub_m.is_present("detached-ok") => source_arg(sub_m).and_then(|source| {
+                    app.unlink_detached_ok(
+                        source,
+                        sub_m.value_of_os("to").map(Path::new),
+                        sub_m.is_present("force"),
+                        sub_m.is_present("remove-dest"),
+                    )
+                }),
+                None => source_arg(sub_m).and_then(|source| {
+                    app.unlink_to(
+                        source,
+                        sub_m.value_of_os("to").map(Path::new),
+                        sub_m.is_present("force"),
+                    )
+                }),
+            }
+        }
+        "link" if is_batch_link(m.subcommand().1.unwrap()) => {
+            let sub_m = m.subcommand().1.unwrap();
+            app.set_link_tags(sub_m.values_of_lossy("tag").unwrap_or_default());
+            app.set_link_after(&link_after(sub_m));
+            app.set_link_hardlink(sub_m.is_present("hardlink"));
+            if let Some(root) = sub_m.value_of("dest-root") {
+                app.set_dest_root(root);
+            }
+            app.set_dest_case(value_t!(sub_m, "dest-case", DestCase).unwrap());
+            let dir_mode = link_dir_mode(sub_m).map(|mode| app.set_link_dir_mode(mode));
+            let sources = match sub_m.value_of("source") {
+                _ if sub_m.is_present("dest") || sub_m.is_present("dest-ext") => {
+                    Err(ErrorKind::DestInvalid
+                        .error("Dest can't be specified when linking multiple files!"))
+                }
+                _ if ["recursive", "render", "link-at", "name"]
+                    .iter()
+                    .any(|name| sub_m.is_present(name)) =>
+                {
+                    Err(ErrorKind::SourceInvalid.error(
+                        "--recursive, --render, --link-at and --name can't be used when linking multiple files!",
+                    ))
+                }
+                Some(pattern) => app::expand_user(pattern)
+                    .and_then(|pattern| app::expand_glob(&pattern.to_string_lossy())),
+                None => io::stdin()
+                    .lock()
+                    .lines()
+                    .filter(|line| !matches!(line, Ok(line) if line.trim().is_empty()))
+                    .map(|line| app::expand_user(line?.trim()))
+                    .collect(),
+            };
+            dir_mode.and(sources).and_then(|sources| {
+                sub_m
+                    .value_of("strip-components")
+                    .map(str::parse)
+                    .transpose()
+                    .map_err(|err| anyhow!("Invalid value for --strip-components: {}", err))
+                    .and_then(|strip_components| {
+                        app.link_batch(
+                            &sources,
+                            sub_m.value_of("prefix").map(app::expand_src).as_deref(),
+                            strip_components,
+                            value_t!(sub_m, "if-exists", IfExists).unwrap(),
+                            value_t!(sub_m, "dest-collision", DestCollision).unwrap(),
+                        )
+                    })
+            })
+        }
+        "link" => {
+            let sub_m = m.subcommand().1.unwrap();
+            app.set_link_tags(sub_m.values_of_lossy("tag").unwrap_or_default());
+            app.set_link_after(&link_after(sub_m));
+            app.set_link_hardlink(sub_m.is_present("hardlink"));
+            if let Some(root) = sub_m.value_of("dest-root") {
+                app.set_dest_root(root);
+            }
+            app.set_dest_case(value_t!(sub_m, "dest-case", DestCase).unwrap());
+            let dir_mode = link_dir_mode(sub_m).map(|mode| app.set_link_dir_mode(mode));
+            let from_stdin = sub_m.value_of_os("source").unwrap() == "-";
+            // Equals to the source unless the source is stdin
+            let link_at = match (from_stdin, sub_m.value_of_os("link-at")) {
+                (true, None) => {
+                    Err(ErrorKind::SourceInvalid.error("--link-at is required when source is '-'!"))
+                }
+                (false, Some(_)) => Err(ErrorKind::SourceInvalid
+                    .error("--link-at can be used only when source is '-'!")),
+                (true, Some(link_at)) => app::expand_user(link_at),
+                (false, None) => source_arg(sub_m),
+            };
+            let dest = dir_mode.and(link_at).and_then(|link_at| {
+                sub_m
+                    .value_of("strip-components")
+                    .map(str::parse)
+                    .transpose()
+                    .map_err(|err| anyhow!("Invalid value for --strip-components: {}", err))
+                    .and_then(|strip_components| {
+                        app.resolve_dest(
+                            &link_at,
+                            sub_m.value_of("dest"),
+                            sub_m.is_present("mirror"),
+                            strip_components,
+                            sub_m.value_of("prefix").map(app::expand_src).as_deref(),
+                        )
+                    })
+                    .and_then(|dest| match sub_m.value_of("dest-ext") {
+                        Some(extension) => app::set_dest_extension(&dest, extension),
+                        None => Ok(dest),
+                    })
+                    .map(|dest| (link_at, dest))
+            });
+            let if_exists = value_t!(sub_m, "if-exists", IfExists).unwrap();
+            dest.and_then(|(link_at, dest)| {
+                if from_stdin {
+                    let stdin = io::stdin();
+                    app.link_from_reader(&mut stdin.lock(), &dest, link_at, if_exists)
+                } else if sub_m.is_present("recursive") {
+                    sub_m
+                        .value_of("depth")
+                        .map(str::parse)
+                        .transpose()
+                        .map_err(|err| anyhow!("Invalid value for --depth: {}", err))
+                        .and_then(|depth| app.link_recursive(link_at, &dest, depth, if_exists))
+                } else if sub_m.is_present("render") {
+                    app.link_rendered(link_at, &dest, if_exists)
+                } else if let Some(name) = sub_m.value_of_os("name") {
+                    app.link_named(link_at, name, &dest, if_exists)
+                } else {
+                    app.link(link_at, &dest, if_exists)
+                }
+            })
+        }
+        "which" => {
+            let sub_m = m.subcommand().1.unwrap();
+            source_arg(sub_m).and_then(|source| app.which(source, sub_m.is_present("print0")))
+        }
+        "resolve-dest" => {
+            let sub_m = m.subcommand().1.unwrap();
+            sub_m
+                .value_of("strip-components")
+                .map(str::parse)
+                .transpose()
+                .map_err(|err| anyhow!("Invalid value for --strip-components: {}", err))
+                .and_then(|strip_components| {
+                    app.resolve_dest(
+                        source_arg(sub_m)?,
+                        sub_m.value_of("dest"),
+                        sub_m.is_present("mirror"),
+                        strip_components,
+                        None,
+                    )
+                })
+                .and_then(|dest| app::print_path(&dest, sub_m.is_present("print0")))
+        }
+        "remap" => {
+            let sub_m = m.subcommand().1.unwrap();
+            app.remap(
+                sub_m.value_of("from").unwrap(),
+                sub_m.value_of("to").unwrap(),
+                sub_m.is_present("dry-run"),
+            )
+        }
+        "fix-permissions" => app.fix_permissions(),
+        "undo" => app.undo(),
+        "gc" => {
+            let sub_m = m.subcommand().1.unwrap();
+            app.gc(sub_m.is_present("dry-run"), sub_m.is_present("yes"))
+        }
+        "verify" => {
+            let sub_m = m.subcommand().1.unwrap();
+            app.verify(sub_m.is_present("fix"), sub_m.is_present("yes"))
+        }
+        "gitignore-sync" => app.gitignore_sync(),
+        "repair" => app.repair(),
+        "reconcile" => {
+            let sub_m = m.subcommand().1.unwrap();
+            let root = sub_m.value_of_os("root").map(app::expand_user).transpose();
+            root.and_then(|root| app.reconcile(root.as_deref(), sub_m.is_present("dry-run")))
+        }
+        "alias" => {
+            let sub_m = m.subcommand().1.unwrap();
+            match sub_m.value_of_os("remove") {
+                Some(alias) => {
+                    app::expand_user(alias).and_then(|alias| app.alias(None::<&Path>, alias))
+                }
+                None => source_arg(sub_m).and_then(|source| {
+                    app::expand_user(sub_m.value_of_os("alias").unwrap())
+                        .and_then(|alias| app.alias(Some(source), alias))
+                }),
+            }
+        }
+        "doctor" => {
+            let sub_m = m.subcommand().1.unwrap();
+            if sub_m.is_present("fix") {
+                // `--fix` without kinds enables every kind
+                let fixes = match values_t!(sub_m, "fix", DoctorFix) {
+                    Ok(fixes) if !fixes.is_empty() => fixes,
+                    _ => DoctorFix::ALL.to_vec(),
+                };
+                app.doctor_fix(&fixes)
+            } else {
+                app.doctor(sub_m.is_present("json"))
+            }
+        }
+        "repos" => {
+            app.repos();
+            Ok(())
+        }
+        "watch" => {
+            let sub_m = m.subcommand().1.unwrap();
+            match sub_m.value_of("interval").unwrap().parse() {
+                Ok(interval) => app.watch(Duration::from_secs(interval), sub_m.is_present("force")),
+                Err(err) => Err(anyhow!("Invalid value for --interval: {}", err)),
+            }
+        }
+        "import" => {
+            let sub_m = m.subcommand().1.unwrap();
+            let policy = if sub_m.is_present("prefer-theirs") {
+                Some(ConflictPolicy::PreferTheirs)
+            } else if sub_m.is_present("prefer-ours") {
+                Some(ConflictPolicy::PreferOurs)
+            } else if sub_m.is_present("fail") {
+                Some(ConflictPolicy::Fail)
+            } else {
+                None
+            };
+            let path = sub_m.value_of_os("path").unwrap();
+            if path == "-" {
+                let stdin = io::stdin();
+                app.import_from_reader(
+                    stdin.lock(),
+                    "stdin",
+                    sub_m.is_present("merge"),
+                    policy,
+                    sub_m.is_present("json"),
+                )
+            } else {
+                app.import(
+                    path,
+                    sub_m.is_present("merge"),
+                    policy,
+                    sub_m.is_present("json"),
+                )
+            }
+        }
+        "export" => {
+            let sub_m = m.subcommand().1.unwrap();
+            let path = sub_m
+                .value_of_os("output")
+                .or_else(|| sub_m.value_of_os("path"))
+                .unwrap();
+            let format = value_t!(sub_m, "format", ExportFormat).unwrap();
+            if path == "-" {
+                let stdout = io::stdout();
+                app.export(&mut stdout.lock(), format)
+            } else {
+                app::write_output(Path::new(path), |writer| app.export(writer, format))
+            }
+        }
+        unknown => panic!("'{}' IS UNKNOWN SUBCOMMAND!", unknown),
+    };
+    let finished = app.finish();
+    if let Err(err) = result.and(finished) {
+        exit_with_error(&err, json);
+    }
+}
+
+fn build_cli() -> ClapApp<'static, 'static> {
+    ClapApp::new("dotman")
         .author(crate_authors!())
         .version(crate_version!())
-        .subcommand(SubCommand::with_name("mappings").about("Prints list of mappings"))
+        .arg(
+            Arg::with_name("backup-dir")
+                .long("backup-dir")
+                .takes_value(true)
+                .help("Stores snapshots of files before link and unlink into this directory"),
+        )
+        .arg(
+            Arg::with_name("json")
+                .long("json")
+                .help("Prints errors as JSON to stderr"),
+        )
+        .arg(
+            Arg::with_name("repo")
+                .long("repo")
+                .takes_value(true)
+                .help("Selects workspace from repos in config"),
+        )
+        .arg(
+            Arg::with_name("profile")
+                .long("profile")
+                .takes_value(true)
+                .help("Loads .file_mappings.<profile>.json on top of the mappings and saves changed entries into it"),
+        )
+        .arg(
+            Arg::with_name("print-workspace")
+                .long("print-workspace")
+                .help("Prints the workspace resolved from config and --repo, then exits"),
+        )
+        .arg(
+            Arg::with_name("retries")
+                .long("retries")
+                .value_name("N")
+                .takes_value(true)
+                .help("Retries network git operations up to N times on network errors"),
+        )
+        .arg(
+            Arg::with_name("case-insensitive")
+                .long("case-insensitive")
+                .help("Treats sources which differ only in case as the same file"),
+        )
+        .arg(
+            Arg::with_name("no-git")
+                .long("no-git")
+                .help("Manages symlinks without git, for workspaces not under version control"),
+        )
+        .arg(
+            Arg::with_name("workspace-check")
+                .long("workspace-check")
+                .help("Fails if the workspace is not an existing directory before running the command"),
+        )
+        .arg(
+            Arg::with_name("fail-fast")
+                .long("fail-fast")
+                .help("Aborts restore and batch link or unlink at the first failure instead of processing the rest"),
+        )
+        .arg(
+            Arg::with_name("progress-json")
+                .long("progress-json")
+                .help("Prints a JSON line to stdout per file processed by restore and batch or recursive link"),
+        )
+        .arg(
+            Arg::with_name("progress-fd")
+                .long("progress-fd")
+                .value_name("FD")
+                .requires("progress-json")
+                .help("Writes --progress-json events to the open file descriptor FD instead of stdout, e.g. 3. Unix only"),
+        )
+        .subcommand(
+            SubCommand::with_name("mappings")
+                .about("Prints list of mappings")
+                .arg(
+                    Arg::with_name("print0")
+                        .long("print0")
+                        .help("Prints NUL-terminated 'dest\\0src\\0' records"),
+                )
+                .arg(
+                    Arg::with_name("validate")
+                        .long("validate")
+                        .conflicts_with_all(&["print0", "json", "filter"])
+                        .help("Checks the mappings file and prints only problems. Fails if there are any"),
+                )
+                .arg(
+                    Arg::with_name("sort-paths")
+                        .long("sort-paths")
+                        .conflicts_with_all(&["print0", "json", "filter", "validate"])
+                        .help("Rewrites the mappings file in canonical order for minimal diffs"),
+                )
+                .arg(
+                    Arg::with_name("sort")
+                        .long("sort")
+                        .takes_value(true)
+                        .possible_values(&["source", "dest", "date"])
+                        .default_value("source")
+                        .help("Orders by source, dest or the time when the file was linked"),
+                )
+                .arg(
+                    Arg::with_name("json")
+                        .long("json")
+                        .conflicts_with("print0")
+                        .help("Prints mappings as JSON"),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .long("output")
+                        .value_name("path")
+                        .takes_value(true)
+                        .requires("json")
+                        .help("Writes JSON to the file instead of stdout"),
+                )
+                .arg(
+                    Arg::with_name("filter")
+                        .long("filter")
+                        .value_name("glob")
+                        .takes_value(true)
+                        .help("Shows only entries whose source or dest matches the glob"),
+                )
+                .arg(
+                    Arg::with_name("filter-field")
+                        .long("filter-field")
+                        .takes_value(true)
+                        .possible_values(&["source", "dest", "any"])
+                        .default_value("any")
+                        .help("Field which --filter matches against"),
+                ),
+        )
         .subcommand(
-            SubCommand::with_name("status").about("Executes 'git status' in dotfiles folder"),
+            SubCommand::with_name("status")
+                .about("Executes 'git status' in dotfiles folder")
+                .arg(
+                    Arg::with_name("filter")
+                        .long("filter")
+                        .value_name("glob")
+                        .takes_value(true)
+                        .help("Shows only entries whose source or dest matches the glob"),
+                )
+                .arg(
+                    Arg::with_name("filter-field")
+                        .long("filter-field")
+                        .takes_value(true)
+                        .possible_values(&["source", "dest", "any"])
+                        .default_value("any")
+                        .help("Field which --filter matches against"),
+                )
+                .arg(
+                    Arg::with_name("count-only")
+                        .long("count-only")
+                        .help("Prints only the number of mapped files"),
+                )
+                .arg(
+                    Arg::with_name("verbose")
+                        .long("verbose")
+                        .short("v")
+                        .help("Prints actual target of each symlink alongside the expected dest"),
+                )
+                .arg(
+                    Arg::with_name("workspace-relative")
+                        .long("workspace-relative")
+                        .help("Shows sources relative to home and dests relative to the workspace"),
+                )
+                .arg(
+                    Arg::with_name("fail-on-broken")
+                        .long("fail-on-broken")
+                        .help("Prints health of mapped files and fails if any of them is broken"),
+                )
+                .arg(
+                    Arg::with_name("tag")
+                        .long("tag")
+                        .takes_value(true)
+                        .help("Prints health of mapped files which have the tag"),
+                )
+                .arg(
+                    Arg::with_name("watch")
+                        .long("watch")
+                        .conflicts_with_all(&["count-only", "verbose", "fail-on-broken"])
+                        .help("Redraws health of mapped files until Ctrl-C is pressed"),
+                )
+                .arg(
+                    Arg::with_name("interval")
+                        .long("interval")
+                        .takes_value(true)
+                        .default_value("2")
+                        .help("Seconds between redraws of --watch"),
+                )
+                .arg(
+                    Arg::with_name("translate")
+                        .long("translate")
+                        .conflicts_with_all(&["count-only", "verbose", "fail-on-broken", "watch", "tag"])
+                        .help("Prints 'git status --porcelain' with paths of linked files instead of workspace paths"),
+                )
+                .arg(
+                    Arg::with_name("format")
+                        .long("format")
+                        .takes_value(true)
+                        .possible_values(&["porcelain=v2"])
+                        .conflicts_with_all(&["count-only", "verbose", "watch", "translate"])
+                        .help("Prints health of mapped files in the stable format for scripts"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("init")
+                .about("Initializes dotfiles folder as a git repository")
+                .arg(
+                    Arg::with_name("gitignore")
+                        .long("gitignore")
+                        .help("Also creates a .gitignore"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("restore")
+                .about("Creates symlinks of mapped files which do not exist, e.g. on a new machine")
+                .arg(
+                    Arg::with_name("force")
+                        .long("force")
+                        .help("Moves existing files aside and replaces them with symlinks"),
+                )
+                .arg(
+                    Arg::with_name("tag")
+                        .long("tag")
+                        .takes_value(true)
+                        .help("Restores only mapped files which have the tag"),
+                )
+                .arg(
+                    Arg::with_name("full")
+                        .long("full")
+                        .help("Checks every mapped file, including ones which were healthy on the last run"),
+                )
+                .arg(
+                    Arg::with_name("link-missing-only")
+                        .long("link-missing-only")
+                        .help("Creates only symlinks which do not exist. This is the default"),
+                )
+                .arg(
+                    Arg::with_name("reconcile")
+                        .long("reconcile")
+                        .conflicts_with("link-missing-only")
+                        .help("Also replaces detached files and symlinks which point elsewhere. Detached files are moved to <source>.dotman-backup"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("restore-backup")
+                .about("Lists snapshots, or restores the specified snapshot")
+                .arg(Arg::with_name("id"))
+                .arg(
+                    Arg::with_name("prune")
+                        .long("prune")
+                        .value_name("keep")
+                        .takes_value(true)
+                        .help("Removes all but the newest <keep> snapshots"),
+                ),
         )
-        .subcommand(SubCommand::with_name("restore").about("Not implemented now"))
         .subcommand(
             SubCommand::with_name("git")
                 .about("Executes any subcommands of git in dotfiles folder")
                 .setting(AppSettings::TrailingVarArg)
+                .arg(
+                    Arg::with_name("capture")
+                        .long("capture")
+                        .help("Captures output of git and prints it after git exited"),
+                )
                 .arg(Arg::with_name("args").required(false).multiple(true)),
         )
-        .subcommand(SubCommand::with_name("unlink")
-            .about("Unlink mapped file")
-            .arg(Arg::with_name("source")))
+        .subcommand(
+            SubCommand::with_name("diff")
+                .about("Executes 'git diff' in dotfiles folder")
+                .arg(
+                    Arg::with_name("since")
+                        .long("since")
+                        .value_name("ref")
+                        .takes_value(true)
+                        .help("Compares with a ref or a date (YYYY-MM-DD)"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("history")
+                .about("Executes 'git log' for the dest of mapped file")
+                .arg(Arg::with_name("source").required(true))
+                .arg(
+                    Arg::with_name("since")
+                        .long("since")
+                        .value_name("ref")
+                        .takes_value(true)
+                        .help("Shows only commits after a ref or a date (YYYY-MM-DD)"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("save")
+                .about("Commits all changes in dotfiles folder")
+                .arg(
+                    Arg::with_name("message")
+                        .short("m")
+                        .long("message")
+                        .takes_value(true)
+                        .help("Commit message. Defaults to 'Update dotfiles'"),
+                )
+                .arg(
+                    Arg::with_name("timestamp")
+                        .long("timestamp")
+                        .value_name("position")
+                        .takes_value(true)
+                        .min_values(0)
+                        .require_equals(true)
+                        .possible_values(&["append", "prepend"])
+                        .help("Adds the current time to the message, or uses 'dotman: update <time>' without --message"),
+                )
+                .arg(
+                    Arg::with_name("push")
+                        .long("push")
+                        .help("Pushes after committing"),
+                )
+                .arg(
+                    Arg::with_name("set-upstream")
+                        .long("set-upstream")
+                        .requires("push")
+                        .help(
+                            "Pushes to origin and sets upstream if current branch has no upstream",
+                        ),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("unlink")
+                .about("Unlink mapped file")
+                .arg(Arg::with_name("source").required_unless("tag"))
+                .arg(
+                    Arg::with_name("tag")
+                        .long("tag")
+                        .takes_value(true)
+                        .conflicts_with("source")
+                        .help("Unlinks all mapped files which have the tag"),
+                )
+                .arg(
+                    Arg::with_name("to")
+                        .long("to")
+                        .value_name("path")
+                        .takes_value(true)
+                        .conflicts_with("tag")
+                        .help("Restores the file at the path instead of the symlink location"),
+                )
+                .arg(
+                    Arg::with_name("keep")
+                        .long("keep")
+                        .conflicts_with_all(&["tag", "to"])
+                        .help("Removes the mapping only. The symlink and the workspace file are kept"),
+                )
+                .arg(
+                    Arg::with_name("detached-ok")
+                        .long("detached-ok")
+                        .conflicts_with_all(&["tag", "keep"])
+                        .help("If the symlink was replaced by a regular file, removes the mapping and keeps the file"),
+                )
+                .arg(
+                    Arg::with_name("remove-dest")
+                        .long("remove-dest")
+                        .requires("detached-ok")
+                        .help("Also removes the workspace copy of a detached file"),
+                )
+                .arg(
+                    Arg::with_name("force")
+                        .long("force")
+                        .help("Unlinks even if the symlink does not point to the mapped dest"),
+                ),
+        )
         .subcommand(
             SubCommand::with_name("link")
                 .about("Link specified file")
-                .arg(Arg::with_name("source"))
-                .arg(Arg::with_name("dest")),
-        )
-        .get_matches();
-    let mut app = App::new().unwrap();
-    match m.subcommand_name().unwrap_or_else(|| "status") {
-        "mappings" => app.mappings(),
-        "status" => app.status(),
-        "restore" => app.restore(),
-        "git" => {
-            let sub_m = m.subcommand().1.unwrap();
-            app.git(&sub_m.values_of_lossy("args").unwrap_or_else(Vec::new));
-        }
-        "unlink" => {
-            let sub_m = m.subcommand().1.unwrap();
-            app.unlink(sub_m.value_of("source").unwrap());
+                .arg(
+                    Arg::with_name("source")
+                        .required_unless("stdin")
+                        .help("File or glob to link, or '-' to read contents from stdin"),
+                )
+                .arg(Arg::with_name("dest"))
+                .arg(
+                    Arg::with_name("mirror")
+                        .long("mirror")
+                        .conflicts_with("dest")
+                        .help("Derives dest from the home-relative path of source"),
+                )
+                .arg(
+                    Arg::with_name("dest-root")
+                        .long("dest-root")
+                        .value_name("subdir")
+                        .takes_value(true)
+                        .help("Places dests under the directory in the workspace. Overrides dest_root in config"),
+                )
+                .arg(
+                    Arg::with_name("dest-case")
+                        .long("dest-case")
+                        .takes_value(true)
+                        .possible_values(&["keep", "lower"])
+                        .default_value("keep")
+                        .help("Case of dests derived from sources. The symlinks keep their names"),
+                )
+                .arg(
+                    Arg::with_name("stdin")
+                        .long("stdin")
+                        .conflicts_with_all(&["source", "dest", "recursive", "render", "link-at"])
+                        .help("Reads sources to link from stdin, one per line"),
+                )
+                .arg(
+                    Arg::with_name("tag")
+                        .long("tag")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help("Attaches the tag to the mapping. Can be repeated"),
+                )
+                .arg(
+                    Arg::with_name("after")
+                        .long("after")
+                        .value_name("source")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help("Makes 'restore' link this after the source. Can be repeated"),
+                )
+                .arg(
+                    Arg::with_name("prefix")
+                        .long("prefix")
+                        .value_name("path")
+                        .takes_value(true)
+                        .conflicts_with("dest")
+                        .help("Derives dest from the path relative to this instead of home"),
+                )
+                .arg(
+                    Arg::with_name("strip-components")
+                        .long("strip-components")
+                        .value_name("N")
+                        .takes_value(true)
+                        .conflicts_with("dest")
+                        .help("Removes N leading components from the derived dest"),
+                )
+                .arg(
+                    Arg::with_name("dest-ext")
+                        .long("dest-ext")
+                        .value_name("ext")
+                        .takes_value(true)
+                        .conflicts_with("recursive")
+                        .help("Sets extension of the dest. The symlink keeps the original name"),
+                )
+                .arg(
+                    Arg::with_name("recursive")
+                        .long("recursive")
+                        .short("r")
+                        .conflicts_with_all(&["render", "link-at"])
+                        .help("Links files in the source directory one by one"),
+                )
+                .arg(
+                    Arg::with_name("depth")
+                        .long("depth")
+                        .value_name("N")
+                        .takes_value(true)
+                        .requires("recursive")
+                        .help("Links directories below N levels as a whole instead of their files"),
+                )
+                .arg(
+                    Arg::with_name("render").long("render").help(
+                        "Writes the file rendered with variables in config instead of symlink",
+                    ),
+                )
+                .arg(
+                    Arg::with_name("name")
+                        .long("name")
+                        .takes_value(true)
+                        .conflicts_with_all(&["recursive", "render", "link-at"])
+                        .help("Creates the symlink with this file name in the directory of the source, e.g. 'config' for 'config.example'"),
+                )
+                .arg(
+                    Arg::with_name("hardlink")
+                        .long("hardlink")
+                        .conflicts_with("render")
+                        .help("Creates a hard link instead of symlink. The source must be on the same filesystem as the workspace"),
+                )
+                .arg(
+                    Arg::with_name("dir-mode")
+                        .long("dir-mode")
+                        .value_name("mode")
+                        .takes_value(true)
+                        .help("Octal mode of directories created for the dest, e.g. 700. Unix only"),
+                )
+                .arg(
+                    Arg::with_name("link-at")
+                        .long("link-at")
+                        .takes_value(true)
+                        .help("Where to create the symlink when source is '-'"),
+                )
+                .arg(
+                    Arg::with_name("if-exists")
+                        .long("if-exists")
+                        .takes_value(true)
+                        .possible_values(&["skip", "error", "overwrite"])
+                        .default_value("error")
+                        .help("What to do when the entry or the dest file already exists"),
+                )
+                .arg(
+                    Arg::with_name("dest-collision")
+                        .long("dest-collision")
+                        .takes_value(true)
+                        .possible_values(&["error", "skip", "suffix"])
+                        .default_value("error")
+                        .help("What to do when multiple sources get the same dest. 'suffix' appends -1, -2, ..."),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("which")
+                .about("Prints path of the workspace file which source is linked to")
+                .arg(Arg::with_name("source").required(true))
+                .arg(
+                    Arg::with_name("print0")
+                        .long("print0")
+                        .help("Terminates the path with NUL instead of newline"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("resolve-dest")
+                .about("Prints dest which 'link' would use for source")
+                .arg(Arg::with_name("source").required(true))
+                .arg(Arg::with_name("dest"))
+                .arg(
+                    Arg::with_name("mirror")
+                        .long("mirror")
+                        .conflicts_with("dest")
+                        .help("Derives dest from the home-relative path of source"),
+                )
+                .arg(
+                    Arg::with_name("strip-components")
+                        .long("strip-components")
+                        .value_name("N")
+                        .takes_value(true)
+                        .conflicts_with("dest")
+                        .help("Removes N leading components from the derived dest"),
+                )
+                .arg(
+                    Arg::with_name("print0")
+                        .long("print0")
+                        .help("Terminates the path with NUL instead of newline"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("remap")
+                .about("Rewrites dests matching a regex and moves the workspace files")
+                .arg(
+                    Arg::with_name("from")
+                        .long("from")
+                        .takes_value(true)
+                        .required(true)
+                        .help("Regex matched against dests"),
+                )
+                .arg(
+                    Arg::with_name("to")
+                        .long("to")
+                        .takes_value(true)
+                        .required(true)
+                        .help("Replacement. '$1' refers to the first capture group"),
+                )
+                .arg(
+                    Arg::with_name("dry-run")
+                        .long("dry-run")
+                        .help("Prints changes without applying them"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("fix-permissions")
+                .about("Applies modes in config to workspace files (unix only)"),
+        )
+        .subcommand(
+            SubCommand::with_name("gitignore-sync")
+                .about("Adds or removes internal files in .gitignore as configured"),
+        )
+        .subcommand(
+            SubCommand::with_name("gc")
+                .about("Deletes workspace files which are not dest of any mapping")
+                .arg(
+                    Arg::with_name("dry-run")
+                        .long("dry-run")
+                        .help("Prints files without deleting them"),
+                )
+                .arg(
+                    Arg::with_name("yes")
+                        .long("yes")
+                        .short("y")
+                        .help("Deletes without confirmation"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("verify")
+                .about("Reports dests whose content differs from the hash recorded on link")
+                .arg(
+                    Arg::with_name("fix")
+                        .long("fix")
+                        .help("Updates recorded hashes to the current content"),
+                )
+                .arg(
+                    Arg::with_name("yes")
+                        .long("yes")
+                        .short("y")
+                        .requires("fix")
+                        .help("Updates without confirmation"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("repair")
+                .about("Removes entries whose dest is already mapped from another source"),
+        )
+        .subcommand(
+            SubCommand::with_name("alias")
+                .about("Adds another path which refers to a managed file")
+                .arg(
+                    Arg::with_name("source")
+                        .help("Managed file")
+                        .required_unless("remove"),
+                )
+                .arg(
+                    Arg::with_name("alias")
+                        .help("Path which is looked up as the managed file. e.g. a path through a symlinked directory")
+                        .required_unless("remove"),
+                )
+                .arg(
+                    Arg::with_name("remove")
+                        .long("remove")
+                        .value_name("alias")
+                        .takes_value(true)
+                        .conflicts_with_all(&["source", "alias"])
+                        .help("Removes the alias"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("reconcile")
+                .about("Adds entries for existing symlinks which point into the workspace")
+                .arg(
+                    Arg::with_name("root")
+                        .long("root")
+                        .takes_value(true)
+                        .help("Directory to search symlinks in. Defaults to home"),
+                )
+                .arg(
+                    Arg::with_name("dry-run")
+                        .long("dry-run")
+                        .help("Prints entries to be added without adding them"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("doctor")
+                .about("Reports health of every entry and the ratio of healthy entries")
+                .arg(
+                    Arg::with_name("json")
+                        .long("json")
+                        .help("Prints the report as JSON for dashboards"),
+                )
+                .arg(
+                    Arg::with_name("fix")
+                        .long("fix")
+                        .value_name("kinds")
+                        .takes_value(true)
+                        .min_values(0)
+                        .require_equals(true)
+                        .use_delimiter(true)
+                        .possible_values(&["prune", "relink", "recreate"])
+                        .conflicts_with("json")
+                        .help("Repairs problems which can be fixed safely. Limited to the comma-separated kinds if specified"),
+                ),
+        )
+        .subcommand(SubCommand::with_name("undo").about("Reverts the last link, unlink or remap"))
+        .subcommand(
+            SubCommand::with_name("config")
+                .about("Reads or writes config file")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    SubCommand::with_name("get")
+                        .about("Prints value of the key")
+                        .arg(
+                            Arg::with_name("key")
+                                .required(true)
+                                .possible_values(config::EDITABLE_KEYS),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("set")
+                        .about("Sets value of the key, keeping other contents")
+                        .arg(
+                            Arg::with_name("key")
+                                .required(true)
+                                .possible_values(config::EDITABLE_KEYS),
+                        )
+                        .arg(Arg::with_name("value").required(true)),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("completions")
+                .about("Prints completion script, or installs it with --install")
+                .arg(
+                    Arg::with_name("shell")
+                        .long("shell")
+                        .takes_value(true)
+                        .possible_values(&Shell::variants())
+                        .help("Shell to generate completion for. Detected from $SHELL by default"),
+                )
+                .arg(
+                    Arg::with_name("install")
+                        .long("install")
+                        .help("Writes the script to the conventional location of the shell"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("version")
+                .about("Prints version, commit, rustc version and target of this build")
+                .arg(
+                    Arg::with_name("json")
+                        .long("json")
+                        .help("Prints as a JSON object, e.g. for bug reports"),
+                ),
+        )
+        .subcommand(SubCommand::with_name("repos").about("Prints list of configured repos"))
+        .subcommand(
+            SubCommand::with_name("watch")
+                .about("Watches mapped files and re-links them when they are replaced")
+                .arg(
+                    Arg::with_name("interval")
+                        .long("interval")
+                        .takes_value(true)
+                        .default_value("2")
+                        .help("Seconds between checks"),
+                )
+                .arg(
+                    Arg::with_name("force")
+                        .long("force")
+                        .help("Replaces workspace copies without --backup-dir"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("import")
+                .about("Imports mappings from another mappings file or workspace")
+                .arg(
+                    Arg::with_name("path")
+                        .required(true)
+                        .help("Mappings file or workspace to import, or '-' to read from stdin"),
+                )
+                .arg(
+                    Arg::with_name("merge")
+                        .long("merge")
+                        .help("Merges into existing mappings and prints a report"),
+                )
+                .arg(
+                    Arg::with_name("prefer-theirs")
+                        .long("prefer-theirs")
+                        .help("Resolves conflicts using imported dests"),
+                )
+                .arg(
+                    Arg::with_name("prefer-ours")
+                        .long("prefer-ours")
+                        .help("Resolves conflicts by keeping current dests"),
+                )
+                .arg(
+                    Arg::with_name("fail")
+                        .long("fail")
+                        .help("Aborts if there are any conflicts"),
+                )
+                .group(ArgGroup::with_name("policy").args(&[
+                    "prefer-theirs",
+                    "prefer-ours",
+                    "fail",
+                ]))
+                .arg(
+                    Arg::with_name("json")
+                        .long("json")
+                        .help("Prints the report as JSON"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("export")
+                .about("Writes mappings in the format which 'import' reads")
+                .arg(
+                    Arg::with_name("path")
+                        .required_unless("output")
+                        .help("File to write, or '-' to write to stdout"),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .long("output")
+                        .value_name("path")
+                        .takes_value(true)
+                        .conflicts_with("path")
+                        .help("File to write. Same as the positional path"),
+                )
+                .arg(
+                    Arg::with_name("format")
+                        .long("format")
+                        .takes_value(true)
+                        .possible_values(&["json", "shell"])
+                        .default_value("json")
+                        .help("'shell' writes 'dotman link' commands which recreate the mappings"),
+                ),
+        )
+}
+
+fn completions_command(m: &clap::ArgMatches) -> anyhow::Result<()> {
+    let shell = match m.value_of("shell") {
+        Some(shell) => shell.parse().map_err(|err: String| anyhow!(err))?,
+        None => completions::detect_shell(env::var_os("SHELL").as_deref())?,
+    };
+    let mut script = Vec::new();
+    build_cli().gen_completions_to("dotman", shell, &mut script);
+    if !m.is_present("install") {
+        io::stdout().write_all(&script)?;
+        return Ok(());
+    }
+    let home = dirs::home_dir().ok_or_else(|| anyhow!("Cannot retrieve home directory"))?;
+    let path = completions::install_path(shell, &home)?;
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(&path, script)?;
+    println!(
+        "Installed completion for {} to {}",
+        shell.to_string().to_lowercase(),
+        path.to_string_lossy()
+    );
+    if let Shell::Zsh = shell {
+        println!(
+            "Add 'fpath=({} $fpath)' to .zshrc before compinit if it is not in fpath yet.",
+            path.parent().unwrap().to_string_lossy()
+        );
+    }
+    Ok(())
+}
+
+fn config_command(m: &clap::ArgMatches) -> anyhow::Result<()> {
+    match m.subcommand() {
+        ("get", Some(sub_m)) => {
+            let key = sub_m.value_of("key").unwrap();
+            match Config::load()?.get(key)? {
+                Some(value) => println!("{}", value),
+                None => return Err(anyhow!("{} is not set", key)),
+            }
         }
-        "link" => {
-            let sub_m = m.subcommand().1.unwrap();
-            app.link(
-                sub_m.value_of("source").unwrap(),
-                sub_m.value_of("dest").unwrap(),
-            );
+        ("set", Some(sub_m)) => {
+            let path = Config::path()?;
+            let key = sub_m.value_of("key").unwrap();
+            let value = sub_m.value_of("value").unwrap();
+            Config::set_in_file(&path, key, value)?;
+            println!("Set {} to {} in {}", key, value, path.to_string_lossy());
         }
-        unknown => panic!("'{}' IS UNKNOWN SUBCOMMAND!", unknown),
+        _ => unreachable!(),
+    }
+    Ok(())
+}
+
+/// Sources of `--after` with leading `~` expanded.
+fn link_after(m: &clap::ArgMatches) -> Vec<std::path::PathBuf> {
+    m.values_of("after")
+        .map(|sources| sources.map(app::expand_src).collect())
+        .unwrap_or_default()
+}
+
+/// Value of `source` argument of `m` with leading `~user` expanded.
+fn source_arg(m: &clap::ArgMatches) -> anyhow::Result<std::path::PathBuf> {
+    app::expand_user(m.value_of_os("source").unwrap())
+}
+
+/// Filter of `mappings` and `status`. `None` if `--filter` is not specified.
+fn mapping_filter(m: &clap::ArgMatches) -> anyhow::Result<Option<MappingFilter>> {
+    let field = value_t!(m, "filter-field", FilterField).unwrap();
+    m.value_of("filter")
+        .map(|pattern| MappingFilter::new(pattern, field))
+        .transpose()
+}
+
+/// Prints the resolved workspace to stdout and where it came from to stderr.
+fn print_workspace(repo: Option<&str>) -> anyhow::Result<()> {
+    let config_path = Config::path()?;
+    let (workspace, origin) = Config::load()?.resolve_workspace_origin(repo)?;
+    println!("{}", workspace.to_string_lossy());
+    eprintln!(
+        "Resolved from {} (config: {})",
+        origin,
+        config_path.to_string_lossy()
+    );
+    Ok(())
+}
+
+/// Octal mode of `--dir-mode`. `None` if it is not specified.
+fn link_dir_mode(m: &clap::ArgMatches) -> anyhow::Result<Option<u32>> {
+    m.value_of("dir-mode")
+        .map(|mode| u32::from_str_radix(mode, 8))
+        .transpose()
+        .map_err(|err| anyhow!("Invalid value for --dir-mode: {}", err))
+}
+
+/// Whether `link` takes sources from stdin or a glob instead of a single file.
+fn is_batch_link(m: &clap::ArgMatches) -> bool {
+    m.is_present("stdin") || m.value_of("source").is_some_and(app::is_glob)
+}
+
+/// Reports `err` and exits with the code of its kind.
+/// Destination of `--progress-json` events. Stdout unless `fd` is given.
+fn progress_out(fd: Option<&str>) -> anyhow::Result<Box<dyn Write>> {
+    let fd: u32 = match fd {
+        Some(fd) => fd
+            .parse()
+            .map_err(|err| anyhow!("Invalid value for --progress-fd: {}", err))?,
+        None => return Ok(Box::new(io::stdout())),
+    };
+    open_fd(fd)
+}
+
+/// Opens `fd` by its path instead of taking ownership of it, which fails cleanly if it is
+/// not open.
+#[cfg(unix)]
+fn open_fd(fd: u32) -> anyhow::Result<Box<dyn Write>> {
+    let file = fs::OpenOptions::new()
+        .append(true)
+        .open(format!("/dev/fd/{}", fd))
+        .map_err(|err| {
+            ErrorKind::Config.error(format!(
+                "File descriptor: {} is not open for writing! error: {}",
+                fd, err
+            ))
+        })?;
+    Ok(Box::new(file))
+}
+
+#[cfg(not(unix))]
+fn open_fd(fd: u32) -> anyhow::Result<Box<dyn Write>> {
+    Err(ErrorKind::Config.error(format!("--progress-fd {} is supported only on unix.", fd)))
+}
+
+fn exit_with_error(err: &anyhow::Error, json: bool) -> ! {
+    if json {
+        eprintln!("{}", error::to_json(err));
+    } else {
+        error!("{}", err);
     }
+    std::process::exit(ErrorKind::of(err).exit_code());
 }
@@ -0,0 +1,138 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// Dest of `source` was moved from `dest` to `new_dest`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Move {
+    pub source: String,
+    pub dest: String,
+    pub new_dest: String,
+}
+
+/// Operation which changed mappings. Sources are stored in the same form as mapping keys.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "operation", rename_all = "lowercase")]
+pub enum Operation {
+    Link {
+        source: String,
+        dest: String,
+    },
+    Unlink {
+        source: String,
+        dest: String,
+        /// Where the file was moved instead of `source`, by `unlink --to`
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        to: Option<String>,
+    },
+    Remap {
+        moves: Vec<Move>,
+    },
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Operation::Link { source, dest } => write!(f, "link {} -> {}", source, dest),
+            Operation::Unlink {
+                source,
+                dest,
+                to: None,
+            } => write!(f, "unlink {} -> {}", source, dest),
+            Operation::Unlink {
+                source,
+                dest,
+                to: Some(to),
+            } => write!(f, "unlink {} -> {} to {}", source, dest, to),
+            Operation::Remap { moves } => write!(f, "remap of {} dests", moves.len()),
+        }
+    }
+}
+
+/// Append-only log of operations. One JSON object per line.
+#[derive(Debug)]
+pub struct OpLog {
+    path: PathBuf,
+}
+
+impl OpLog {
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+        }
+    }
+
+    pub fn append(&self, operation: &Operation) -> Result<()> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        writeln!(file, "{}", serde_json::to_string(operation)?)?;
+        Ok(())
+    }
+
+    /// Returns the newest operation.
+    pub fn last(&self) -> Result<Option<Operation>> {
+        match self.lines()?.last() {
+            Some(line) => Ok(Some(serde_json::from_str(line).map_err(|err| {
+                anyhow!(
+                    "Operation log: {} is broken! error: {}",
+                    self.path.to_string_lossy(),
+                    err
+                )
+            })?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Removes the newest operation.
+    pub fn pop(&self) -> Result<()> {
+        let mut lines = self.lines()?;
+        lines.pop();
+        let content: String = lines.iter().map(|line| format!("{}\n", line)).collect();
+        fs::write(&self.path, content)?;
+        Ok(())
+    }
+
+    fn lines(&self) -> Result<Vec<String>> {
+        if !self.path.exists() {
+            return Ok(Vec::new());
+        }
+        Ok(fs::read_to_string(&self.path)?
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(str::to_string)
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::oplog::{OpLog, Operation};
+
+    #[test]
+    fn append_and_pop() {
+        let dir = tempfile::tempdir().unwrap();
+        let oplog = OpLog::new(dir.path().join("operations.jsonl"));
+        assert_eq!(oplog.last().unwrap(), None);
+        let link = Operation::Link {
+            source: "~/.vimrc".to_string(),
+            dest: "vimrc".to_string(),
+        };
+        let unlink = Operation::Unlink {
+            source: "~/.vimrc".to_string(),
+            dest: "vimrc".to_string(),
+            to: Some("~/vimrc.old".to_string()),
+        };
+        oplog.append(&link).unwrap();
+        oplog.append(&unlink).unwrap();
+        assert_eq!(oplog.last().unwrap(), Some(unlink));
+        oplog.pop().unwrap();
+        assert_eq!(oplog.last().unwrap(), Some(link));
+        oplog.pop().unwrap();
+        assert_eq!(oplog.last().unwrap(), None);
+    }
+}
@@ -0,0 +1,88 @@
+use anyhow::Result;
+use serde::Serialize;
+use std::cell::RefCell;
+use std::fmt;
+use std::io::Write;
+use std::path::Path;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Ok,
+    Skipped,
+    Error,
+}
+
+/// One line of the progress stream. e.g.
+/// `{"event":"link","source":"/home/user/.vimrc","dest":"vimrc","status":"ok"}`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Event<'a> {
+    /// Step of the operation. e.g. `link`, `restore`
+    pub event: &'a str,
+    pub source: String,
+    pub dest: &'a str,
+    pub status: Status,
+    /// Message of the error if `status` is `error`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Emits newline-delimited JSON events while loop-based commands (e.g. `restore`) run, so
+/// that frontends can follow them without parsing human-readable output.
+///
+/// Disabled by default. Nothing is written then.
+#[derive(Default)]
+pub struct Progress {
+    out: Option<RefCell<Box<dyn Write>>>,
+}
+
+impl fmt::Debug for Progress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Progress")
+            .field("enabled", &self.out.is_some())
+            .finish()
+    }
+}
+
+impl Progress {
+    pub fn new(out: Box<dyn Write>) -> Self {
+        Self {
+            out: Some(RefCell::new(out)),
+        }
+    }
+
+    /// Emits the outcome of one step.
+    pub fn result(&self, event: &str, source: &Path, dest: &str, result: &Result<()>) {
+        let (status, error) = match result {
+            Ok(_) => (Status::Ok, None),
+            Err(err) => (Status::Error, Some(err.to_string())),
+        };
+        self.emit(event, source, dest, status, error);
+    }
+
+    /// Emits that one step was not performed.
+    pub fn skipped(&self, event: &str, source: &Path, dest: &str) {
+        self.emit(event, source, dest, Status::Skipped, None);
+    }
+
+    fn emit(&self, event: &str, source: &Path, dest: &str, status: Status, error: Option<String>) {
+        let out = match &self.out {
+            Some(out) => out,
+            None => return,
+        };
+        let event = Event {
+            event,
+            source: source.to_string_lossy().to_string(),
+            dest,
+            status,
+            error,
+        };
+        let mut out = out.borrow_mut();
+        let written = serde_json::to_string(&event)
+            .map_err(anyhow::Error::from)
+            .and_then(|line| Ok(writeln!(out, "{}", line).and_then(|_| out.flush())?));
+        if let Err(err) = written {
+            warn!("Failed to write progress event error: {}", err);
+        }
+    }
+}
@@ -0,0 +1,70 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::Path;
+
+/// Entries which `restore` found healthy, so that the next run can skip checking them.
+///
+/// The state is valid only while the mappings file is unchanged. It describes this
+/// machine only, so it is kept out of the workspace.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RestoreState {
+    /// SHA-256 of the mappings file when the state was saved
+    pub mappings: String,
+    /// Sources of healthy entries
+    pub healthy: BTreeSet<String>,
+}
+
+impl RestoreState {
+    /// Loads state saved for the mappings file of hash `mappings`. Empty if the state
+    /// does not exist, is broken or was saved for other mappings.
+    pub fn load(path: &Path, mappings: &str) -> Self {
+        let state: Self = match fs::read_to_string(path)
+            .map_err(anyhow::Error::from)
+            .and_then(|content| Ok(serde_json::from_str(&content)?))
+        {
+            Ok(state) => state,
+            Err(err) => {
+                debug!(
+                    "Ignored restore state: {} error: {}",
+                    path.to_string_lossy(),
+                    err
+                );
+                return Self::default();
+            }
+        };
+        if state.mappings != mappings {
+            debug!("Restore state is stale since mappings were changed");
+            return Self::default();
+        }
+        state
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(path, serde_json::to_string(self)?)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::state::RestoreState;
+
+    #[test]
+    fn load_ignores_stale_state() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state/restore.json");
+        assert_eq!(RestoreState::load(&path, "abc"), RestoreState::default());
+        let state = RestoreState {
+            mappings: "abc".to_string(),
+            healthy: vec!["~/.vimrc".to_string()].into_iter().collect(),
+        };
+        state.save(&path).unwrap();
+        assert_eq!(RestoreState::load(&path, "abc"), state);
+        assert_eq!(RestoreState::load(&path, "def"), RestoreState::default());
+    }
+}
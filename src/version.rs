@@ -0,0 +1,52 @@
+use serde::Serialize;
+use std::fmt;
+
+/// Metadata of this build, embedded by `build.rs`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct BuildInfo {
+    pub version: &'static str,
+    /// Abbreviated commit hash, or `unknown` if built outside of a git repository
+    pub commit: &'static str,
+    /// e.g. `rustc 1.70.0 (90c541806 2023-05-31)`
+    pub rustc: &'static str,
+    /// e.g. `x86_64-unknown-linux-gnu`
+    pub target: &'static str,
+}
+
+pub const BUILD_INFO: BuildInfo = BuildInfo {
+    version: env!("CARGO_PKG_VERSION"),
+    commit: env!("DOTMAN_GIT_COMMIT"),
+    rustc: env!("DOTMAN_RUSTC_VERSION"),
+    target: env!("DOTMAN_TARGET"),
+};
+
+impl fmt::Display for BuildInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "dotman {} ({})", self.version, self.commit)?;
+        writeln!(f, "{}", self.rustc)?;
+        write!(f, "target: {}", self.target)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::version::BUILD_INFO;
+
+    #[test]
+    fn json_keys() {
+        let json = serde_json::to_value(&BUILD_INFO).unwrap();
+        let keys: Vec<&str> = json
+            .as_object()
+            .unwrap()
+            .keys()
+            .map(String::as_str)
+            .collect();
+        assert_eq!(keys, vec!["commit", "rustc", "target", "version"]);
+        assert_eq!(json["version"], env!("CARGO_PKG_VERSION"));
+        assert!(json
+            .as_object()
+            .unwrap()
+            .values()
+            .all(|value| value.as_str().is_some_and(|value| !value.is_empty())));
+    }
+}